@@ -0,0 +1,103 @@
+// encode.rs
+//
+// Benchmarks for building, encoding and decoding messages, from small control
+// messages to the largest inv and headers messages peers send.
+//
+// Run with `cargo bench --bench encode`.
+//
+
+use btcnetmsg::{
+    Address,
+    BlockHeader,
+    Command,
+    Decode,
+    Encode,
+    Inventory,
+    Magic,
+    Message,
+    MessagePayload,
+    VersionMessage
+};
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    hashes::Hash,
+    BlockHash,
+    Network
+};
+use criterion::{
+    black_box,
+    criterion_group,
+    criterion_main,
+    BatchSize,
+    Criterion,
+    Throughput
+};
+use std::io::{
+    self,
+    Write
+};
+
+// Largest inv message accepted by bitcoin core (MAX_INV_SZ)
+const MAX_INV: usize = 50_000;
+// Headers returned for a single getheaders (MAX_HEADERS_RESULTS)
+const MAX_HEADERS: usize = 2_000;
+
+fn messages() -> Vec<(&'static str, MessagePayload, Command)> {
+    let genesis = genesis_block(Network::Bitcoin);
+    let inv = (0..MAX_INV).map(|i| {
+        let mut hash = [0; 32];
+        hash[..8].copy_from_slice(&(i as u64).to_le_bytes());
+        Inventory::Block(BlockHash::from_inner(hash))
+    }).collect();
+
+    vec![
+        ("ping", MessagePayload::PingPong(42), Command::Ping),
+        ("version", MessagePayload::Version(VersionMessage::from(Address::me())), Command::Version),
+        ("inv", MessagePayload::InvVect(inv), Command::Inv),
+        ("headers", MessagePayload::Headers(vec![BlockHeader::from(genesis.header); MAX_HEADERS]), Command::Headers),
+        ("block", MessagePayload::Block(genesis), Command::Block)
+    ]
+}
+
+// Writer that only counts calls, standing in for an unbuffered socket where each
+// call is a syscall
+struct CountingWriter(usize);
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += 1;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn bench_messages(c: &mut Criterion) {
+    for (name, payload, command) in messages() {
+        let msg = Message::new(payload.clone(), Magic::Main, command.clone());
+        let bytes = msg.to_bytes();
+
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function("new", |b| b.iter_batched(
+            || payload.clone(),
+            |payload| Message::new(payload, Magic::Main, command.clone()),
+            BatchSize::LargeInput
+        ));
+        group.bench_function("encode", |b| b.iter(|| {
+            let mut out = CountingWriter(0);
+            black_box(&msg).net_encode(&mut out);
+            out.0
+        }));
+        group.bench_function("to_bytes", |b| b.iter(|| black_box(&msg).to_bytes()));
+        let mut buf = vec![0; bytes.len()];
+        group.bench_function("encode_into", |b| b.iter(|| black_box(&msg).encode_into(&mut buf).unwrap()));
+        group.bench_function("decode", |b| b.iter(|| Message::net_decode(black_box(&bytes[..])).unwrap()));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_messages);
+criterion_main!(benches);
@@ -0,0 +1,115 @@
+#ifndef BTCNETMSG_H
+#define BTCNETMSG_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of the functions of the C interface
+typedef enum BtcnetmsgStatus {
+  BTCNETMSG_STATUS_OK = 0,
+  // A required pointer was null
+  BTCNETMSG_STATUS_NULL_POINTER,
+  // The bytes are not a valid message or payload
+  BTCNETMSG_STATUS_INVALID_DATA,
+  // The bytes end before the message does
+  BTCNETMSG_STATUS_INCOMPLETE,
+  // A string is not valid UTF-8 or not a valid command
+  BTCNETMSG_STATUS_INVALID_STRING,
+  // The buffer is too small, the length needed is written instead
+  BTCNETMSG_STATUS_BUFFER_TOO_SMALL,
+} BtcnetmsgStatus;
+
+// A decoded message
+typedef struct BtcnetmsgMessage BtcnetmsgMessage;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Decode a message from the start of `bytes`. On success `*out` holds a message to free
+// with `btcnetmsg_message_free` and `*consumed`, if not null, the length of the message
+// in bytes. The checksum is not verified.
+//
+// # Safety
+// `bytes` must point to `len` readable bytes and `out` to a writable pointer.
+BtcnetmsgStatus btcnetmsg_message_decode(const uint8_t *bytes,
+                                         uintptr_t len,
+                                         BtcnetmsgMessage **out,
+                                         uintptr_t *consumed);
+
+// Create a message of the network with the given magic, in the byte order of
+// `btcnetmsg_message_magic`, from a command and its encoded payload. Payloads of commands
+// without a decoder are kept as given. On success `*out` holds a message to free with
+// `btcnetmsg_message_free`.
+//
+// # Safety
+// `command` must be a NUL terminated string, `payload` must point to `payload_len` readable
+// bytes and `out` to a writable pointer.
+BtcnetmsgStatus btcnetmsg_message_new(uint32_t magic,
+                                      const char *command,
+                                      const uint8_t *payload,
+                                      uintptr_t payload_len,
+                                      BtcnetmsgMessage **out);
+
+// Encode a message into `buf`. `*written` is set to the length of the message, which is
+// the length needed when the status is `BufferTooSmall`.
+//
+// # Safety
+// `msg` must be a message from this interface, `buf` must point to `buf_len` writable
+// bytes and `written` to a writable length.
+BtcnetmsgStatus btcnetmsg_message_encode(const BtcnetmsgMessage *msg,
+                                         uint8_t *buf,
+                                         uintptr_t buf_len,
+                                         uintptr_t *written);
+
+// Encode the payload of a message into `buf`, like `btcnetmsg_message_encode`
+//
+// # Safety
+// See `btcnetmsg_message_encode`.
+BtcnetmsgStatus btcnetmsg_message_payload(const BtcnetmsgMessage *msg,
+                                          uint8_t *buf,
+                                          uintptr_t buf_len,
+                                          uintptr_t *written);
+
+// The network magic of a message, as the little endian integer of its wire bytes.
+// Zero for a null message.
+//
+// # Safety
+// `msg` must be null or a message from this interface.
+uint32_t btcnetmsg_message_magic(const BtcnetmsgMessage *msg);
+
+// The command of a message as a string to free with `btcnetmsg_string_free`, null for a
+// null message
+//
+// # Safety
+// `msg` must be null or a message from this interface.
+char *btcnetmsg_message_command(const BtcnetmsgMessage *msg);
+
+// A one line summary of a message, as printed by the command line tool, to free with
+// `btcnetmsg_string_free`. Null for a null message.
+//
+// # Safety
+// `msg` must be null or a message from this interface.
+char *btcnetmsg_message_to_string(const BtcnetmsgMessage *msg);
+
+// Free a message. Does nothing for null.
+//
+// # Safety
+// `msg` must be null or a message from this interface that was not freed yet.
+void btcnetmsg_message_free(BtcnetmsgMessage *msg);
+
+// Free a string returned by this interface. Does nothing for null.
+//
+// # Safety
+// `s` must be null or a string from this interface that was not freed yet.
+void btcnetmsg_string_free(char *s);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BTCNETMSG_H */
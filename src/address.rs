@@ -1,8 +1,16 @@
-use std::net::{
-    SocketAddr,
-    IpAddr,
-    Ipv4Addr,
-    Ipv6Addr
+use crate::encode::Error;
+use sha3::{
+    Digest,
+    Sha3_256
+};
+use std::{
+    convert::TryFrom,
+    net::{
+        SocketAddr,
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr
+    }
 };
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -42,4 +50,221 @@ impl From<SocketAddr> for Address {
     fn from(addr: SocketAddr) -> Self {
         Self(addr)
     }
-}
\ No newline at end of file
+}
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+/// Network address as defined by BIP155, used in addrv2 messages.
+///   <https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki>
+pub enum AddrV2 {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// Deprecated Tor v2 onion service (80 bit hash)
+    TorV2([u8; 10]),
+    /// Tor v3 onion service, stored as its ed25519 public key
+    TorV3([u8; 32]),
+    /// I2P destination, stored as the SHA256 hash of the destination
+    I2p([u8; 32]),
+    Cjdns(Ipv6Addr),
+    /// Address on a network this library does not know, kept so it can be relayed
+    Unknown(u8, Vec<u8>)
+}
+
+impl AddrV2 {
+    /// Longest address accepted in an addrv2 message (MAX_ADDRV2_SIZE in bitcoin core)
+    pub const MAX_LENGTH: usize = 512;
+
+    /// BIP155 network ID of the address
+    pub fn network_id(&self) -> u8 {
+        match self {
+            Self::Ipv4(_) => 0x01,
+            Self::Ipv6(_) => 0x02,
+            Self::TorV2(_) => 0x03,
+            Self::TorV3(_) => 0x04,
+            Self::I2p(_) => 0x05,
+            Self::Cjdns(_) => 0x06,
+            Self::Unknown(id, _) => *id
+        }
+    }
+
+    /// Raw address bytes as sent on the wire
+    pub fn bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Ipv4(ip) => ip.octets().to_vec(),
+            Self::Ipv6(ip) | Self::Cjdns(ip) => ip.octets().to_vec(),
+            Self::TorV2(hash) => hash.to_vec(),
+            Self::TorV3(key) | Self::I2p(key) => key.to_vec(),
+            Self::Unknown(_, bytes) => bytes.clone()
+        }
+    }
+
+    /// Create an address from its network ID and raw bytes.
+    /// Fails if the length does not match what the network requires.
+    pub fn from_bytes(network: u8, bytes: Vec<u8>) -> Result<Self, Error> {
+        let invalid = |bytes: &[u8]| Error::InvalidAddress(format!("Network {} address of {} bytes", network, bytes.len()));
+        let addr = match network {
+            0x01 => Self::Ipv4(<[u8; 4]>::try_from(&bytes[..]).map_err(|_| invalid(&bytes))?.into()),
+            0x02 => Self::Ipv6(<[u8; 16]>::try_from(&bytes[..]).map_err(|_| invalid(&bytes))?.into()),
+            0x03 => Self::TorV2(<[u8; 10]>::try_from(&bytes[..]).map_err(|_| invalid(&bytes))?),
+            0x04 => Self::TorV3(<[u8; 32]>::try_from(&bytes[..]).map_err(|_| invalid(&bytes))?),
+            0x05 => Self::I2p(<[u8; 32]>::try_from(&bytes[..]).map_err(|_| invalid(&bytes))?),
+            0x06 => Self::Cjdns(<[u8; 16]>::try_from(&bytes[..]).map_err(|_| invalid(&bytes))?.into()),
+            _ if bytes.len() > Self::MAX_LENGTH => return Err(invalid(&bytes)),
+            _ => Self::Unknown(network, bytes)
+        };
+        Ok(addr)
+    }
+}
+
+impl From<IpAddr> for AddrV2 {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self::Ipv4(ip),
+            IpAddr::V6(ip) => Self::Ipv6(ip)
+        }
+    }
+}
+
+impl std::fmt::Display for AddrV2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ipv4(ip) => write!(f, "{}", ip),
+            Self::Ipv6(ip) | Self::Cjdns(ip) => write!(f, "{}", ip),
+            Self::TorV2(hash) => write!(f, "{}.onion", base32_encode(hash)),
+            Self::TorV3(key) => {
+                // Onion v3 addresses are base32(pubkey | checksum | version)
+                let mut bytes = key.to_vec();
+                bytes.extend_from_slice(&onion_checksum(key));
+                bytes.push(ONION_VERSION);
+                write!(f, "{}.onion", base32_encode(&bytes))
+            },
+            Self::I2p(hash) => write!(f, "{}.b32.i2p", base32_encode(hash)),
+            Self::Unknown(id, bytes) => write!(f, "[network {}: {} bytes]", id, bytes.len())
+        }
+    }
+}
+
+impl std::str::FromStr for AddrV2 {
+    type Err = Error;
+
+    /// Parse an IP address, a Tor v3 onion address or an I2P b32 address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidAddress(s.to_string());
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Self::from(ip))
+        }
+
+        if let Some(name) = s.strip_suffix(".b32.i2p") {
+            // 52 characters encode the 32 byte hash
+            let bytes = base32_decode(name).filter(|_| name.len() == 52).ok_or_else(invalid)?;
+            return <[u8; 32]>::try_from(&bytes[..]).map(Self::I2p).map_err(|_| invalid())
+        }
+
+        let name = s.strip_suffix(".onion").ok_or_else(invalid)?;
+        let bytes = base32_decode(name).ok_or_else(invalid)?;
+        if bytes.len() != 35 || bytes[34] != ONION_VERSION {
+            return Err(invalid())
+        }
+
+        let key = <[u8; 32]>::try_from(&bytes[..32]).map_err(|_| invalid())?;
+        match bytes[32..34] == onion_checksum(&key) {
+            true => Ok(Self::TorV3(key)),
+            false => Err(invalid())
+        }
+    }
+}
+
+// Version byte at the end of Tor v3 onion addresses
+const ONION_VERSION: u8 = 0x03;
+
+/// First two bytes of SHA3-256(".onion checksum" | pubkey | version)
+fn onion_checksum(key: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(key);
+    hasher.update([ONION_VERSION]);
+    let hash = hasher.finalize();
+    [hash[0], hash[1]]
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Lowercase RFC4648 base32 without padding, as used by onion and I2P addresses
+pub(crate) fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut acc, mut bits) = (0u16, 0);
+    for byte in data {
+        acc = (acc << 8) | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((acc >> bits) & 0x1F) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((acc << (5 - bits)) & 0x1F) as usize] as char);
+    }
+    out
+}
+
+/// Decode unpadded base32, ignoring case. Trailing bits that do not fill a byte are dropped.
+pub(crate) fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u16, 0);
+    for c in data.bytes() {
+        let val = BASE32_ALPHABET.iter().position(|a| *a == c.to_ascii_lowercase())? as u16;
+        acc = (acc << 5) | val;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn onion_addresses() {
+        // Tor project's onion service, public key taken from the address itself
+        let name = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        let addr: AddrV2 = name.parse().unwrap();
+        assert!(matches!(addr, AddrV2::TorV3(_)));
+        assert_eq!(addr.to_string(), name);
+        assert_eq!(AddrV2::from_bytes(addr.network_id(), addr.bytes()).unwrap(), addr);
+
+        // Corrupted checksum
+        assert!("2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wic.onion".parse::<AddrV2>().is_err());
+        assert!("example.onion".parse::<AddrV2>().is_err());
+    }
+
+    #[test]
+    fn i2p_addresses() {
+        let name = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p";
+        let addr: AddrV2 = name.parse().unwrap();
+        assert_eq!(addr.network_id(), 0x05);
+        assert_eq!(addr.to_string(), name);
+        assert_eq!(AddrV2::from_bytes(0x05, addr.bytes()).unwrap(), addr);
+
+        assert!("ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnk.b32.i2p".parse::<AddrV2>().is_err());
+        assert!(AddrV2::from_bytes(0x05, vec![0; 31]).is_err());
+    }
+
+    #[test]
+    fn addrv2_lengths() {
+        assert_eq!(AddrV2::from_bytes(0x01, vec![1, 2, 3, 4]).unwrap(), AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(AddrV2::from_bytes(0x01, vec![1, 2, 3]).is_err());
+        assert!(AddrV2::from_bytes(0x04, vec![0; 33]).is_err());
+        assert_eq!(AddrV2::from_bytes(0x42, vec![7; 3]).unwrap(), AddrV2::Unknown(0x42, vec![7; 3]));
+        assert!(AddrV2::from_bytes(0x42, vec![0; AddrV2::MAX_LENGTH + 1]).is_err());
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32_decode("MZXW6YTBOI").unwrap(), b"foobar");
+        assert!(base32_decode("not base32!").is_none());
+    }
+}
@@ -0,0 +1,156 @@
+// blockdata/hash.rs
+//
+// 32 byte double SHA256 hashes, the form of txids, block hashes and the hash
+// that message checksums are cut from. Hashes are held in the byte order they
+// are encoded in and displayed reversed, the way bitcoin core and block explorers
+// print them.
+//
+
+use crate::{
+    encode::{
+        Decode,
+        Encode,
+        Error
+    },
+    msg::header::sha256d
+};
+use crate::bitcoin::{
+    hash_types::{
+        BlockHash,
+        Txid,
+        Wtxid
+    },
+    hashes::Hash as _
+};
+use std::{
+    fmt,
+    str::FromStr
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+/// A double SHA256 hash in wire byte order
+pub struct Hash([u8; 32]);
+
+impl Hash {
+    /// The all zero hash, used where a hash is absent such as the stop hash of a locator
+    pub const ZERO: Self = Self([0; 32]);
+
+    /// Hash data with double SHA256
+    pub fn sha256d<T: AsRef<[u8]>>(data: T) -> Self {
+        Self(sha256d(data))
+    }
+
+    pub const fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// The hash in wire byte order
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> [u8; 32] {
+        self.0
+    }
+
+    /// The first 4 bytes, which are the checksum of a message whose payload this is the hash of
+    pub fn checksum(&self) -> [u8; 4] {
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&self.0[..4]);
+        checksum
+    }
+}
+
+impl From<[u8; 32]> for Hash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+macro_rules! hash_conversions {
+    ($hash: ty) => {
+        impl From<$hash> for Hash {
+            fn from(hash: $hash) -> Self {
+                Self(hash.into_inner())
+            }
+        }
+
+        impl From<Hash> for $hash {
+            fn from(hash: Hash) -> Self {
+                <$hash>::from_inner(hash.0)
+            }
+        }
+    };
+}
+
+hash_conversions!(Txid);
+hash_conversions!(Wtxid);
+hash_conversions!(BlockHash);
+
+impl fmt::Display for Hash {
+    /// Hex in reversed byte order
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().rev().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash({})", self)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = Error;
+
+    /// Parse 64 hex digits in reversed byte order, as hashes are displayed
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(Error::InvalidData)
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().rev().enumerate() {
+            *byte = u8::from_str_radix(&s[i*2..i*2+2], 16).map_err(|_| Error::InvalidData)?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl Encode for Hash {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        self.0.net_encode(w)
+    }
+}
+
+impl Decode for Hash {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(Self(Decode::net_decode(r)?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdata::GENESIS_HASH;
+
+    #[test]
+    fn displays_reversed() {
+        let genesis = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+        let hash: Hash = genesis.parse().unwrap();
+        assert_eq!(hash.to_string(), genesis);
+        assert_eq!(hash.as_bytes()[31], 0x00);
+        assert_eq!(BlockHash::from(hash).to_string(), genesis);
+        assert_eq!(hash, Hash::from({ let mut b = GENESIS_HASH; b.reverse(); b }));
+
+        let mut encoded = vec![];
+        assert_eq!(hash.net_encode(&mut encoded), 32);
+        assert_eq!(Hash::net_decode(&encoded[..]).unwrap(), hash);
+
+        // Checksum of an empty payload
+        assert_eq!(Hash::sha256d([]).checksum(), [0x5d, 0xf6, 0xe0, 0xe2]);
+        assert!("00".parse::<Hash>().is_err() && "zz".repeat(32).parse::<Hash>().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+// blockdata/header.rs
+//
+// 80 byte block headers as sent in headers messages, with the proof of work
+// helpers header sync needs: expanding the compact nBits field into a target
+// and the expected work of meeting that target.
+//
+
+use crate::{
+    blockdata::hash::Hash,
+    encode::{
+        Decode,
+        Encode,
+        Error
+    }
+};
+use crate::bitcoin::{
+    self,
+    hash_types::BlockHash,
+    hashes::Hash as _,
+    util::uint::Uint256
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A block header
+pub struct BlockHeader {
+    pub version: i32,
+    /// Hash of the previous block's header
+    pub prev_blockhash: Hash,
+    pub merkle_root: Hash,
+    /// Unix time in seconds
+    pub time: u32,
+    /// The target in compact form, see [`BlockHeader::target`]
+    pub bits: u32,
+    pub nonce: u32
+}
+
+impl BlockHeader {
+    /// Encoded length
+    pub const SIZE: usize = 80;
+
+    /// The hash identifying the block, double SHA256 of the encoded header
+    pub fn block_hash(&self) -> BlockHash {
+        let mut encoded = Vec::with_capacity(Self::SIZE);
+        self.net_encode(&mut encoded);
+        Hash::sha256d(encoded).into()
+    }
+
+    /// The target the block hash must not exceed, expanded from the compact bits field.
+    /// Bits that encode a negative or overflowing target give a target of zero, which no
+    /// hash meets.
+    pub fn target(&self) -> Uint256 {
+        let exponent = self.bits >> 24;
+        let mantissa = self.bits & 0x007f_ffff;
+        let negative = mantissa != 0 && self.bits & 0x0080_0000 != 0;
+        let overflow = mantissa != 0 && (exponent > 34 || (mantissa > 0xff && exponent > 33) || (mantissa > 0xffff && exponent > 32));
+        if negative || overflow {
+            return Uint256::default()
+        }
+        let mantissa = Uint256([mantissa as u64, 0, 0, 0]);
+        match exponent {
+            0..=3 => mantissa >> (8 * (3 - exponent) as usize),
+            _ => mantissa << (8 * (exponent - 3) as usize)
+        }
+    }
+
+    /// The expected number of hashes to find a block at this header's target,
+    /// 2^256 / (target + 1). Zero for a target of zero.
+    pub fn work(&self) -> Uint256 {
+        let target = self.target();
+        if target == Uint256::default() {
+            return target
+        }
+        // 2^256 does not fit, but (2^256 - target - 1) / (target + 1) + 1 is the same
+        let one = Uint256([1, 0, 0, 0]);
+        (!target / (target + one)) + one
+    }
+
+    /// Whether the block hash meets the target
+    pub fn check_pow(&self) -> bool {
+        let target = self.target();
+        target != Uint256::default() && hash_value(&self.block_hash().into_inner()) <= target
+    }
+}
+
+// A hash in wire byte order as a 256 bit number
+fn hash_value(hash: &[u8; 32]) -> Uint256 {
+    let mut words = [0; 4];
+    for (word, bytes) in words.iter_mut().zip(hash.chunks(8)) {
+        let mut le = [0; 8];
+        le.copy_from_slice(bytes);
+        *word = u64::from_le_bytes(le);
+    }
+    Uint256(words)
+}
+
+impl From<bitcoin::BlockHeader> for BlockHeader {
+    fn from(header: bitcoin::BlockHeader) -> Self {
+        Self {
+            version: header.version,
+            prev_blockhash: header.prev_blockhash.into(),
+            merkle_root: Hash::from(header.merkle_root.into_inner()),
+            time: header.time,
+            bits: header.bits,
+            nonce: header.nonce
+        }
+    }
+}
+
+impl From<BlockHeader> for bitcoin::BlockHeader {
+    fn from(header: BlockHeader) -> Self {
+        Self {
+            version: header.version,
+            prev_blockhash: header.prev_blockhash.into(),
+            merkle_root: bitcoin::TxMerkleNode::from_inner(header.merkle_root.into_inner()),
+            time: header.time,
+            bits: header.bits,
+            nonce: header.nonce
+        }
+    }
+}
+
+impl Encode for BlockHeader {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        (self.version as u32).net_encode(&mut w) +
+        self.prev_blockhash.net_encode(&mut w) +
+        self.merkle_root.net_encode(&mut w) +
+        self.time.net_encode(&mut w) +
+        self.bits.net_encode(&mut w) +
+        self.nonce.net_encode(&mut w)
+    }
+}
+
+impl Decode for BlockHeader {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(Self {
+            version: u32::net_decode(&mut r)? as i32,
+            prev_blockhash: Decode::net_decode(&mut r)?,
+            merkle_root: Decode::net_decode(&mut r)?,
+            time: Decode::net_decode(&mut r)?,
+            bits: Decode::net_decode(&mut r)?,
+            nonce: Decode::net_decode(&mut r)?
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{
+        blockdata::constants::genesis_block,
+        consensus::serialize,
+        Network
+    };
+
+    #[test]
+    fn genesis_work() {
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let header = BlockHeader::from(genesis);
+        let mut encoded = vec![];
+        assert_eq!(header.net_encode(&mut encoded), BlockHeader::SIZE);
+        assert_eq!(encoded, serialize(&genesis));
+        assert_eq!(BlockHeader::net_decode(&encoded[..]).unwrap(), header);
+        assert_eq!(bitcoin::BlockHeader::from(header), genesis);
+
+        assert_eq!(header.block_hash(), genesis.block_hash());
+        assert_eq!((header.target(), header.work()), (genesis.target(), genesis.work()));
+        assert_eq!(header.work(), Uint256([0x1_0001_0001, 0, 0, 0]));
+        assert!(header.check_pow());
+
+        // Regtest's minimum difficulty and bits of a negative or overflowing target
+        let regtest = BlockHeader::from(genesis_block(Network::Regtest).header);
+        assert_eq!((regtest.target(), regtest.work()), (Uint256([0, 0, 0, 0x7fff_ff00_0000_0000]), Uint256([2, 0, 0, 0])));
+        assert!(regtest.check_pow());
+        for bits in [0x0480_0001, 0xff7f_ffff, 0] {
+            let header = BlockHeader { bits, ..header };
+            assert_eq!((header.target(), header.work()), (Uint256::default(), Uint256::default()));
+            assert!(!header.check_pow());
+        }
+    }
+}
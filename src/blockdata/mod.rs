@@ -4,6 +4,10 @@
 //
 //
 
+pub mod hash;
+pub mod header;
+pub mod transaction;
+
 pub use crate::bitcoin::{
     hash_types::BlockHash,
     hashes::Hash,
@@ -0,0 +1,113 @@
+// blockdata/transaction.rs
+//
+// The two ids of a transaction: the txid, hash of the legacy serialization
+// without witness data, and the wtxid, hash of the serialization with it
+// (BIP141). Peers announce transactions by either depending on whether they
+// negotiated wtxidrelay, and compact blocks identify them by short ids cut
+// from the wtxid.
+//
+
+use crate::{
+    blockdata::{
+        hash::Hash,
+        Transaction
+    },
+    msg::inventory::{
+        Inventory,
+        Txid,
+        Wtxid
+    }
+};
+use crate::bitcoin::consensus::Encodable;
+
+/// Computation of the ids of a transaction
+pub trait TxHashes {
+    /// Serialization without witness data
+    fn legacy_bytes(&self) -> Vec<u8>;
+
+    /// Serialization with witness data, the same as the legacy one for transactions
+    /// without any
+    fn witness_bytes(&self) -> Vec<u8>;
+
+    /// Hash of the legacy serialization
+    fn compute_txid(&self) -> Txid {
+        Hash::sha256d(self.legacy_bytes()).into()
+    }
+
+    /// Hash of the witness serialization
+    fn compute_wtxid(&self) -> Wtxid {
+        Hash::sha256d(self.witness_bytes()).into()
+    }
+
+    /// Whether an inventory item identifies this transaction, by txid or by wtxid
+    fn is_inventory(&self, inv: &Inventory) -> bool {
+        match inv {
+            Inventory::Tx(txid) | Inventory::WitnessTx(txid) => *txid == self.compute_txid(),
+            Inventory::Wtx(wtxid) => *wtxid == self.compute_wtxid(),
+            _ => false
+        }
+    }
+}
+
+impl TxHashes for Transaction {
+    fn legacy_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        // Writing to a vector cannot fail
+        let _ = self.version.consensus_encode(&mut bytes);
+        let _ = self.input.consensus_encode(&mut bytes);
+        let _ = self.output.consensus_encode(&mut bytes);
+        let _ = self.lock_time.consensus_encode(&mut bytes);
+        bytes
+    }
+
+    fn witness_bytes(&self) -> Vec<u8> {
+        if self.input.iter().all(|i| i.witness.is_empty()) {
+            return self.legacy_bytes()
+        }
+        let mut bytes = vec![];
+        let _ = self.version.consensus_encode(&mut bytes);
+        // Marker and flag
+        bytes.extend_from_slice(&[0x00, 0x01]);
+        let _ = self.input.consensus_encode(&mut bytes);
+        let _ = self.output.consensus_encode(&mut bytes);
+        for input in &self.input {
+            let _ = input.witness.consensus_encode(&mut bytes);
+        }
+        let _ = self.lock_time.consensus_encode(&mut bytes);
+        bytes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{
+        blockdata::constants::genesis_block,
+        consensus::encode::{
+            deserialize,
+            serialize
+        },
+        hashes::hex::FromHex,
+        Network
+    };
+
+    #[test]
+    fn computes_ids() {
+        // A coinbase without witness data has the same txid and wtxid
+        let coinbase = genesis_block(Network::Bitcoin).txdata[0].clone();
+        assert_eq!(coinbase.compute_txid(), coinbase.txid());
+        assert_eq!(Hash::from(coinbase.compute_wtxid()), Hash::from(coinbase.compute_txid()));
+
+        // One input spending a witness output with a two item witness
+        let hex = format!("02000000 0001 01 {} 00000000 00 ffffffff 01 e803000000000000 0151 02 0201ab 00 00000000", "11".repeat(32));
+        let tx: Transaction = deserialize(&Vec::<u8>::from_hex(&hex.replace(' ', "")).unwrap()).unwrap();
+        assert_eq!(tx.witness_bytes(), serialize(&tx));
+        assert_eq!(tx.legacy_bytes().len(), serialize(&tx).len() - 7);
+        assert_eq!((tx.compute_txid(), tx.compute_wtxid()), (tx.txid(), tx.wtxid()));
+        assert_ne!(Hash::from(tx.compute_wtxid()), Hash::from(tx.compute_txid()));
+
+        assert!(tx.is_inventory(&Inventory::Tx(tx.txid())) && tx.is_inventory(&Inventory::Wtx(tx.wtxid())));
+        assert!(!tx.is_inventory(&Inventory::Wtx(Hash::from(tx.txid()).into())));
+    }
+}
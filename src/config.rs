@@ -0,0 +1,262 @@
+// config.rs
+//
+// TOML configuration file holding the defaults of long running deployments:
+// the network, where to find peers, how to connect to them and how many
+// connections to keep. Every setting is optional so command line flags can
+// override any of them.
+//
+//      network = "signet"
+//      connections = 16
+//      max_inbound = 40
+//      dns_seeds = ["seed.example.org"]
+//      seed_cache = "seeds.dat"
+//      peers = ["203.0.113.5", "198.51.100.7:38334"]
+//      connect_only = true
+//      proxy = "127.0.0.1:9050"
+//      bind = "192.0.2.10:0"
+//      external = "203.0.113.80:8333"
+//      family = "prefer-ipv6"
+//      user_agent = "/Satoshi:27.0.0/"
+//      start_height = 850000
+//      relay = false
+//      connect_timeout = 10
+//      read_timeout = 1200
+//      write_timeout = 1200
+//      ping_timeout = 600
+//      inactivity_timeout = 1200
+//
+
+use crate::{
+    msg::{
+        agent::UserAgent,
+        header::Magic,
+        network::ProtocolVersion
+    },
+    net::{
+        peer::{
+            AddressFamily,
+            Peer
+        },
+        stream::StreamOptions,
+        Error
+    },
+    seeds::Seeds
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{
+        Path,
+        PathBuf
+    },
+    str::FromStr,
+    time::Duration
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Settings read from a configuration file, `None` or empty where not given
+pub struct Config {
+    pub network: Option<Magic>,
+    /// Peers to connect to instead of finding them through the seeds. Peers given without
+    /// a port are on the default port of the file's network.
+    pub peers: Vec<Peer>,
+    /// Connect to the peers and no others, see [`ConnectionManagerBuilder::connect_only`](crate::net::manager::ConnectionManagerBuilder::connect_only)
+    pub connect_only: Option<bool>,
+    /// DNS seeds queried in addition to the network's own
+    pub dns_seeds: Vec<String>,
+    /// File the DNS seeds' answers are cached in, see [`Seeds::candidates_cached`]
+    pub seed_cache: Option<PathBuf>,
+    /// SOCKS5 proxy to connect through
+    pub proxy: Option<SocketAddr>,
+    /// Local address to connect from
+    pub bind: Option<SocketAddr>,
+    /// Public address peers can reach us on, advertised to them
+    pub external: Option<SocketAddr>,
+    /// Address family of the peers connected to and taken from the seeds
+    pub family: Option<AddressFamily>,
+    /// User agent sent to peers, checked against BIP14
+    pub user_agent: Option<UserAgent>,
+    /// Height of our best chain sent to peers
+    pub start_height: Option<u32>,
+    /// Ask peers to announce transactions to us
+    pub relay: Option<bool>,
+    pub v2: Option<bool>,
+    pub connect_timeout: Option<Duration>,
+    /// Time a read may block before failing during the handshake, 0 blocks forever
+    pub read_timeout: Option<Duration>,
+    /// Time a write may block before failing, 0 blocks forever
+    pub write_timeout: Option<Duration>,
+    /// Time peers have to answer a ping
+    pub ping_timeout: Option<Duration>,
+    /// Time peers may send nothing before they are disconnected, 0 waits forever
+    pub inactivity_timeout: Option<Duration>,
+    /// Outbound connections to keep open
+    pub connections: Option<usize>,
+    /// Inbound connections to accept before evicting
+    pub max_inbound: Option<usize>,
+    /// Oldest protocol version accepted from peers
+    pub min_version: Option<ProtocolVersion>
+}
+
+// The file as written, before names and addresses are parsed
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    network: Option<String>,
+    peers: Vec<String>,
+    connect_only: Option<bool>,
+    dns_seeds: Vec<String>,
+    seed_cache: Option<PathBuf>,
+    proxy: Option<SocketAddr>,
+    bind: Option<SocketAddr>,
+    external: Option<SocketAddr>,
+    family: Option<String>,
+    user_agent: Option<String>,
+    start_height: Option<u32>,
+    relay: Option<bool>,
+    v2: Option<bool>,
+    connect_timeout: Option<u64>,
+    read_timeout: Option<u64>,
+    write_timeout: Option<u64>,
+    ping_timeout: Option<u64>,
+    inactivity_timeout: Option<u64>,
+    connections: Option<usize>,
+    max_inbound: Option<usize>,
+    min_version: Option<u32>
+}
+
+impl Config {
+    /// Read a configuration file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        fs::read_to_string(path)?.parse()
+            .map_err(|e| match e {
+                Error::Config(e) => Error::Config(format!("{}: {}", path.display(), e)),
+                e => e
+            })
+    }
+
+    /// Stream options with the proxy, bind and external addresses, user agent, start height, relay flag, transport, timeouts and stall detection of the file applied to the defaults
+    pub fn stream_options(&self) -> StreamOptions {
+        let defaults = StreamOptions::default();
+        StreamOptions {
+            connect_timeout: self.connect_timeout.unwrap_or(defaults.connect_timeout),
+            read_timeout: self.read_timeout.map_or(defaults.read_timeout, |t| Some(t).filter(|t| !t.is_zero())),
+            write_timeout: self.write_timeout.map_or(defaults.write_timeout, |t| Some(t).filter(|t| !t.is_zero())),
+            ping_timeout: self.ping_timeout.unwrap_or(defaults.ping_timeout),
+            inactivity_timeout: self.inactivity_timeout.map_or(defaults.inactivity_timeout, |t| Some(t).filter(|t| !t.is_zero())),
+            proxy: self.proxy.or(defaults.proxy),
+            bind: self.bind.or(defaults.bind),
+            external: self.external.or(defaults.external),
+            family: self.family.unwrap_or(defaults.family),
+            user_agent: self.user_agent.clone().unwrap_or(defaults.user_agent),
+            start_height: self.start_height.unwrap_or(defaults.start_height),
+            relay: self.relay.unwrap_or(defaults.relay),
+            v2: self.v2.unwrap_or(defaults.v2),
+            min_version: self.min_version.unwrap_or(defaults.min_version)
+        }
+    }
+
+    /// Seeds of a network with the extra DNS seeds and address family of the file applied
+    pub fn seeds(&self, magic: Magic) -> Seeds {
+        self.dns_seeds.iter().fold(Seeds::for_network(magic), |seeds, dns| seeds.with_dns(dns.as_str()))
+            .with_family(self.family.unwrap_or_default())
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    /// Parse the contents of a configuration file. Unknown settings are rejected so typos
+    /// do not go unnoticed.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file: File = toml::from_str(s).map_err(|e| Error::Config(e.to_string()))?;
+        let network: Option<Magic> = file.network.map(|n| n.parse()).transpose()?;
+        let peers = file.peers.iter()
+            .map(|p| Peer::parse_for_network(p, network.unwrap_or(Magic::Main)))
+            .collect::<Result<Vec<Peer>, _>>()?;
+        let user_agent = file.user_agent.map(|agent| UserAgent::parse(&agent)).transpose()?;
+        let family = file.family.map(|f| f.parse()).transpose()?;
+
+        Ok(Self {
+            network,
+            peers,
+            connect_only: file.connect_only,
+            dns_seeds: file.dns_seeds,
+            seed_cache: file.seed_cache,
+            proxy: file.proxy,
+            bind: file.bind,
+            external: file.external,
+            family,
+            user_agent,
+            start_height: file.start_height,
+            relay: file.relay,
+            v2: file.v2,
+            connect_timeout: file.connect_timeout.map(Duration::from_secs),
+            read_timeout: file.read_timeout.map(Duration::from_secs),
+            write_timeout: file.write_timeout.map(Duration::from_secs),
+            ping_timeout: file.ping_timeout.map(Duration::from_secs),
+            inactivity_timeout: file.inactivity_timeout.map(Duration::from_secs),
+            connections: file.connections,
+            max_inbound: file.max_inbound,
+            min_version: file.min_version.map(ProtocolVersion)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn parses_config() {
+        let config: Config = "
+            network = \"signet\"
+            connections = 16
+            max_inbound = 40
+            dns_seeds = [\"seed.example.org\"]
+            seed_cache = \"seeds.dat\"
+            peers = [\"203.0.113.5\", \"198.51.100.7:38334\"]
+            connect_only = true
+            proxy = \"127.0.0.1:9050\"
+            bind = \"192.0.2.10:0\"
+            external = \"203.0.113.80:8333\"
+            family = \"ipv6\"
+            user_agent = \"/Satoshi:27.0.0/\"
+            start_height = 850000
+            relay = true
+            connect_timeout = 10
+            read_timeout = 0
+            write_timeout = 30
+            ping_timeout = 600
+            inactivity_timeout = 0
+            min_version = 70016
+        ".parse().unwrap();
+        assert_eq!(config.network, Some(Magic::Signet));
+        assert_eq!((config.connections, config.max_inbound, config.connect_only), (Some(16), Some(40), Some(true)));
+        assert_eq!(config.seed_cache, Some(PathBuf::from("seeds.dat")));
+        assert_eq!(config.peers, vec![Peer::new(Ipv4Addr::new(203, 0, 113, 5), 38333), Peer::new(Ipv4Addr::new(198, 51, 100, 7), 38334)]);
+
+        let options = config.stream_options();
+        assert_eq!(options.proxy, Some(SocketAddr::from(([127, 0, 0, 1], 9050))));
+        assert_eq!(options.bind, Some(SocketAddr::from(([192, 0, 2, 10], 0))));
+        assert_eq!(options.external, Some(SocketAddr::from(([203, 0, 113, 80], 8333))));
+        assert_eq!((options.family, config.seeds(Magic::Signet).family), (AddressFamily::Ipv6Only, AddressFamily::Ipv6Only));
+        assert_eq!((options.user_agent.as_str(), options.start_height, options.relay), ("/Satoshi:27.0.0/", 850_000, true));
+        assert_eq!((options.connect_timeout, options.read_timeout), (Duration::from_secs(10), None));
+        assert_eq!(options.write_timeout, Some(Duration::from_secs(30)));
+        assert_eq!((options.ping_timeout, options.inactivity_timeout), (Duration::from_secs(600), None));
+        assert_eq!(options.min_version, ProtocolVersion::WTXID_RELAY);
+
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert_eq!(Config::default().stream_options(), StreamOptions::default());
+        assert!(matches!("conections = 3".parse::<Config>(), Err(Error::Config(_))));
+        assert!(matches!("network = \"moon\"".parse::<Config>(), Err(Error::Decode(encode::Error::UnknownNetwork(_)))));
+        assert!(matches!("user_agent = \"Satoshi\"".parse::<Config>(), Err(Error::Decode(encode::Error::InvalidUserAgent(_)))));
+        assert!(matches!("family = \"ipv5\"".parse::<Config>(), Err(Error::Config(_))));
+    }
+}
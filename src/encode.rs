@@ -21,27 +21,46 @@ use crate::{
         header::{
             Magic,
             Command,
-            MessageHeader
+            MessageHeader,
+            MAX_PAYLOAD_SIZE
         },
         network::{
             ServicesList,
             VersionMessage,
-            Service,
-            SERVICE_BITS,
+            ProtocolVersion,
             NetAddress,
-            TimestampedNetAddress
+            TimestampedNetAddress,
+            NetAddressV2
         },
         inventory::{
             Inventory,
+            InvVect,
             BlockdataLocatorInfo
         },
+        compact::{
+            SendCmpct,
+            HeaderAndShortIds,
+            PrefilledTransaction,
+            BlockTransactionsRequest,
+            BlockTransactions
+        },
+        bloom::{
+            BloomFilter,
+            MerkleBlock
+        },
+        cfilter::CFilter,
         VariableInteger
     },
-    address::Address,
+    address::{
+        Address,
+        AddrV2
+    },
+    msg::agent::UserAgent,
+
+    blockdata::header::BlockHeader,
 
     bitcoin::{
         Transaction,
-        BlockHeader,
         Block
     }
 };
@@ -73,7 +92,12 @@ pub enum Error {
     InvalidData,
     BadNetworkMagic(Magic),
     Io(std::io::Error),
-    UnknownCommand(String)
+    UnknownCommand(String),
+    InvalidUserAgent(String),
+    InvalidAddress(String),
+    UnknownNetwork(String),
+    /// The buffer given to encode into is smaller than the bytes needed
+    BufferTooSmall(usize)
 }
 
 
@@ -85,6 +109,19 @@ pub fn decode_partial<T: Decode>(data: &[u8]) -> Result<(T, usize), Error> {
     Ok((Decode::net_decode(&mut cursor)?, cursor.position() as usize))
 }
 
+/// Read exactly `len` bytes. The buffer grows as the bytes arrive instead of being allocated
+/// up front, so a length taken from a peer cannot exhaust memory.
+pub(crate) fn read_bytes<R: std::io::Read>(r: R, len: u64) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()))
+    }
+    Ok(buf)
+}
+
 /// Macro to encode integers in little endian.
 macro_rules! integer_le_encode {
     ($int: ty) => {
@@ -107,7 +144,7 @@ macro_rules! integer_le_decode {
                 Self: Sized
             {
                 let mut buf = [0; std::mem::size_of::<$int>()];
-                r.read_exact(&mut buf).expect("Failed to read");
+                r.read_exact(&mut buf)?;
                 
                 let mut ret: u64 = 0;
                 let mut i = buf.len() - 1;
@@ -158,7 +195,7 @@ macro_rules! array_decode {
                 Self: Sized
             {
                 let mut buf: [u8; $len] = [0; $len];
-                r.read_exact(&mut buf).expect("Failed to read");
+                r.read_exact(&mut buf)?;
                 
                 Ok(buf)
             }
@@ -221,7 +258,7 @@ impl Decode for VariableInteger {
         // Read the first byte as a length indicator and match it with protocol varint length indicators
         // to set the buffer length of the integer that follows
         let mut len_indic: [u8; 1] = [0; 1];
-        r.read_exact(&mut len_indic).expect("Failed to read");
+        r.read_exact(&mut len_indic)?;
         let mut buf: Vec<u8> = match len_indic[0] {
             0xFD => vec![0; 2],
             0xFE => vec![0; 4],
@@ -236,7 +273,7 @@ impl Decode for VariableInteger {
 
         // The varint did have a length indicating prefix.
         // Read the integer and append zeroes to cast it as a LE u64.
-        r.read_exact(&mut buf).expect("Failed to read");
+        r.read_exact(&mut buf)?;
         while buf.len() != 8 {
             buf.push(0x00);
         }
@@ -257,14 +294,11 @@ impl Decode for Magic {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let mut buf = [0; 4];
-        r.read(&mut buf).expect("Failed to read");
-        buf.reverse();
+        r.read_exact(&mut buf)?;
 
-        // If the network magic is not known, return an error.
-        match Magic::from(buf) {
-            Magic::Unknown(v) => return Err(Error::BadNetworkMagic(Magic::Unknown(v))),
-            x => Ok(x)
-        }
+        // Unknown network magics are decoded as custom magics. Checking the magic
+        // against the expected network is left to the caller.
+        Ok(Magic::from(buf))
     }
 }
 
@@ -282,7 +316,7 @@ impl Decode for Command {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let mut buf = [0; 12];
-        r.read(&mut buf).expect("Failed to read");
+        r.read_exact(&mut buf)?;
 
         Self::from_str(
         buf
@@ -331,9 +365,12 @@ impl Encode for Message {
         if self.payload == MessagePayload::EmptyPayload {
             assert_eq!(self.header.length, 0)
         }
-        
-        self.header.net_encode(&mut w) +
-        self.payload.net_encode(&mut w)
+
+        // Assembled first so the message takes a single write rather than one per field,
+        // which would each be a syscall on an unbuffered stream
+        let bytes = self.to_bytes();
+        w.write_all(&bytes).expect("Failed to write");
+        bytes.len()
     }
 }
 
@@ -341,10 +378,31 @@ impl Decode for Message {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let header: MessageHeader = Decode::net_decode(&mut r)?;
+        let payload = MessagePayload::decode_with(&header, &mut r)?;
+        
+        Ok(
+            Message {
+                header,
+                payload
+            }
+        )
+    }
+}
+
+impl MessagePayload {
+    /// Decode a message payload using the command and payload length from its header.
+    /// 
+    /// Message payload doesn't implement the [`Decode`] trait on it's own as
+    /// it cannot be decoded without the header context. Exactly `header.length` bytes
+    /// are consumed from the reader, regardless of how many the payload decoder uses.
+    pub fn decode_with<R>(header: &MessageHeader, r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        if header.length > MAX_PAYLOAD_SIZE {
+            return Err(Error::InvalidData)
+        }
+        let buf = read_bytes(r, header.length as u64)?;
+        let mut r = &buf[..];
 
-        // Message payload doesn't implement the [`Decode`] trait on it's own as
-        // it cannot be decoded without the header context.
-        // TODO: Read header.len bytes into a vector and then decode the vector to avoid comsuming more bytes than necessary.
         let payload: MessagePayload = match header.command {
             Command::Version => MessagePayload::Version(Decode::net_decode(&mut r)?),
             Command::Verack => MessagePayload::EmptyPayload,
@@ -354,14 +412,26 @@ impl Decode for Message {
             Command::Pong => MessagePayload::PingPong(Decode::net_decode(&mut r)?),
             Command::Addr => { 
                 let count: VariableInteger = Decode::net_decode(&mut r)?;
-                assert!(count.inner() <= 100); // Max of 100 addresses
+                if count.inner() > 1000 { return Err(Error::InvalidData) } // Max of 1000 addresses
                 let mut addrs: Vec<TimestampedNetAddress> = Vec::new();
                 for _ in 0..count.inner() {
                     addrs.push(Decode::net_decode(&mut r)?)
                 }
                 MessagePayload::AddrList(addrs)
             },
-            Command::GetAddr => MessagePayload::EmptyPayload,
+            Command::AddrV2 => {
+                let count: VariableInteger = Decode::net_decode(&mut r)?;
+                if count.inner() > 1000 { return Err(Error::InvalidData) } // Max of 1000 addresses
+                let mut addrs: Vec<NetAddressV2> = Vec::new();
+                for _ in 0..count.inner() {
+                    addrs.push(Decode::net_decode(&mut r)?)
+                }
+                MessagePayload::AddrV2List(addrs)
+            },
+            Command::GetAddr |
+            Command::SendAddrV2 |
+            Command::MemPool |
+            Command::FilterClear => MessagePayload::EmptyPayload,
             Command::Inv |
             Command::GetData |
             Command::NotFound => {
@@ -380,28 +450,36 @@ impl Decode for Message {
                 let count = VariableInteger::net_decode(&mut r)?.inner();
                 let mut headers: Vec<BlockHeader> = Vec::new();
                 for _ in 0..count {
-                    headers.push(Decodable::consensus_decode(&mut r)?)
+                    headers.push(Decode::net_decode(&mut r)?);
+                    // Each header is followed by a transaction count which is always zero
+                    VariableInteger::net_decode(&mut r)?;
                 }
                 MessagePayload::Headers(headers)
             },
             Command::Block => MessagePayload::Block(Decodable::consensus_decode(&mut r)?),
-
-            // Upon receiving an unknown/invalid command in the header...
-            Command::Unknown(_) => {
-                // Consume the payload and store it as a hex dump
-                let mut buf = vec![0; header.length as usize];
-                r.read_exact(&mut buf).expect("Failed to read");
-
-                MessagePayload::Dump(buf)
-            }
+            Command::SendCmpct => MessagePayload::SendCmpct(Decode::net_decode(&mut r)?),
+            Command::CmpctBlock => MessagePayload::CompactBlock(Decode::net_decode(&mut r)?),
+            Command::GetBlockTxn => MessagePayload::GetBlockTxn(Decode::net_decode(&mut r)?),
+            Command::BlockTxn => MessagePayload::BlockTxn(Decode::net_decode(&mut r)?),
+            Command::FilterLoad => MessagePayload::FilterLoad(Decode::net_decode(&mut r)?),
+            Command::MerkleBlock => MessagePayload::MerkleBlock(Decode::net_decode(&mut r)?),
+            Command::CFilter => MessagePayload::CFilter(Decode::net_decode(&mut r)?),
+
+            // Commands without a payload decoder yet and unknown/invalid commands
+            // in the header have their payload kept as it was received
+            Command::Reject |
+            Command::FeeFilter |
+            Command::FilterAdd |
+            Command::GetCFilters |
+            Command::GetCFHeaders |
+            Command::CFHeaders |
+            Command::GetCFCheckpt |
+            Command::CFCheckpt |
+            Command::SendTxRcncl |
+            Command::Unknown(_) => MessagePayload::Raw { command: header.command.clone(), bytes: buf.clone() }
         };
-        
-        Ok(
-            Message {
-                header,
-                payload
-            }
-        )
+
+        Ok(payload)
     }
 }
 
@@ -413,12 +491,23 @@ impl Encode for MessagePayload {
             MessagePayload::PingPong(int) => int.net_encode(w),
             MessagePayload::EmptyPayload =>  EmptyPayload.net_encode(w),
             MessagePayload::AddrList(addrs) => VariableInteger::from(addrs.len()).net_encode(&mut w) + addrs.net_encode(&mut w),
+            MessagePayload::AddrV2List(addrs) => VariableInteger::from(addrs.len()).net_encode(&mut w) + addrs.net_encode(&mut w),
             MessagePayload::InvVect(inv) => VariableInteger::from(inv.len()).net_encode(&mut w) + inv.net_encode(&mut w),
             MessagePayload::Transction(tx) => tx.consensus_encode(w).expect("Failed to write"),
             MessagePayload::BlockLocator(loc) => loc.net_encode(w),
             MessagePayload::Block(block) => block.consensus_encode(w).expect("Failed to write"),
-            MessagePayload::Headers(hdrs) => VariableInteger::from(hdrs.len()).net_encode(&mut w) + hdrs.iter().fold(0, |acc, h| acc + h.consensus_encode(&mut w).expect("Failed to write")),
-            MessagePayload::Dump(d) => d.net_encode(w)
+            MessagePayload::SendCmpct(send) => send.net_encode(w),
+            MessagePayload::CompactBlock(block) => block.net_encode(w),
+            MessagePayload::GetBlockTxn(req) => req.net_encode(w),
+            MessagePayload::BlockTxn(txs) => txs.net_encode(w),
+            MessagePayload::FilterLoad(filter) => filter.net_encode(w),
+            MessagePayload::MerkleBlock(block) => block.net_encode(w),
+            MessagePayload::CFilter(filter) => filter.net_encode(w),
+            MessagePayload::Headers(hdrs) => VariableInteger::from(hdrs.len()).net_encode(&mut w) + hdrs.iter().fold(0, |acc, h| acc + h.net_encode(&mut w) + VariableInteger(0).net_encode(&mut w)),
+            MessagePayload::Raw { bytes, .. } => {
+                w.write_all(bytes).expect("Failed to write");
+                bytes.len()
+            }
         }
     }
 }   
@@ -436,8 +525,7 @@ impl Decode for String {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let varint: VariableInteger = Decode::net_decode(&mut r)?;
-        let mut buf = vec![0; varint.inner() as usize];
-        r.read_exact(&mut buf).expect("Failed to read");
+        let buf = read_bytes(r, varint.inner())?;
 
         Ok(
             buf
@@ -448,6 +536,26 @@ impl Decode for String {
     }
 }
 
+impl Encode for UserAgent {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        self.as_str().to_string().net_encode(w)
+    }
+}
+
+/// User agents from peers are not checked against BIP14, only refused beyond
+/// [`UserAgent::MAX_LENGTH`] as bitcoin core does
+impl Decode for UserAgent {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let len = VariableInteger::net_decode(&mut r)?.inner();
+        if len > UserAgent::MAX_LENGTH as u64 {
+            return Err(Error::InvalidUserAgent(format!("User agent of {} bytes", len)))
+        }
+        Ok(Self::from_raw(read_bytes(r, len)?.iter().map(|x| *x as char).collect()))
+    }
+}
+
 impl Encode for Address {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
@@ -475,7 +583,7 @@ impl Decode for SocketAddr {
     where R: std::io::Read {
         let ip = Decode::net_decode(&mut r)?;
         let portb: [u8; 2] = Decode::net_decode(&mut r)?;
-        Ok(SocketAddr::new(ip, portb[0] as u16 >> 8 | portb[1] as u16))
+        Ok(SocketAddr::new(ip, u16::from_be_bytes(portb)))
     }
 }
 
@@ -544,18 +652,7 @@ impl Decode for Ipv6Addr {
 impl Encode for ServicesList {
     fn net_encode<W>(&self, w: W) -> usize
     where W: std::io::Write {
-        // Collect all the service flags and XOR them up
-        let flag: u64 = 
-        self
-            .get_flags()
-            .iter()
-            .fold(
-                0,
-                |acc, num| 
-                acc ^ num.value()
-            );
-
-        flag.net_encode(w) //always 8 bytes
+        self.bits().net_encode(w) //always 8 bytes
     }
 }
 
@@ -563,22 +660,7 @@ impl Decode for ServicesList {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let flags: u64 = Decode::net_decode(&mut r)?;
-
-        // Early exit for flags with no bits set...
-        if flags == 0 {
-            return Ok(ServicesList::default());
-        }
-
-        // Iterate through all possible set flags and record any flags that are set.
-        let mut services = ServicesList::new();
-        for bitp in SERVICE_BITS {
-            if flags & (1<<bitp) == (1<<bitp) {
-                let flag = Service::try_from_bit(1<<bitp)?;
-                services.add_flag(flag);
-            }
-        }
-
-        Ok(services)
+        Ok(ServicesList::from_bits(flags))
     }
 }
 
@@ -624,6 +706,60 @@ impl Decode for TimestampedNetAddress {
     }
 }
 
+impl Encode for AddrV2 {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        let bytes = self.bytes();
+        self.network_id().net_encode(&mut w) +
+        VariableInteger::from(bytes.len()).net_encode(&mut w) +
+        bytes.net_encode(&mut w)
+    }
+}
+
+impl Decode for AddrV2 {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let network: u8 = Decode::net_decode(&mut r)?;
+        let len = VariableInteger::net_decode(&mut r)?.inner() as usize;
+        if len > AddrV2::MAX_LENGTH {
+            return Err(Error::InvalidAddress(format!("Network {} address of {} bytes", network, len)))
+        }
+
+        let mut bytes = vec![0; len];
+        r.read_exact(&mut bytes)?;
+        AddrV2::from_bytes(network, bytes)
+    }
+}
+
+impl Encode for NetAddressV2 {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        // Timestamp is a 32bit int and services are a varint in addrv2
+        (self.timestamp.as_secs() as u32).net_encode(&mut w) +
+        VariableInteger(self.services.bits()).net_encode(&mut w) +
+        self.addr.net_encode(&mut w) +
+        self.port.to_be_bytes().net_encode(&mut w)
+    }
+}
+
+impl Decode for NetAddressV2 {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let secs: u32 = Decode::net_decode(&mut r)?;
+        let services = VariableInteger::net_decode(&mut r)?.inner();
+        let addr = Decode::net_decode(&mut r)?;
+        let port: [u8; 2] = Decode::net_decode(&mut r)?;
+        Ok(
+            Self::new(
+                Duration::from_secs(secs as u64),
+                ServicesList::from_bits(services),
+                addr,
+                u16::from_be_bytes(port)
+            )
+        )
+    }
+}
+
 impl Encode for Duration {
     fn net_encode<W>(&self, w: W) -> usize
     where W: std::io::Write {
@@ -642,38 +778,84 @@ impl Decode for Duration {
     }
 }
 
+impl Encode for ProtocolVersion {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        self.0.net_encode(w)
+    }
+}
+
+impl Decode for ProtocolVersion {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(Self(Decode::net_decode(&mut r)?))
+    }
+}
+
 impl Encode for VersionMessage {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
-        self.version.net_encode(&mut w) +
-        self.service.net_encode(&mut w) +
-        self.timestamp.net_encode(&mut w) +
-        self.addr_recv.net_encode(&mut w) +
-        self.addr_from.net_encode(&mut w) +
-        self.nonce.net_encode(&mut w) +
-        self.agent.net_encode(&mut w) +
-        self.start_height.net_encode(&mut w) +
-        (self.relay as u8).net_encode(&mut w)
+        let mut len = 
+            self.version.net_encode(&mut w) +
+            self.service.net_encode(&mut w) +
+            self.timestamp.net_encode(&mut w) +
+            self.addr_recv.net_encode(&mut w);
+
+        // Fields that only exist from certain protocol versions onwards
+        if self.version >= ProtocolVersion::ADDR_FROM {
+            len +=
+                self.addr_from.net_encode(&mut w) +
+                self.nonce.net_encode(&mut w) +
+                self.agent.net_encode(&mut w) +
+                self.start_height.net_encode(&mut w);
+        }
+        if self.version >= ProtocolVersion::RELAY {
+            len += (self.relay as u8).net_encode(&mut w);
+        }
+
+        len
     }
 }
 
 impl Decode for VersionMessage {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
-        let version: u32 = Decode::net_decode(&mut r)?;
+        let version: ProtocolVersion = Decode::net_decode(&mut r)?;
         let services: ServicesList = Decode::net_decode(&mut r)?;
         let timestamp: Duration = Decode::net_decode(&mut r)?;
         let addr_recv: NetAddress = Decode::net_decode(&mut r)?;
+
+        // Peers older than the addr_from version stop here
+        if version < ProtocolVersion::ADDR_FROM {
+            return Ok(VersionMessage::new(
+                version,
+                services,
+                timestamp,
+                addr_recv,
+                NetAddress::default(),
+                0,
+                UserAgent::from_raw(String::new()),
+                0,
+                true
+            ))
+        }
+
         let addr_from: NetAddress = Decode::net_decode(&mut r)?;
         let nonce: u64 = Decode::net_decode(&mut r)?;
-        let agent: String = Decode::net_decode(&mut r)?;
+        let agent: UserAgent = Decode::net_decode(&mut r)?;
         let start_height: u32 = Decode::net_decode(&mut r)?;
-        let relay = match u8::net_decode(&mut r)? {
-            0 => false,
-            _ => true
+
+        // The relay flag is optional even for peers that support it and defaults to true
+        let relay = if version >= ProtocolVersion::RELAY {
+            match u8::net_decode(&mut r) {
+                Ok(flag) => flag != 0,
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => true,
+                Err(e) => return Err(e)
+            }
+        } else {
+            true
         };
         
-        
         Ok(VersionMessage::new(
             version,
             services,
@@ -703,29 +885,19 @@ impl Decode for EmptyPayload {
 }
 
 
-impl Encode for Inventory {
+impl Encode for InvVect {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
-        self.identifier().net_encode(&mut w) +
-        match self {
-            Self::Error => [0; 32],
-            Self::Tx(_) => self.inner(),
-            Self::Block(_) => self.inner(),
-            Self::FilteredBlock(_) => self.inner(),
-            Self::CompactBlock(_) => self.inner(),
-            Self::WitnessTx(_) => self.inner(),
-            Self::WitnessBlock(_) => self.inner(),
-            Self::FilteredWitnessBlock(_) => self.inner(),
-            Self::Unknown{inv_type: _, hash: h} => *h
-        }.net_encode(&mut w)
+        self.inv_type.net_encode(&mut w) +
+        self.hash.net_encode(&mut w)
     }
 }
 
-impl Decode for Inventory {
+impl Decode for InvVect {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         Ok(
-            Self::from_id_and_hash(
+            Self::new(
                 Decode::net_decode(&mut r)?,
                 Decode::net_decode(&mut r)?
             )
@@ -733,6 +905,20 @@ impl Decode for Inventory {
     }
 }
 
+impl Encode for Inventory {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        InvVect::from(self).net_encode(w)
+    }
+}
+
+impl Decode for Inventory {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(InvVect::net_decode(r)?.into())
+    }
+}
+
 
 impl Encode for BlockdataLocatorInfo {
     fn net_encode<W>(&self, mut w: W) -> usize
@@ -765,6 +951,211 @@ impl Decode for BlockdataLocatorInfo {
 }
 
 
+impl Encode for SendCmpct {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        (self.announce as u8).net_encode(&mut w) +
+        self.version.net_encode(&mut w)
+    }
+}
+
+impl Decode for SendCmpct {
+    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
+        Ok(Self {
+            announce: u8::net_decode(&mut r)? != 0,
+            version: Decode::net_decode(&mut r)?
+        })
+    }
+}
+
+/// Indexes in compact block messages are encoded as the difference from the index
+/// after the previous one. `next` is updated to the index after this one.
+fn encode_index<W: std::io::Write>(index: u16, next: &mut u32, w: W) -> usize {
+    let diff = (index as u32).saturating_sub(*next);
+    *next = index as u32 + 1;
+    VariableInteger::from(diff).net_encode(w)
+}
+
+fn decode_index<R: std::io::Read>(next: &mut u32, r: R) -> Result<u16, Error> {
+    let diff = VariableInteger::net_decode(r)?.inner();
+    let index = (*next as u64).checked_add(diff).filter(|i| *i <= u16::MAX as u64).ok_or(Error::InvalidData)?;
+    *next = index as u32 + 1;
+    Ok(index as u16)
+}
+
+impl Encode for HeaderAndShortIds {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        let mut next = 0;
+        self.header.consensus_encode(&mut w).expect("Failed to write") +
+        self.nonce.net_encode(&mut w) +
+        VariableInteger::from(self.short_ids.len()).net_encode(&mut w) +
+        self.short_ids.iter().fold(0, |len, id| len + w.write(&id.to_le_bytes()[..6]).expect("Failed to write")) +
+        VariableInteger::from(self.prefilled.len()).net_encode(&mut w) +
+        self.prefilled.iter().fold(0, |len, p| {
+            len + encode_index(p.index, &mut next, &mut w) + p.tx.consensus_encode(&mut w).expect("Failed to write")
+        })
+    }
+}
+
+impl Decode for HeaderAndShortIds {
+    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
+        let header = Decodable::consensus_decode(&mut r)?;
+        let nonce = Decode::net_decode(&mut r)?;
+
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut short_ids = Vec::new();
+        for _ in 0..count {
+            // Short ids are 6 byte little endian integers
+            let mut buf = [0; 8];
+            r.read_exact(&mut buf[..6])?;
+            short_ids.push(u64::from_le_bytes(buf));
+        }
+
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut prefilled = Vec::new();
+        let mut next = 0;
+        for _ in 0..count {
+            prefilled.push(PrefilledTransaction {
+                index: decode_index(&mut next, &mut r)?,
+                tx: Decodable::consensus_decode(&mut r)?
+            });
+        }
+
+        Ok(Self { header, nonce, short_ids, prefilled })
+    }
+}
+
+impl Encode for BlockTransactionsRequest {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        let mut next = 0;
+        self.block_hash.net_encode(&mut w) +
+        VariableInteger::from(self.indexes.len()).net_encode(&mut w) +
+        self.indexes.iter().fold(0, |len, i| len + encode_index(*i, &mut next, &mut w))
+    }
+}
+
+impl Decode for BlockTransactionsRequest {
+    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
+        let block_hash = Decode::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut indexes = Vec::new();
+        let mut next = 0;
+        for _ in 0..count {
+            indexes.push(decode_index(&mut next, &mut r)?);
+        }
+
+        Ok(Self { block_hash, indexes })
+    }
+}
+
+impl Encode for BlockTransactions {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        self.block_hash.net_encode(&mut w) +
+        VariableInteger::from(self.transactions.len()).net_encode(&mut w) +
+        self.transactions.iter().fold(0, |len, tx| len + tx.consensus_encode(&mut w).expect("Failed to write"))
+    }
+}
+
+impl Decode for BlockTransactions {
+    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
+        let block_hash = Decode::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut transactions = Vec::new();
+        for _ in 0..count {
+            transactions.push(Decodable::consensus_decode(&mut r)?);
+        }
+
+        Ok(Self { block_hash, transactions })
+    }
+}
+
+impl Encode for BloomFilter {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        VariableInteger::from(self.filter.len()).net_encode(&mut w) +
+        self.filter.net_encode(&mut w) +
+        self.hash_funcs.net_encode(&mut w) +
+        self.tweak.net_encode(&mut w) +
+        self.flags.net_encode(&mut w)
+    }
+}
+
+impl Decode for BloomFilter {
+    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut filter = Vec::new();
+        for _ in 0..count {
+            filter.push(Decode::net_decode(&mut r)?);
+        }
+
+        Ok(Self {
+            filter,
+            hash_funcs: Decode::net_decode(&mut r)?,
+            tweak: Decode::net_decode(&mut r)?,
+            flags: Decode::net_decode(&mut r)?
+        })
+    }
+}
+
+impl Encode for MerkleBlock {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        self.header.net_encode(&mut w) +
+        self.total_transactions.net_encode(&mut w) +
+        VariableInteger::from(self.hashes.len()).net_encode(&mut w) +
+        self.hashes.net_encode(&mut w) +
+        VariableInteger::from(self.flags.len()).net_encode(&mut w) +
+        self.flags.net_encode(&mut w)
+    }
+}
+
+impl Decode for MerkleBlock {
+    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
+        let header = Decode::net_decode(&mut r)?;
+        let total_transactions = Decode::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            hashes.push(Decode::net_decode(&mut r)?);
+        }
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut flags = Vec::new();
+        for _ in 0..count {
+            flags.push(Decode::net_decode(&mut r)?);
+        }
+
+        Ok(Self { header, total_transactions, hashes, flags })
+    }
+}
+
+impl Encode for CFilter {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        self.filter_type.net_encode(&mut w) +
+        self.block_hash.net_encode(&mut w) +
+        VariableInteger::from(self.filter.len()).net_encode(&mut w) +
+        self.filter.net_encode(&mut w)
+    }
+}
+
+impl Decode for CFilter {
+    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
+        let filter_type = Decode::net_decode(&mut r)?;
+        let block_hash = Decode::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut filter = Vec::new();
+        for _ in 0..count {
+            filter.push(Decode::net_decode(&mut r)?);
+        }
+
+        Ok(Self { filter_type, block_hash, filter })
+    }
+}
+
+
 // Macro to implement hashing for the imported hash types from rust-bitcoin
 macro_rules! bitcoin_hash_encode {
     ($hash: ty) => {
@@ -784,6 +1175,13 @@ macro_rules! bitcoin_hash_encode {
     };
 }
 
+// Conversion of io::Error to Error
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
 // Conversion of encode::Error to Error
 impl From<crate::bitcoin::consensus::encode::Error> for Error {
     fn from(err: crate::bitcoin::consensus::encode::Error) -> Error {
@@ -810,7 +1208,6 @@ mod tests {
     use super::*;
     use crate::msg::network::Service;
     use bitcoin::hashes::Hash;
-    use bitcoin::TxMerkleNode;
 
     #[test]
     fn varint_test() {
@@ -826,14 +1223,21 @@ mod tests {
 
     #[test]
     fn network_magic() {
-        let mut main: Vec<u8> = Vec::new();
-        let mut test: Vec<u8> = Vec::new();
-
-        Magic::Main.net_encode(&mut main);
-        Magic::Test.net_encode(&mut test);
+        let magics = [
+            (Magic::Main, [0xF9, 0xBE, 0xB4, 0xD9]),
+            (Magic::Test, [0x0B, 0x11, 0x09, 0x07]),
+            (Magic::Testnet4, [0x1C, 0x16, 0x3F, 0x28]),
+            (Magic::Signet, [0x0A, 0x03, 0xCF, 0x40]),
+            (Magic::Regtest, [0xFA, 0xBF, 0xB5, 0xDA]),
+            (Magic::Custom([0x01, 0x02, 0x03, 0x04]), [0x01, 0x02, 0x03, 0x04])
+        ];
 
-        assert_eq!(main, [0xF9, 0xBE, 0xB4, 0xD9]);
-        assert_eq!(test, [0xFA, 0xBF, 0xB5, 0xDA]);
+        for (magic, bytes) in magics {
+            let mut enc: Vec<u8> = Vec::new();
+            magic.net_encode(&mut enc);
+            assert_eq!(enc, bytes);
+            assert_eq!(Magic::net_decode(&enc[..]).expect("Failed to decode"), magic);
+        }
     }
 
     #[test]
@@ -844,7 +1248,19 @@ mod tests {
         let mut encoded = Vec::new();
         flags.net_encode(&mut encoded);
         
-        assert_eq!(encoded, &[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+        assert_eq!(encoded, &[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        // Known and unknown bits survive a round trip
+        let bits: u64 = 1 | 8 | 1024 | 2048 | (1 << 24);
+        let mut enc = Vec::new();
+        bits.net_encode(&mut enc);
+        let dec: ServicesList = Decode::net_decode(&enc[..]).expect("Failed to decode");
+        assert!(dec.has(Service::Network));
+        assert!(dec.has(Service::Witness));
+        assert!(dec.has(Service::NetworkLimited));
+        assert!(dec.has(Service::P2PV2));
+        assert!(dec.has(Service::Unknown(1 << 24)));
+        assert_eq!(dec.bits(), bits);
     }
 
     #[test]
@@ -874,6 +1290,61 @@ mod tests {
         assert_eq!(int, dec);
     }
 
+    #[test]
+    fn message_single_write() {
+        // Counts the write calls made, each would be a syscall on a TcpStream
+        struct Writes(usize, Vec<u8>);
+
+        impl std::io::Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 += 1;
+                self.1.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let msg = Message::new(MessagePayload::Version(VersionMessage::from(Address::me())), Magic::Main, Command::Version);
+        let mut out = Writes(0, vec![]);
+        assert_eq!(msg.net_encode(&mut out), msg.to_bytes().len());
+        assert_eq!(out.0, 1);
+        assert_eq!(Message::net_decode(&out.1[..]).unwrap(), msg);
+    }
+
+    #[test]
+    fn encode_into_buffer() {
+        let msg = Message::new(MessagePayload::Version(VersionMessage::from(Address::me())), Magic::Main, Command::Version);
+        let bytes = msg.to_bytes();
+
+        let mut buf = [0xFF; 256];
+        assert_eq!(msg.encode_into(&mut buf).unwrap(), bytes.len());
+        assert_eq!(&buf[..bytes.len()], &bytes[..]);
+        assert_eq!(buf[bytes.len()], 0xFF);
+
+        // Nothing is written if the message does not fit
+        let mut short = vec![0; bytes.len() - 1];
+        assert!(matches!(msg.encode_into(&mut short), Err(Error::BufferTooSmall(n)) if n == bytes.len()));
+        assert!(short.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn raw_payload_round_trip() {
+        let payload = MessagePayload::Raw { command: Command::Unknown(String::from("future")), bytes: vec![0xde, 0xad, 0xbe, 0xef] };
+        let msg = Message::new(payload, Magic::Main, Command::Unknown(String::from("future")));
+        let bytes = msg.to_bytes();
+
+        let decoded = Message::net_decode(&bytes[..]).expect("Failed to decode");
+        assert_eq!(decoded, msg);
+        assert_eq!(decoded.to_bytes(), bytes);
+
+        // Known commands without a decoder are kept too
+        let reject = Message::new(MessagePayload::Raw { command: Command::Reject, bytes: vec![1, 2, 3] }, Magic::Main, Command::Reject);
+        assert_eq!(Message::net_decode(&reject.to_bytes()[..]).expect("Failed to decode"), reject);
+    }
+
     #[test]
     fn header_decode() {
         let header = MessageHeader::new(Magic::Main, Command::Verack, 00, [0x5D, 0xF6, 0xE0, 0xE2]);
@@ -883,6 +1354,25 @@ mod tests {
         assert_eq!(header, dec);
     }
 
+    #[test]
+    fn command_round_trip() {
+        for cmd in Command::ALL {
+            assert!(cmd.to_str().len() <= 12);
+            assert_eq!(&Command::from_str(cmd.to_str().to_string()).expect("Unknown command"), cmd);
+
+            let mut enc: Vec<u8> = Vec::new();
+            assert_eq!(cmd.net_encode(&mut enc), 12);
+            assert_eq!(&Command::net_decode(&enc[..]).expect("Failed to decode"), cmd);
+        }
+
+        // Each command string appears exactly once in the table
+        for (i, cmd) in Command::ALL.iter().enumerate() {
+            assert!(Command::ALL[i+1..].iter().all(|other| other.to_str() != cmd.to_str()));
+        }
+
+        assert!(matches!(Command::from_str("bogus".to_string()), Err(Error::UnknownCommand(_))));
+    }
+
     #[test]
     fn version_encode_decode() {
         let peer = crate::address::Address::me();
@@ -892,6 +1382,116 @@ mod tests {
         let dec: VersionMessage = Decode::net_decode(&enc[..]).expect("Failed to decode");
 
         assert_eq!(vm, dec);
+
+        // Oversized user agents are refused, even with the bytes all there
+        let agent = |len| VersionMessage::builder(peer).user_agent(UserAgent::from_raw("a".repeat(len))).build();
+        let mut enc = Vec::new();
+        agent(UserAgent::MAX_LENGTH).net_encode(&mut enc);
+        assert!(VersionMessage::net_decode(&enc[..]).is_ok());
+        let mut enc = Vec::new();
+        agent(UserAgent::MAX_LENGTH + 1).net_encode(&mut enc);
+        assert!(matches!(VersionMessage::net_decode(&enc[..]), Err(Error::InvalidUserAgent(_))));
+    }
+
+    #[test]
+    fn version_builder() {
+        let mut services = ServicesList::new();
+        services.add_flag(Service::Network);
+        services.add_flag(Service::Witness);
+
+        let vm = VersionMessage::builder(crate::address::Address::me())
+            .version(ProtocolVersion::WTXID_RELAY)
+            .services(services.clone())
+            .nonce(42)
+            .user_agent(UserAgent::new("custom", "1.0").unwrap())
+            .start_height(800000)
+            .relay(true)
+            .build();
+
+        assert_eq!(vm.version, ProtocolVersion::WTXID_RELAY);
+        assert_eq!(vm.service, services);
+        assert_eq!(vm.nonce, 42);
+        assert_eq!(vm.agent.as_str(), "/custom:1.0/");
+        assert_eq!(vm.start_height, 800000);
+        assert!(vm.relay);
+
+        let mut enc = Vec::new();
+        vm.net_encode(&mut enc);
+        let dec: VersionMessage = Decode::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(vm, dec);
+    }
+
+    #[test]
+    fn version_gated_fields() {
+        let mut vm = VersionMessage::from(crate::address::Address::me());
+
+        // Relay flag is only encoded from 70001
+        vm.version = ProtocolVersion(60002);
+        let mut old = Vec::new();
+        vm.net_encode(&mut old);
+        vm.version = ProtocolVersion::RELAY;
+        let mut new = Vec::new();
+        vm.net_encode(&mut new);
+        assert_eq!(new.len(), old.len() + 1);
+
+        // A missing relay flag decodes as true
+        let dec: VersionMessage = Decode::net_decode(&new[..new.len()-1]).expect("Failed to decode");
+        assert!(dec.relay);
+
+        // Version messages before 106 end after addr_recv
+        vm.version = ProtocolVersion(105);
+        let mut ancient = Vec::new();
+        assert_eq!(vm.net_encode(&mut ancient), 4 + 8 + 8 + 26);
+        let dec: VersionMessage = Decode::net_decode(&ancient[..]).expect("Failed to decode");
+        assert_eq!(dec.addr_recv, vm.addr_recv);
+        assert_eq!(dec.nonce, 0);
+    }
+
+    #[test]
+    fn net_address_formats() {
+        let addr = NetAddress::new(
+            ServicesList::default(),
+            crate::address::Address::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 8333)
+        );
+        let tsna = TimestampedNetAddress::new(Duration::from_secs(0x01020304), addr.clone());
+
+        // The version message format has no timestamp
+        let mut plain = Vec::new();
+        assert_eq!(addr.net_encode(&mut plain), 26);
+
+        // The addr message format prefixes a 4 byte timestamp
+        let mut stamped = Vec::new();
+        assert_eq!(tsna.net_encode(&mut stamped), 30);
+        assert_eq!(stamped[..4], [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(stamped[4..], plain[..]);
+        assert_eq!(&stamped[28..], &[0x20, 0x8D]);
+
+        let dec: TimestampedNetAddress = Decode::net_decode(&stamped[..]).expect("Failed to decode");
+        assert_eq!(dec, tsna);
+        assert_eq!(NetAddress::from(dec), addr);
+    }
+
+    #[test]
+    fn addrv2_encdec() {
+        let onion: AddrV2 = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion".parse().unwrap();
+        let addrs = vec![
+            NetAddressV2::new(Duration::from_secs(1), ServicesList::from_bits(0x409), onion, 8333),
+            NetAddressV2::new(Duration::from_secs(2), ServicesList::default(), AddrV2::Ipv4(Ipv4Addr::new(10, 0, 0, 1)), 8333),
+            NetAddressV2::new(Duration::from_secs(3), ServicesList::default(), AddrV2::Unknown(0x42, vec![1, 2, 3]), 1)
+        ];
+        let msg = Message::new(MessagePayload::AddrV2List(addrs), Magic::Main, Command::AddrV2);
+
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+        // Services are a varint and the onion key is prefixed by its network ID and length
+        assert_eq!(enc[24..35], [0x03, 0x01, 0x00, 0x00, 0x00, 0xFD, 0x09, 0x04, 0x04, 0x20, 0xD1]);
+
+        let dec: Message = Decode::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(dec, msg);
+
+        // Known networks must have the right address length
+        let bad = [0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x04, 0x00, 0x00];
+        assert!(NetAddressV2::net_decode(&bad[..]).is_err());
     }
 
     #[test]
@@ -904,6 +1504,49 @@ mod tests {
         assert_eq!(msg, dec);
     }
 
+    #[test]
+    fn inventory_types() {
+        let wtx = Inventory::from(InvVect::new(InvVect::MSG_WTX, [7; 32]));
+        assert_eq!(wtx, Inventory::Wtx(bitcoin::Wtxid::from_inner([7; 32])));
+        assert_eq!(InvVect::MSG_WITNESS_BLOCK, 0x40000002);
+
+        let msg = Message::new(MessagePayload::InvVect(vec![wtx, Inventory::Unknown { inv_type: 9, hash: [1; 32] }]), Magic::Main, Command::GetData);
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+        assert_eq!(&enc[25..29], &InvVect::MSG_WTX.to_le_bytes());
+        let dec: Message = Decode::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(msg, dec);
+    }
+
+    #[test]
+    fn payload_length_honoured() {
+        // Two back to back messages in one stream should decode independently
+        let ping = Message::new(MessagePayload::PingPong(42), Magic::Main, Command::Ping);
+        let verack = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::Verack);
+        let mut enc = Vec::new();
+        ping.net_encode(&mut enc);
+        verack.net_encode(&mut enc);
+
+        let mut r = &enc[..];
+        let first: Message = Decode::net_decode(&mut r).expect("Failed to decode");
+        let second: Message = Decode::net_decode(&mut r).expect("Failed to decode");
+        assert_eq!(first, ping);
+        assert_eq!(second, verack);
+        assert!(r.is_empty());
+
+        // Payloads shorter than their decoder expects are an error, not a panic
+        let header = MessageHeader::new(Magic::Main, Command::Ping, 4, [0; 4]);
+        assert!(MessagePayload::decode_with(&header, &[0u8; 4][..]).is_err());
+
+        // Lengths beyond what is there are refused before anything is allocated for them
+        let header = MessageHeader::new(Magic::Main, Command::Ping, MAX_PAYLOAD_SIZE as usize + 1, [0; 4]);
+        assert!(matches!(MessagePayload::decode_with(&header, &[0u8; 8][..]), Err(Error::InvalidData)));
+        let mut string = Vec::new();
+        VariableInteger(u64::MAX).net_encode(&mut string);
+        string.extend_from_slice(b"abc");
+        assert!(matches!(String::net_decode(&string[..]), Err(Error::Io(_))));
+    }
+
     #[test]
     fn blocklocator_obj_test() {
         let h1 = BlockHash::from_inner([0; 32]);
@@ -929,24 +1572,24 @@ mod tests {
         let headers = vec![
             BlockHeader {
                 version: 1,
-                prev_blockhash: BlockHash::from_slice(&[0; 32]).unwrap(),
-                merkle_root: TxMerkleNode::from_slice(&[0; 32]).unwrap(),
+                prev_blockhash: [0; 32].into(),
+                merkle_root: [0; 32].into(),
                 time: 1645835601,
                 bits: 0,
                 nonce: 1
             },
             BlockHeader {
                 version: 1,
-                prev_blockhash: BlockHash::from_slice(&[1; 32]).unwrap(),
-                merkle_root: TxMerkleNode::from_slice(&[1; 32]).unwrap(),
+                prev_blockhash: [1; 32].into(),
+                merkle_root: [1; 32].into(),
                 time: 1645836201,
                 bits: 0,
                 nonce: 5
             },
             BlockHeader {
                 version: 1,
-                prev_blockhash: BlockHash::from_slice(&[2; 32]).unwrap(),
-                merkle_root: TxMerkleNode::from_slice(&[2; 32]).unwrap(),
+                prev_blockhash: [2; 32].into(),
+                merkle_root: [2; 32].into(),
                 time: 1645837201,
                 bits: 0,
                 nonce: 900
@@ -960,4 +1603,40 @@ mod tests {
 
         assert_eq!(msg, dec)
     }
+
+    #[test]
+    fn compact_block_encdec() {
+        let coinbase = Transaction { version: 1, lock_time: 0, input: vec![], output: vec![] };
+        let cmpct = HeaderAndShortIds {
+            header: crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Regtest).header,
+            nonce: 7,
+            short_ids: vec![0xffff_ffff_ffff, 1],
+            prefilled: vec![
+                PrefilledTransaction { index: 0, tx: coinbase.clone() },
+                PrefilledTransaction { index: 3, tx: coinbase }
+            ]
+        };
+        let request = BlockTransactionsRequest { block_hash: BlockHash::from_inner([1; 32]), indexes: vec![1, 2, 5] };
+
+        // Indexes are sent as the gap from the index after the previous one
+        let mut enc = Vec::new();
+        request.net_encode(&mut enc);
+        assert_eq!(&enc[32..], &[3, 1, 0, 2]);
+
+        for (payload, command) in [
+            (MessagePayload::CompactBlock(cmpct), Command::CmpctBlock),
+            (MessagePayload::GetBlockTxn(request), Command::GetBlockTxn),
+            (MessagePayload::SendCmpct(SendCmpct { announce: true, version: 2 }), Command::SendCmpct)
+        ] {
+            let msg = Message::new(payload, Magic::Main, command);
+            let mut enc = Vec::new();
+            msg.net_encode(&mut enc);
+            assert_eq!(Message::net_decode(&enc[..]).expect("Failed to decode"), msg);
+        }
+
+        // Indexes past u16::MAX are rejected
+        let mut enc = BlockHash::from_inner([1; 32]).into_inner().to_vec();
+        enc.extend_from_slice(&[2, 0xfd, 0xff, 0xff, 0]);
+        assert!(BlockTransactionsRequest::net_decode(&enc[..]).is_err());
+    }
 }
\ No newline at end of file
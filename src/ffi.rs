@@ -0,0 +1,270 @@
+// ffi.rs
+//
+// C interface to the message encoder and decoder, so tools written in other
+// languages can reuse the wire format implementation. Messages are opaque
+// handles owned by the caller and freed with `btcnetmsg_message_free`.
+// The C header in include/btcnetmsg.h is generated from this file with
+// cbindgen. Only compiled with the `ffi` feature, build a shared library with
+//
+//      cargo rustc --lib --release --no-default-features --features ffi --crate-type cdylib
+//
+
+use crate::{
+    encode::{
+        decode_partial,
+        Encode,
+        Error
+    },
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Command,
+            Magic,
+            MessageHeader
+        }
+    }
+};
+use std::{
+    ffi::{
+        CStr,
+        CString
+    },
+    io,
+    os::raw::c_char,
+    ptr,
+    slice
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Result of the functions of the C interface
+pub enum BtcnetmsgStatus {
+    Ok = 0,
+    /// A required pointer was null
+    NullPointer,
+    /// The bytes are not a valid message or payload
+    InvalidData,
+    /// The bytes end before the message does
+    Incomplete,
+    /// A string is not valid UTF-8 or not a valid command
+    InvalidString,
+    /// The buffer is too small, the length needed is written instead
+    BufferTooSmall
+}
+
+impl From<Error> for BtcnetmsgStatus {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Self::Incomplete,
+            Error::BufferTooSmall(_) => Self::BufferTooSmall,
+            _ => Self::InvalidData
+        }
+    }
+}
+
+/// A decoded message
+pub struct BtcnetmsgMessage(Message);
+
+// The slice behind a pointer and length from C, empty for a null pointer
+unsafe fn input<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    match ptr.is_null() {
+        true => &[],
+        false => slice::from_raw_parts(ptr, len)
+    }
+}
+
+// Copy `bytes` into a caller's buffer, reporting the length written or needed
+unsafe fn copy_out(bytes: &[u8], buf: *mut u8, buf_len: usize, written: *mut usize) -> BtcnetmsgStatus {
+    if written.is_null() {
+        return BtcnetmsgStatus::NullPointer
+    }
+    *written = bytes.len();
+    if bytes.len() > buf_len {
+        return BtcnetmsgStatus::BufferTooSmall
+    }
+    if !bytes.is_empty() {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len());
+    }
+    BtcnetmsgStatus::Ok
+}
+
+fn string(s: &str) -> *mut c_char {
+    // Command names and summaries hold no NUL bytes but are cut at one just in case
+    let s = s.split('\0').next().unwrap_or_default();
+    CString::new(s).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Decode a message from the start of `bytes`. On success `*out` holds a message to free
+/// with `btcnetmsg_message_free` and `*consumed`, if not null, the length of the message
+/// in bytes. The checksum is not verified.
+///
+/// # Safety
+/// `bytes` must point to `len` readable bytes and `out` to a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_decode(bytes: *const u8, len: usize, out: *mut *mut BtcnetmsgMessage, consumed: *mut usize) -> BtcnetmsgStatus {
+    if out.is_null() || (bytes.is_null() && len > 0) {
+        return BtcnetmsgStatus::NullPointer
+    }
+    match decode_partial::<Message>(input(bytes, len)) {
+        Ok((msg, read)) => {
+            *out = Box::into_raw(Box::new(BtcnetmsgMessage(msg)));
+            if !consumed.is_null() {
+                *consumed = read;
+            }
+            BtcnetmsgStatus::Ok
+        },
+        Err(e) => e.into()
+    }
+}
+
+/// Create a message of the network with the given magic, in the byte order of
+/// `btcnetmsg_message_magic`, from a command and its encoded payload. Payloads of commands
+/// without a decoder are kept as given. On success `*out` holds a message to free with
+/// `btcnetmsg_message_free`.
+///
+/// # Safety
+/// `command` must be a NUL terminated string, `payload` must point to `payload_len` readable
+/// bytes and `out` to a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_new(magic: u32, command: *const c_char, payload: *const u8, payload_len: usize, out: *mut *mut BtcnetmsgMessage) -> BtcnetmsgStatus {
+    if out.is_null() || command.is_null() || (payload.is_null() && payload_len > 0) {
+        return BtcnetmsgStatus::NullPointer
+    }
+    let command = match CStr::from_ptr(command).to_str() {
+        Ok(s) if s.len() <= 12 => Command::from_str(s.to_string()).unwrap_or_else(|_| Command::Unknown(s.to_string())),
+        _ => return BtcnetmsgStatus::InvalidString
+    };
+    let magic = Magic::from(magic.to_le_bytes());
+    let header = MessageHeader::new(magic, command.clone(), payload_len, [0; 4]);
+    match MessagePayload::decode_with(&header, input(payload, payload_len)) {
+        Ok(payload) => {
+            *out = Box::into_raw(Box::new(BtcnetmsgMessage(Message::new(payload, magic, command))));
+            BtcnetmsgStatus::Ok
+        },
+        Err(e) => e.into()
+    }
+}
+
+/// Encode a message into `buf`. `*written` is set to the length of the message, which is
+/// the length needed when the status is `BufferTooSmall`.
+///
+/// # Safety
+/// `msg` must be a message from this interface, `buf` must point to `buf_len` writable
+/// bytes and `written` to a writable length.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_encode(msg: *const BtcnetmsgMessage, buf: *mut u8, buf_len: usize, written: *mut usize) -> BtcnetmsgStatus {
+    match msg.as_ref() {
+        Some(msg) => copy_out(&msg.0.to_bytes(), buf, buf_len, written),
+        None => BtcnetmsgStatus::NullPointer
+    }
+}
+
+/// Encode the payload of a message into `buf`, like `btcnetmsg_message_encode`
+///
+/// # Safety
+/// See `btcnetmsg_message_encode`.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_payload(msg: *const BtcnetmsgMessage, buf: *mut u8, buf_len: usize, written: *mut usize) -> BtcnetmsgStatus {
+    match msg.as_ref() {
+        Some(msg) => {
+            let mut payload = vec![];
+            msg.0.payload.net_encode(&mut payload);
+            copy_out(&payload, buf, buf_len, written)
+        },
+        None => BtcnetmsgStatus::NullPointer
+    }
+}
+
+/// The network magic of a message, as the little endian integer of its wire bytes.
+/// Zero for a null message.
+///
+/// # Safety
+/// `msg` must be null or a message from this interface.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_magic(msg: *const BtcnetmsgMessage) -> u32 {
+    msg.as_ref().map_or(0, |msg| msg.0.header.magic.bytes())
+}
+
+/// The command of a message as a string to free with `btcnetmsg_string_free`, null for a
+/// null message
+///
+/// # Safety
+/// `msg` must be null or a message from this interface.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_command(msg: *const BtcnetmsgMessage) -> *mut c_char {
+    msg.as_ref().map_or(ptr::null_mut(), |msg| string(msg.0.header.command.to_str()))
+}
+
+/// A one line summary of a message, as printed by the command line tool, to free with
+/// `btcnetmsg_string_free`. Null for a null message.
+///
+/// # Safety
+/// `msg` must be null or a message from this interface.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_to_string(msg: *const BtcnetmsgMessage) -> *mut c_char {
+    msg.as_ref().map_or(ptr::null_mut(), |msg| string(&msg.0.to_string()))
+}
+
+/// Free a message. Does nothing for null.
+///
+/// # Safety
+/// `msg` must be null or a message from this interface that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_message_free(msg: *mut BtcnetmsgMessage) {
+    if !msg.is_null() {
+        drop(Box::from_raw(msg));
+    }
+}
+
+/// Free a string returned by this interface. Does nothing for null.
+///
+/// # Safety
+/// `s` must be null or a string from this interface that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn btcnetmsg_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_messages() {
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping).to_bytes();
+        unsafe {
+            let (mut msg, mut consumed) = (ptr::null_mut(), 0);
+            assert_eq!(btcnetmsg_message_decode(ping.as_ptr(), ping.len(), &mut msg, &mut consumed), BtcnetmsgStatus::Ok);
+            assert_eq!((consumed, btcnetmsg_message_magic(msg)), (ping.len(), Magic::Main.bytes()));
+
+            let summary = btcnetmsg_message_to_string(msg);
+            assert_eq!(CStr::from_ptr(summary).to_str(), Ok("ping: nonce=7"));
+            btcnetmsg_string_free(summary);
+
+            // Too small a buffer reports the length needed
+            let (mut buf, mut written) = ([0; 64], 0);
+            assert_eq!(btcnetmsg_message_encode(msg, buf.as_mut_ptr(), 4, &mut written), BtcnetmsgStatus::BufferTooSmall);
+            assert_eq!(written, ping.len());
+            assert_eq!(btcnetmsg_message_encode(msg, buf.as_mut_ptr(), buf.len(), &mut written), BtcnetmsgStatus::Ok);
+            assert_eq!(&buf[..written], &ping[..]);
+            btcnetmsg_message_free(msg);
+
+            let mut created = ptr::null_mut();
+            let nonce = 7u64.to_le_bytes();
+            let command = CString::new("ping").unwrap();
+            assert_eq!(btcnetmsg_message_new(Magic::Main.bytes(), command.as_ptr(), nonce.as_ptr(), nonce.len(), &mut created), BtcnetmsgStatus::Ok);
+            assert_eq!(btcnetmsg_message_encode(created, buf.as_mut_ptr(), buf.len(), &mut written), BtcnetmsgStatus::Ok);
+            assert_eq!(&buf[..written], &ping[..]);
+            btcnetmsg_message_free(created);
+
+            assert_eq!(btcnetmsg_message_decode(ping.as_ptr(), 30, &mut msg, ptr::null_mut()), BtcnetmsgStatus::Incomplete);
+            assert_eq!(btcnetmsg_message_decode(ptr::null(), 4, &mut msg, ptr::null_mut()), BtcnetmsgStatus::NullPointer);
+        }
+    }
+}
@@ -10,6 +10,22 @@
 //
 //  - Message structures refactor to enforce payload restrictions based on command type.
 
+//! Encoding, decoding and networking for the Bitcoin P2P protocol.
+//!
+//! - [`msg`] holds the message structures, starting from [`Message`] and [`MessagePayload`].
+//! - [`encode`] serializes them to and from the wire format with [`Encode`] and [`Decode`].
+//! - [`net`] connects to peers, completes the handshake and exchanges messages, from a
+//!   single [`Connection`](net::connection::Connection) to a pool of them kept open by a
+//!   [`ConnectionManager`](net::manager::ConnectionManager).
+//! - [`params`] holds what differs between networks: magic, port, seeds and genesis block.
+//! - [`seeds`] finds peers to connect to through DNS and fixed seeds, looking up the DNS
+//!   seeds with a [`Resolver`](resolver::Resolver).
+//! - `config` reads these settings from a TOML file, with the `config` feature.
+//!
+//! `net`, `seeds` and `resolver` are part of the default `net` feature. Without it, the message and
+//! encoding layers build on their own, including for `wasm32-unknown-unknown`.
+//!
+//! The `btcnetmsg` binary is a small consumer of this API.
 
 
 // Modules
@@ -17,6 +33,23 @@ pub mod msg;
 pub mod encode;
 pub mod blockdata;
 pub mod address;
+pub mod params;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(feature = "net")]
+pub mod seeds;
+#[cfg(feature = "net")]
+pub mod resolver;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+// The conversions generated by pyo3's macros for `PyResult` returns trip this lint
+#[allow(clippy::useless_conversion)]
+pub mod python;
 
 // Re-exports
 pub use bitcoin as bitcoin;
@@ -33,14 +66,22 @@ pub use msg::{
 
     network::{
         VersionMessage,
+        VersionMessageBuilder,
+        ProtocolVersion,
         ServicesList,
         Service
     },
-    inventory::Inventory
+    inventory::{
+        Inventory,
+        InvVect
+    },
+    agent::UserAgent
 };
 pub use encode::{
     Encode,
     Decode,
     Error
 };
-pub use address::Address;
\ No newline at end of file
+pub use address::Address;
+pub use blockdata::header::BlockHeader;
+pub use params::Params;
\ No newline at end of file
@@ -0,0 +1,781 @@
+// main.rs
+//
+// Command line tool built on the btcnetmsg library. Each subcommand is a thin
+// wrapper around the library: connecting to or accepting peers and printing
+// their messages, crawling the network, decoding recordings, broadcasting
+// transactions, downloading blocks, watching relayed transactions and how they
+// propagate, and checking on the DNS seeds.
+//
+// Defaults can be kept in a TOML file passed with --config, see the config
+// module of the library. Flags given on the command line take precedence.
+//
+// Log output is controlled with RUST_LOG, e.g. RUST_LOG=btcnetmsg=debug.
+//
+// Ctrl-C or SIGTERM stops connect and listen cleanly: connections are closed
+// and the known addresses and bans saved. Mempool and propagation stop once the
+// next transaction or timeline is printed. A second signal exits immediately.
+// State files that cannot be loaded or saved, and a metrics endpoint or
+// dashboard that fails, are reported without stopping the connections.
+//
+
+use btcnetmsg::{
+    blockdata::BlockHash,
+    config::Config,
+    net::{
+        addrman::AddrMan,
+        anchors,
+        blocks::{
+            self,
+            BlockSummary
+        },
+        broadcast::{
+            Broadcast,
+            BROADCAST_TIMEOUT
+        },
+        capture::Capture,
+        connection::Connection,
+        crawler::{
+            CrawlOptions,
+            Crawler
+        },
+        feeler::FEELER_INTERVAL,
+        manager::{
+            ConnectionManager,
+            ConnectionManagerBuilder,
+            ADVERTISE_INTERVAL,
+            MAX_INBOUND
+        },
+        mempool::MempoolMonitor,
+        misbehavior::BanList,
+        propagation::PropagationTracker,
+        peer::{
+            AddressFamily,
+            Peer
+        },
+        peerdb,
+        reader::ChecksumPolicy,
+        replay::{
+            Replay,
+            ReplayedMessage
+        },
+        stream::StreamOptions,
+        Error
+    },
+    seeds::{
+        Seeds,
+        RESOLVE_TIMEOUT
+    },
+    Magic,
+    ProtocolVersion,
+    UserAgent
+};
+use clap::{
+    Args,
+    Parser,
+    Subcommand,
+    ValueEnum
+};
+use std::{
+    fs::File,
+    io::{
+        self,
+        BufReader,
+        Write
+    },
+    net::{
+        SocketAddr,
+        TcpStream
+    },
+    path::{
+        Path,
+        PathBuf
+    },
+    process::{
+        self,
+        ExitCode
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering
+        },
+        Arc
+    },
+    time::Duration
+};
+use tracing_subscriber::EnvFilter;
+#[cfg(feature = "geoip")]
+use btcnetmsg::net::geoip::GeoIp;
+
+#[derive(Parser)]
+#[command(name = "btcnetmsg", version, about = "Talk to and listen in on Bitcoin P2P nodes")]
+struct Cli {
+    /// TOML file with default settings, overridden by flags
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Network to use: main, testnet, testnet4, signet, regtest or the magic bytes in hex [default: main]
+    #[arg(long, global = true, value_parser = parse_network)]
+    network: Option<Magic>,
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Keep connections to peers open and print the messages they send
+    Connect {
+        #[command(flatten)]
+        peers: PeerArgs,
+        /// Outbound connections to keep open [default: 8]
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        connections: Option<u16>,
+        /// Keep private, loopback and other unroutable addresses peers send, for local networks
+        #[arg(long)]
+        allow_unroutable: bool,
+        /// Every two minutes, test an address never connected to with a short lived connection
+        #[arg(long)]
+        feelers: bool,
+        /// Connect to the peers given and no others, without querying the DNS seeds
+        #[arg(long)]
+        connect_only: bool,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        state: StateArgs,
+        /// Serve Prometheus metrics at http://ADDR/metrics
+        #[cfg(feature = "metrics")]
+        #[arg(long, value_name = "ADDR")]
+        metrics: Option<SocketAddr>,
+        /// Show a dashboard of the connected peers instead of printing messages
+        #[cfg(feature = "tui")]
+        #[arg(long)]
+        tui: bool
+    },
+    /// Accept inbound connections and print the messages peers send
+    Listen {
+        /// Address to accept connections on [default: 0.0.0.0 on the network's port]
+        #[arg(long)]
+        bind: Option<SocketAddr>,
+        /// Inbound connections to accept before evicting existing ones for new ones [default: 114]
+        #[arg(long)]
+        max_inbound: Option<usize>,
+        #[command(flatten)]
+        stream: StreamArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        state: StateArgs
+    },
+    /// Visit peers breadth first, collecting the addresses they know of
+    Crawl {
+        #[command(flatten)]
+        peers: PeerArgs,
+        /// Hops away from the starting peers to visit
+        #[arg(long, default_value_t = 2)]
+        depth: u32,
+        /// Peers visited at the same time
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u16).range(1..))]
+        concurrency: u16,
+        /// Stop recording new peers once this many are known
+        #[arg(long, default_value_t = 10_000)]
+        max_peers: usize,
+        /// Visit private, loopback and other unroutable addresses, for local networks
+        #[arg(long)]
+        allow_unroutable: bool,
+        /// Start from the peers in a .json or .csv file as well, such as one written with --export-peers
+        #[arg(long, value_name = "FILE")]
+        import_peers: Option<PathBuf>,
+        /// Write the reachable peers to a .json or .csv file
+        #[arg(long = "export-peers", alias = "export", value_name = "FILE")]
+        export: Option<PathBuf>,
+        /// MaxMind DB file to look up the country and ASN of peers in, can be repeated
+        #[cfg(feature = "geoip")]
+        #[arg(long, value_name = "FILE")]
+        geoip: Vec<PathBuf>
+    },
+    /// Decode a capture file or raw dump of messages without connecting to anyone
+    Decode {
+        /// Capture file written with --capture, or raw messages as sent on the wire
+        file: PathBuf,
+        /// How to print decoded messages
+        #[arg(long, value_enum, default_value_t = Output::Text)]
+        output: Output,
+        /// Decode messages whose checksum does not match instead of reporting an error
+        #[arg(long)]
+        lenient_checksums: bool
+    },
+    /// Announce a transaction to peers and report which of them accepted it
+    Broadcast {
+        /// Serialized transaction in hex, read from stdin if left out or given as -
+        tx: Option<String>,
+        #[command(flatten)]
+        peers: PeerArgs,
+        /// Peers to announce to when none are given with --peer [default: 8]
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        connections: Option<u16>,
+        /// Seconds to wait for peers to request and relay the transaction
+        #[arg(long, default_value_t = BROADCAST_TIMEOUT.as_secs())]
+        timeout: u64
+    },
+    /// Download a block from a peer, check it matches its hash and save it
+    Block {
+        /// Hash of the block, as block explorers display it
+        #[arg(value_parser = parse_block_hash)]
+        hash: BlockHash,
+        /// File to write the block to, in its raw serialization
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+        /// Also write a JSON summary of the block to FILE
+        #[arg(long, value_name = "FILE")]
+        summary: Option<PathBuf>,
+        #[command(flatten)]
+        peers: PeerArgs
+    },
+    /// Print the transactions a peer relays as they arrive, with their size and fee when known
+    Mempool {
+        #[command(flatten)]
+        peers: PeerArgs
+    },
+    /// Keep connections to peers open and report how long each transaction takes to be announced by all of them
+    Propagation {
+        #[command(flatten)]
+        peers: PeerArgs,
+        /// Outbound connections to keep open [default: 8]
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        connections: Option<u16>,
+        /// Seconds after a transaction is first announced to wait for announcements from the other peers
+        #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        window: u64,
+        /// Print each transaction's timeline as a JSON object per line
+        #[arg(long)]
+        jsonl: bool
+    },
+    /// Resolve the network's DNS seeds and report which are alive and how useful their results are
+    Seeds {
+        /// Seconds each seed is given to answer
+        #[arg(long, default_value_t = RESOLVE_TIMEOUT.as_secs())]
+        timeout: u64
+    }
+}
+
+#[derive(Args)]
+struct PeerArgs {
+    /// Peer to connect to, can be repeated. The port defaults to the network's.
+    /// Peers are found through the DNS seeds otherwise.
+    #[arg(long = "peer", value_name = "HOST[:PORT]", value_parser = check_peer)]
+    peers: Vec<String>,
+    /// Start from the DNS seeds' answers cached in FILE, refreshing it in the background
+    #[arg(long, value_name = "FILE")]
+    seed_cache: Option<PathBuf>,
+    #[command(flatten)]
+    stream: StreamArgs
+}
+
+#[derive(Args)]
+struct StreamArgs {
+    /// SOCKS5 proxy to connect through, such as Tor at 127.0.0.1:9050
+    #[arg(long, value_name = "ADDR")]
+    proxy: Option<SocketAddr>,
+    /// Local address to connect from, port 0 for any source port
+    #[arg(long, value_name = "ADDR")]
+    source: Option<SocketAddr>,
+    /// Public address peers can reach us on, sent in version messages and advertised to peers
+    #[arg(long, value_name = "ADDR")]
+    external: Option<SocketAddr>,
+    /// Address family of the peers to connect to: any, prefer-ipv4, prefer-ipv6, ipv4 or ipv6 [default: any]
+    #[arg(long, value_name = "FAMILY", value_parser = parse_family)]
+    family: Option<AddressFamily>,
+    /// User agent to send peers, in the BIP14 format such as /Satoshi:27.0.0/ [default: /btcnetmsg:VERSION/]
+    #[arg(long, value_name = "AGENT", value_parser = parse_user_agent)]
+    user_agent: Option<UserAgent>,
+    /// Height of our best chain to send peers, 0 as we keep no chain [default: 0]
+    #[arg(long, value_name = "HEIGHT")]
+    start_height: Option<u32>,
+    /// Ask peers not to announce transactions and drop any they send, keeping only blocks
+    #[arg(long)]
+    no_txrelay: bool,
+    /// Try the BIP324 v2 transport before v1
+    #[arg(long)]
+    v2: bool,
+    /// Seconds allowed to establish each connection [default: 5]
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+    /// Seconds a read may block during the handshake before failing, 0 blocks forever [default: 1200]
+    #[arg(long, value_name = "SECS")]
+    read_timeout: Option<u64>,
+    /// Seconds a write may block before failing, 0 blocks forever [default: 1200]
+    #[arg(long, value_name = "SECS")]
+    write_timeout: Option<u64>,
+    /// Seconds peers have to answer a ping before they are disconnected [default: 1200]
+    #[arg(long, value_name = "SECS")]
+    ping_timeout: Option<u64>,
+    /// Seconds peers may send nothing before they are disconnected, 0 waits forever [default: 1200]
+    #[arg(long, value_name = "SECS")]
+    inactivity_timeout: Option<u64>,
+    /// Disconnect peers older than this protocol version [default: 31800]
+    #[arg(long, value_name = "VERSION")]
+    min_version: Option<u32>
+}
+
+#[derive(Args)]
+struct OutputArgs {
+    /// How to print received messages
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    output: Output,
+    /// Also append every message exchanged to a capture file
+    #[arg(long, value_name = "FILE")]
+    capture: Option<PathBuf>
+}
+
+#[derive(Args)]
+struct StateArgs {
+    /// Load known addresses from FILE if it exists and save them to it on exit
+    #[arg(long, value_name = "FILE")]
+    peers_file: Option<PathBuf>,
+    /// Load bans from FILE if it exists and save them to it on exit
+    #[arg(long, value_name = "FILE")]
+    bans_file: Option<PathBuf>,
+    /// Connect first to the peers saved in FILE, if it exists, and save outbound peers to it on exit
+    #[arg(long, value_name = "FILE")]
+    anchors_file: Option<PathBuf>,
+    /// Add the peers in a .json or .csv file, such as one written with --export-peers, to the known addresses
+    #[arg(long, value_name = "FILE")]
+    import_peers: Option<PathBuf>,
+    /// Write every known address to a .json or .csv file on exit
+    #[arg(long, value_name = "FILE")]
+    export_peers: Option<PathBuf>
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    /// One line per message with the decoded payload
+    Text,
+    /// One JSON object per line
+    Jsonl,
+    /// One line per message followed by its bytes, annotated with the fields they encode
+    Hexdump
+}
+
+fn parse_network(s: &str) -> Result<Magic, String> {
+    s.parse().map_err(|e| format!("{:?}", e))
+}
+
+fn parse_block_hash(s: &str) -> Result<BlockHash, String> {
+    s.parse().map_err(|e| format!("{:?}", e))
+}
+
+fn parse_family(s: &str) -> Result<AddressFamily, String> {
+    s.parse().map_err(|e| format!("{:?}", e))
+}
+
+fn parse_user_agent(s: &str) -> Result<UserAgent, String> {
+    UserAgent::parse(s).map_err(|e| format!("{:?}", e))
+}
+
+fn check_peer(s: &str) -> Result<String, String> {
+    Peer::parse_for_network(s, Magic::Main).map(|_| s.to_string()).map_err(|e| format!("{:?}", e))
+}
+
+impl PeerArgs {
+    /// Peers given on the command line, or else in the config file
+    fn given(&self, magic: Magic, config: &Config) -> Vec<Peer> {
+        match self.peers.is_empty() {
+            true => config.peers.clone(),
+            false => self.peers.iter().map(|p| Peer::parse_for_network(p, magic).expect("Checked when parsing arguments")).collect()
+        }
+    }
+
+    /// Peers given on the command line or in the config file, or candidates from the seeds
+    fn resolve(&self, magic: Magic, config: &Config) -> Vec<Peer> {
+        match self.given(magic, config) {
+            peers if peers.is_empty() => match self.seed_cache.as_ref().or(config.seed_cache.as_ref()) {
+                Some(path) => self.seeds(magic, config).candidates_cached(path),
+                None => self.seeds(magic, config).candidates()
+            },
+            peers => peers
+        }
+    }
+
+    /// Connect to the first of the peers that completes the handshake, reporting the failures
+    /// of those before it
+    fn connect(&self, magic: Magic, config: &Config, options: &StreamOptions) -> Result<(Peer, Connection<TcpStream>), Error> {
+        let mut failure = Error::FailedToConnect(String::from("No peers to connect to"));
+        for peer in self.resolve(magic, config) {
+            match Connection::connect_with(peer, magic, options) {
+                Ok(conn) => return Ok((peer, conn)),
+                Err(e) => {
+                    eprintln!("warning: {} failed: {:?}", peer, e);
+                    failure = e;
+                }
+            }
+        }
+        Err(failure)
+    }
+
+    /// Seeds of the config file, keeping to the address family of the flags
+    fn seeds(&self, magic: Magic, config: &Config) -> Seeds {
+        config.seeds(magic).with_family(self.stream.family.or(config.family).unwrap_or_default())
+    }
+}
+
+impl StreamArgs {
+    /// Options of the config file with the flags applied on top
+    fn options(&self, config: &Config) -> StreamOptions {
+        let options = config.stream_options();
+        StreamOptions {
+            connect_timeout: self.connect_timeout.map_or(options.connect_timeout, Duration::from_secs),
+            read_timeout: self.read_timeout.map_or(options.read_timeout, |t| Some(Duration::from_secs(t)).filter(|t| !t.is_zero())),
+            write_timeout: self.write_timeout.map_or(options.write_timeout, |t| Some(Duration::from_secs(t)).filter(|t| !t.is_zero())),
+            ping_timeout: self.ping_timeout.map_or(options.ping_timeout, Duration::from_secs),
+            inactivity_timeout: self.inactivity_timeout.map_or(options.inactivity_timeout, |t| Some(Duration::from_secs(t)).filter(|t| !t.is_zero())),
+            proxy: self.proxy.or(options.proxy),
+            bind: self.source.or(options.bind),
+            external: self.external.or(options.external),
+            family: self.family.unwrap_or(options.family),
+            user_agent: self.user_agent.clone().unwrap_or(options.user_agent),
+            start_height: self.start_height.unwrap_or(options.start_height),
+            // Peers are asked to relay transactions unless turned off, as bitcoin core does
+            relay: !self.no_txrelay && config.relay.unwrap_or(true),
+            v2: self.v2 || options.v2,
+            min_version: self.min_version.map_or(options.min_version, ProtocolVersion)
+        }
+    }
+}
+
+impl OutputArgs {
+    fn manager(&self, manager: ConnectionManagerBuilder) -> Result<ConnectionManagerBuilder, Error> {
+        Ok(match &self.capture {
+            Some(path) => manager.with_capture(Capture::create(path)?),
+            None => manager
+        })
+    }
+}
+
+impl StateArgs {
+    /// Addresses saved by a previous run, if any. An unreadable file is reported and
+    /// addresses are learnt again rather than refusing to start.
+    fn addrman(&self) -> AddrMan {
+        match &self.peers_file {
+            Some(path) if path.exists() => recover(AddrMan::load(path), "peers file", path).unwrap_or_default(),
+            _ => AddrMan::new()
+        }
+    }
+
+    fn manager(&self, manager: ConnectionManagerBuilder) -> ConnectionManagerBuilder {
+        let manager = match self.bans_file.as_ref().filter(|p| p.exists()).and_then(|p| recover(BanList::load(p), "bans file", p)) {
+            Some(bans) => manager.with_ban_list(bans),
+            None => manager
+        };
+        match self.anchors_file.as_ref().filter(|p| p.exists()).and_then(|p| recover(anchors::load(p), "anchors file", p)) {
+            Some(anchors) => manager.with_anchors(anchors),
+            None => manager
+        }
+    }
+
+    /// Add the peers of the import file to a started manager. A file that cannot be read
+    /// is reported and the peers learnt otherwise.
+    fn import(&self, manager: &ConnectionManager) {
+        if let Some(path) = &self.import_peers {
+            if let Some(added) = recover(manager.import_peers(path), "peers import file", path) {
+                eprintln!("Imported {} new peers from {}", added, path.display());
+            }
+        }
+    }
+
+    /// Save each file given, carrying on past failures. Returns the first failure.
+    fn save(&self, manager: &ConnectionManager) -> Result<(), Error> {
+        let saves = vec![
+            self.peers_file.as_ref().map(|p| (p, manager.save_addresses(p))),
+            self.bans_file.as_ref().map(|p| (p, manager.save_bans(p))),
+            self.anchors_file.as_ref().map(|p| (p, manager.save_anchors(p))),
+            self.export_peers.as_ref().map(|p| (p, manager.export_peers(p)))
+        ];
+        let mut result = Ok(());
+        for (path, saved) in saves.into_iter().flatten() {
+            if let Err(e) = saved {
+                eprintln!("warning: failed to save {}: {:?}", path.display(), e);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+/// Report a failure to load a state file and carry on without it
+fn recover<T>(loaded: Result<T, Error>, what: &str, path: &Path) -> Option<T> {
+    match loaded {
+        Ok(loaded) => Some(loaded),
+        Err(e) => {
+            eprintln!("warning: ignoring {} {}: {:?}", what, path.display(), e);
+            None
+        }
+    }
+}
+
+/// Flag set on Ctrl-C or SIGTERM. A second signal exits straight away.
+fn stop_signal() -> Result<Arc<AtomicBool>, Error> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+    }).map_err(|e| Error::Io(io::Error::other(e)))?;
+    Ok(stop)
+}
+
+/// Print messages from the manager's peers until `stop` is set, then shut the manager down
+/// and print the messages that were still waiting.
+///
+/// The manager is shut down even if printing fails, such as when the output is a closed
+/// pipe, so the caller can still save its state.
+fn print_messages(manager: &ConnectionManager, output: Output, stop: &AtomicBool) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut print = |peer, msg| -> Result<(), Error> {
+        let msg = ReplayedMessage::received(peer, msg);
+        match output {
+            Output::Text => writeln!(out, "{}", msg)?,
+            Output::Jsonl => writeln!(out, "{}", msg.to_json())?,
+            Output::Hexdump => writeln!(out, "{}\n{}", msg, msg.message.hexdump())?
+        }
+        Ok(())
+    };
+
+    let mut printed = Ok(());
+    while printed.is_ok() && !stop.load(Ordering::SeqCst) {
+        if let Some((peer, msg)) = manager.recv_timeout(Duration::from_millis(200)) {
+            printed = print(peer, msg);
+        }
+    }
+    manager.shutdown();
+    while printed.is_ok() {
+        match manager.try_recv() {
+            Some((peer, msg)) => printed = print(peer, msg),
+            None => break
+        }
+    }
+    printed
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default()
+    };
+    let network = cli.network.or(config.network);
+    let magic = network.unwrap_or(Magic::Main);
+    let connections = |flag: Option<u16>| flag.map(usize::from).or(config.connections).unwrap_or(8);
+
+    match cli.command {
+        Command::Connect { peers, connections: count, allow_unroutable, feelers, connect_only, output, state, #[cfg(feature = "metrics")] metrics, #[cfg(feature = "tui")] tui } => {
+            let manager = match connect_only || config.connect_only.unwrap_or(false) {
+                true => match peers.given(magic, &config) {
+                    given if given.is_empty() => return Err(Error::Config("--connect-only needs peers given with --peer or in the config file".to_string())),
+                    given => ConnectionManagerBuilder::connect_only(magic, given)
+                },
+                false => ConnectionManager::builder(magic, connections(count), peers.resolve(magic, &config))
+            };
+            let manager = manager
+                .with_stream_options(peers.stream.options(&config))
+                .with_addrman(state.addrman().with_unroutable(allow_unroutable));
+            let manager = state.manager(output.manager(manager)?).build();
+            let stop = stop_signal()?;
+            manager.start();
+            state.import(&manager);
+            if feelers {
+                manager.start_feelers(FEELER_INTERVAL);
+            }
+            manager.start_advertising(ADVERTISE_INTERVAL);
+
+            #[cfg(feature = "metrics")]
+            if let Some(addr) = metrics {
+                // Metrics are not worth giving up the connections for
+                if let Err(e) = manager.serve_metrics(addr) {
+                    eprintln!("warning: not serving metrics on {}: {:?}", addr, e);
+                }
+            }
+            #[cfg(feature = "tui")]
+            if tui {
+                match btcnetmsg::tui::run_until(&manager, &stop) {
+                    Ok(()) => {
+                        manager.shutdown();
+                        return state.save(&manager)
+                    },
+                    Err(e) => eprintln!("warning: dashboard failed, printing messages instead: {:?}", e)
+                }
+            }
+            let printed = print_messages(&manager, output.output, &stop);
+            let saved = state.save(&manager);
+            printed.and(saved)
+        },
+        Command::Listen { bind, max_inbound, stream, output, state } => {
+            let manager = ConnectionManager::builder(magic, 0, vec![])
+                .with_stream_options(stream.options(&config))
+                .with_max_inbound(max_inbound.or(config.max_inbound).unwrap_or(MAX_INBOUND))
+                .with_addrman(state.addrman());
+            let manager = state.manager(output.manager(manager)?).build();
+            let stop = stop_signal()?;
+            let local = manager.listen(bind.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], magic.params().port))))?;
+            eprintln!("Listening on {} with {} inbound slots", local, manager.slots().max_inbound);
+            state.import(&manager);
+            manager.start_advertising(ADVERTISE_INTERVAL);
+            let printed = print_messages(&manager, output.output, &stop);
+            let saved = state.save(&manager);
+            printed.and(saved)
+        },
+        Command::Crawl { peers, depth, concurrency, max_peers, allow_unroutable, import_peers, export, #[cfg(feature = "geoip")] geoip } => {
+            #[cfg(feature = "geoip")]
+            let geoip = match geoip.split_first() {
+                Some((first, rest)) => Some(rest.iter().try_fold(GeoIp::open(first)?, |g, path| g.with_database(path))?),
+                None => None
+            };
+
+            let options = CrawlOptions {
+                concurrency: concurrency as usize,
+                max_depth: depth,
+                max_peers,
+                stream: peers.stream.options(&config),
+                unroutable: allow_unroutable,
+                ..CrawlOptions::default()
+            };
+            // Imported peers are crawled along with those given, the seeds are only asked if there are none
+            let mut start = peers.given(magic, &config);
+            if let Some(path) = import_peers {
+                let mut imported = AddrMan::new();
+                imported.import(path)?;
+                start.extend(imported.iter().map(|info| info.peer));
+            }
+            if start.is_empty() {
+                start = peers.resolve(magic, &config);
+            }
+            let snapshot = Crawler::with_options(magic, options).crawl(&start);
+            for (node, version) in snapshot.reachable().filter_map(|n| Some((n, n.version.as_ref()?))) {
+                print!("{} depth {} version {} height {} {}", node.peer, node.depth, version.version.0, version.start_height, version.user_agent);
+                #[cfg(feature = "geoip")]
+                if let Some(geoip) = &geoip {
+                    let info = geoip.lookup_peer(&node.peer);
+                    print!(" country {} asn {}", info.country.as_deref().unwrap_or("-"), info.asn.map_or_else(|| String::from("-"), |asn| asn.to_string()));
+                }
+                println!();
+            }
+            if let Some(path) = export {
+                let records = snapshot.records();
+                #[cfg(feature = "geoip")]
+                let records = match &geoip {
+                    Some(geoip) => {
+                        let mut records = records;
+                        geoip.annotate(&mut records);
+                        records
+                    },
+                    None => records
+                };
+                peerdb::write_file(&records, path)?;
+            }
+            eprintln!("{} of {} visited peers reachable, {} not visited", snapshot.reachable().count(), snapshot.nodes.len(), snapshot.unvisited.len());
+            Ok(())
+        },
+        Command::Decode { file, output, lenient_checksums } => {
+            // Raw dumps must be of the chosen network, otherwise of the first message's
+            let replay = match network {
+                Some(magic) => Replay::with_magic(BufReader::new(File::open(file)?), magic)?,
+                None => Replay::open(file)?
+            };
+            let replay = match lenient_checksums {
+                true => replay.with_checksum_policy(ChecksumPolicy::Lenient),
+                false => replay
+            };
+            let stdout = io::stdout();
+            let decoded = match output {
+                Output::Text => replay.print(&mut stdout.lock())?,
+                Output::Jsonl => replay.write_jsonl(&mut stdout.lock())?,
+                Output::Hexdump => replay.print_hexdump(&mut stdout.lock())?
+            };
+            eprintln!("{} messages decoded", decoded);
+            Ok(())
+        },
+        Command::Broadcast { tx, peers, connections: count, timeout } => {
+            let tx = match tx.filter(|tx| tx != "-") {
+                Some(tx) => tx,
+                None => io::read_to_string(io::stdin())?
+            };
+            let broadcast = Broadcast::from_hex(&tx)?.with_timeout(Duration::from_secs(timeout));
+            let mut targets = peers.resolve(magic, &config);
+            targets.truncate(match peers.given(magic, &config).is_empty() {
+                true => connections(count),
+                false => targets.len()
+            });
+
+            println!("Broadcasting {}", broadcast.txid());
+            for (peer, report) in broadcast.broadcast(&targets, magic, &peers.stream.options(&config)) {
+                match report {
+                    Ok(report) => println!("{} requested: {} announced: {}", peer, report.requested, report.announced),
+                    Err(e) => println!("{} failed: {:?}", peer, e)
+                }
+            }
+            Ok(())
+        },
+        Command::Block { hash, out, summary, peers } => {
+            let (peer, mut conn) = peers.connect(magic, &config, &peers.stream.options(&config))?;
+            let block = blocks::download_block(&mut conn, hash)?;
+            blocks::save_block(&block, &out)?;
+            if let Some(path) = summary {
+                blocks::save_summary(&block, path)?;
+            }
+            let info = BlockSummary::from(&block);
+            println!("Block {} from {}: {} transactions, {} bytes, saved to {}", hash, peer, info.tx_count, info.size, out.display());
+            Ok(())
+        },
+        Command::Mempool { peers } => {
+            // Peers only announce transactions to connections asking for them
+            let options = StreamOptions { relay: true, ..peers.stream.options(&config) };
+            let (peer, mut conn) = peers.connect(magic, &config, &options)?;
+            eprintln!("Watching transactions relayed by {}", peer);
+            let stop = stop_signal()?;
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            MempoolMonitor::new().watch(&mut conn, |entry| writeln!(out, "{}", entry).is_ok() && !stop.load(Ordering::SeqCst))
+        },
+        Command::Propagation { peers, connections: count, window, jsonl } => {
+            // Peers only announce transactions to connections asking for them
+            let options = StreamOptions { relay: true, ..peers.stream.options(&config) };
+            let manager = ConnectionManager::builder(magic, connections(count), peers.resolve(magic, &config))
+                .with_stream_options(options)
+                .build();
+            let stop = stop_signal()?;
+            manager.start();
+
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            PropagationTracker::new().run(&manager, Duration::from_secs(window), |timeline| {
+                let written = match jsonl {
+                    true => writeln!(out, "{}", timeline.to_json()),
+                    false => writeln!(out, "{} announced by {} peers over {} ms", timeline.txid, timeline.announcements.len(), timeline.spread().as_millis())
+                };
+                written.is_ok() && !stop.load(Ordering::SeqCst)
+            });
+            manager.shutdown();
+            Ok(())
+        },
+        Command::Seeds { timeout } => {
+            let report = config.seeds(magic).with_timeout(Duration::from_secs(timeout)).report();
+            println!("{}", report);
+            Ok(())
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .init();
+
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
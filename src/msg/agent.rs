@@ -0,0 +1,151 @@
+// agent.rs
+//
+// BIP14 user agent strings sent in version messages.
+//   https://github.com/bitcoin/bips/blob/master/bip-0014.mediawiki
+//
+
+use crate::encode::Error;
+
+/// Characters reserved by BIP14 that cannot appear in client names, versions or comments
+const RESERVED: [char; 5] = ['/', ':', '(', ')', ';'];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// User agent string in the BIP14 format `/Name:Version(comment; comment)/`.
+/// Clients built on top of other clients are stacked, e.g. `/Satoshi:25.0.0/Wallet:1.0/`.
+pub struct UserAgent(String);
+
+impl UserAgent {
+    /// Maximum length of a user agent string (MAX_SUBVERSION_LENGTH in bitcoin core)
+    pub const MAX_LENGTH: usize = 256;
+
+    /// Create a user agent for a single client
+    pub fn new(name: &str, version: &str) -> Result<Self, Error> {
+        Self(String::from("/")).stacked(name, version)
+    }
+
+    /// Create a user agent from a string without validating it.
+    /// Used for user agents received from peers, which may not follow BIP14.
+    pub fn from_raw(agent: String) -> Self {
+        Self(agent)
+    }
+
+    /// Create a user agent from a string, validating it against BIP14
+    pub fn parse(agent: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidUserAgent(agent.to_string());
+        if agent.len() > Self::MAX_LENGTH || !agent.starts_with('/') || !agent.ends_with('/') || agent.len() < 2 {
+            return Err(invalid())
+        }
+
+        for client in agent[1..agent.len()-1].split('/') {
+            // Split off the optional comments
+            let (id, comments) = match client.find('(') {
+                Some(i) if client.ends_with(')') => (&client[..i], Some(&client[i+1..client.len()-1])),
+                Some(_) => return Err(invalid()),
+                None => (client, None)
+            };
+
+            let mut parts = id.splitn(2, ':');
+            let (name, version) = (parts.next().unwrap_or(""), parts.next().ok_or_else(invalid)?);
+            if !valid_token(name) || !valid_token(version) {
+                return Err(invalid())
+            }
+            if let Some(comments) = comments {
+                if !comments.split("; ").all(valid_token) {
+                    return Err(invalid())
+                }
+            }
+        }
+
+        Ok(Self(agent.to_string()))
+    }
+
+    /// Stack another client on top of this user agent
+    pub fn stacked(mut self, name: &str, version: &str) -> Result<Self, Error> {
+        if !valid_token(name) || !valid_token(version) {
+            return Err(Error::InvalidUserAgent(format!("{}:{}", name, version)))
+        }
+
+        self.0.push_str(&format!("{}:{}/", name, version));
+        self.checked()
+    }
+
+    /// Add a comment to the most recently stacked client
+    pub fn with_comment(mut self, comment: &str) -> Result<Self, Error> {
+        if !valid_token(comment) {
+            return Err(Error::InvalidUserAgent(comment.to_string()))
+        }
+
+        // Append to an existing comment list or open a new one before the trailing '/'
+        self.0.pop();
+        if self.0.ends_with(')') {
+            self.0.pop();
+            self.0.push_str(&format!("; {})/", comment));
+        } else {
+            self.0.push_str(&format!("({})/", comment));
+        }
+        self.checked()
+    }
+
+    /// Return the user agent string
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // Check the length limit after the user agent has grown
+    fn checked(self) -> Result<Self, Error> {
+        match self.0.len() > Self::MAX_LENGTH {
+            true => Err(Error::InvalidUserAgent(self.0)),
+            false => Ok(self)
+        }
+    }
+}
+
+impl Default for UserAgent {
+    /// User agent identifying this library
+    fn default() -> Self {
+        Self::new("btcnetmsg", env!("CARGO_PKG_VERSION")).expect("Valid user agent")
+    }
+}
+
+impl std::fmt::Display for UserAgent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Check that a name, version or comment is non-empty printable ASCII without reserved characters
+fn valid_token(token: &str) -> bool {
+    !token.is_empty() &&
+    token.chars().all(|c| c.is_ascii() && !c.is_ascii_control() && !RESERVED.contains(&c))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_user_agents() {
+        let agent = UserAgent::new("BitcoinJ", "0.2").unwrap()
+            .with_comment("iPad").unwrap()
+            .with_comment("U").unwrap()
+            .stacked("AndroidBuild", "0.8").unwrap();
+        assert_eq!(agent.as_str(), "/BitcoinJ:0.2(iPad; U)/AndroidBuild:0.8/");
+        assert_eq!(UserAgent::parse(agent.as_str()).unwrap(), agent);
+
+        assert_eq!(UserAgent::default().as_str(), format!("/btcnetmsg:{}/", env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn invalid_user_agents() {
+        assert!(UserAgent::new("bad/name", "1.0").is_err());
+        assert!(UserAgent::new("name", "").is_err());
+        assert!(UserAgent::new("name", "1.0").unwrap().with_comment("a)b").is_err());
+        assert!(UserAgent::new(&"a".repeat(UserAgent::MAX_LENGTH), "1.0").is_err());
+
+        assert!(UserAgent::parse("Satoshi:25.0.0").is_err());
+        assert!(UserAgent::parse("/Satoshi/").is_err());
+        assert!(UserAgent::parse("/Satoshi:25.0.0(oops/").is_err());
+        assert!(UserAgent::parse("/Satoshi:25.0.0/").is_ok());
+    }
+}
@@ -0,0 +1,415 @@
+// bloom.rs
+//
+// Structures for the BIP37 bloom filtering messages. Clients load a bloom filter
+// of the outpoints, public keys and hashes they are interested in with
+// filterload, after which the peer only relays transactions matching it. A peer
+// that was sent a filter answers getdata for filtered blocks with merkleblock:
+// the block header and a partial merkle tree proving which of the block's
+// transactions matched.
+//
+
+use crate::{
+    blockdata::{
+        hash::Hash,
+        header::BlockHeader
+    },
+    encode::Error,
+    msg::inventory::Txid
+};
+use crate::bitcoin::{
+    OutPoint,
+    PublicKey
+};
+use std::f64::consts::LN_2;
+
+/// Most transactions a block can hold, a transaction weighing at least 240
+pub const MAX_BLOCK_TRANSACTIONS: u32 = 4_000_000 / 240;
+/// Largest filter peers accept, in bytes
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+/// Most hash functions peers accept in a filter
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A bloom filter, the payload of a filterload message
+pub struct BloomFilter {
+    /// Bits of the filter, least significant bit of each byte first
+    pub filter: Vec<u8>,
+    pub hash_funcs: u32,
+    /// Added to the seed of each hash function, so filters of the same elements differ
+    pub tweak: u32,
+    /// How the peer adds the outpoints of matched transactions to the filter, one of the
+    /// `BLOOM_UPDATE` constants
+    pub flags: u8
+}
+
+impl BloomFilter {
+    /// Never update the filter
+    pub const BLOOM_UPDATE_NONE: u8 = 0;
+    /// Add the outpoint of any output that matched
+    pub const BLOOM_UPDATE_ALL: u8 = 1;
+    /// Add the outpoints of matched pay to pubkey and bare multisig outputs only
+    pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+
+    /// An empty filter sized to hold `elements` with a false positive rate of `fp_rate`,
+    /// between 0 and 1. Filters are capped at the size and number of hash functions peers
+    /// accept, which raises the false positive rate of filters of many elements.
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, flags: u8) -> Self {
+        let elements = elements.max(1) as f64;
+        let bits = -elements * fp_rate.clamp(f64::MIN_POSITIVE, 1.0).ln() / (LN_2 * LN_2);
+        let size = ((bits / 8.0) as usize).clamp(1, MAX_BLOOM_FILTER_SIZE);
+        let hash_funcs = ((size * 8) as f64 / elements * LN_2) as u32;
+        Self {
+            filter: vec![0; size],
+            hash_funcs: hash_funcs.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+            flags
+        }
+    }
+
+    // Bit set for data by the n-th hash function
+    fn bit(&self, n: u32, data: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xfba4_c795).wrapping_add(self.tweak);
+        murmur3(seed, data) as usize % (self.filter.len() * 8)
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        if self.filter.is_empty() {
+            return
+        }
+        for n in 0..self.hash_funcs {
+            let bit = self.bit(n, data);
+            self.filter[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Whether data may have been inserted. False positives happen at about the rate the
+    /// filter was sized for, false negatives never.
+    pub fn contains(&self, data: &[u8]) -> bool {
+        !self.filter.is_empty() && (0..self.hash_funcs).all(|n| {
+            let bit = self.bit(n, data);
+            self.filter[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// Insert an outpoint, matching transactions that spend it
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        self.insert(&outpoint_bytes(outpoint));
+    }
+
+    pub fn contains_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.contains(&outpoint_bytes(outpoint))
+    }
+
+    /// Insert a public key as serialized in scripts, matching transactions that pay to or
+    /// spend from it
+    pub fn insert_pubkey(&mut self, key: &PublicKey) {
+        self.insert(&key.to_bytes());
+    }
+
+    /// Insert a hash in wire byte order, such as a txid or the hash of a public key
+    pub fn insert_hash<H: Into<Hash>>(&mut self, hash: H) {
+        self.insert(hash.into().as_bytes());
+    }
+}
+
+// Outpoints are inserted as serialized, the txid followed by the output index
+fn outpoint_bytes(outpoint: &OutPoint) -> [u8; 36] {
+    let mut bytes = [0; 36];
+    bytes[..32].copy_from_slice(Hash::from(outpoint.txid).as_bytes());
+    bytes[32..].copy_from_slice(&outpoint.vout.to_le_bytes());
+    bytes
+}
+
+/// 32 bit MurmurHash3 (x86), the hash functions of bloom filters
+pub fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k = tail.iter().rev().fold(0u32, |k, b| (k << 8) | *b as u32);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Payload of a merkleblock message
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    /// Number of transactions in the block
+    pub total_transactions: u32,
+    /// Hashes of the partial merkle tree, depth first
+    pub hashes: Vec<Hash>,
+    /// Bits of the depth first traversal, least significant bit first. A set bit marks a
+    /// matched transaction or a node above one, whose children follow.
+    pub flags: Vec<u8>
+}
+
+impl MerkleBlock {
+    /// Build the merkle block of a block with the given txids, in block order, proving the
+    /// transactions for which `matched` is true
+    pub fn new<F: Fn(&Txid) -> bool>(header: BlockHeader, txids: &[Txid], matched: F) -> Self {
+        let tree = Tree { total: txids.len() as u32 };
+        let txids: Vec<Hash> = txids.iter().copied().map(Hash::from).collect();
+        let matches: Vec<bool> = txids.iter().map(|t| matched(&(*t).into())).collect();
+        let mut builder = Builder { tree, txids: &txids, matches: &matches, hashes: vec![], bits: vec![] };
+        // A block without transactions has no tree
+        if !txids.is_empty() {
+            builder.build(tree.height(), 0);
+        }
+
+        let mut flags = vec![0; builder.bits.len().div_ceil(8)];
+        for (i, bit) in builder.bits.iter().enumerate() {
+            flags[i / 8] |= (*bit as u8) << (i % 8);
+        }
+        Self {
+            header,
+            total_transactions: tree.total,
+            hashes: builder.hashes,
+            flags
+        }
+    }
+
+    /// Verify the partial merkle tree against the header's merkle root and return the
+    /// txids of the matched transactions, in block order.
+    ///
+    /// Fails with [`Error::InvalidData`] if the tree is malformed, leaves hashes or flag
+    /// bytes unused, or does not hash to the merkle root.
+    pub fn extract_matches(&self) -> Result<Vec<Txid>, Error> {
+        if self.total_transactions == 0 || self.total_transactions > MAX_BLOCK_TRANSACTIONS {
+            return Err(Error::InvalidData)
+        }
+        // Each hash takes up at least one bit of the flags
+        if self.hashes.len() > self.total_transactions as usize || self.flags.len() * 8 < self.hashes.len() {
+            return Err(Error::InvalidData)
+        }
+
+        let tree = Tree { total: self.total_transactions };
+        let mut extract = Extract { tree, block: self, bits_used: 0, hashes_used: 0, matches: vec![] };
+        let root = extract.extract(tree.height(), 0)?;
+        if extract.bits_used.div_ceil(8) != self.flags.len() || extract.hashes_used != self.hashes.len() {
+            return Err(Error::InvalidData)
+        }
+        if root != self.header.merkle_root {
+            return Err(Error::InvalidData)
+        }
+        Ok(extract.matches.into_iter().map(Txid::from).collect())
+    }
+}
+
+// Shape of the merkle tree of a block, with leaves at height 0
+#[derive(Clone, Copy)]
+struct Tree {
+    total: u32
+}
+
+impl Tree {
+    // Number of nodes at a height
+    fn width(&self, height: u32) -> u32 {
+        ((self.total as u64 + (1 << height) - 1) >> height) as u32
+    }
+
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+}
+
+// Nodes hash their two children. The last node of a level without a sibling is paired with itself.
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut data = [0; 64];
+    data[..32].copy_from_slice(left.as_bytes());
+    data[32..].copy_from_slice(right.as_bytes());
+    Hash::sha256d(data)
+}
+
+struct Builder<'a> {
+    tree: Tree,
+    txids: &'a [Hash],
+    matches: &'a [bool],
+    hashes: Vec<Hash>,
+    bits: Vec<bool>
+}
+
+impl Builder<'_> {
+    fn hash(&self, height: u32, pos: u32) -> Hash {
+        if height == 0 {
+            return self.txids[pos as usize]
+        }
+        let left = self.hash(height - 1, pos * 2);
+        let right = match pos * 2 + 1 < self.tree.width(height - 1) {
+            true => self.hash(height - 1, pos * 2 + 1),
+            false => left
+        };
+        parent(&left, &right)
+    }
+
+    fn build(&mut self, height: u32, pos: u32) {
+        let start = (pos as usize) << height;
+        let end = ((pos as usize + 1) << height).min(self.tree.total as usize);
+        let matched = self.matches[start..end].iter().any(|m| *m);
+        self.bits.push(matched);
+        if height == 0 || !matched {
+            let hash = self.hash(height, pos);
+            self.hashes.push(hash);
+        } else {
+            self.build(height - 1, pos * 2);
+            if pos * 2 + 1 < self.tree.width(height - 1) {
+                self.build(height - 1, pos * 2 + 1);
+            }
+        }
+    }
+}
+
+struct Extract<'a> {
+    tree: Tree,
+    block: &'a MerkleBlock,
+    bits_used: usize,
+    hashes_used: usize,
+    matches: Vec<Hash>
+}
+
+impl Extract<'_> {
+    fn extract(&mut self, height: u32, pos: u32) -> Result<Hash, Error> {
+        if self.bits_used >= self.block.flags.len() * 8 {
+            return Err(Error::InvalidData)
+        }
+        let flag = self.block.flags[self.bits_used / 8] & (1 << (self.bits_used % 8)) != 0;
+        self.bits_used += 1;
+
+        if height == 0 || !flag {
+            let hash = *self.block.hashes.get(self.hashes_used).ok_or(Error::InvalidData)?;
+            self.hashes_used += 1;
+            if height == 0 && flag {
+                self.matches.push(hash);
+            }
+            return Ok(hash)
+        }
+
+        let left = self.extract(height - 1, pos * 2)?;
+        let right = match pos * 2 + 1 < self.tree.width(height - 1) {
+            true => self.extract(height - 1, pos * 2 + 1)?,
+            false => left
+        };
+        // Identical children give a tree for a different list of transactions the same root (CVE-2012-2459)
+        if right == left && pos * 2 + 1 < self.tree.width(height - 1) {
+            return Err(Error::InvalidData)
+        }
+        Ok(parent(&left, &right))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::{
+            blockdata::constants::genesis_block,
+            hashes::hex::{
+                FromHex,
+                ToHex
+            },
+            Network
+        },
+        encode::decode_partial,
+        msg::{
+            data::{
+                Message,
+                MessagePayload
+            },
+            header::{
+                Command,
+                Magic
+            }
+        }
+    };
+
+    #[test]
+    fn verifies_partial_trees() {
+        // A single transaction is the root itself
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = genesis.txdata[0].txid();
+        let block = MerkleBlock::new(genesis.header.into(), &[coinbase], |_| true);
+        assert_eq!((block.hashes.len(), block.flags.clone()), (1, vec![1]));
+        assert_eq!(block.extract_matches().unwrap(), vec![coinbase]);
+
+        let txids: Vec<Txid> = (0..7u8).map(|i| Hash::sha256d([i]).into()).collect();
+        let mut header = BlockHeader::from(genesis.header);
+        header.merkle_root = MerkleBlock::new(header, &txids, |_| false).hashes[0];
+        let block = MerkleBlock::new(header, &txids, |t| *t == txids[2] || *t == txids[6]);
+        assert_eq!(block.extract_matches().unwrap(), vec![txids[2], txids[6]]);
+        assert_eq!(MerkleBlock::new(header, &txids, |_| true).extract_matches().unwrap(), txids);
+        let msg = Message::new(MessagePayload::MerkleBlock(block.clone()), Magic::Main, Command::MerkleBlock);
+        assert_eq!(decode_partial::<Message>(&msg.to_bytes()).unwrap(), (msg.clone(), msg.to_bytes().len()));
+
+        // Trees that do not hash to the root, leave data unused or run out of it
+        let mut wrong = block.clone();
+        wrong.hashes[0] = Hash::ZERO;
+        let mut extra = block.clone();
+        extra.flags.push(0);
+        let mut short = block.clone();
+        short.hashes.pop();
+        for invalid in [wrong, extra, short, MerkleBlock { total_transactions: 0, ..block.clone() }] {
+            assert!(invalid.extract_matches().is_err());
+        }
+
+        // Duplicating the last transaction gives the same root but is rejected
+        let mut duplicated = txids.clone();
+        duplicated.push(txids[6]);
+        let block = MerkleBlock::new(header, &duplicated, |t| *t == txids[6]);
+        assert_eq!(block.hashes.last(), block.hashes.get(block.hashes.len() - 2));
+        assert!(block.extract_matches().is_err());
+    }
+
+    #[test]
+    fn builds_filters() {
+        let hex = |s: &str| Vec::<u8>::from_hex(s).unwrap();
+        for (seed, data, hash) in [(0, "", 0), (0xfba4_c795, "", 0x6a39_6f08), (0, "00", 0x514e_28b7), (0, "0011", 0x16c6_b7ab), (0, "0011223344", 0xe230_1fa8)] {
+            assert_eq!(murmur3(seed, &hex(data)), hash);
+        }
+
+        // Bitcoin core's bloom_create_insert_serialize test, with and without a tweak
+        for (tweak, encoded) in [(0, "03614e9b050000000000000001"), (2147483649, "03ce4299050000000100008001")] {
+            let mut filter = BloomFilter::new(3, 0.01, tweak, BloomFilter::BLOOM_UPDATE_ALL);
+            filter.insert(&hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8"));
+            assert!(filter.contains(&hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+            assert!(!filter.contains(&hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+            filter.insert(&hex("b5a2c786d9ef4658287ced5914b37a1b4aa32eee"));
+            filter.insert(&hex("b9300670b4c5366e95b2699e8b18bc75e5f729c5"));
+
+            let msg = Message::new(MessagePayload::FilterLoad(filter), Magic::Main, Command::FilterLoad);
+            assert_eq!(msg.to_bytes()[24..].to_hex(), encoded);
+            assert_eq!(decode_partial::<Message>(&msg.to_bytes()).unwrap().0, msg);
+        }
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let outpoint = OutPoint::new(genesis.txdata[0].txid(), 0);
+        let mut filter = BloomFilter::new(10, 0.0001, 7, BloomFilter::BLOOM_UPDATE_NONE);
+        filter.insert_outpoint(&outpoint);
+        filter.insert_hash(genesis.block_hash());
+        assert!(filter.contains_outpoint(&outpoint) && filter.contains(&Hash::from(genesis.block_hash()).into_inner()));
+        assert!(!filter.contains_outpoint(&OutPoint::new(genesis.txdata[0].txid(), 1)));
+        assert_eq!(BloomFilter::new(1_000_000, 0.0001, 0, 0).filter.len(), MAX_BLOOM_FILTER_SIZE);
+    }
+}
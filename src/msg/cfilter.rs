@@ -0,0 +1,175 @@
+// cfilter.rs
+//
+// Structures for the BIP157 compact block filter messages. A cfilter holds the
+// BIP158 filter of a block: a Golomb-Rice coded set of hashes of the scripts a
+// block's transactions pay to and spend from, which light clients query for
+// their own scripts to learn whether to download the block.
+//
+
+use crate::{
+    bitcoin::hashes::{
+        siphash24,
+        Hash
+    },
+    encode::{
+        Decode,
+        Error
+    },
+    msg::{
+        inventory::BlockHash,
+        VariableInteger
+    }
+};
+
+/// Bits of the remainder of each value in basic filters
+const P: u8 = 19;
+/// Inverse of the false positive rate of basic filters
+const M: u64 = 784_931;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Payload of a cfilter message
+pub struct CFilter {
+    /// Type of the filter, [`CFilter::BASIC`] is the only one defined
+    pub filter_type: u8,
+    pub block_hash: BlockHash,
+    /// The number of items followed by their Golomb-Rice coded values
+    pub filter: Vec<u8>
+}
+
+impl CFilter {
+    /// Filter of the scripts of a block's outputs and of the outputs its inputs spend
+    pub const BASIC: u8 = 0;
+
+    /// Whether the filter matches a script. False positives happen for one in 784931
+    /// scripts, false negatives never.
+    pub fn matches(&self, script: &[u8]) -> Result<bool, Error> {
+        self.matches_any([script])
+    }
+
+    /// Whether the filter matches any of the scripts, decoding the filter once.
+    /// Fails with [`Error::InvalidData`] if the filter ends early.
+    pub fn matches_any<I, S>(&self, scripts: I) -> Result<bool, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>
+    {
+        let mut r = &self.filter[..];
+        let n = VariableInteger::net_decode(&mut r)?.inner();
+        let range = n.checked_mul(M).ok_or(Error::InvalidData)?;
+
+        // Hash the queries into the range of the filter's values and walk both in order
+        let (k0, k1) = self.keys();
+        let mut queries: Vec<u64> = scripts.into_iter()
+            .map(|s| map_to_range(siphash24::Hash::hash_to_u64_with_keys(k0, k1, s.as_ref()), range))
+            .collect();
+        queries.sort_unstable();
+
+        let mut reader = BitReader { data: r, pos: 0 };
+        let mut value = 0u64;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..n {
+            value = value.checked_add(reader.golomb_rice()?).ok_or(Error::InvalidData)?;
+            while let Some(query) = queries.peek() {
+                match (*query).cmp(&value) {
+                    std::cmp::Ordering::Less => { queries.next(); },
+                    std::cmp::Ordering::Equal => return Ok(true),
+                    std::cmp::Ordering::Greater => break
+                }
+            }
+            if queries.peek().is_none() {
+                break
+            }
+        }
+        Ok(false)
+    }
+
+    // SipHash keys, the first 16 bytes of the block hash as two little endian integers
+    fn keys(&self) -> (u64, u64) {
+        let hash = self.block_hash.into_inner();
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&hash[0..8]);
+        k1.copy_from_slice(&hash[8..16]);
+        (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+    }
+}
+
+// Scale a 64 bit hash to [0, range) without division
+fn map_to_range(hash: u64, range: u64) -> u64 {
+    ((hash as u128 * range as u128) >> 64) as u64
+}
+
+// Reads bits most significant first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<bool, Error> {
+        let byte = self.data.get(self.pos / 8).ok_or(Error::InvalidData)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    // A value coded as its quotient by 2^P in unary followed by the remainder in P bits
+    fn golomb_rice(&mut self) -> Result<u64, Error> {
+        let mut quotient = 0u64;
+        while self.bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0u64;
+        for _ in 0..P {
+            remainder = (remainder << 1) | self.bit()? as u64;
+        }
+        quotient.checked_mul(1 << P).map(|q| q | remainder).ok_or(Error::InvalidData)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::{
+            blockdata::constants::genesis_block,
+            hashes::hex::FromHex,
+            Network
+        },
+        encode::decode_partial,
+        msg::{
+            data::{
+                Message,
+                MessagePayload
+            },
+            header::{
+                Command,
+                Magic
+            }
+        }
+    };
+
+    #[test]
+    fn matches_scripts() {
+        // Basic filter of the testnet genesis block from the BIP158 test vectors
+        let genesis = genesis_block(Network::Testnet);
+        let filter = CFilter {
+            filter_type: CFilter::BASIC,
+            block_hash: genesis.block_hash(),
+            filter: Vec::from_hex("019dfca8").unwrap()
+        };
+        let script = genesis.txdata[0].output[0].script_pubkey.as_bytes();
+        assert!(filter.matches(script).unwrap());
+        assert!(!filter.matches(&[0x51]).unwrap());
+        assert!(filter.matches_any([&[0x51][..], script]).unwrap());
+        assert!(!filter.matches_any(Vec::<Vec<u8>>::new()).unwrap());
+
+        let msg = Message::new(MessagePayload::CFilter(filter.clone()), Magic::Test, Command::CFilter);
+        assert_eq!(decode_partial::<Message>(&msg.to_bytes()).unwrap().0, msg);
+
+        // A filter that ends before all its values
+        let truncated = CFilter { filter: vec![0x02, 0x9d], ..filter };
+        assert!(truncated.matches(&[0x51]).is_err());
+    }
+}
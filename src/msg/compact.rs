@@ -0,0 +1,86 @@
+// compact.rs
+//
+// Structures for the BIP152 compact block messages: sendcmpct, cmpctblock,
+// getblocktxn and blocktxn.
+//
+
+use crate::bitcoin::{
+    consensus::encode::serialize,
+    hash_types::BlockHash,
+    hashes::{
+        sha256,
+        siphash24,
+        Hash
+    },
+    BlockHeader,
+    Transaction
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Payload of a sendcmpct message
+pub struct SendCmpct {
+    /// Announce new blocks with cmpctblock instead of inv or headers
+    pub announce: bool,
+    /// 1 for short ids of txids, 2 for short ids of wtxids
+    pub version: u64
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A transaction sent in full as part of a compact block
+pub struct PrefilledTransaction {
+    /// Position of the transaction in the block. Encoded relative to the previous one.
+    pub index: u16,
+    pub tx: Transaction
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Payload of a cmpctblock message, a block header with short ids in place of most transactions
+pub struct HeaderAndShortIds {
+    pub header: BlockHeader,
+    /// Salt for the short id keys
+    pub nonce: u64,
+    /// 6 byte short ids of the transactions not prefilled, in block order
+    pub short_ids: Vec<u64>,
+    pub prefilled: Vec<PrefilledTransaction>
+}
+
+impl HeaderAndShortIds {
+    /// SipHash keys for this block's short ids: the first two little endian u64s of
+    /// the SHA256 of the header followed by the nonce
+    pub fn keys(&self) -> (u64, u64) {
+        let mut data = serialize(&self.header);
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        let hash = sha256::Hash::hash(&data).into_inner();
+
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&hash[0..8]);
+        k1.copy_from_slice(&hash[8..16]);
+        (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+    }
+
+    /// Number of transactions in the block
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+}
+
+/// Short id of a transaction hash (the wtxid for version 2) with the keys of a compact block
+pub fn short_id(keys: (u64, u64), hash: &[u8]) -> u64 {
+    siphash24::Hash::hash_to_u64_with_keys(keys.0, keys.1, hash) & 0xffff_ffff_ffff
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Payload of a getblocktxn message, asking for the transactions of a block at some positions
+pub struct BlockTransactionsRequest {
+    pub block_hash: BlockHash,
+    /// Positions in the block, ascending. Encoded relative to the previous one.
+    pub indexes: Vec<u16>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Payload of a blocktxn message, the transactions asked for with getblocktxn in the same order
+pub struct BlockTransactions {
+    pub block_hash: BlockHash,
+    pub transactions: Vec<Transaction>
+}
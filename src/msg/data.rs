@@ -29,24 +29,41 @@
 //        structured data.
 
 use crate::{
+    blockdata::hash::Hash,
     msg::header::{
         MessageHeader,
         Magic,
-        Command,
-        Checksum
+        Command
     },
     msg::network::{
         VersionMessage,
-        TimestampedNetAddress
+        TimestampedNetAddress,
+        NetAddressV2
     },
     msg::inventory::{
         Inventory,
         BlockdataLocatorInfo
     },
-    encode::Encode,
+    msg::compact::{
+        SendCmpct,
+        HeaderAndShortIds,
+        BlockTransactionsRequest,
+        BlockTransactions
+    },
+    msg::bloom::{
+        BloomFilter,
+        MerkleBlock
+    },
+    msg::cfilter::CFilter,
+    encode::{
+        Encode,
+        Error
+    },
+    msg::header::HEADER_SIZE,
 
     bitcoin::Transaction
 };
+use std::io;
 
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -58,11 +75,40 @@ pub struct Message {
 
 impl Message {
     pub fn new(payload: MessagePayload, magic: Magic, command: Command) -> Message {
+        // Encoded once for both the length and the checksum
+        let mut encoded = Vec::new();
+        payload.net_encode(&mut encoded);
+        let checksum = Hash::sha256d(&encoded).checksum();
+
         Self {
-            header: MessageHeader::new(magic, command, payload.len(), payload.checksum()),
+            header: MessageHeader::new(magic, command, encoded.len(), checksum),
             payload
         }
     }
+
+    /// The message as sent with the v1 transport, header included, in a single buffer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.header.length as usize);
+        self.header.net_encode(&mut bytes);
+        self.payload.net_encode(&mut bytes);
+        bytes
+    }
+
+    /// Encode the message as sent with the v1 transport into `buf` without allocating,
+    /// returning the number of bytes written. Fails with [`Error::BufferTooSmall`] and the
+    /// size needed, leaving `buf` untouched, if the message does not fit.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        // Encoding to a sink measures the payload without storing it
+        let len = HEADER_SIZE + self.payload.net_encode(io::sink());
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall(len))
+        }
+
+        let mut out = &mut buf[..len];
+        self.header.net_encode(&mut out);
+        self.payload.net_encode(&mut out);
+        Ok(len)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -71,15 +117,28 @@ pub enum MessagePayload {
     Version(VersionMessage),
     PingPong(u64),
     AddrList(Vec<TimestampedNetAddress>),
+    AddrV2List(Vec<NetAddressV2>),
     InvVect(Vec<Inventory>),
     Transction(Transaction),
     BlockLocator(BlockdataLocatorInfo),
-    Headers(Vec<crate::bitcoin::BlockHeader>),
+    Headers(Vec<crate::blockdata::header::BlockHeader>),
     Block(crate::bitcoin::Block),
+    SendCmpct(SendCmpct),
+    CompactBlock(HeaderAndShortIds),
+    GetBlockTxn(BlockTransactionsRequest),
+    BlockTxn(BlockTransactions),
+    FilterLoad(BloomFilter),
+    MerkleBlock(MerkleBlock),
+    CFilter(CFilter),
     
     // Generic payloads for:
     EmptyPayload,   // Payloads with no data
-    Dump(Vec<u8>)   // Unknown structure payloads
+    /// Payload of a command without a decoder, kept byte for byte so the message can
+    /// still be logged, re-encoded identically or forwarded
+    Raw {
+        command: Command,
+        bytes: Vec<u8>
+    }
 }
 
 impl MessagePayload {
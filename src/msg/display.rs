@@ -0,0 +1,207 @@
+// display.rs
+//
+// Human readable one line summaries of decoded messages, such as
+//
+//      version: 70016, agent=/Satoshi:27.0/, height=850000, services=NETWORK|WITNESS, relay=true
+//
+// used wherever messages are printed instead of their debug representation.
+//
+
+use crate::{
+    address::AddrV2,
+    bitcoin::hashes::hex::ToHex,
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        inventory::Inventory,
+        network::{
+            Service,
+            ServicesList
+        }
+    }
+};
+use std::fmt;
+
+// Addresses and raw bytes shown before the rest is elided
+const MAX_ADDRESSES: usize = 3;
+const MAX_RAW_BYTES: usize = 16;
+
+impl fmt::Display for Message {
+    /// The command followed by a summary of the payload, see [`MessagePayload`]'s `Display`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.payload {
+            MessagePayload::EmptyPayload => write!(f, "{}", self.header.command.to_str()),
+            payload => write!(f, "{}: {}", self.header.command.to_str(), payload)
+        }
+    }
+}
+
+impl fmt::Display for MessagePayload {
+    /// The main fields of the payload as comma separated `key=value` pairs. Lists are
+    /// summarized by their length, empty payloads are empty.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(v) => write!(f, "{}, agent={}, height={}, services={}, relay={}",
+                v.version.0, v.agent.as_str(), v.start_height, services(&v.service), v.relay),
+            Self::PingPong(nonce) => write!(f, "nonce={}", nonce),
+            Self::AddrList(list) => addresses(f, list.iter().map(|a| a.netaddress.address.0.to_string()), list.len()),
+            Self::AddrV2List(list) => addresses(f, list.iter().map(|a| match a.addr {
+                AddrV2::Ipv6(ip) => format!("[{}]:{}", ip, a.port),
+                _ => format!("{}:{}", a.addr, a.port)
+            }), list.len()),
+            Self::InvVect(inv) => {
+                write!(f, "count={}", inv.len())?;
+                let mut kinds: Vec<(&str, usize)> = vec![];
+                for kind in inv.iter().map(inventory_kind) {
+                    match kinds.iter_mut().find(|(k, _)| *k == kind) {
+                        Some((_, n)) => *n += 1,
+                        None => kinds.push((kind, 1))
+                    }
+                }
+                kinds.iter().try_for_each(|(kind, n)| write!(f, ", {}={}", kind, n))
+            },
+            Self::Transction(tx) => write!(f, "txid={}, inputs={}, outputs={}", tx.txid(), tx.input.len(), tx.output.len()),
+            Self::BlockLocator(locator) => write!(f, "{}, locator={}, stop={}", locator.version, locator.hashes.len(), locator.stop),
+            Self::Headers(headers) => {
+                write!(f, "count={}", headers.len())?;
+                match (headers.first(), headers.last()) {
+                    (Some(first), Some(last)) => write!(f, ", first={}, last={}", first.block_hash(), last.block_hash()),
+                    _ => Ok(())
+                }
+            },
+            Self::Block(block) => write!(f, "hash={}, txs={}", block.block_hash(), block.txdata.len()),
+            Self::SendCmpct(cmpct) => write!(f, "announce={}, version={}", cmpct.announce, cmpct.version),
+            Self::CompactBlock(block) => write!(f, "hash={}, short_ids={}, prefilled={}",
+                block.header.block_hash(), block.short_ids.len(), block.prefilled.len()),
+            Self::GetBlockTxn(req) => write!(f, "hash={}, indexes={}", req.block_hash, req.indexes.len()),
+            Self::BlockTxn(txn) => write!(f, "hash={}, txs={}", txn.block_hash, txn.transactions.len()),
+            Self::FilterLoad(filter) => write!(f, "size={}, hash_funcs={}, tweak={}, flags={}",
+                filter.filter.len(), filter.hash_funcs, filter.tweak, filter.flags),
+            Self::MerkleBlock(block) => write!(f, "hash={}, txs={}, hashes={}",
+                block.header.block_hash(), block.total_transactions, block.hashes.len()),
+            Self::CFilter(filter) => write!(f, "type={}, hash={}, size={}", filter.filter_type, filter.block_hash, filter.filter.len()),
+            Self::EmptyPayload => Ok(()),
+            Self::Raw { command: _, bytes } => {
+                write!(f, "len={}, bytes={}", bytes.len(), bytes[..bytes.len().min(MAX_RAW_BYTES)].to_hex())?;
+                if bytes.len() > MAX_RAW_BYTES {
+                    write!(f, "..")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Flag names as bitcoin core prints them, ordered by bit and joined with `|`
+pub(crate) fn services(list: &ServicesList) -> String {
+    let mut flags = list.get_flags();
+    flags.sort_by_key(|f| f.value());
+    let names: Vec<String> = flags.iter()
+        .filter_map(|f| match f {
+            Service::None => None,
+            Service::Network => Some(String::from("NETWORK")),
+            Service::GetUTXO => Some(String::from("GETUTXO")),
+            Service::Bloom => Some(String::from("BLOOM")),
+            Service::Witness => Some(String::from("WITNESS")),
+            Service::CompactFilters => Some(String::from("COMPACT_FILTERS")),
+            Service::NetworkLimited => Some(String::from("NETWORK_LIMITED")),
+            Service::P2PV2 => Some(String::from("P2P_V2")),
+            Service::Unknown(bit) => Some(format!("UNKNOWN[{:#x}]", bit))
+        })
+        .collect();
+
+    match names.is_empty() {
+        true => String::from("NONE"),
+        false => names.join("|")
+    }
+}
+
+fn addresses<I: Iterator<Item = String>>(f: &mut fmt::Formatter<'_>, addrs: I, count: usize) -> fmt::Result {
+    write!(f, "count={}", count)?;
+    let shown: Vec<String> = addrs.take(MAX_ADDRESSES).collect();
+    if !shown.is_empty() {
+        write!(f, ", [{}{}]", shown.join(", "), if count > MAX_ADDRESSES { ", .." } else { "" })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn inventory_kind(inv: &Inventory) -> &'static str {
+    match inv {
+        Inventory::Error => "error",
+        Inventory::Tx(_) => "tx",
+        Inventory::Block(_) => "block",
+        Inventory::FilteredBlock(_) => "filtered_block",
+        Inventory::CompactBlock(_) => "cmpct_block",
+        Inventory::WitnessTx(_) => "witness_tx",
+        Inventory::Wtx(_) => "wtx",
+        Inventory::WitnessBlock(_) => "witness_block",
+        Inventory::FilteredWitnessBlock(_) => "filtered_witness_block",
+        Inventory::Unknown { .. } => "unknown"
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::{
+            BlockHash,
+            Txid
+        },
+        msg::{
+            agent::UserAgent,
+            header::{
+                Command,
+                Magic
+            },
+            network::{
+                NetAddress,
+                ProtocolVersion,
+                TimestampedNetAddress,
+                VersionMessageBuilder
+            }
+        }
+    };
+    use std::{
+        net::SocketAddr,
+        time::Duration
+    };
+
+    #[test]
+    fn summarizes_messages() {
+        let addr = Address::from(SocketAddr::from(([1, 2, 3, 4], 8333)));
+        let mut services = ServicesList::new();
+        services.add_flag(Service::Witness);
+        services.add_flag(Service::Network);
+        let version = VersionMessageBuilder::new(addr)
+            .version(ProtocolVersion(70016))
+            .services(services)
+            .user_agent(UserAgent::new("Satoshi", "27.0").unwrap())
+            .start_height(850000)
+            .relay(true)
+            .build();
+        let msg = Message::new(MessagePayload::Version(version), Magic::Main, Command::Version);
+        assert_eq!(msg.to_string(), "version: 70016, agent=/Satoshi:27.0/, height=850000, services=NETWORK|WITNESS, relay=true");
+
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping);
+        assert_eq!(ping.to_string(), "ping: nonce=7");
+        assert_eq!(Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::Verack).to_string(), "verack");
+
+        let inv = MessagePayload::InvVect(vec![
+            Inventory::Tx(Txid::default()),
+            Inventory::Block(BlockHash::default()),
+            Inventory::Tx(Txid::default())
+        ]);
+        assert_eq!(inv.to_string(), "count=3, tx=2, block=1");
+
+        let addrs = MessagePayload::AddrList(vec![TimestampedNetAddress::new(Duration::from_secs(0), NetAddress::new(ServicesList::new(), addr)); 4]);
+        assert_eq!(addrs.to_string(), "count=4, [1.2.3.4:8333, 1.2.3.4:8333, 1.2.3.4:8333, ..]");
+
+        let raw = MessagePayload::Raw { command: Command::Unknown(String::from("foo")), bytes: (0..20).collect() };
+        assert_eq!(raw.to_string(), "len=20, bytes=000102030405060708090a0b0c0d0e0f..");
+    }
+}
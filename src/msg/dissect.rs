@@ -0,0 +1,312 @@
+// dissect.rs
+//
+// Dissection of encoded messages into their fields: the name of each field of
+// the header and payload, the bytes it occupies and its decoded value, for tools
+// such as packet viewers built on top of the decoders. The hexdump of
+// `Message::hexdump` is one rendering of it.
+//
+
+use crate::{
+    blockdata::hash::Hash,
+    bitcoin::{
+        consensus::encode::{
+            serialize,
+            Encodable
+        },
+        hashes::hex::ToHex,
+        Transaction
+    },
+    encode::Encode,
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        display::{
+            inventory_kind,
+            services
+        },
+        header::HEADER_SIZE,
+        network::{
+            NetAddress,
+            ProtocolVersion
+        },
+        VariableInteger
+    }
+};
+#[cfg(feature = "export")]
+use serde::Serialize;
+use std::{
+    io,
+    ops::Range
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "export", derive(Serialize))]
+/// A field of an encoded message
+pub struct Field {
+    /// Name of the field. Fields of list items and nested structures are prefixed with the
+    /// item's position and the structure's name, such as `addr[0].services`.
+    pub name: String,
+    /// Bytes of the field in the encoded message, counted from the start of the header
+    pub range: Range<usize>,
+    /// The decoded value as text, hashes in the byte order they are displayed in
+    pub value: String
+}
+
+impl Message {
+    /// The fields of the header and payload in the order they are encoded, see [`Field`].
+    /// The ranges of the fields are contiguous and cover every byte of [`Message::to_bytes`].
+    pub fn dissect(&self) -> Vec<Field> {
+        let mut dissection = Dissection { fields: vec![], pos: 0 };
+        dissection.header(self);
+        debug_assert_eq!(dissection.pos, HEADER_SIZE);
+        dissection.payload(&self.payload);
+
+        // Lists of length zero leave fields without bytes behind
+        dissection.fields.into_iter().filter(|f| !f.range.is_empty()).collect()
+    }
+}
+
+struct Dissection {
+    fields: Vec<Field>,
+    pos: usize
+}
+
+impl Dissection {
+    fn bytes<N: Into<String>, V: ToString>(&mut self, name: N, len: usize, value: V) {
+        self.fields.push(Field {
+            name: name.into(),
+            range: self.pos..self.pos + len,
+            value: value.to_string()
+        });
+        self.pos += len;
+    }
+
+    fn field<N: Into<String>, T: Encode, V: ToString>(&mut self, name: N, encoded: &T, value: V) {
+        self.bytes(name, encoded.net_encode(io::sink()), value);
+    }
+
+    fn consensus<N: Into<String>, T: Encodable, V: ToString>(&mut self, name: N, encoded: &T, value: V) {
+        self.bytes(name, serialize(encoded).len(), value);
+    }
+
+    fn count<N: Into<String>>(&mut self, name: N, len: usize) {
+        self.field(name, &VariableInteger::from(len), len);
+    }
+
+    fn header(&mut self, msg: &Message) {
+        self.bytes("magic", 4, format!("{:?}", msg.header.magic));
+        self.bytes("command", 12, msg.header.command.to_str());
+        self.bytes("length", 4, msg.header.length);
+        self.bytes("checksum", 4, msg.header.checksum.to_hex());
+    }
+
+    fn net_address(&mut self, prefix: &str, addr: &NetAddress) {
+        self.field(format!("{}.services", prefix), &addr.services, services(&addr.services));
+        self.field(format!("{}.address", prefix), &addr.address, addr.address.0);
+    }
+
+    fn transaction(&mut self, prefix: &str, tx: &Transaction) {
+        let witness = tx.input.iter().any(|i| !i.witness.is_empty());
+        self.consensus(format!("{}.version", prefix), &tx.version, tx.version);
+        if witness {
+            self.bytes(format!("{}.marker", prefix), 1, 0);
+            self.bytes(format!("{}.flag", prefix), 1, 1);
+        }
+        self.count(format!("{}.inputs", prefix), tx.input.len());
+        for (i, input) in tx.input.iter().enumerate() {
+            let len = serialize(&input.previous_output).len() + serialize(&input.script_sig).len() + 4;
+            self.bytes(format!("{}.input[{}]", prefix, i), len, input.previous_output);
+        }
+        self.count(format!("{}.outputs", prefix), tx.output.len());
+        for (i, output) in tx.output.iter().enumerate() {
+            self.consensus(format!("{}.output[{}]", prefix, i), output, format!("{} sat", output.value));
+        }
+        if witness {
+            for (i, input) in tx.input.iter().enumerate() {
+                self.consensus(format!("{}.witness[{}]", prefix, i), &input.witness, format!("{} items", input.witness.len()));
+            }
+        }
+        self.consensus(format!("{}.lock_time", prefix), &tx.lock_time, tx.lock_time);
+    }
+
+    fn payload(&mut self, payload: &MessagePayload) {
+        match payload {
+            MessagePayload::Version(v) => {
+                self.field("version", &v.version, v.version.0);
+                self.field("services", &v.service, services(&v.service));
+                self.field("timestamp", &v.timestamp, v.timestamp.as_secs());
+                self.net_address("addr_recv", &v.addr_recv);
+                // Fields that only exist from certain protocol versions onwards
+                if v.version >= ProtocolVersion::ADDR_FROM {
+                    self.net_address("addr_from", &v.addr_from);
+                    self.field("nonce", &v.nonce, v.nonce);
+                    self.field("user_agent", &v.agent, v.agent.as_str());
+                    self.field("start_height", &v.start_height, v.start_height);
+                }
+                if v.version >= ProtocolVersion::RELAY {
+                    self.bytes("relay", 1, v.relay);
+                }
+            },
+            MessagePayload::PingPong(nonce) => self.field("nonce", nonce, nonce),
+            MessagePayload::AddrList(addrs) => {
+                self.count("count", addrs.len());
+                for (i, addr) in addrs.iter().enumerate() {
+                    self.bytes(format!("addr[{}].time", i), 4, addr.timestamp.as_secs());
+                    self.net_address(&format!("addr[{}]", i), &addr.netaddress);
+                }
+            },
+            MessagePayload::AddrV2List(addrs) => {
+                self.count("count", addrs.len());
+                for (i, addr) in addrs.iter().enumerate() {
+                    self.bytes(format!("addr[{}].time", i), 4, addr.timestamp.as_secs());
+                    self.field(format!("addr[{}].services", i), &VariableInteger(addr.services.bits()), services(&addr.services));
+                    self.field(format!("addr[{}].address", i), &addr.addr, &addr.addr);
+                    self.bytes(format!("addr[{}].port", i), 2, addr.port);
+                }
+            },
+            MessagePayload::InvVect(inv) => {
+                self.count("count", inv.len());
+                for (i, item) in inv.iter().enumerate() {
+                    self.bytes(format!("inv[{}].type", i), 4, inventory_kind(item));
+                    self.bytes(format!("inv[{}].hash", i), 32, Hash::from(item.inner()));
+                }
+            },
+            MessagePayload::Transction(tx) => self.transaction("tx", tx),
+            MessagePayload::BlockLocator(locator) => {
+                self.field("version", &locator.version, locator.version);
+                self.count("count", locator.hashes.len());
+                for (i, hash) in locator.hashes.iter().enumerate() {
+                    self.bytes(format!("locator[{}]", i), 32, hash);
+                }
+                self.bytes("stop", 32, locator.stop);
+            },
+            MessagePayload::Headers(headers) => {
+                self.count("count", headers.len());
+                for (i, header) in headers.iter().enumerate() {
+                    self.field(format!("header[{}]", i), header, header.block_hash());
+                    // Always zero
+                    self.bytes(format!("header[{}].tx_count", i), 1, 0);
+                }
+            },
+            MessagePayload::Block(block) => {
+                self.consensus("header", &block.header, block.block_hash());
+                self.count("tx_count", block.txdata.len());
+                for (i, tx) in block.txdata.iter().enumerate() {
+                    self.consensus(format!("tx[{}]", i), tx, tx.txid());
+                }
+            },
+            MessagePayload::SendCmpct(cmpct) => {
+                self.bytes("announce", 1, cmpct.announce);
+                self.field("version", &cmpct.version, cmpct.version);
+            },
+            MessagePayload::CompactBlock(block) => {
+                self.consensus("header", &block.header, block.header.block_hash());
+                self.field("nonce", &block.nonce, block.nonce);
+                self.count("short_ids.count", block.short_ids.len());
+                let ids: Vec<String> = block.short_ids.iter().map(|id| format!("{:012x}", id)).collect();
+                self.bytes("short_ids", block.short_ids.len() * 6, ids.join(","));
+                self.count("prefilled.count", block.prefilled.len());
+                let mut next = 0;
+                for (i, prefilled) in block.prefilled.iter().enumerate() {
+                    // Indexes are encoded as the difference from the one after the previous
+                    let diff = (prefilled.index as u32).saturating_sub(next);
+                    next = prefilled.index as u32 + 1;
+                    self.field(format!("prefilled[{}].index", i), &VariableInteger::from(diff), prefilled.index);
+                    self.consensus(format!("prefilled[{}].tx", i), &prefilled.tx, prefilled.tx.txid());
+                }
+            },
+            MessagePayload::GetBlockTxn(req) => {
+                self.bytes("block_hash", 32, req.block_hash);
+                self.count("count", req.indexes.len());
+                let start = self.pos;
+                let indexes: Vec<String> = req.indexes.iter().map(|i| i.to_string()).collect();
+                self.bytes("indexes", HEADER_SIZE + payload.len() - start, indexes.join(","));
+            },
+            MessagePayload::BlockTxn(txn) => {
+                self.bytes("block_hash", 32, txn.block_hash);
+                self.count("count", txn.transactions.len());
+                for (i, tx) in txn.transactions.iter().enumerate() {
+                    self.consensus(format!("tx[{}]", i), tx, tx.txid());
+                }
+            },
+            MessagePayload::FilterLoad(filter) => {
+                self.count("filter.count", filter.filter.len());
+                self.bytes("filter", filter.filter.len(), filter.filter.to_hex());
+                self.field("hash_funcs", &filter.hash_funcs, filter.hash_funcs);
+                self.field("tweak", &filter.tweak, filter.tweak);
+                self.field("flags", &filter.flags, filter.flags);
+            },
+            MessagePayload::MerkleBlock(block) => {
+                self.field("header", &block.header, block.header.block_hash());
+                self.field("total_transactions", &block.total_transactions, block.total_transactions);
+                self.count("hashes.count", block.hashes.len());
+                for (i, hash) in block.hashes.iter().enumerate() {
+                    self.field(format!("hashes[{}]", i), hash, hash);
+                }
+                self.count("flags.count", block.flags.len());
+                self.bytes("flags", block.flags.len(), block.flags.to_hex());
+            },
+            MessagePayload::CFilter(filter) => {
+                self.field("filter_type", &filter.filter_type, filter.filter_type);
+                self.bytes("block_hash", 32, filter.block_hash);
+                self.count("filter.count", filter.filter.len());
+                self.bytes("filter", filter.filter.len(), filter.filter.to_hex());
+            },
+            MessagePayload::EmptyPayload => {},
+            MessagePayload::Raw { bytes, .. } => self.bytes("payload", bytes.len(), bytes.to_hex())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::{
+            blockdata::constants::genesis_block,
+            Network
+        },
+        msg::{
+            header::{
+                Command,
+                Magic
+            },
+            network::VersionMessageBuilder
+        }
+    };
+    use std::net::SocketAddr;
+
+    // Fields follow each other without gaps up to the last byte of the message
+    fn assert_covers(msg: &Message) -> Vec<Field> {
+        let fields = msg.dissect();
+        assert_eq!(fields.first().map(|f| f.range.start), Some(0));
+        assert!(fields.windows(2).all(|w| w[0].range.end == w[1].range.start));
+        assert_eq!(fields.last().map(|f| f.range.end), Some(msg.to_bytes().len()));
+        fields
+    }
+
+    #[test]
+    fn dissects_messages() {
+        let version = VersionMessageBuilder::new(Address::from(SocketAddr::from(([1, 2, 3, 4], 8333))))
+            .start_height(850000)
+            .build();
+        let msg = Message::new(MessagePayload::Version(version), Magic::Main, Command::Version);
+        let fields = assert_covers(&msg);
+        let field = |name: &str| fields.iter().find(|f| f.name == name).cloned().unwrap();
+        assert_eq!((field("magic").range, field("magic").value), (0..4, String::from("Main")));
+        assert_eq!(field("command").value, "version");
+        assert_eq!(field("addr_recv.address").value, "1.2.3.4:8333");
+        assert_eq!((field("start_height").range.len(), field("start_height").value), (4, String::from("850000")));
+
+        let block = genesis_block(Network::Bitcoin);
+        let tx = assert_covers(&Message::new(MessagePayload::Transction(block.txdata[0].clone()), Magic::Main, Command::Tx));
+        assert_eq!(tx.iter().find(|f| f.name == "tx.output[0]").map(|f| f.value.as_str()), Some("5000000000 sat"));
+        let fields = assert_covers(&Message::new(MessagePayload::Block(block.clone()), Magic::Main, Command::Block));
+        assert_eq!(fields[4].value, block.block_hash().to_string());
+        assert_eq!(fields[6].value, block.txdata[0].txid().to_string());
+    }
+}
@@ -4,11 +4,20 @@
 //
 //
 
-use crate::encode::{Encode, Error};
+use crate::{
+    blockdata::hash::Hash,
+    encode::{Encode, Error}
+};
 use sha2::{
     Sha256, Digest
 };
 
+/// Length of an encoded message header
+pub const HEADER_SIZE: usize = 24;
+
+/// Largest payload size a peer may send (MAX_SIZE in bitcoin core)
+pub const MAX_PAYLOAD_SIZE: u32 = 0x02000000;
+
 /// Message header structure
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MessageHeader {
@@ -30,113 +39,145 @@ impl MessageHeader {
 }
 
 /// Network magic enum
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Magic {
     Main,
     Test,
-    Unknown(u32)
+    Testnet4,
+    Signet,
+    Regtest,
+    // Magic bytes (in wire order) for any other network
+    Custom([u8; 4])
 }
 
 impl Magic {
     /// Return the magic bytes for the specified network.
+    /// Magic values are encoded in little endian on the wire.
     pub fn bytes(&self) -> u32 {
         match self {
             Magic::Main => 0xD9B4BEF9,
-            Magic::Test => 0xDAB5BFFA,
-            Magic::Unknown(v)=> *v
+            Magic::Test => 0x0709110B,
+            Magic::Testnet4 => 0x283F161C,
+            Magic::Signet => 0x40CF030A,
+            Magic::Regtest => 0xDAB5BFFA,
+            Magic::Custom(b) => u32::from_le_bytes(*b)
+        }
+    }
+}
+
+impl std::str::FromStr for Magic {
+    type Err = Error;
+
+    /// Parse a network name such as `main`, `testnet4` or `regtest`, or the magic
+    /// bytes of any other network as 8 hex digits in wire order
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "main" | "mainnet" | "bitcoin" => Ok(Magic::Main),
+            "test" | "testnet" | "testnet3" => Ok(Magic::Test),
+            "testnet4" => Ok(Magic::Testnet4),
+            "signet" => Ok(Magic::Signet),
+            "regtest" => Ok(Magic::Regtest),
+            hex if hex.len() == 8 => {
+                let mut bytes = [0; 4];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(hex.get(i*2..i*2+2).unwrap_or("-"), 16)
+                        .map_err(|_| Error::UnknownNetwork(s.to_string()))?;
+                }
+                Ok(Magic::from(bytes))
+            },
+            _ => Err(Error::UnknownNetwork(s.to_string()))
         }
     }
 }
 
 impl From<[u8; 4]> for Magic {
+    /// Create a magic from bytes in wire order.
+    /// Bytes that do not match a known network are kept as a custom magic.
     fn from(bytes: [u8; 4]) -> Self {
-        if bytes == Magic::Main.bytes().to_be_bytes() { return Magic::Main }
-        else if bytes == Magic::Test.bytes().to_be_bytes() { return Magic::Test }
-        else { return Magic::Unknown(
-            bytes[0] as u32 >> 24 |
-            bytes[1] as u32 >> 16 |
-            bytes[2] as u32 >> 8 |
-            bytes[3] as u32
-        ) }
+        [Magic::Main, Magic::Test, Magic::Testnet4, Magic::Signet, Magic::Regtest]
+            .iter()
+            .find(|m| m.bytes().to_le_bytes() == bytes)
+            .copied()
+            .unwrap_or(Magic::Custom(bytes))
     }
 }
 
 
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-#[allow(dead_code)]
-/// Network command enum
+/// Macro to define the command enum from a single table of variants and command strings.
 //  Adding a new command requires:
+//     - A new entry in the table below
 //     - A new entry in the payload enum (Or reuse the same payload for a different command)
 //     - Associated match statements modified to support the new command/payload.
-pub enum Command {
-    Version,
-    Verack,
-    SendHeaders,
-    WTxIdRelay,
-    Ping,
-    Pong,
-    Addr,
-    GetAddr,
-    Inv,
-    GetData,
-    NotFound,
-    Tx,
-    GetBlocks,
-    GetHeaders,
-    Block,
-    Headers,
-    //More to come...
-
-    // Command enum option for unknonwn/invalid command strings
-    Unknown(String)
-    
-}
-
-impl Command {
-    pub fn to_str(&self) -> &str {
-        match self {
-            Self::Version => "version",
-            Self::Verack =>  "verack",
-            Self::SendHeaders => "sendheaders",
-            Self::WTxIdRelay => "wtxidrelay",
-            Self::Ping => "ping",
-            Self::Pong => "pong",
-            Self::Addr => "addr",
-            Self::GetAddr => "getaddr",
-            Self::Inv => "inv",
-            Self::GetData => "getdata",
-            Self::NotFound => "notfound",
-            Self::Tx => "tx",
-            Self::GetBlocks => "getblocks",
-            Self::GetHeaders => "getheaders",
-            Self::Block => "block",
-            Self::Headers => "headers",
-            Self::Unknown(s) => &s
+macro_rules! commands {
+    ($($var: ident => $str: expr),* $(,)?) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        /// Network command enum
+        pub enum Command {
+            $($var,)*
+
+            // Command enum option for unknonwn/invalid command strings
+            Unknown(String)
         }
-    }
 
-    pub fn from_str(cmd: String) -> Result<Self, Error> {
-        match &cmd[..] {
-            "version" => Ok(Self::Version),
-            "verack" => Ok(Self::Verack),
-            "sendheaders" => Ok(Self::SendHeaders),
-            "wtxidrelay" => Ok(Self::WTxIdRelay),
-            "ping" => Ok(Self::Ping),
-            "pong" => Ok(Self::Pong),
-            "addr" => Ok(Self::Addr),
-            "getaddr" => Ok(Self::GetAddr),
-            "inv" => Ok(Self::Inv),
-            "getdata" => Ok(Self::GetData),
-            "notfound" => Ok(Self::NotFound),
-            "tx" => Ok(Self::Tx),
-            "getblocks" => Ok(Self::GetBlocks),
-            "getheaders" => Ok(Self::GetHeaders),
-            "block" => Ok(Self::Block),
-            "headers" => Ok(Self::Headers),
-            _ => Err(Error::UnknownCommand(cmd))
+        impl Command {
+            /// Every known command
+            pub const ALL: &'static [Command] = &[$(Command::$var),*];
+
+            pub fn to_str(&self) -> &str {
+                match self {
+                    $(Self::$var => $str,)*
+                    Self::Unknown(s) => s
+                }
+            }
+
+            pub fn from_str(cmd: String) -> Result<Self, Error> {
+                match &cmd[..] {
+                    $($str => Ok(Self::$var),)*
+                    _ => Err(Error::UnknownCommand(cmd))
+                }
+            }
         }
-    }
+    };
+}
+
+commands! {
+    Version => "version",
+    Verack => "verack",
+    SendHeaders => "sendheaders",
+    WTxIdRelay => "wtxidrelay",
+    Ping => "ping",
+    Pong => "pong",
+    Addr => "addr",
+    AddrV2 => "addrv2",
+    SendAddrV2 => "sendaddrv2",
+    GetAddr => "getaddr",
+    Inv => "inv",
+    GetData => "getdata",
+    NotFound => "notfound",
+    Tx => "tx",
+    GetBlocks => "getblocks",
+    GetHeaders => "getheaders",
+    Block => "block",
+    Headers => "headers",
+    MemPool => "mempool",
+    Reject => "reject",
+    FeeFilter => "feefilter",
+    SendCmpct => "sendcmpct",
+    CmpctBlock => "cmpctblock",
+    GetBlockTxn => "getblocktxn",
+    BlockTxn => "blocktxn",
+    FilterLoad => "filterload",
+    FilterAdd => "filteradd",
+    FilterClear => "filterclear",
+    MerkleBlock => "merkleblock",
+    GetCFilters => "getcfilters",
+    CFilter => "cfilter",
+    GetCFHeaders => "getcfheaders",
+    CFHeaders => "cfheaders",
+    GetCFCheckpt => "getcfcheckpt",
+    CFCheckpt => "cfcheckpt",
+    SendTxRcncl => "sendtxrcncl",
 }
 
 
@@ -159,11 +200,8 @@ pub fn sha256d<T: AsRef<[u8]>>(data: T) -> [u8; 32] {
 
 impl<T: Encode> Checksum for T {
     fn checksum(&self) -> [u8; 4] {
-        let mut ret: [u8; 4] = [0; 4];
         let mut payload = Vec::new();
         self.net_encode(&mut payload);
-
-        ret.copy_from_slice(&sha256d(payload)[..4]);
-        ret
+        Hash::sha256d(payload).checksum()
     }
 }
\ No newline at end of file
@@ -0,0 +1,74 @@
+// hexdump.rs
+//
+// Annotated hexdumps of encoded messages, one row per field with the name and
+// value of the field next to its bytes, for learning and debugging the wire format:
+//
+//      00000000  f9 be b4 d9                                      magic (Main)
+//      00000004  70 69 6e 67 00 00 00 00 00 00 00 00              command (ping)
+//      00000010  08 00 00 00                                      length (8)
+//      00000014  f7 a3 55 c6                                      checksum (f7a355c6)
+//      00000018  07 00 00 00 00 00 00 00                          nonce (7)
+//
+
+use crate::msg::data::Message;
+use std::fmt::Write;
+
+// Bytes shown on each row
+const ROW_SIZE: usize = 16;
+// Longer values, such as raw payloads, are left to be read from the bytes
+const MAX_VALUE: usize = 64;
+
+impl Message {
+    /// The encoded message as a hexdump, starting a new row at the start of each field of the
+    /// header and payload with the field's name and value at its end, see [`Message::dissect`].
+    /// Fields longer than a row continue on the following rows.
+    pub fn hexdump(&self) -> String {
+        let bytes = self.to_bytes();
+        let mut out = String::new();
+        for field in self.dissect() {
+            let label = match field.value.len() {
+                1..=MAX_VALUE => format!("{} ({})", field.name, field.value),
+                _ => field.name
+            };
+            for (i, row) in bytes[field.range.clone()].chunks(ROW_SIZE).enumerate() {
+                let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+                let label = if i == 0 { label.as_str() } else { "" };
+                let line = format!("{:08x}  {:<width$}  {}", field.range.start + i * ROW_SIZE, hex.join(" "), label, width = ROW_SIZE * 3 - 1);
+                // Writing to a string cannot fail
+                let _ = writeln!(out, "{}", line.trim_end());
+            }
+        }
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{
+        data::MessagePayload,
+        header::{
+            Command,
+            Magic
+        }
+    };
+
+    #[test]
+    fn annotates_fields() {
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping);
+        let dump = ping.hexdump();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], format!("00000000  f9 be b4 d9{}  magic (Main)", " ".repeat(36)));
+        assert!(lines[1].starts_with("00000004  70 69 6e 67 00 00 00 00 00 00 00 00 ") && lines[1].ends_with(" command (ping)"));
+        assert!(lines[4].starts_with("00000018  07 00 00 00 00 00 00 00 ") && lines[4].ends_with(" nonce (7)"));
+
+        // Long fields continue on unlabelled rows and long values are left out
+        let raw = Message::new(MessagePayload::Raw { command: Command::FeeFilter, bytes: vec![0xab; 40] }, Magic::Main, Command::FeeFilter);
+        let dump = raw.hexdump();
+        let lines: Vec<&str> = dump.lines().skip(4).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" payload") && lines[1].starts_with("00000028  ab ") && lines[2].ends_with(" ab ab ab ab ab ab ab ab"));
+    }
+}
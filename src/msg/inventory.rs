@@ -7,6 +7,7 @@
 pub use crate::bitcoin::{
     hash_types::{
         Txid,
+        Wtxid,
         BlockHash
     },
     hashes::Hash
@@ -14,6 +15,48 @@ pub use crate::bitcoin::{
 
 use crate::msg::VariableInteger;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// An inventory vector as encoded on the wire, shared by inv, getdata and notfound messages.
+/// [`Inventory`] is the same with the hash typed by what it identifies.
+pub struct InvVect {
+    pub inv_type: u32,
+    pub hash: [u8; 32]
+}
+
+impl InvVect {
+    pub const MSG_ERROR: u32 = 0;
+    pub const MSG_TX: u32 = 1;
+    pub const MSG_BLOCK: u32 = 2;
+    pub const MSG_FILTERED_BLOCK: u32 = 3;
+    pub const MSG_CMPCT_BLOCK: u32 = 4;
+    /// Transaction identified by its wtxid (BIP339)
+    pub const MSG_WTX: u32 = 5;
+    /// Flag set on types asking for data to be sent with witnesses (BIP144)
+    pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+    pub const MSG_WITNESS_TX: u32 = Self::MSG_TX | Self::MSG_WITNESS_FLAG;
+    pub const MSG_WITNESS_BLOCK: u32 = Self::MSG_BLOCK | Self::MSG_WITNESS_FLAG;
+    pub const MSG_FILTERED_WITNESS_BLOCK: u32 = Self::MSG_FILTERED_BLOCK | Self::MSG_WITNESS_FLAG;
+
+    pub fn new(inv_type: u32, hash: [u8; 32]) -> Self {
+        Self {
+            inv_type,
+            hash
+        }
+    }
+}
+
+impl From<InvVect> for Inventory {
+    fn from(inv: InvVect) -> Self {
+        Self::from_id_and_hash(inv.inv_type, inv.hash)
+    }
+}
+
+impl From<&Inventory> for InvVect {
+    fn from(inv: &Inventory) -> Self {
+        Self::new(inv.identifier(), inv.inner())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Inventory {
     // If an inv value has this flag, ignore it
@@ -28,6 +71,8 @@ pub enum Inventory {
     CompactBlock(BlockHash),
     // Hash of a TX with witness data
     WitnessTx(Txid),
+    // Witness hash of a TX, announced and requested by peers that sent wtxidrelay
+    Wtx(Wtxid),
     // Hash of a block with witness data
     WitnessBlock(BlockHash),
     // Only used in getdata message. Indicates a reply should be merkleblock rather than block
@@ -43,14 +88,15 @@ impl Inventory {
     /// Return the u32 identifier of self
     pub fn identifier(&self) -> u32 {
         match self {
-            Self::Error => 0,
-            Self::Tx(_) => 1,
-            Self::Block(_) => 2,
-            Self::FilteredBlock(_) => 3,
-            Self::CompactBlock(_) => 4,
-            Self::WitnessTx(_) => 0x40000001,
-            Self::WitnessBlock(_) => 0x40000002,
-            Self::FilteredWitnessBlock(_) => 0x40000003,
+            Self::Error => InvVect::MSG_ERROR,
+            Self::Tx(_) => InvVect::MSG_TX,
+            Self::Block(_) => InvVect::MSG_BLOCK,
+            Self::FilteredBlock(_) => InvVect::MSG_FILTERED_BLOCK,
+            Self::CompactBlock(_) => InvVect::MSG_CMPCT_BLOCK,
+            Self::WitnessTx(_) => InvVect::MSG_WITNESS_TX,
+            Self::Wtx(_) => InvVect::MSG_WTX,
+            Self::WitnessBlock(_) => InvVect::MSG_WITNESS_BLOCK,
+            Self::FilteredWitnessBlock(_) => InvVect::MSG_FILTERED_WITNESS_BLOCK,
             Self::Unknown{inv_type, ..} => *inv_type
         }
     }
@@ -58,14 +104,15 @@ impl Inventory {
     /// Creates self from a u32 identified and hash
     pub fn from_id_and_hash(identifier: u32, hash: [u8; 32]) -> Self {
         match identifier {
-            0 => Self::Error,
-            1 => Self::Tx(Txid::from_inner(hash)),
-            2 => Self::Block(BlockHash::from_inner(hash)),
-            3 => Self::FilteredBlock(BlockHash::from_inner(hash)),
-            4 => Self::CompactBlock(BlockHash::from_inner(hash)),
-            0x40000001 => Self::WitnessTx(Txid::from_inner(hash)),
-            0x40000002 => Self::WitnessBlock(BlockHash::from_inner(hash)),
-            0x40000003 => Self::FilteredWitnessBlock(BlockHash::from_inner(hash)),
+            InvVect::MSG_ERROR => Self::Error,
+            InvVect::MSG_TX => Self::Tx(Txid::from_inner(hash)),
+            InvVect::MSG_BLOCK => Self::Block(BlockHash::from_inner(hash)),
+            InvVect::MSG_FILTERED_BLOCK => Self::FilteredBlock(BlockHash::from_inner(hash)),
+            InvVect::MSG_CMPCT_BLOCK => Self::CompactBlock(BlockHash::from_inner(hash)),
+            InvVect::MSG_WTX => Self::Wtx(Wtxid::from_inner(hash)),
+            InvVect::MSG_WITNESS_TX => Self::WitnessTx(Txid::from_inner(hash)),
+            InvVect::MSG_WITNESS_BLOCK => Self::WitnessBlock(BlockHash::from_inner(hash)),
+            InvVect::MSG_FILTERED_WITNESS_BLOCK => Self::FilteredWitnessBlock(BlockHash::from_inner(hash)),
             x => Self::Unknown { inv_type: x, hash }
         }
         
@@ -81,6 +128,7 @@ impl Inventory {
             Self::FilteredBlock(x) => x.into_inner(),
             Self::CompactBlock(x) => x.into_inner(),
             Self::WitnessTx(x) => x.into_inner(),
+            Self::Wtx(x) => x.into_inner(),
             Self::WitnessBlock(x) => x.into_inner(),
             Self::FilteredWitnessBlock(x) => x.into_inner(),
             Self::Unknown{inv_type: _, hash} => *hash
@@ -97,12 +145,13 @@ impl std::fmt::Display for Inventory {
             Self::FilteredBlock(_) => "Filtered Block",
             Self::CompactBlock(_) => "Compact Block",
             Self::WitnessTx(_) => "Witness Transaction",
+            Self::Wtx(_) => "Witness Transaction ID",
             Self::WitnessBlock(_) => "Witness Block",
             Self::FilteredWitnessBlock(_) => "Filtered Witness Block",
             Self::Unknown{inv_type: _, hash: _} => "Unknown"
         };
 
-        write!(f, "INV: [{}] {}", obj_type, self.inner().iter().rev().map(|x| format!("{:02x}", x)).collect::<String>())
+        write!(f, "INV: [{}] {}", obj_type, crate::blockdata::hash::Hash::from(self.inner()))
     }
 }
 
@@ -0,0 +1,217 @@
+// json.rs
+//
+// Conversion of decoded messages to JSON, with the fields of each payload
+// kept structured so output can be filtered with tools like jq.
+//
+// Hashes are in the usual reversed hex, scripts and unknown payloads in hex.
+//
+
+use crate::{
+    blockdata::{
+        hash::Hash,
+        header::BlockHeader
+    },
+    bitcoin::{
+        hashes::hex::ToHex,
+        Transaction
+    },
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        network::{
+            NetAddress,
+            ServicesList
+        }
+    }
+};
+use serde_json::{
+    json,
+    Value
+};
+
+impl Message {
+    /// The header fields and the payload, see [`MessagePayload::to_json`]
+    pub fn to_json(&self) -> Value {
+        json!({
+            "magic": format!("{:?}", self.header.magic),
+            "command": self.header.command.to_str(),
+            "length": self.header.length,
+            "checksum": self.header.checksum.to_hex(),
+            "payload": self.payload.to_json()
+        })
+    }
+}
+
+impl MessagePayload {
+    /// The payload's fields as a JSON value, `null` for empty payloads
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::Version(v) => json!({
+                "version": v.version.0,
+                "services": services(&v.service),
+                "timestamp": v.timestamp.as_secs(),
+                "addr_recv": net_address(&v.addr_recv),
+                "addr_from": net_address(&v.addr_from),
+                "nonce": v.nonce,
+                "user_agent": v.agent.as_str(),
+                "start_height": v.start_height,
+                "relay": v.relay
+            }),
+            Self::PingPong(nonce) => json!({ "nonce": nonce }),
+            Self::AddrList(list) => Value::Array(list.iter().map(|a| {
+                let mut addr = net_address(&a.netaddress);
+                addr["timestamp"] = json!(a.timestamp.as_secs());
+                addr
+            }).collect()),
+            Self::AddrV2List(list) => Value::Array(list.iter().map(|a| json!({
+                "timestamp": a.timestamp.as_secs(),
+                "services": services(&a.services),
+                "address": a.addr.to_string(),
+                "port": a.port
+            })).collect()),
+            Self::InvVect(inv) => Value::Array(inv.iter().map(|i| json!({
+                "type": i.identifier(),
+                "hash": Hash::from(i.inner()).to_string()
+            })).collect()),
+            Self::Transction(tx) => transaction(tx),
+            Self::BlockLocator(locator) => json!({
+                "version": locator.version,
+                "hashes": locator.hashes.iter().map(|h| h.to_string()).collect::<Vec<String>>(),
+                "stop": locator.stop.to_string()
+            }),
+            Self::Headers(headers) => Value::Array(headers.iter().map(header).collect()),
+            Self::Block(block) => json!({
+                "header": header(&block.header.into()),
+                "transactions": block.txdata.iter().map(transaction).collect::<Vec<Value>>()
+            }),
+            Self::SendCmpct(s) => json!({ "announce": s.announce, "version": s.version }),
+            Self::CompactBlock(c) => json!({
+                "header": header(&c.header.into()),
+                "nonce": c.nonce,
+                "short_ids": c.short_ids.iter().map(|id| format!("{:012x}", id)).collect::<Vec<String>>(),
+                "prefilled": c.prefilled.iter().map(|p| json!({
+                    "index": p.index,
+                    "tx": transaction(&p.tx)
+                })).collect::<Vec<Value>>()
+            }),
+            Self::GetBlockTxn(request) => json!({
+                "block_hash": request.block_hash.to_string(),
+                "indexes": request.indexes
+            }),
+            Self::BlockTxn(txs) => json!({
+                "block_hash": txs.block_hash.to_string(),
+                "transactions": txs.transactions.iter().map(transaction).collect::<Vec<Value>>()
+            }),
+            Self::FilterLoad(filter) => json!({
+                "filter": filter.filter.to_hex(),
+                "hash_funcs": filter.hash_funcs,
+                "tweak": filter.tweak,
+                "flags": filter.flags
+            }),
+            Self::MerkleBlock(block) => json!({
+                "header": header(&block.header),
+                "total_transactions": block.total_transactions,
+                "hashes": block.hashes.iter().map(|h| h.to_string()).collect::<Vec<String>>(),
+                "flags": block.flags.to_hex()
+            }),
+            Self::CFilter(filter) => json!({
+                "filter_type": filter.filter_type,
+                "block_hash": filter.block_hash.to_string(),
+                "filter": filter.filter.to_hex()
+            }),
+            Self::EmptyPayload => Value::Null,
+            Self::Raw { bytes, .. } => json!({ "hex": bytes.to_hex() })
+        }
+    }
+}
+
+// Names of the service flags, ordered by bit
+fn services(list: &ServicesList) -> Value {
+    let mut flags = list.get_flags();
+    flags.sort_by_key(|f| f.value());
+    json!({
+        "bits": list.bits(),
+        "flags": flags.iter().filter(|f| f.value() != 0).map(|f| format!("{:?}", f)).collect::<Vec<String>>()
+    })
+}
+
+fn net_address(addr: &NetAddress) -> Value {
+    json!({
+        "services": services(&addr.services),
+        "address": addr.address.ip().to_string(),
+        "port": addr.address.port()
+    })
+}
+
+fn header(header: &BlockHeader) -> Value {
+    json!({
+        "hash": header.block_hash().to_string(),
+        "version": header.version,
+        "prev_blockhash": header.prev_blockhash.to_string(),
+        "merkle_root": header.merkle_root.to_string(),
+        "time": header.time,
+        "bits": header.bits,
+        "nonce": header.nonce
+    })
+}
+
+fn transaction(tx: &Transaction) -> Value {
+    json!({
+        "txid": tx.txid().to_string(),
+        "wtxid": tx.wtxid().to_string(),
+        "version": tx.version,
+        "lock_time": tx.lock_time,
+        "inputs": tx.input.iter().map(|input| json!({
+            "txid": input.previous_output.txid.to_string(),
+            "vout": input.previous_output.vout,
+            "script_sig": input.script_sig.as_bytes().to_hex(),
+            "sequence": input.sequence,
+            "witness": input.witness.to_vec().iter().map(|w| w.to_hex()).collect::<Vec<String>>()
+        })).collect::<Vec<Value>>(),
+        "outputs": tx.output.iter().map(|output| json!({
+            "value": output.value,
+            "script_pubkey": output.script_pubkey.as_bytes().to_hex()
+        })).collect::<Vec<Value>>()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        msg::{
+            header::{
+                Command,
+                Magic
+            },
+            inventory::{
+                Inventory,
+                Txid
+            },
+            network::VersionMessage
+        },
+        blockdata::Hash
+    };
+
+    #[test]
+    fn converts_messages() {
+        let version = VersionMessage::builder(Address::me()).build();
+        let msg = Message::new(MessagePayload::Version(version.clone()), Magic::Main, Command::Version);
+        let json = msg.to_json();
+        assert_eq!(json["command"], "version");
+        assert_eq!(json["length"], msg.header.length);
+        assert_eq!(json["payload"]["user_agent"], version.agent.as_str());
+        assert_eq!(json["payload"]["nonce"], version.nonce);
+
+        let mut hash = [0; 32];
+        hash[31] = 0xab;
+        let inv = MessagePayload::InvVect(vec![Inventory::WitnessTx(Txid::from_inner(hash))]);
+        assert_eq!(inv.to_json(), json!([{ "type": 0x40000001, "hash": format!("ab{}", "00".repeat(31)) }]));
+        assert_eq!(MessagePayload::EmptyPayload.to_json(), Value::Null);
+        assert_eq!(MessagePayload::Raw { command: Command::Reject, bytes: vec![1, 2] }.to_json(), json!({ "hex": "0102" }));
+    }
+}
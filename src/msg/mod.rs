@@ -7,6 +7,15 @@ pub mod data;
 pub mod header;
 pub mod network;
 pub mod inventory;
+pub mod agent;
+pub mod compact;
+pub mod bloom;
+pub mod cfilter;
+pub mod display;
+pub mod dissect;
+pub mod hexdump;
+#[cfg(feature = "export")]
+pub mod json;
 
 // Variable length integer structure
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -6,7 +6,11 @@
 
 use crate::{
     encode::Error,
-    address::Address
+    address::{
+        Address,
+        AddrV2
+    },
+    msg::agent::UserAgent
 };
 use std::collections::HashSet;
 use std::time::{
@@ -26,17 +30,21 @@ pub enum Service {
     Bloom,
     Witness,
     CompactFilters,
-    NetworkLimited
+    NetworkLimited,
+    P2PV2,
+    // Service bit not known to this library, holding the bit value
+    Unknown(u64)
 }
 
 // Constant array containing the right shift amount for each service flag.
-pub const SERVICE_BITS: [usize; 6] = [
-    0, // Network
-    1, // GetUTXO
-    2, // Bloom
-    3, // Witness
-    6, // CompactFilters
-    10 // NetworkLimited
+pub const SERVICE_BITS: [usize; 7] = [
+    0,  // Network
+    1,  // GetUTXO
+    2,  // Bloom
+    3,  // Witness
+    6,  // CompactFilters
+    10, // NetworkLimited
+    11  // P2PV2
 ];
 
 impl Service {
@@ -44,15 +52,19 @@ impl Service {
         match self {
             // Each service is a bit flag
             Self::None => 0,                              // No service available
-            Self::Network =>        (1<<SERVICE_BITS[0]), // Full chain history available
-            Self::GetUTXO =>        (1<<SERVICE_BITS[1]), // Can be queried for UTXOs
-            Self::Bloom =>          (1<<SERVICE_BITS[2]), // Capable of handling bloom filtered connections
-            Self::Witness =>        (1<<SERVICE_BITS[3]), // Witness data available
-            Self::CompactFilters => (1<<SERVICE_BITS[4]), // Can serve basic block filte requests
-            Self::NetworkLimited => (1<<SERVICE_BITS[5])  // Can serve blocks from the last 2 days
+            Self::Network =>        1<<SERVICE_BITS[0], // Full chain history available
+            Self::GetUTXO =>        1<<SERVICE_BITS[1], // Can be queried for UTXOs
+            Self::Bloom =>          1<<SERVICE_BITS[2], // Capable of handling bloom filtered connections
+            Self::Witness =>        1<<SERVICE_BITS[3], // Witness data available
+            Self::CompactFilters => 1<<SERVICE_BITS[4], // Can serve basic block filte requests
+            Self::NetworkLimited => 1<<SERVICE_BITS[5], // Can serve blocks from the last 2 days
+            Self::P2PV2 =>          1<<SERVICE_BITS[6], // Supports the BIP324 v2 transport
+            Self::Unknown(bit) =>   *bit
         }
     }
 
+    /// Create a service from a flag value with a single bit set.
+    /// Bits not known to this library are returned as `Service::Unknown`.
     pub fn try_from_bit(flag: u64) -> Result<Self, Error> {
         match flag {
             0 => Ok(Self::None),
@@ -62,6 +74,8 @@ impl Service {
             8 => Ok(Self::Witness),
             64 => Ok(Self::CompactFilters),
             1024 => Ok(Self::NetworkLimited),
+            2048 => Ok(Self::P2PV2),
+            x if x.is_power_of_two() => Ok(Self::Unknown(x)),
             _ => Err(Error::InvalidData)
         }
     }
@@ -84,6 +98,33 @@ impl ServicesList {
     pub fn get_flags(&self) -> Vec<Service> {
         self.0.iter().map(|flag| *flag).collect()
     }
+
+    /// Check if a service flag is set
+    pub fn has(&self, flag: Service) -> bool {
+        self.0.contains(&flag)
+    }
+
+    /// Create a service list from the u64 bit field used on the wire.
+    /// Unknown bits are preserved as `Service::Unknown` flags.
+    pub fn from_bits(bits: u64) -> Self {
+        // Early exit for flags with no bits set...
+        if bits == 0 {
+            return ServicesList::default();
+        }
+
+        let mut services = ServicesList::new();
+        for bitp in 0..64 {
+            if bits & (1<<bitp) != 0 {
+                services.add_flag(Service::try_from_bit(1<<bitp).expect("Single bit flag"));
+            }
+        }
+        services
+    }
+
+    /// Return the u64 bit field used on the wire
+    pub fn bits(&self) -> u64 {
+        self.0.iter().fold(0, |acc, flag| acc | flag.value())
+    }
 }
 
 impl Default for ServicesList {
@@ -94,29 +135,69 @@ impl Default for ServicesList {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Protocol version advertised in version messages.
+/// Named constants mark the versions at which protocol features were introduced
+/// (<https://github.com/bitcoin/bitcoin/blob/master/src/node/protocol_version.h>)
+pub struct ProtocolVersion(pub u32);
+
+impl ProtocolVersion {
+    /// addr_from, nonce, user agent and start height fields in version messages
+    pub const ADDR_FROM: Self = Self(106);
+    /// Initial protocol version
+    pub const INIT: Self = Self(209);
+    /// Oldest protocol version bitcoin core will connect to
+    pub const MIN_PEER: Self = Self(31800);
+    /// Nonces in ping messages and pong replies (BIP31)
+    pub const BIP0031: Self = Self(60001);
+    /// Relay flag in version messages and bloom filtering (BIP37)
+    pub const RELAY: Self = Self(70001);
+    /// sendheaders command (BIP130)
+    pub const SENDHEADERS: Self = Self(70012);
+    /// feefilter command (BIP133)
+    pub const FEEFILTER: Self = Self(70013);
+    /// Compact blocks (BIP152)
+    pub const SHORT_IDS_BLOCKS: Self = Self(70014);
+    /// Invalid compact blocks are not punished
+    pub const INVALID_CB_NO_BAN: Self = Self(70015);
+    /// wtxidrelay command (BIP339)
+    pub const WTXID_RELAY: Self = Self(70016);
+
+    /// Return the version both sides of a connection should use
+    pub fn negotiate(self, other: Self) -> Self {
+        self.min(other)
+    }
+}
+
+impl From<u32> for ProtocolVersion {
+    fn from(version: u32) -> Self {
+        Self(version)
+    }
+}
+
 #[derive(Debug, Clone, Eq)]
 /// The message payload for version commands.
 pub struct VersionMessage {
-    pub version: u32,
+    pub version: ProtocolVersion,
     pub service: ServicesList,
     pub timestamp: Duration,
     pub addr_recv: NetAddress,
     pub addr_from: NetAddress,
     pub nonce: u64,
-    pub agent: String,
+    pub agent: UserAgent,
     pub start_height: u32,
     pub relay: bool
 }
 
 impl VersionMessage {
     pub fn new(
-        version: u32,
+        version: ProtocolVersion,
         service: ServicesList,
         timestamp: Duration,
         addr_recv: NetAddress,
         addr_from: NetAddress,
         nonce: u64,
-        agent: String,
+        agent: UserAgent,
         start_height: u32,
         relay: bool
     ) -> VersionMessage {
@@ -132,28 +213,120 @@ impl VersionMessage {
             relay
         }
     }
+
+    /// Start building a version message to send to the given address
+    pub fn builder(addr_recv: Address) -> VersionMessageBuilder {
+        VersionMessageBuilder::new(addr_recv)
+    }
 }
 
 impl From<Address> for VersionMessage {
-    /// Create a default VersionMessage struct from a peer with:
-    /// * Protocol version 70016 (line 12: https://github.com/bitcoin/bitcoin/blob/master/src/version.h)
-    /// * No service flags
-    /// * Current time at fuction evoke
-    /// * Default net address structs
-    /// * Random nonce capped at u64 ceiling
-    /// * Agent "bit-tune-v0.0.1"
-    /// * Relay flag set to false
+    /// Create a default VersionMessage struct from a peer.
+    /// See [`VersionMessageBuilder`] for the defaults used.
     fn from(address: Address) -> Self {
+        VersionMessageBuilder::new(address).build()
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Builder for version messages.
+/// 
+/// Fields that are not set use the following defaults:
+/// * Protocol version 70015
+/// * No service flags
+/// * Current time when the message is built
+/// * Default net address struct for addr_from
+/// * Random nonce capped at u64 ceiling
+/// * Agent "/btcnetmsg:`<crate version>`/"
+/// * Start height of 0
+/// * Relay flag set to false
+pub struct VersionMessageBuilder {
+    version: ProtocolVersion,
+    services: ServicesList,
+    timestamp: Option<Duration>,
+    addr_recv: NetAddress,
+    addr_from: NetAddress,
+    nonce: Option<u64>,
+    agent: UserAgent,
+    start_height: u32,
+    relay: bool
+}
+
+impl VersionMessageBuilder {
+    /// Start building a version message to send to the given address
+    pub fn new(addr_recv: Address) -> Self {
+        Self {
+            version: ProtocolVersion::INVALID_CB_NO_BAN,
+            services: ServicesList::default(),
+            timestamp: None,
+            addr_recv: NetAddress::new(ServicesList::default(), addr_recv),
+            addr_from: NetAddress::default(),
+            nonce: None,
+            agent: UserAgent::default(),
+            start_height: 0,
+            relay: false // Setting this option to true will get the other node to broadcast transaction regardless of bloom filter status
+        }
+    }
+
+    pub fn version(mut self, version: ProtocolVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn services(mut self, services: ServicesList) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: Duration) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Set the services and address of the receiving node
+    pub fn addr_recv(mut self, addr_recv: NetAddress) -> Self {
+        self.addr_recv = addr_recv;
+        self
+    }
+
+    /// Set the services and address of this node
+    pub fn addr_from(mut self, addr_from: NetAddress) -> Self {
+        self.addr_from = addr_from;
+        self
+    }
+
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn user_agent(mut self, agent: UserAgent) -> Self {
+        self.agent = agent;
+        self
+    }
+
+    pub fn start_height(mut self, start_height: u32) -> Self {
+        self.start_height = start_height;
+        self
+    }
+
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Build the version message, filling in the timestamp and nonce if they were not set
+    pub fn build(self) -> VersionMessage {
         VersionMessage::new(
-            70015, 
-            ServicesList::default(), 
-            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get time"), 
-            NetAddress::new(ServicesList::default(), address),
-            NetAddress::default(),
-            rand::thread_rng().gen_range(0..u64::MAX), 
-            String::from("bit-tune-v0.0.1"), 
-            0u32,
-            false // Setting this option to true will get the other node to broadcast transaction regardless of bloom filter status
+            self.version,
+            self.services,
+            self.timestamp.unwrap_or_else(|| SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get time")),
+            self.addr_recv,
+            self.addr_from,
+            self.nonce.unwrap_or_else(|| rand::thread_rng().gen_range(0..u64::MAX)),
+            self.agent,
+            self.start_height,
+            self.relay
         )
     }
 }
@@ -211,10 +384,46 @@ impl TimestampedNetAddress {
             netaddress
         }
     }
+
+    /// Timestamp a net address with the current time
+    pub fn now(netaddress: NetAddress) -> Self {
+        Self::new(
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get time"),
+            netaddress
+        )
+    }
 }
 
 impl From<TimestampedNetAddress> for NetAddress {
     fn from(tsna: TimestampedNetAddress) -> Self {
         tsna.netaddress
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// BIP155 network address with its services and a timestamp, as sent in addrv2 messages.
+/// Unlike [`TimestampedNetAddress`] it can carry non-IP addresses such as onion services.
+pub struct NetAddressV2 {
+    pub timestamp: Duration,
+    pub services: ServicesList,
+    pub addr: AddrV2,
+    pub port: u16
+}
+
+impl NetAddressV2 {
+    pub fn new(timestamp: Duration, services: ServicesList, addr: AddrV2, port: u16) -> Self {
+        Self {
+            timestamp,
+            services,
+            addr,
+            port
+        }
+    }
+}
+
+impl From<TimestampedNetAddress> for NetAddressV2 {
+    fn from(tsna: TimestampedNetAddress) -> Self {
+        let addr = tsna.netaddress.address.0;
+        Self::new(tsna.timestamp, tsna.netaddress.services, AddrV2::from(addr.ip()), addr.port())
+    }
+}
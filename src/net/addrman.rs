@@ -0,0 +1,622 @@
+// addrman.rs
+//
+// Peer address manager modelled on bitcoin core's addrman.
+//
+// Learned addresses start in the "new" table and move to the "tried" table once a
+// connection to them succeeds. Tables are split into buckets picked by a keyed hash of
+// the address's network group (and for new addresses, the group of the peer that sent
+// it) so that no single source can fill the tables. Both tables are saved to disk so
+// addresses survive between runs.
+//
+
+use crate::{
+    msg::{
+        header::sha256d,
+        network::{
+            NetAddressV2,
+            ProtocolVersion,
+            ServicesList
+        },
+        VariableInteger
+    },
+    address::AddrV2,
+    encode::{
+        self,
+        Decode,
+        Encode
+    },
+    net::{
+        peer::{
+            Peer,
+            Host
+        },
+        Error
+    }
+};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    path::Path,
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+
+/// Number of buckets in the new table
+pub const NEW_BUCKET_COUNT: usize = 1024;
+
+/// Number of buckets in the tried table
+pub const TRIED_BUCKET_COUNT: usize = 256;
+
+/// Addresses held per bucket
+pub const BUCKET_SIZE: usize = 64;
+
+/// Addresses that failed a connection within this period are not offered again
+pub const RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Most addresses sent in reply to a getaddr (MAX_ADDR_TO_SEND in bitcoin core)
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+/// Largest share of known addresses sent in reply to a getaddr, in percent
+/// (MAX_PCT_ADDR_TO_SEND in bitcoin core)
+pub const MAX_PCT_ADDR_TO_SEND: usize = 23;
+
+// Buckets a single source group (new table) or address group (tried table) may use
+const NEW_BUCKETS_PER_SOURCE: u64 = 64;
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+// Addresses not seen for this long, or never connected to after this many attempts, are terrible
+const HORIZON: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const RETRIES: u32 = 3;
+
+// Leading bytes and format version of a saved address file
+const FILE_MAGIC: [u8; 4] = *b"ADRM";
+const FILE_VERSION: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An address known to the address manager and its connection history
+pub struct AddrInfo {
+    pub peer: Peer,
+    pub services: ServicesList,
+    /// Last time the address was heard of, as a unix timestamp
+    pub last_seen: Duration,
+    /// Last time a connection to the address succeeded
+    pub last_success: Option<Duration>,
+    /// Last time a connection to the address was attempted
+    pub last_try: Option<Duration>,
+    /// Failed attempts since the last success
+    pub attempts: u32,
+    /// Whether the address is in the tried table
+    pub tried: bool,
+    /// User agent the peer sent on our last connection to it
+    pub user_agent: Option<String>,
+    /// Round trip time of the peer's last answered ping
+    pub latency: Option<Duration>,
+    /// Protocol version the peer sent on our last connection to it
+    pub version: Option<ProtocolVersion>,
+    // Host of the peer that sent us the address
+    source: Option<Host>
+}
+
+impl AddrInfo {
+    fn new(peer: Peer, services: ServicesList, last_seen: Duration, source: Option<Host>) -> Self {
+        Self {
+            peer,
+            services,
+            last_seen,
+            last_success: None,
+            last_try: None,
+            attempts: 0,
+            tried: false,
+            user_agent: None,
+            latency: None,
+            version: None,
+            source
+        }
+    }
+
+    /// Check if the address is not worth keeping: not seen in a month, or never connected
+    /// to despite repeated attempts
+    pub fn is_terrible(&self, now: Duration) -> bool {
+        now.saturating_sub(self.last_seen) > HORIZON || (self.last_success.is_none() && self.attempts >= RETRIES)
+    }
+
+    // A connection failed within the retry delay and has not succeeded since
+    fn recently_failed(&self, now: Duration) -> bool {
+        match self.last_try {
+            Some(tried) => now.saturating_sub(tried) < RETRY_DELAY && self.last_success.is_none_or(|s| s < tried),
+            None => false
+        }
+    }
+
+    // Relative chance of being selected, lowered for every failed attempt
+    fn chance(&self) -> f64 {
+        0.66f64.powi(self.attempts.min(8) as i32)
+    }
+}
+
+/// Store of peer addresses that supplies randomized connection candidates
+pub struct AddrMan {
+    // Secret mixed into bucket selection so peers cannot predict placement
+    key: [u8; 32],
+    entries: HashMap<Peer, AddrInfo>,
+    new: Vec<Vec<Peer>>,
+    tried: Vec<Vec<Peer>>,
+    // Keep addresses received from peers that are not publicly routable
+    unroutable: bool
+}
+
+impl AddrMan {
+    /// Create an empty address manager
+    pub fn new() -> Self {
+        Self::with_key(rand::thread_rng().gen())
+    }
+
+    fn with_key(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            entries: HashMap::new(),
+            new: vec![Vec::new(); NEW_BUCKET_COUNT],
+            tried: vec![Vec::new(); TRIED_BUCKET_COUNT],
+            unroutable: false
+        }
+    }
+
+    /// Keep addresses received from peers even if they are not publicly routable, see
+    /// [`Host::is_routable`]. Needed for private and local test networks.
+    pub fn with_unroutable(mut self, allow: bool) -> Self {
+        self.unroutable = allow;
+        self
+    }
+
+    /// Number of known addresses
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if no addresses are known
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get what is known about an address
+    pub fn get(&self, peer: &Peer) -> Option<&AddrInfo> {
+        self.entries.get(peer)
+    }
+
+    /// Iterate over all known addresses
+    pub fn iter(&self) -> impl Iterator<Item = &AddrInfo> {
+        self.entries.values()
+    }
+
+    /// Add an address heard of at `last_seen`, learned from `source` (`None` for seeds and
+    /// addresses supplied by the user). Returns true if the address was not already known.
+    pub fn add(&mut self, peer: Peer, services: ServicesList, last_seen: Duration, source: Option<Host>) -> bool {
+        // Timestamps from the future are treated as now
+        let last_seen = last_seen.min(now());
+        if let Some(info) = self.entries.get_mut(&peer) {
+            info.last_seen = info.last_seen.max(last_seen);
+            info.services = ServicesList::from_bits(info.services.bits() | services.bits());
+            return false
+        }
+
+        self.insert_new(AddrInfo::new(peer, services, last_seen, source))
+    }
+
+    /// Add addresses received from a peer in an addr or addrv2 message, returning how many
+    /// were new. Addresses on networks that cannot be dialed are skipped, as are unroutable
+    /// ones unless [allowed](Self::with_unroutable).
+    pub fn add_received(&mut self, addrs: &[NetAddressV2], source: Host) -> usize {
+        let mut added = 0;
+        for addr in addrs {
+            if let Ok(peer) = Peer::try_from(addr.clone()) {
+                if !self.unroutable && !peer.addr.is_routable() {
+                    continue
+                }
+                if self.add(peer, addr.services.clone(), addr.timestamp, Some(source)) {
+                    added += 1;
+                }
+            }
+        }
+        added
+    }
+
+    /// Record a connection attempt to an address
+    pub fn attempt(&mut self, peer: &Peer) {
+        if let Some(info) = self.entries.get_mut(peer) {
+            info.last_try = Some(now());
+            info.attempts += 1;
+        }
+    }
+
+    /// Record a successful connection to an address, moving it to the tried table.
+    /// Unknown addresses are added first.
+    pub fn good(&mut self, peer: &Peer) {
+        let now = now();
+        if !self.entries.contains_key(peer) {
+            self.add(*peer, ServicesList::default(), now, None);
+        }
+        let mut info = match self.entries.remove(peer) {
+            Some(info) => info,
+            None => return // Dropped straight away by a full bucket
+        };
+        info.last_seen = now;
+        info.last_success = Some(now);
+        info.last_try = Some(now);
+        info.attempts = 0;
+
+        // Addresses already tried stay in their bucket, only their history changes
+        if info.tried {
+            self.entries.insert(*peer, info);
+            return
+        }
+        let bucket = self.new_bucket(&info);
+        self.new[bucket].retain(|p| p != peer);
+        self.insert_tried(info);
+    }
+
+    /// Record the services and user agent a connected peer sent in its version message
+    pub fn record_version(&mut self, peer: &Peer, services: &ServicesList, user_agent: &str) {
+        if let Some(info) = self.entries.get_mut(peer) {
+            info.services = services.clone();
+            info.user_agent = Some(user_agent.to_string());
+        }
+    }
+
+    /// Record the protocol version a peer sent, including versions too old to stay connected to
+    pub fn record_protocol_version(&mut self, peer: &Peer, version: ProtocolVersion) {
+        if let Some(info) = self.entries.get_mut(peer) {
+            info.version = Some(version);
+        }
+    }
+
+    /// Record the round trip time of a ping answered by a peer
+    pub fn record_latency(&mut self, peer: &Peer, latency: Duration) {
+        if let Some(info) = self.entries.get_mut(peer) {
+            info.latency = Some(latency);
+        }
+    }
+
+    /// Pick a random address to connect to from either table, skipping addresses that
+    /// recently failed and those rejected by `usable` (such as ones already connected to).
+    /// Addresses with fewer failed attempts are more likely to be picked.
+    pub fn select<F: Fn(&Peer) -> bool>(&self, usable: F) -> Option<Peer> {
+        let now = now();
+        let (tried, new): (Vec<&AddrInfo>, Vec<&AddrInfo>) = self.entries
+            .values()
+            .filter(|info| usable(&info.peer) && !info.recently_failed(now))
+            .partition(|info| info.tried);
+
+        let mut rng = rand::thread_rng();
+        let table = match (tried.is_empty(), new.is_empty()) {
+            (true, true) => return None,
+            (false, true) => tried,
+            (true, false) => new,
+            (false, false) => if rng.gen_bool(0.5) { tried } else { new }
+        };
+
+        loop {
+            let info = table[rng.gen_range(0..table.len())];
+            if rng.gen_bool(info.chance()) {
+                return Some(info.peer)
+            }
+        }
+    }
+
+    /// Random sample of known addresses to send a peer that asked with getaddr: at most
+    /// `max` addresses and `max_pct` percent of those known, leaving out terrible ones.
+    /// Each address is timestamped with when it was last heard of.
+    pub fn get_addr(&self, max: usize, max_pct: usize) -> Vec<NetAddressV2> {
+        let now = now();
+        let count = max.min(self.entries.len() * max_pct / 100);
+        let mut sample: Vec<&AddrInfo> = self.entries.values().collect();
+        let mut rng = rand::thread_rng();
+        let mut addrs = Vec::with_capacity(count);
+        // Partial Fisher-Yates shuffle, stopping once enough usable addresses are drawn
+        for i in 0..sample.len() {
+            if addrs.len() >= count {
+                break
+            }
+            let j = rng.gen_range(i..sample.len());
+            sample.swap(i, j);
+            let info = sample[i];
+            if !info.is_terrible(now) {
+                addrs.push(NetAddressV2::new(info.last_seen, info.services.clone(), AddrV2::from(info.peer.addr), info.peer.port.to_u16()));
+            }
+        }
+        addrs
+    }
+
+    /// Load addresses saved with [`save`](Self::save)
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let data = fs::read(path)?;
+        let mut r = &data[..];
+
+        let magic: [u8; 4] = Decode::net_decode(&mut r)?;
+        let version: u8 = Decode::net_decode(&mut r)?;
+        if magic != FILE_MAGIC || version == 0 || version > FILE_VERSION {
+            return Err(Error::Decode(encode::Error::InvalidData))
+        }
+
+        let mut addrman = Self::with_key(Decode::net_decode(&mut r)?);
+        for _ in 0..VariableInteger::net_decode(&mut r)?.inner() {
+            let info = decode_info(&mut r, version)?;
+            match info.tried {
+                true => addrman.insert_tried(info),
+                false => { addrman.insert_new(info); }
+            }
+        }
+        Ok(addrman)
+    }
+
+    /// Save all addresses to a file, replacing it
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut data = Vec::new();
+        FILE_MAGIC.net_encode(&mut data);
+        FILE_VERSION.net_encode(&mut data);
+        self.key.net_encode(&mut data);
+        VariableInteger(self.entries.len() as u64).net_encode(&mut data);
+        for info in self.entries.values() {
+            encode_info(info, &mut data);
+        }
+
+        super::write_atomic(path.as_ref(), &data)
+    }
+
+    /// Insert an address into its new bucket, evicting the worst entry of a full bucket
+    fn insert_new(&mut self, info: AddrInfo) -> bool {
+        let bucket = self.new_bucket(&info);
+        if self.new[bucket].len() >= BUCKET_SIZE {
+            let worst = self.worst(&self.new[bucket], |i| i.last_seen);
+            self.new[bucket].retain(|p| *p != worst);
+            self.entries.remove(&worst);
+        }
+
+        self.new[bucket].push(info.peer);
+        self.entries.insert(info.peer, AddrInfo { tried: false, ..info });
+        true
+    }
+
+    /// Insert an address into its tried bucket. The least recently successful entry of a
+    /// full bucket is moved back to the new table.
+    fn insert_tried(&mut self, info: AddrInfo) {
+        let bucket = self.tried_bucket(&info.peer);
+        if self.tried[bucket].len() >= BUCKET_SIZE {
+            let oldest = self.worst(&self.tried[bucket], |i| i.last_success.unwrap_or_default());
+            self.tried[bucket].retain(|p| *p != oldest);
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.insert_new(evicted);
+            }
+        }
+
+        self.tried[bucket].push(info.peer);
+        self.entries.insert(info.peer, AddrInfo { tried: true, ..info });
+    }
+
+    // Terrible entry of a bucket if there is one, otherwise the one with the lowest `age`
+    fn worst<F: Fn(&AddrInfo) -> Duration>(&self, bucket: &[Peer], age: F) -> Peer {
+        let now = now();
+        let infos = bucket.iter().map(|p| &self.entries[p]);
+        match infos.clone().find(|i| i.is_terrible(now)) {
+            Some(info) => info.peer,
+            None => infos.min_by_key(|i| age(i)).expect("Bucket is full").peer
+        }
+    }
+
+    fn new_bucket(&self, info: &AddrInfo) -> usize {
+        let source = info.source.map(group).unwrap_or_default();
+        let slot = self.hash(&[&source, &group(info.peer.addr)]) % NEW_BUCKETS_PER_SOURCE;
+        (self.hash(&[&source, &slot.to_le_bytes()]) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    fn tried_bucket(&self, peer: &Peer) -> usize {
+        let mut addr = AddrV2::from(peer.addr).bytes();
+        addr.extend_from_slice(&peer.port.0);
+        let slot = self.hash(&[&addr]) % TRIED_BUCKETS_PER_GROUP;
+        (self.hash(&[&group(peer.addr), &slot.to_le_bytes()]) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    // Keyed hash of the concatenated parts
+    fn hash(&self, parts: &[&[u8]]) -> u64 {
+        let mut data = self.key.to_vec();
+        for part in parts {
+            data.extend_from_slice(part);
+        }
+        let mut out = [0; 8];
+        out.copy_from_slice(&sha256d(data)[..8]);
+        u64::from_le_bytes(out)
+    }
+}
+
+impl Default for AddrMan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Network group of a host: the /16 of an IPv4 address, the /32 of an IPv6 address, or the network and first four
+/// bits for overlay networks. Addresses in the same group are likely run by the same operator.
+pub(crate) fn group(host: Host) -> Vec<u8> {
+    let addr = AddrV2::from(host);
+    let bytes = addr.bytes();
+    match host {
+        Host::Ipv4(_) => vec![addr.network_id(), bytes[0], bytes[1]],
+        Host::Ipv6(_) => vec![addr.network_id(), bytes[0], bytes[1], bytes[2], bytes[3]],
+        Host::TorV3(_) | Host::I2p(_) => vec![addr.network_id(), bytes[0] >> 4]
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get time")
+}
+
+// Saved entries are the address as an addrv2 entry followed by the connection history.
+// Times are unix timestamps, zero for never. Empty user agents and zero latencies are unknown.
+fn encode_info(info: &AddrInfo, w: &mut Vec<u8>) {
+    NetAddressV2::new(info.last_seen, info.services.clone(), AddrV2::from(info.peer.addr), info.peer.port.to_u16()).net_encode(&mut *w);
+    info.last_success.unwrap_or_default().net_encode(&mut *w);
+    info.last_try.unwrap_or_default().net_encode(&mut *w);
+    info.attempts.net_encode(&mut *w);
+    (info.tried as u8).net_encode(&mut *w);
+    match info.source {
+        Some(host) => { 1u8.net_encode(&mut *w); AddrV2::from(host).net_encode(&mut *w); },
+        None => { 0u8.net_encode(&mut *w); }
+    }
+    info.user_agent.clone().unwrap_or_default().net_encode(&mut *w);
+    (info.latency.unwrap_or_default().as_micros() as u64).net_encode(&mut *w);
+    info.version.map_or(0, |v| v.0).net_encode(&mut *w);
+}
+
+// Files of version 1 have no protocol versions
+fn decode_info(r: &mut &[u8], file_version: u8) -> Result<AddrInfo, Error> {
+    let addr = NetAddressV2::net_decode(&mut *r)?;
+    let last_success: Duration = Decode::net_decode(&mut *r)?;
+    let last_try: Duration = Decode::net_decode(&mut *r)?;
+    let attempts: u32 = Decode::net_decode(&mut *r)?;
+    let tried: u8 = Decode::net_decode(&mut *r)?;
+    let source = match u8::net_decode(&mut *r)? {
+        0 => None,
+        _ => Some(Host::try_from(AddrV2::net_decode(&mut *r)?)?)
+    };
+    let user_agent = String::net_decode(&mut *r)?;
+    let latency: u64 = Decode::net_decode(&mut *r)?;
+    let version: u32 = match file_version {
+        1 => 0,
+        _ => Decode::net_decode(&mut *r)?
+    };
+
+    let mut info = AddrInfo::new(Peer::try_from(addr.clone())?, addr.services, addr.timestamp, source);
+    info.last_success = Some(last_success).filter(|t| !t.is_zero());
+    info.last_try = Some(last_try).filter(|t| !t.is_zero());
+    info.attempts = attempts;
+    info.tried = tried != 0;
+    info.user_agent = Some(user_agent).filter(|a| !a.is_empty());
+    info.latency = Some(Duration::from_micros(latency)).filter(|l| !l.is_zero());
+    info.version = Some(ProtocolVersion(version)).filter(|v| v.0 != 0);
+    Ok(info)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::mock::TempFile;
+    use std::net::Ipv4Addr;
+
+    fn peer(a: u8, b: u8, c: u8) -> Peer {
+        Peer::new(Ipv4Addr::new(a, b, c, 1), 8333)
+    }
+
+    #[test]
+    fn new_and_tried_tables() {
+        let mut addrman = AddrMan::new();
+        let (a, b) = (peer(1, 2, 3), peer(4, 5, 6));
+        assert!(addrman.add(a, ServicesList::default(), now(), None));
+        assert!(!addrman.add(a, ServicesList::default(), now(), Some(b.addr)));
+        addrman.add(b, ServicesList::default(), now(), None);
+
+        addrman.good(&a);
+        assert!(addrman.get(&a).unwrap().tried);
+        assert!(!addrman.get(&b).unwrap().tried);
+        assert_eq!(addrman.len(), 2);
+
+        // Recently failed addresses are not offered
+        addrman.attempt(&b);
+        for _ in 0..20 {
+            assert_eq!(addrman.select(|_| true), Some(a));
+        }
+        assert_eq!(addrman.select(|p| *p != a), None);
+    }
+
+    #[test]
+    fn reconnects_to_tried_addresses() {
+        let mut addrman = AddrMan::new();
+        let a = peer(1, 2, 3);
+        for _ in 0..(BUCKET_SIZE * 2) {
+            addrman.good(&a);
+        }
+        assert_eq!(addrman.len(), 1);
+        assert_eq!(addrman.tried.iter().map(|b| b.len()).sum::<usize>(), 1);
+        assert!(addrman.new.iter().all(|b| b.is_empty()));
+    }
+
+    #[test]
+    fn samples_addresses_for_getaddr() {
+        let mut addrman = AddrMan::new();
+        for i in 0..100 {
+            addrman.add(peer(10, i, 1), ServicesList::default(), now(), None);
+        }
+        // Addresses not heard of in a month are left out
+        addrman.add(peer(11, 0, 1), ServicesList::default(), Duration::from_secs(1), None);
+
+        let addrs = addrman.get_addr(MAX_ADDR_TO_SEND, MAX_PCT_ADDR_TO_SEND);
+        assert_eq!(addrs.len(), 23);
+        assert!(addrs.iter().all(|a| a.addr != AddrV2::Ipv4(Ipv4Addr::new(11, 0, 1, 1))));
+        assert_eq!(addrman.get_addr(10, MAX_PCT_ADDR_TO_SEND).len(), 10);
+        assert!(AddrMan::new().get_addr(MAX_ADDR_TO_SEND, MAX_PCT_ADDR_TO_SEND).is_empty());
+    }
+
+    #[test]
+    fn sources_limited_to_their_buckets() {
+        // A single source flooding addresses is confined to a few buckets
+        let mut addrman = AddrMan::new();
+        let source = peer(9, 9, 9).addr;
+        for i in 0..32 {
+            for j in 0..=255 {
+                addrman.add(peer(10, i, j), ServicesList::default(), now(), Some(source));
+            }
+        }
+        assert!(addrman.len() <= NEW_BUCKETS_PER_SOURCE as usize * BUCKET_SIZE);
+        assert!(addrman.new.iter().all(|b| b.len() <= BUCKET_SIZE));
+    }
+
+    #[test]
+    fn skips_unroutable_addresses() {
+        let received: Vec<NetAddressV2> = [peer(8, 8, 8), peer(192, 168, 1), peer(127, 0, 0)].iter()
+            .map(|p| NetAddressV2::new(now(), ServicesList::default(), AddrV2::from(p.addr), p.port.to_u16()))
+            .collect();
+        assert_eq!(AddrMan::new().add_received(&received, peer(9, 9, 9).addr), 1);
+        assert_eq!(AddrMan::new().with_unroutable(true).add_received(&received, peer(9, 9, 9).addr), 3);
+    }
+
+    #[test]
+    fn terrible_addresses() {
+        let now = now();
+        let mut info = AddrInfo::new(peer(1, 2, 3), ServicesList::default(), now, None);
+        assert!(!info.is_terrible(now));
+        info.attempts = RETRIES;
+        assert!(info.is_terrible(now));
+        info.last_success = Some(now);
+        assert!(!info.is_terrible(now));
+        assert!(info.is_terrible(now + HORIZON * 2));
+    }
+
+    #[test]
+    fn persists_to_disk() {
+        let path = TempFile::new("addrman.dat");
+        let mut addrman = AddrMan::new();
+        let onion = Peer::new(Host::TorV3([7; 32]), 8333);
+        addrman.add(peer(1, 2, 3), ServicesList::from_bits(1 | 8), now(), Some(onion.addr));
+        addrman.add(onion, ServicesList::default(), now(), None);
+        addrman.good(&onion);
+        addrman.record_version(&onion, &ServicesList::from_bits(1), "/Satoshi:27.0.0/");
+        addrman.record_latency(&onion, Duration::from_millis(250));
+        addrman.record_protocol_version(&onion, ProtocolVersion::WTXID_RELAY);
+        addrman.save(&path).unwrap();
+
+        let loaded = AddrMan::load(&path).unwrap();
+        assert_eq!(loaded.key, addrman.key);
+        assert_eq!(loaded.len(), 2);
+        for info in addrman.iter() {
+            // Timestamps are saved with second precision
+            let saved = loaded.get(&info.peer).unwrap();
+            assert_eq!((saved.tried, saved.source, &saved.services), (info.tried, info.source, &info.services));
+            assert_eq!(saved.last_seen.as_secs(), info.last_seen.as_secs());
+            assert_eq!((&saved.user_agent, saved.latency, saved.version), (&info.user_agent, info.latency, info.version));
+        }
+        assert_eq!(loaded.tried[loaded.tried_bucket(&onion)], vec![onion]);
+    }
+}
@@ -0,0 +1,98 @@
+// anchors.rs
+//
+// Anchor peers, kept across restarts like bitcoin core's anchors.dat: a few
+// outbound peers that were connected on shutdown are saved and connected to first
+// on the next start, so a restart does not hand the choice of every peer to
+// whoever filled the address manager.
+//
+
+use crate::{
+    msg::VariableInteger,
+    address::AddrV2,
+    encode::{
+        self,
+        Decode,
+        Encode
+    },
+    net::{
+        peer::{
+            Host,
+            Peer,
+            Port
+        },
+        Error
+    }
+};
+use std::{
+    convert::TryFrom,
+    fs,
+    path::Path
+};
+
+/// Number of anchors saved on shutdown (MAX_BLOCK_RELAY_ONLY_ANCHORS in bitcoin core)
+pub const MAX_ANCHORS: usize = 2;
+
+// Header of anchor files
+const FILE_MAGIC: [u8; 4] = *b"ANCH";
+const FILE_VERSION: u8 = 1;
+
+/// Load the anchors saved with [`save`] and delete the file, as bitcoin core does, so
+/// a crash before the next shutdown does not bring back anchors that were replaced.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Peer>, Error> {
+    let data = fs::read(&path)?;
+    fs::remove_file(&path)?;
+    let mut r = &data[..];
+
+    let magic: [u8; 4] = Decode::net_decode(&mut r)?;
+    let version: u8 = Decode::net_decode(&mut r)?;
+    if magic != FILE_MAGIC || version != FILE_VERSION {
+        return Err(Error::Decode(encode::Error::InvalidData))
+    }
+
+    let mut anchors = Vec::new();
+    for _ in 0..VariableInteger::net_decode(&mut r)?.inner() {
+        let host = Host::try_from(AddrV2::net_decode(&mut r)?)?;
+        let port: [u8; 2] = Decode::net_decode(&mut r)?;
+        anchors.push(Peer { addr: host, port: Port::from(port) });
+    }
+    Ok(anchors)
+}
+
+/// Save up to [`MAX_ANCHORS`] peers to a file, replacing it
+pub fn save<P: AsRef<Path>>(path: P, anchors: &[Peer]) -> Result<(), Error> {
+    let anchors = &anchors[..anchors.len().min(MAX_ANCHORS)];
+    let mut data = Vec::new();
+    FILE_MAGIC.net_encode(&mut data);
+    FILE_VERSION.net_encode(&mut data);
+    VariableInteger(anchors.len() as u64).net_encode(&mut data);
+    for peer in anchors {
+        AddrV2::from(peer.addr).net_encode(&mut data);
+        peer.port.0.net_encode(&mut data);
+    }
+
+    super::write_atomic(path.as_ref(), &data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::mock::TempFile;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn anchors_are_used_once() {
+        let path = TempFile::new("anchors.dat");
+        let peers = [
+            Peer::new(Ipv4Addr::new(1, 2, 3, 4), 8333),
+            Peer::new(Host::TorV3([7; 32]), 8333),
+            Peer::new(Ipv4Addr::new(5, 6, 7, 8), 18333)
+        ];
+
+        // Only the first peers are kept, and the file is gone once loaded
+        save(&path, &peers).unwrap();
+        assert_eq!(load(&path).unwrap(), peers[..MAX_ANCHORS]);
+        assert!(!path.exists());
+        assert!(load(&path).is_err());
+    }
+}
@@ -0,0 +1,1012 @@
+// asynchronous.rs
+//
+// Async (tokio) equivalents of the blocking networking code.
+// Only compiled with the `async` feature.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Magic,
+            Command
+        },
+        network::{
+            VersionMessage,
+            ProtocolVersion,
+            ServicesList
+        }
+    },
+    encode::Encode,
+    net::{
+        peer::{
+            Peer,
+            Host
+        },
+        reader::{
+            next_message,
+            ChecksumPolicy,
+            READ_CHUNK
+        },
+        connection::{
+            require_version,
+            version_builder,
+            version_message,
+            Handshake,
+            Transport
+        },
+        misbehavior::{
+            Misbehavior,
+            Violation
+        },
+        nonce::NonceTracker,
+        socks,
+        ping::{
+            Keepalive,
+        },
+        ratelimit::{
+            RateLimit,
+            TokenBucket
+        },
+        responder::Responder,
+        stream::StreamOptions,
+        traffic::Traffic,
+        v2::{
+            self,
+            Negotiated,
+            PacketDecoder,
+            PacketEncoder
+        },
+        Error
+    }
+};
+use std::{
+    collections::VecDeque,
+    io::{
+        self,
+        ErrorKind
+    },
+    net::SocketAddr,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+use tokio::{
+    io::{
+        split,
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
+        ReadHalf,
+        WriteHalf
+    },
+    net::{
+        TcpSocket,
+        TcpStream
+    },
+    time::{
+        sleep,
+        timeout,
+        timeout_at
+    }
+};
+use tracing::{
+    debug,
+    debug_span,
+    Instrument,
+    Span
+};
+
+/// Create a tcp stream from a peer using the default connect timeout
+pub async fn stream_from(peer: Peer) -> Result<TcpStream, Error> {
+    stream_with(peer, &StreamOptions::default()).await
+}
+
+/// Create a tcp stream from a peer, applying the connect timeout, proxy and bind address from `options`.
+/// Read and write timeouts are left to the caller, e.g. with `tokio::time::timeout`.
+pub async fn stream_with(peer: Peer, options: &StreamOptions) -> Result<TcpStream, Error> {
+    if let Host::I2p(_) = peer.addr {
+        return Err(Error::Proxy(format!("I2P peer {} must be dialed through a SamSession", peer)))
+    }
+    if !options.family.allows(&peer.addr) {
+        return Err(Error::FailedToConnect(format!("{} is excluded by the address family", peer)))
+    }
+
+    // Onion peers can only be reached through the proxy
+    let addr = match (peer.socket_addr(), options.proxy) {
+        (Some(addr), None) => addr,
+        _ => return stream_to_host(&peer.addr.to_string(), peer.port.to_u16(), options).await
+    };
+
+    match timeout(options.connect_timeout, connect(addr, options.bind)).await {
+        Ok(Ok(x)) => Ok(x),
+        _ => Err(Error::FailedToConnect(peer.to_string()))
+    }
+}
+
+// Open a TCP connection, from `bind` if given
+async fn connect(addr: SocketAddr, bind: Option<SocketAddr>) -> io::Result<TcpStream> {
+    let bind = match bind {
+        Some(bind) => bind,
+        None => return TcpStream::connect(addr).await
+    };
+
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(bind)?;
+    socket.connect(addr).await
+}
+
+/// Create a tcp stream to a host by name through the configured proxy.
+/// See [`stream::stream_to_host`](crate::net::stream::stream_to_host).
+pub async fn stream_to_host(host: &str, port: u16, options: &StreamOptions) -> Result<TcpStream, Error> {
+    let proxy = match options.proxy {
+        Some(proxy) => proxy,
+        None => return Err(Error::Proxy(format!("No proxy configured to reach {}", host)))
+    };
+
+    let mut stream = match timeout(options.connect_timeout, connect(proxy, options.bind)).await {
+        Ok(Ok(x)) => x,
+        _ => return Err(Error::FailedToConnect(proxy.to_string()))
+    };
+    match timeout(options.connect_timeout, socks_handshake(&mut stream, host, port)).await {
+        Ok(res) => res.map(|_| stream),
+        Err(_) => Err(Error::Proxy(String::from("Proxy timed out")))
+    }
+}
+
+/// Async counterpart of [`socks::handshake`]
+async fn socks_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, host: &str, port: u16) -> Result<(), Error> {
+    let (mut negotiation, mut step) = socks::Negotiation::new(host, port)?;
+    loop {
+        stream.write_all(&step.send).await?;
+        let mut received = vec![0; step.receive];
+        stream.read_exact(&mut received).await?;
+        step = match negotiation.next(&received)? {
+            Some(step) => step,
+            None => return Ok(())
+        };
+    }
+}
+
+/// Encode an item and write it to an async writer in one go.
+/// Returns the number of bytes written.
+pub async fn write_encoded<T, W>(item: &T, w: &mut W) -> Result<usize, Error>
+where
+    T: Encode,
+    W: AsyncWrite + Unpin
+{
+    let mut buf = Vec::new();
+    let len = item.net_encode(&mut buf);
+    w.write_all(&buf).await?;
+    w.flush().await?;
+    Ok(len)
+}
+
+/// Async counterpart of [`MessageReader`](crate::net::reader::MessageReader).
+/// Buffers bytes from an async reader and yields complete decoded messages.
+pub struct AsyncMessageReader<R> {
+    inner: R,
+    magic: [u8; 4],
+    buf: Vec<u8>,
+    decoder: Option<PacketDecoder>,
+    checksum: ChecksumPolicy
+}
+
+impl<R: AsyncRead + Unpin> AsyncMessageReader<R> {
+    /// Create a new reader expecting messages for the given network
+    pub fn new(inner: R, magic: Magic) -> Self {
+        Self {
+            inner,
+            magic: magic.bytes().to_le_bytes(),
+            buf: Vec::new(),
+            decoder: None,
+            checksum: ChecksumPolicy::Strict
+        }
+    }
+
+    /// Create a reader for a stream that has already been read from.
+    /// See [`MessageReader`](crate::net::reader::MessageReader).
+    fn resume(inner: R, magic: Magic, decoder: Option<PacketDecoder>, buf: Vec<u8>) -> Self {
+        Self {
+            inner,
+            magic: magic.bytes().to_le_bytes(),
+            buf,
+            decoder,
+            checksum: ChecksumPolicy::Strict
+        }
+    }
+
+    /// Set how checksum mismatches are handled, [`ChecksumPolicy::Strict`] by default
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) {
+        self.checksum = policy;
+    }
+
+    /// Read the next complete message from the stream
+    pub async fn read_message(&mut self) -> Result<Message, Error> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            let next = match &mut self.decoder {
+                Some(decoder) => decoder.next_message(&mut self.buf, None),
+                None => next_message(&mut self.buf, self.magic, self.checksum, None, None)
+            };
+            if let Some(msg) = next {
+                return msg
+            }
+
+            match self.inner.read(&mut chunk).await? {
+                0 => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+                n => self.buf.extend_from_slice(&chunk[..n])
+            }
+        }
+    }
+
+    /// Get a reference to the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying reader
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+/// Async counterpart of [`Connection`](crate::net::connection::Connection),
+/// a connection with a peer that has completed the version handshake.
+pub struct AsyncConnection<S> {
+    reader: AsyncMessageReader<S>,
+    magic: Magic,
+    claim: NonceClaim,
+    version: ProtocolVersion,
+    peer_version: VersionMessage,
+    pending: VecDeque<Message>,
+    violations: Vec<Violation>,
+    keepalive: Keepalive,
+    // Answers the peer's requests when set
+    responder: Option<Responder>,
+    // Set when using the v2 transport
+    encoder: Option<PacketEncoder>,
+    limiter: Option<TokenBucket>,
+    traffic: Traffic,
+    // Span the connection's events are logged in
+    span: Span
+}
+
+impl AsyncConnection<TcpStream> {
+    /// Connect to a peer and complete the version handshake using a default version message
+    pub async fn connect(peer: Peer, magic: Magic) -> Result<Self, Error> {
+        Self::connect_with(peer, magic, &StreamOptions::default()).await
+    }
+
+    /// Connect to a peer using the given connect timeout and complete the version handshake.
+    /// The v2 transport is attempted first if `options.v2` is set, see
+    /// [`Connection::connect_tracked`](crate::net::connection::Connection::connect_tracked).
+    pub async fn connect_with(peer: Peer, magic: Magic, options: &StreamOptions) -> Result<Self, Error> {
+        let nonces = NonceTracker::new();
+        let span = debug_span!("peer", peer = %peer.to_string());
+        if options.v2 {
+            match Self::dial(peer, magic, options, &nonces, true).instrument(span.clone()).await {
+                Err(e @ Error::Handshake(_)) | Err(e @ Error::Io(_)) | Err(e @ Error::Decode(_)) => {
+                    debug!(parent: &span, error = ?e, "v2 handshake failed, reconnecting over v1");
+                },
+                conn => return conn
+            }
+        }
+        Self::dial(peer, magic, options, &nonces, false).instrument(span).await
+    }
+
+    async fn dial(peer: Peer, magic: Magic, options: &StreamOptions, nonces: &NonceTracker, v2: bool) -> Result<Self, Error> {
+        let stream = stream_with(peer, options).await?;
+        let version = version_builder(peer, stream.peer_addr().ok(), options).build();
+
+        let mut conn = Self::open(stream, magic, version, nonces, true, v2).await?;
+        require_version(conn.peer_version(), options.min_version)?;
+        conn.set_ping_timeout(options.ping_timeout);
+        conn.set_inactivity_timeout(options.inactivity_timeout);
+        Ok(conn)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncConnection<S> {
+    /// Perform the version handshake over a stream.
+    /// See [`Connection::handshake`](crate::net::connection::Connection::handshake).
+    pub async fn handshake(stream: S, magic: Magic, version: VersionMessage) -> Result<Self, Error> {
+        Self::handshake_tracked(stream, magic, version, &NonceTracker::new()).await
+    }
+
+    /// Perform the version handshake, detecting self connections using the nonces of all
+    /// connections sharing `nonces`.
+    pub async fn handshake_tracked(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, true, false).await
+    }
+
+    /// Perform the v2 key exchange as the initiator, then the version handshake.
+    /// See [`Connection::handshake_v2`](crate::net::connection::Connection::handshake_v2).
+    pub async fn handshake_v2(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, true, true).await
+    }
+
+    /// Perform the responder side of the version handshake on an inbound stream.
+    /// See [`Connection::accept`](crate::net::connection::Connection::accept).
+    pub async fn accept(stream: S, magic: Magic, version: VersionMessage) -> Result<Self, Error> {
+        Self::accept_tracked(stream, magic, version, &NonceTracker::new()).await
+    }
+
+    /// Perform the responder side of the version handshake, detecting self connections
+    /// using the nonces of all connections sharing `nonces`.
+    pub async fn accept_tracked(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, false, false).await
+    }
+
+    /// Perform the responder side of the v2 key exchange and version handshake.
+    /// See [`Connection::accept_v2`](crate::net::connection::Connection::accept_v2).
+    pub async fn accept_v2(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, false, true).await
+    }
+
+    async fn open(stream: S, magic: Magic, mut version: VersionMessage, nonces: &NonceTracker, outbound: bool, v2: bool) -> Result<Self, Error> {
+        let nonce = nonces.claim(version.nonce);
+        version.nonce = nonce;
+
+        let span = debug_span!("connection", outbound, v2, nonce);
+        let conn = async {
+            let (reader, encoder) = Self::framing(stream, magic, outbound, v2).await?;
+            Self::exchange_versions(reader, encoder, magic, version, nonces, outbound, span.clone()).await
+        }.instrument(span.clone()).await;
+        if let Err(e) = &conn {
+            debug!(parent: &span, error = ?e, "Handshake failed");
+            nonces.remove(nonce);
+        }
+        conn
+    }
+
+    /// Set up the transport, running the v2 key exchange if requested
+    async fn framing(mut stream: S, magic: Magic, outbound: bool, v2: bool) -> Result<(AsyncMessageReader<S>, Option<PacketEncoder>), Error> {
+        if !v2 {
+            return Ok((AsyncMessageReader::new(stream, magic), None))
+        }
+
+        Ok(match negotiate(&mut stream, magic, outbound).await? {
+            Negotiated::V2(encoder, decoder, buf) => (AsyncMessageReader::resume(stream, magic, Some(*decoder), buf), Some(encoder)),
+            Negotiated::V1(buf) => (AsyncMessageReader::resume(stream, magic, None, buf), None)
+        })
+    }
+
+    async fn exchange_versions(reader: AsyncMessageReader<S>, encoder: Option<PacketEncoder>, magic: Magic, version: VersionMessage, nonces: &NonceTracker, outbound: bool, span: Span) -> Result<Self, Error> {
+        let nonce = version.nonce;
+        let mut handshake = Handshake::new(&version);
+        let mut conn = Self {
+            reader,
+            magic,
+            claim: NonceClaim { nonces: nonces.clone(), nonce },
+            version: version.version,
+            peer_version: version.clone(),
+            pending: VecDeque::new(),
+            violations: Vec::new(),
+            keepalive: Keepalive::new(version.version),
+            responder: None,
+            encoder,
+            limiter: None,
+            traffic: Traffic::new(),
+            span
+        };
+
+        // The initiator sends its version first, the responder waits for the peer's
+        let mut ours = Some(version);
+        if outbound {
+            conn.send(&version_message(&mut ours, magic)).await?;
+        }
+        while !handshake.is_complete() {
+            let msg = conn.reader.read_message().await?;
+            conn.traffic.record_received(&msg);
+            if handshake.receive(msg, nonces)? {
+                if ours.is_some() {
+                    conn.send(&version_message(&mut ours, magic)).await?;
+                }
+                conn.send(&Message::new(MessagePayload::EmptyPayload, magic, Command::Verack)).await?;
+            }
+        }
+
+        let (version, peer_version, pending, violations) = handshake.finish();
+        conn.version = version;
+        conn.peer_version = peer_version;
+        conn.pending = pending;
+        conn.violations = violations;
+        conn.keepalive = Keepalive::new(version);
+        debug!(parent: &conn.span, version = version.0, agent = %conn.peer_version.agent, transport = ?conn.transport(), "Handshake complete");
+        Ok(conn)
+    }
+
+    /// Send a message to the peer, waiting for the connection's rate limit if there is one
+    pub async fn send(&mut self, msg: &Message) -> Result<(), Error> {
+        send_framed(self.reader.get_mut(), &mut self.encoder, &mut self.limiter, msg, &self.span).await?;
+        self.traffic.record_sent(msg);
+        Ok(())
+    }
+
+    /// Wrap a payload in a message for this connection's network and send it
+    pub async fn send_payload(&mut self, payload: MessagePayload, command: Command) -> Result<(), Error> {
+        self.send(&Message::new(payload, self.magic, command)).await
+    }
+
+    /// Receive the next message from the peer.
+    /// Pings that fall due are sent while waiting, see
+    /// [`Connection::recv`](crate::net::connection::Connection::recv).
+    pub async fn recv(&mut self) -> Result<Message, Error> {
+        let msg = self.receive().await;
+        match &msg {
+            Ok(msg) => debug!(parent: &self.span, command = msg.header.command.to_str(), length = msg.header.length, "Received message"),
+            Err(e) => debug!(parent: &self.span, error = ?e, "Failed to receive message")
+        }
+        msg
+    }
+
+    async fn receive(&mut self) -> Result<Message, Error> {
+        loop {
+            if let Some(nonce) = self.keepalive.poll()? {
+                self.send_payload(MessagePayload::PingPong(nonce), Command::Ping).await?;
+            }
+
+            // Pending messages were counted during the handshake
+            let msg = match (self.pending.pop_front(), self.keepalive.next_check()) {
+                (Some(msg), _) => msg,
+                (None, next_check) => {
+                    let msg = match next_check {
+                        None => self.reader.read_message().await?,
+                        // Reading is cancel safe, buffered bytes are kept in the reader
+                        Some(deadline) => match timeout_at(deadline.into(), self.reader.read_message()).await {
+                            Ok(msg) => msg?,
+                            Err(_) => continue
+                        }
+                    };
+                    self.traffic.record_received(&msg);
+                    msg
+                }
+            };
+            if let Some(violation) = Violation::check(&msg.header.command, true, true) {
+                return Err(Error::Misbehavior(Misbehavior::Handshake(violation)))
+            }
+            self.keepalive.receive(&msg);
+            if let Some((payload, command)) = self.responder.as_mut().and_then(|r| r.reply(&msg)) {
+                self.send_payload(payload, command).await?;
+            }
+            return Ok(msg)
+        }
+    }
+
+    /// Set how often the peer is pinged, `None` disables pings.
+    /// Defaults to [`PING_INTERVAL`](crate::net::ping::PING_INTERVAL).
+    pub fn set_ping_interval(&mut self, interval: Option<Duration>) {
+        self.keepalive.set_interval(interval);
+    }
+
+    /// Set how long the peer has to answer a ping, see
+    /// [`Connection::set_ping_timeout`](crate::net::connection::Connection::set_ping_timeout).
+    pub fn set_ping_timeout(&mut self, timeout: Duration) {
+        self.keepalive.set_timeout(timeout);
+    }
+
+    /// Set how long the peer may send nothing at all, see
+    /// [`Connection::set_inactivity_timeout`](crate::net::connection::Connection::set_inactivity_timeout).
+    pub fn set_inactivity_timeout(&mut self, timeout: Option<Duration>) {
+        self.keepalive.set_inactivity_timeout(timeout);
+    }
+
+    /// Set how checksum mismatches are handled, see
+    /// [`Connection::set_checksum_policy`](crate::net::connection::Connection::set_checksum_policy).
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) {
+        self.reader.set_checksum_policy(policy);
+    }
+
+    /// Automatically answer the peer's requests, see
+    /// [`Connection::set_responder`](crate::net::connection::Connection::set_responder).
+    pub fn set_responder(&mut self, responder: Option<Responder>) {
+        self.responder = responder;
+    }
+
+    /// Messages sent and received on this connection so far, including the handshake
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Limit how fast messages are sent, see
+    /// [`Connection::set_rate_limit`](crate::net::connection::Connection::set_rate_limit).
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.limiter = limit.map(TokenBucket::new);
+    }
+
+    /// Round trip time of the most recently answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.keepalive.latency()
+    }
+
+    /// Network magic used on this connection
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+
+    /// Nonce sent in our version message
+    pub fn nonce(&self) -> u64 {
+        self.claim.nonce
+    }
+
+    /// Transport protocol used on this connection
+    pub fn transport(&self) -> Transport {
+        match self.encoder {
+            Some(_) => Transport::V2,
+            None => Transport::V1
+        }
+    }
+
+    /// Protocol version negotiated with the peer
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Services advertised by the peer
+    pub fn services(&self) -> &ServicesList {
+        &self.peer_version.service
+    }
+
+    /// Version message received from the peer
+    pub fn peer_version(&self) -> &VersionMessage {
+        &self.peer_version
+    }
+
+    /// Messages the peer sent out of order during the handshake, see
+    /// [`Connection::violations`](crate::net::connection::Connection::violations)
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.reader.get_ref()
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        self.reader.get_mut()
+    }
+
+    /// Split the connection into halves that can be used from separate tasks, so sending
+    /// never waits for a read to complete and reading never waits for a send.
+    ///
+    /// Pings and the replies of a [responder](Self::set_responder) are sent by the read half
+    /// through its own [`AsyncConnectionWriter`], between reads. The halves share the
+    /// connection's traffic, rate limit and v2 cipher state.
+    pub fn into_split(self) -> (AsyncConnectionReader<S>, AsyncConnectionWriter<S>) {
+        let AsyncMessageReader { inner, magic, buf, decoder, checksum } = self.reader;
+        let (read, write) = split(inner);
+        let traffic = Arc::new(Mutex::new(self.traffic));
+        let writer = AsyncConnectionWriter {
+            outbound: Arc::new(tokio::sync::Mutex::new(AsyncOutbound {
+                stream: write,
+                encoder: self.encoder,
+                limiter: self.limiter,
+                span: self.span.clone()
+            })),
+            traffic: Arc::clone(&traffic),
+            magic: self.magic
+        };
+        let reader = AsyncConnectionReader {
+            reader: AsyncMessageReader { inner: read, magic, buf, decoder, checksum },
+            writer: writer.clone(),
+            magic: self.magic,
+            claim: self.claim,
+            version: self.version,
+            peer_version: self.peer_version,
+            pending: self.pending,
+            violations: self.violations,
+            keepalive: self.keepalive,
+            responder: self.responder,
+            traffic,
+            span: self.span
+        };
+        (reader, writer)
+    }
+}
+
+/// Read half of an [`AsyncConnection`], see [`AsyncConnection::into_split`]
+pub struct AsyncConnectionReader<S> {
+    reader: AsyncMessageReader<ReadHalf<S>>,
+    // Sends pings and the responder's replies
+    writer: AsyncConnectionWriter<S>,
+    magic: Magic,
+    claim: NonceClaim,
+    version: ProtocolVersion,
+    peer_version: VersionMessage,
+    pending: VecDeque<Message>,
+    violations: Vec<Violation>,
+    keepalive: Keepalive,
+    responder: Option<Responder>,
+    // Shared with the write half
+    traffic: Arc<Mutex<Traffic>>,
+    span: Span
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncConnectionReader<S> {
+    /// Receive the next message from the peer.
+    /// Pings that fall due are sent while waiting, see [`AsyncConnection::recv`].
+    pub async fn recv(&mut self) -> Result<Message, Error> {
+        let msg = self.receive().await;
+        match &msg {
+            Ok(msg) => debug!(parent: &self.span, command = msg.header.command.to_str(), length = msg.header.length, "Received message"),
+            Err(e) => debug!(parent: &self.span, error = ?e, "Failed to receive message")
+        }
+        msg
+    }
+
+    async fn receive(&mut self) -> Result<Message, Error> {
+        loop {
+            if let Some(nonce) = self.keepalive.poll()? {
+                self.writer.send_payload(MessagePayload::PingPong(nonce), Command::Ping).await?;
+            }
+
+            let msg = match (self.pending.pop_front(), self.keepalive.next_check()) {
+                (Some(msg), _) => msg,
+                (None, next_check) => {
+                    let msg = match next_check {
+                        None => self.reader.read_message().await?,
+                        Some(deadline) => match timeout_at(deadline.into(), self.reader.read_message()).await {
+                            Ok(msg) => msg?,
+                            Err(_) => continue
+                        }
+                    };
+                    self.traffic.lock().expect("Traffic lock poisoned").record_received(&msg);
+                    msg
+                }
+            };
+            if let Some(violation) = Violation::check(&msg.header.command, true, true) {
+                return Err(Error::Misbehavior(Misbehavior::Handshake(violation)))
+            }
+            self.keepalive.receive(&msg);
+            if let Some((payload, command)) = self.responder.as_mut().and_then(|r| r.reply(&msg)) {
+                self.writer.send_payload(payload, command).await?;
+            }
+            return Ok(msg)
+        }
+    }
+
+    /// Get a write half of the connection, sharing the one the read half sends pings on
+    pub fn writer(&self) -> AsyncConnectionWriter<S> {
+        self.writer.clone()
+    }
+
+    /// Set how often the peer is pinged, `None` disables pings.
+    /// Defaults to [`PING_INTERVAL`](crate::net::ping::PING_INTERVAL).
+    pub fn set_ping_interval(&mut self, interval: Option<Duration>) {
+        self.keepalive.set_interval(interval);
+    }
+
+    /// Set how long the peer has to answer a ping, see
+    /// [`Connection::set_ping_timeout`](crate::net::connection::Connection::set_ping_timeout).
+    pub fn set_ping_timeout(&mut self, timeout: Duration) {
+        self.keepalive.set_timeout(timeout);
+    }
+
+    /// Set how long the peer may send nothing at all, see
+    /// [`Connection::set_inactivity_timeout`](crate::net::connection::Connection::set_inactivity_timeout).
+    pub fn set_inactivity_timeout(&mut self, timeout: Option<Duration>) {
+        self.keepalive.set_inactivity_timeout(timeout);
+    }
+
+    /// Set how checksum mismatches are handled, see
+    /// [`Connection::set_checksum_policy`](crate::net::connection::Connection::set_checksum_policy).
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) {
+        self.reader.set_checksum_policy(policy);
+    }
+
+    /// Automatically answer the peer's requests, see
+    /// [`Connection::set_responder`](crate::net::connection::Connection::set_responder).
+    pub fn set_responder(&mut self, responder: Option<Responder>) {
+        self.responder = responder;
+    }
+
+    /// Messages sent and received on the connection so far, by either half
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.lock().expect("Traffic lock poisoned").clone()
+    }
+
+    /// Round trip time of the most recently answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.keepalive.latency()
+    }
+
+    /// Network magic used on this connection
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+
+    /// Nonce sent in our version message
+    pub fn nonce(&self) -> u64 {
+        self.claim.nonce
+    }
+
+    /// Protocol version negotiated with the peer
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Version message received from the peer
+    pub fn peer_version(&self) -> &VersionMessage {
+        &self.peer_version
+    }
+
+    /// Messages the peer sent out of order during the handshake, see
+    /// [`Connection::violations`](crate::net::connection::Connection::violations)
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+}
+
+/// Write half of an [`AsyncConnection`], see [`AsyncConnection::into_split`].
+/// Clones send on the same connection, each message whole and in the order sends start.
+pub struct AsyncConnectionWriter<S> {
+    outbound: Arc<tokio::sync::Mutex<AsyncOutbound<S>>>,
+    traffic: Arc<Mutex<Traffic>>,
+    magic: Magic
+}
+
+// Outgoing side of a split connection, locked for the whole of each send so v2 packets
+// are encrypted in the order they are written
+struct AsyncOutbound<S> {
+    stream: WriteHalf<S>,
+    encoder: Option<PacketEncoder>,
+    limiter: Option<TokenBucket>,
+    span: Span
+}
+
+impl<S> Clone for AsyncConnectionWriter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            outbound: Arc::clone(&self.outbound),
+            traffic: Arc::clone(&self.traffic),
+            magic: self.magic
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncConnectionWriter<S> {
+    /// Send a message to the peer, waiting for the connection's rate limit if there is one
+    /// and for sends already started on other clones
+    pub async fn send(&self, msg: &Message) -> Result<(), Error> {
+        let mut guard = self.outbound.lock().await;
+        let out = &mut *guard;
+        send_framed(&mut out.stream, &mut out.encoder, &mut out.limiter, msg, &out.span).await?;
+        self.traffic.lock().expect("Traffic lock poisoned").record_sent(msg);
+        Ok(())
+    }
+
+    /// Wrap a payload in a message for this connection's network and send it
+    pub async fn send_payload(&self, payload: MessagePayload, command: Command) -> Result<(), Error> {
+        self.send(&Message::new(payload, self.magic, command)).await
+    }
+
+    /// Messages sent and received on the connection so far, by either half
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.lock().expect("Traffic lock poisoned").clone()
+    }
+
+    /// Close the sending side of the connection once sends already started are done.
+    /// The peer sees the connection end, the read half keeps reading until it does.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.outbound.lock().await.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+// Releases the nonce of our version message once the connection, or its read half, is dropped
+struct NonceClaim {
+    nonces: NonceTracker,
+    nonce: u64
+}
+
+impl Drop for NonceClaim {
+    fn drop(&mut self) {
+        self.nonces.remove(self.nonce);
+    }
+}
+
+// Frame a message for the connection's transport and write it, waiting for the rate limit first
+async fn send_framed<W: AsyncWrite + Unpin>(w: &mut W, encoder: &mut Option<PacketEncoder>, limiter: &mut Option<TokenBucket>, msg: &Message, span: &Span) -> Result<(), Error> {
+    if let Some(limiter) = limiter {
+        while let Some(wait) = limiter.take(Instant::now()) {
+            sleep(wait).await;
+        }
+    }
+
+    let written = match encoder {
+        Some(encoder) => {
+            let packet = encoder.encode_message(msg);
+            match w.write_all(&packet).await {
+                Ok(()) => w.flush().await.map_err(Error::from),
+                Err(e) => Err(e.into())
+            }
+        },
+        None => write_encoded(msg, w).await.map(|_| ())
+    };
+    if let Err(e) = written {
+        debug!(parent: span, command = msg.header.command.to_str(), error = ?e, "Failed to send message");
+        return Err(e)
+    }
+    debug!(parent: span, command = msg.header.command.to_str(), length = msg.header.length, "Sent message");
+    Ok(())
+}
+
+/// Async counterpart of [`v2::negotiate`]
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, magic: Magic, initiator: bool) -> Result<Negotiated, Error> {
+    let (mut handshake, hello) = v2::Handshake::new(magic, initiator);
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_CHUNK];
+    while !handshake.is_complete() {
+        match stream.read(&mut chunk).await? {
+            0 => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+            n => buf.extend_from_slice(&chunk[..n])
+        }
+        if handshake.detects_v1(&buf) {
+            return Ok(Negotiated::V1(buf))
+        }
+
+        let reply = handshake.receive(&mut buf)?;
+        if !reply.is_empty() {
+            stream.write_all(&reply).await?;
+            stream.flush().await?;
+        }
+    }
+
+    let (encoder, decoder) = handshake.finish();
+    Ok(Negotiated::V2(encoder, Box::new(decoder), buf))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+
+    #[tokio::test]
+    async fn handshake_between_async_connections() {
+        let (a, b) = tokio::io::duplex(1024);
+        let va = VersionMessage::builder(Address::me()).nonce(1).build();
+        let vb = VersionMessage::builder(Address::me()).nonce(2).version(ProtocolVersion::RELAY).build();
+
+        let (a, b) = tokio::join!(
+            AsyncConnection::handshake(a, Magic::Signet, va),
+            AsyncConnection::handshake(b, Magic::Signet, vb)
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.version(), ProtocolVersion::RELAY);
+        assert_eq!(a.peer_version().nonce, 2);
+
+        a.send_payload(MessagePayload::PingPong(5), Command::Ping).await.unwrap();
+        assert_eq!(b.recv().await.unwrap().payload, MessagePayload::PingPong(5));
+    }
+
+    #[tokio::test]
+    async fn pings_while_waiting() {
+        let (a, b) = tokio::io::duplex(1024);
+        let va = VersionMessage::builder(Address::me()).nonce(1).build();
+        let vb = VersionMessage::builder(Address::me()).nonce(2).build();
+
+        let (a, b) = tokio::join!(
+            AsyncConnection::handshake(a, Magic::Signet, va),
+            AsyncConnection::handshake(b, Magic::Signet, vb)
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        a.set_ping_interval(Some(Duration::from_millis(10)));
+        b.set_ping_interval(None);
+
+        // b answers the ping, then sends something a is waiting for
+        let peer = async move {
+            let ping = b.recv().await.unwrap();
+            assert_eq!(ping.header.command, Command::Ping);
+            b.send_payload(ping.payload, Command::Pong).await.unwrap();
+            b.send_payload(MessagePayload::EmptyPayload, Command::SendHeaders).await.unwrap();
+            b
+        };
+        let (pong, _b) = tokio::join!(a.recv(), peer);
+        assert_eq!(pong.unwrap().header.command, Command::Pong);
+        assert!(a.latency().is_some());
+    }
+
+    #[tokio::test]
+    async fn accepts_inbound_handshake() {
+        let (a, b) = tokio::io::duplex(1024);
+        let va = VersionMessage::builder(Address::me()).nonce(1).build();
+        let vb = VersionMessage::builder(Address::me()).nonce(2).build();
+
+        let (a, b) = tokio::join!(
+            AsyncConnection::handshake(a, Magic::Signet, va),
+            AsyncConnection::accept(b, Magic::Signet, vb)
+        );
+        assert_eq!(a.unwrap().peer_version().nonce, 2);
+        assert_eq!(b.unwrap().peer_version().nonce, 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_self_connection() {
+        let (a, b) = tokio::io::duplex(1024);
+        let nonces = NonceTracker::new();
+        let va = VersionMessage::builder(Address::me()).nonce(1).build();
+        let vb = VersionMessage::builder(Address::me()).nonce(2).build();
+
+        let (a, b) = tokio::join!(
+            AsyncConnection::handshake_tracked(a, Magic::Signet, va, &nonces),
+            AsyncConnection::handshake_tracked(b, Magic::Signet, vb, &nonces)
+        );
+        // Whichever side detects the loop first hangs up on the other
+        assert!(a.is_err() && b.is_err());
+        assert!(matches!(a, Err(Error::SelfConnection)) || matches!(b, Err(Error::SelfConnection)));
+        assert!(!nonces.contains(1) && !nonces.contains(2));
+    }
+
+    #[tokio::test]
+    async fn sends_while_reading() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (na, nb) = (NonceTracker::new(), NonceTracker::new());
+        let va = VersionMessage::builder(Address::me()).nonce(1).build();
+        let vb = VersionMessage::builder(Address::me()).nonce(2).build();
+
+        let (a, b) = tokio::join!(
+            AsyncConnection::handshake_v2(a, Magic::Signet, va, &na),
+            AsyncConnection::accept_v2(b, Magic::Signet, vb, &nb)
+        );
+        let (mut reader, writer) = a.unwrap().into_split();
+        let mut b = b.unwrap();
+        reader.set_responder(Some(Responder::new()));
+
+        // The read half waits for a message while the write half sends
+        let reading = tokio::spawn(async move {
+            let msg = reader.recv().await.unwrap();
+            (reader, msg)
+        });
+        writer.send_payload(MessagePayload::PingPong(5), Command::Ping).await.unwrap();
+        assert_eq!(b.recv().await.unwrap().payload, MessagePayload::PingPong(5));
+
+        // Replies are sent by the read half on the shared writer
+        b.send_payload(MessagePayload::PingPong(6), Command::Ping).await.unwrap();
+        let (reader, msg) = reading.await.unwrap();
+        assert_eq!(msg.header.command, Command::Ping);
+        let pong = b.recv().await.unwrap();
+        assert_eq!((pong.header.command, pong.payload), (Command::Pong, MessagePayload::PingPong(6)));
+        assert_eq!(reader.traffic(), writer.traffic());
+        assert_eq!(reader.nonce(), 1);
+        drop(reader);
+        assert!(!na.contains(1));
+    }
+
+    #[tokio::test]
+    async fn v2_handshake() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (na, nb) = (NonceTracker::new(), NonceTracker::new());
+        let va = VersionMessage::builder(Address::me()).nonce(1).build();
+        let vb = VersionMessage::builder(Address::me()).nonce(2).build();
+
+        let (a, b) = tokio::join!(
+            AsyncConnection::handshake_v2(a, Magic::Signet, va, &na),
+            AsyncConnection::accept_v2(b, Magic::Signet, vb, &nb)
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert_eq!((a.transport(), b.transport()), (Transport::V2, Transport::V2));
+
+        a.send_payload(MessagePayload::PingPong(5), Command::Ping).await.unwrap();
+        assert_eq!(b.recv().await.unwrap().payload, MessagePayload::PingPong(5));
+    }
+}
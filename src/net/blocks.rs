@@ -0,0 +1,178 @@
+// blocks.rs
+//
+// Module for downloading blocks from a peer with getdata, verifying them against
+// the requested hash and saving them to disk.
+//
+
+use crate::{
+    bitcoin::{
+        consensus::encode::serialize,
+        Block
+    },
+    blockdata::{
+        BlockHash,
+        Hash
+    },
+    msg::{
+        data::MessagePayload,
+        header::Command,
+        inventory::Inventory
+    },
+    net::{
+        connection::Connection,
+        misbehavior::Misbehavior,
+        Error
+    }
+};
+#[cfg(feature = "export")]
+use serde::Serialize;
+use std::{
+    fs,
+    io::{
+        Read,
+        Write
+    },
+    path::Path
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "export", derive(Serialize))]
+/// Overview of a block, written next to it by [`save_summary`]
+pub struct BlockSummary {
+    pub hash: String,
+    pub prev_blockhash: String,
+    pub merkle_root: String,
+    pub version: i32,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+    pub tx_count: usize,
+    /// Serialized size in bytes
+    pub size: usize,
+    pub weight: usize
+}
+
+impl From<&Block> for BlockSummary {
+    fn from(block: &Block) -> Self {
+        Self {
+            hash: block.block_hash().to_string(),
+            prev_blockhash: block.header.prev_blockhash.to_string(),
+            merkle_root: block.header.merkle_root.to_string(),
+            version: block.header.version,
+            time: block.header.time,
+            bits: block.header.bits,
+            nonce: block.header.nonce,
+            tx_count: block.txdata.len(),
+            size: serialize(block).len(),
+            weight: block.get_weight()
+        }
+    }
+}
+
+/// Request a block with its witness data and wait for it, dropping other messages.
+///
+/// The block must hash to `hash` and its transactions must match the merkle root and
+/// witness commitment. Fails with [`Error::NotFound`] if the peer does not have the block.
+pub fn download_block<S: Read + Write>(conn: &mut Connection<S>, hash: BlockHash) -> Result<Block, Error> {
+    conn.send_payload(MessagePayload::InvVect(vec![Inventory::WitnessBlock(hash)]), Command::GetData)?;
+    loop {
+        let msg = conn.recv()?;
+        match msg.payload {
+            MessagePayload::Block(block) if block.block_hash() == hash => {
+                if !block.check_merkle_root() || !block.check_witness_commitment() {
+                    let reason = format!("Block {} does not match its header", hash);
+                    return Err(Error::Misbehavior(Misbehavior::ProtocolViolation(reason)))
+                }
+                return Ok(block)
+            },
+            MessagePayload::InvVect(inv) if msg.header.command == Command::NotFound && inv.iter().any(|i| i.inner() == hash.into_inner()) => {
+                return Err(Error::NotFound(format!("Block {}", hash)))
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Write a block to a file in its raw consensus serialization
+pub fn save_block<P: AsRef<Path>>(block: &Block, path: P) -> Result<(), Error> {
+    fs::write(path, serialize(block))?;
+    Ok(())
+}
+
+/// Write a JSON summary of a block to a file.
+/// Only available with the `export` feature.
+#[cfg(feature = "export")]
+pub fn save_summary<P: AsRef<Path>>(block: &Block, path: P) -> Result<(), Error> {
+    let file = fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, &BlockSummary::from(block)).map_err(|e| Error::Io(e.into()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::{
+            blockdata::constants::genesis_block,
+            consensus::encode::deserialize,
+            Network
+        },
+        msg::{
+            header::Magic,
+            network::VersionMessage
+        },
+        net::mock::TempFile
+    };
+    use std::{
+        net::{
+            TcpListener,
+            TcpStream
+        },
+        thread
+    };
+
+    #[test]
+    fn downloads_blocks() {
+        let genesis = genesis_block(Network::Regtest);
+        let mut tampered = genesis.clone();
+        tampered.txdata[0].lock_time = 1;
+
+        // The peer answers the first request with an unrelated block then the genesis block, the
+        // second with the tampered block and the third with notfound
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut other = genesis.clone();
+        other.header.nonce = 0;
+        let mut replies = vec![
+            vec![(MessagePayload::Block(other), Command::Block), (MessagePayload::Block(genesis.clone()), Command::Block)],
+            vec![(MessagePayload::Block(tampered.clone()), Command::Block)]
+        ].into_iter();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::accept(stream, Magic::Regtest, VersionMessage::builder(Address::me()).build()).unwrap();
+            while let Ok(msg) = conn.recv() {
+                if let MessagePayload::InvVect(inv) = msg.payload {
+                    for (payload, command) in replies.next().unwrap_or_else(|| vec![(MessagePayload::InvVect(inv), Command::NotFound)]) {
+                        conn.send_payload(payload, command).unwrap();
+                    }
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = Connection::handshake(stream, Magic::Regtest, VersionMessage::builder(Address::from(addr)).build()).unwrap();
+
+        // Blocks with other hashes are skipped, a block not matching its merkle root is rejected
+        assert_eq!(download_block(&mut conn, genesis.block_hash()).unwrap(), genesis);
+        assert!(matches!(download_block(&mut conn, genesis.block_hash()), Err(Error::Misbehavior(_))));
+        assert!(matches!(download_block(&mut conn, BlockHash::from_inner([1; 32])), Err(Error::NotFound(_))));
+
+        let path = TempFile::new("block.dat");
+        save_block(&genesis, &path).unwrap();
+        assert_eq!(deserialize::<Block>(&fs::read(&path).unwrap()).unwrap(), genesis);
+
+        let summary = BlockSummary::from(&genesis);
+        assert_eq!((summary.tx_count, summary.size), (1, 285));
+    }
+}
@@ -0,0 +1,213 @@
+// broadcast.rs
+//
+// Module for broadcasting a transaction: announcing it to peers with inv,
+// serving it to those that request it and watching for it to be relayed back.
+//
+
+use crate::{
+    bitcoin::{
+        consensus::encode::deserialize,
+        hashes::hex::FromHex
+    },
+    blockdata::{
+        transaction::TxHashes,
+        Transaction
+    },
+    encode,
+    msg::{
+        data::MessagePayload,
+        header::{
+            Command,
+            Magic
+        },
+        inventory::{
+            Inventory,
+            Txid
+        }
+    },
+    net::{
+        connection::Connection,
+        peer::Peer,
+        ping::is_timeout,
+        stream::StreamOptions,
+        Error
+    }
+};
+use std::{
+    net::TcpStream,
+    thread,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Default time to wait for peers to request and relay a broadcast transaction
+pub const BROADCAST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What a peer did with a broadcast transaction
+pub struct BroadcastReport {
+    /// The peer asked for the transaction with getdata and was sent it
+    pub requested: bool,
+    /// The peer announced the transaction to us, so it was accepted by it or a peer it heard it from
+    pub announced: bool
+}
+
+/// A transaction to announce to peers
+pub struct Broadcast {
+    tx: Transaction,
+    txid: Txid,
+    timeout: Duration
+}
+
+impl Broadcast {
+    pub fn new(tx: Transaction) -> Self {
+        Self {
+            txid: tx.txid(),
+            tx,
+            timeout: BROADCAST_TIMEOUT
+        }
+    }
+
+    /// Decode a raw transaction in hex, surrounding whitespace is ignored
+    pub fn from_hex(hex: &str) -> Result<Self, encode::Error> {
+        let bytes = Vec::<u8>::from_hex(hex.trim()).map_err(|_| encode::Error::InvalidData)?;
+        Ok(Self::new(deserialize(&bytes)?))
+    }
+
+    /// Wait up to `timeout` on each peer for it to request and announce the transaction
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn txid(&self) -> Txid {
+        self.txid
+    }
+
+    pub fn transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    /// Connect to each peer in parallel and [`send`](Self::send) the transaction to it.
+    /// Connections ask for transaction relay so peers can announce the transaction back.
+    pub fn broadcast(&self, peers: &[Peer], magic: Magic, options: &StreamOptions) -> Vec<(Peer, Result<BroadcastReport, Error>)> {
+        let options = StreamOptions { relay: true, ..options.clone() };
+        let options = &options;
+        thread::scope(|s| {
+            let sends: Vec<_> = peers.iter()
+                .map(|peer| s.spawn(move || {
+                    let mut conn = Connection::connect_with(*peer, magic, options)?;
+                    self.send(&mut conn)
+                }))
+                .collect();
+
+            peers.iter()
+                .zip(sends)
+                .map(|(peer, send)| (*peer, send.join().expect("Broadcast thread panicked")))
+                .collect()
+        })
+    }
+
+    /// Announce the transaction on a connection and serve it if requested, until the peer
+    /// announces it back or the timeout passes.
+    ///
+    /// Peers do not announce a transaction to the peer they received it from, so it is only
+    /// announced back once it has been relayed to the peer by others.
+    pub fn send(&self, conn: &mut Connection<TcpStream>) -> Result<BroadcastReport, Error> {
+        let mut report = BroadcastReport::default();
+        conn.set_ping_interval(None);
+        conn.set_filter(Some(&[Command::GetData, Command::Inv]));
+        conn.send_payload(MessagePayload::InvVect(vec![Inventory::Tx(self.txid)]), Command::Inv)?;
+
+        // Peers that negotiated wtxidrelay announce it by wtxid
+        let ours = |inv: &Inventory| self.tx.is_inventory(inv);
+        let deadline = Instant::now() + self.timeout;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) {
+            conn.get_ref().set_read_timeout(Some(left))?;
+            let (command, inv) = match conn.recv() {
+                Ok(msg) => match msg.payload {
+                    MessagePayload::InvVect(inv) => (msg.header.command, inv),
+                    _ => continue
+                },
+                Err(Error::Misbehavior(_)) | Err(Error::Decode(_)) => continue,
+                Err(Error::Io(e)) if is_timeout(&e) => break,
+                Err(e) => return Err(e)
+            };
+            if !inv.iter().any(ours) {
+                continue
+            }
+
+            match command {
+                Command::GetData => {
+                    conn.send_payload(MessagePayload::Transction(self.tx.clone()), Command::Tx)?;
+                    report.requested = true;
+                },
+                _ => {
+                    report.announced = true;
+                    break
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::{
+            consensus::encode::serialize,
+            hashes::hex::ToHex,
+            TxIn,
+            TxOut
+        },
+        msg::network::VersionMessage
+    };
+    use std::net::{
+        Ipv4Addr,
+        TcpListener
+    };
+
+    // Accept one connection. If `relays`, request announced transactions and announce them back.
+    fn fake_peer(relays: bool) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::accept(stream, Magic::Regtest, VersionMessage::builder(Address::me()).build()).unwrap();
+            while let Ok(msg) = conn.recv() {
+                match msg.payload {
+                    MessagePayload::InvVect(inv) if relays => conn.send_payload(MessagePayload::InvVect(inv), Command::GetData).unwrap(),
+                    MessagePayload::Transction(tx) => conn.send_payload(MessagePayload::InvVect(vec![Inventory::Tx(tx.txid())]), Command::Inv).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        peer
+    }
+
+    #[test]
+    fn broadcasts_transaction() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut { value: 1000, ..Default::default() }]
+        };
+        let broadcast = Broadcast::from_hex(&format!(" {}\n", serialize(&tx).to_hex())).unwrap();
+        assert_eq!(broadcast.txid(), tx.txid());
+        assert!(Broadcast::from_hex("00zz").is_err());
+
+        let (relaying, silent) = (fake_peer(true), fake_peer(false));
+        let reports = broadcast.with_timeout(Duration::from_millis(500)).broadcast(&[relaying, silent], Magic::Regtest, &StreamOptions::default());
+        assert_eq!(reports[0].0, relaying);
+        assert_eq!(reports[0].1.as_ref().unwrap(), &BroadcastReport { requested: true, announced: true });
+        assert_eq!(reports[1].1.as_ref().unwrap(), &BroadcastReport::default());
+    }
+}
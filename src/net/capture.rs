@@ -0,0 +1,250 @@
+// capture.rs
+//
+// Module for capturing the raw messages of connections to a file so sessions
+// can be archived and analyzed later.
+//
+// Capture files start with the magic "BNMC" and a version byte, followed by one
+// record per message:
+//    - u32 length of the rest of the record
+//    - u8 direction, 0 for received and 1 for sent
+//    - u64 unix time in microseconds
+//    - the peer's address in the addrv2 encoding and u16 port
+//    - the message bytes
+//
+
+use crate::{
+    address::AddrV2,
+    encode::{
+        self,
+        Decode,
+        Encode
+    },
+    msg::data::Message,
+    net::{
+        peer::{
+            Host,
+            Peer
+        },
+        Error
+    }
+};
+use std::{
+    convert::TryFrom,
+    fs::{
+        File,
+        OpenOptions
+    },
+    io::{
+        self,
+        BufReader,
+        Read,
+        Write
+    },
+    path::Path,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+
+pub(crate) const FILE_MAGIC: [u8; 4] = *b"BNMC";
+const FILE_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Whether a captured message was received from or sent to the peer
+pub enum Direction {
+    Received,
+    Sent
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A message read back from a capture
+pub struct CaptureRecord {
+    pub direction: Direction,
+    pub time: SystemTime,
+    pub peer: Peer,
+    /// The message as it was serialized on the wire with the v1 transport
+    pub bytes: Vec<u8>
+}
+
+impl CaptureRecord {
+    /// Decode the captured bytes
+    pub fn message(&self) -> Result<Message, encode::Error> {
+        Message::net_decode(&self.bytes[..])
+    }
+}
+
+/// Handle for appending messages to a capture, shared between connections.
+///
+/// Messages received over v1 are captured exactly as read. Sent messages and messages received
+/// over v2 are captured as serialized for the v1 transport, before encryption.
+#[derive(Clone)]
+pub struct Capture {
+    out: Arc<Mutex<Box<dyn Write + Send>>>
+}
+
+impl Capture {
+    /// Capture to a writer, starting with the file header
+    pub fn new<W: Write + Send + 'static>(mut out: W) -> Result<Self, Error> {
+        out.write_all(&FILE_MAGIC)?;
+        out.write_all(&[FILE_VERSION])?;
+        Ok(Self::resume(out))
+    }
+
+    /// Append to a capture file, creating it if it does not exist
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        match file.metadata()?.len() {
+            0 => Self::new(file),
+            _ => Ok(Self::resume(file))
+        }
+    }
+
+    // Capture to a writer that already has the file header
+    fn resume<W: Write + Send + 'static>(out: W) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(Box::new(out)))
+        }
+    }
+
+    /// Append a message to the capture as one record
+    pub fn record(&self, direction: Direction, peer: &Peer, bytes: &[u8]) -> Result<(), Error> {
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(bytes.len() + 64);
+        (direction as u8).net_encode(&mut record);
+        (time.as_micros() as u64).net_encode(&mut record);
+        AddrV2::from(peer.addr).net_encode(&mut record);
+        peer.port.to_u16().net_encode(&mut record);
+        record.extend_from_slice(bytes);
+
+        // The whole record is written at once so records from different threads do not interleave
+        let mut framed = (record.len() as u32).to_le_bytes().to_vec();
+        framed.extend_from_slice(&record);
+        let mut out = self.out.lock().expect("Capture lock poisoned");
+        out.write_all(&framed)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the records of a capture in order
+pub struct CaptureReader<R> {
+    inner: R
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Open a capture file and check its header
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read a capture from a reader, checking its header
+    pub fn new(mut inner: R) -> Result<Self, Error> {
+        let magic: [u8; 4] = Decode::net_decode(&mut inner)?;
+        let version: u8 = Decode::net_decode(&mut inner)?;
+        if magic != FILE_MAGIC || version != FILE_VERSION {
+            return Err(Error::Decode(encode::Error::InvalidData))
+        }
+        Ok(Self { inner })
+    }
+
+    /// Read the next record, `None` at the end of the capture
+    pub fn read_record(&mut self) -> Result<Option<CaptureRecord>, Error> {
+        let mut len = [0; 4];
+        match self.inner.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            read => read?
+        }
+        let record = encode::read_bytes(&mut self.inner, u32::from_le_bytes(len) as u64)?;
+
+        let mut r = &record[..];
+        let direction = match u8::net_decode(&mut r)? {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            _ => return Err(Error::Decode(encode::Error::InvalidData))
+        };
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(Decode::net_decode(&mut r)?);
+        let host = Host::try_from(AddrV2::net_decode(&mut r)?)?;
+        let port: u16 = Decode::net_decode(&mut r)?;
+
+        Ok(Some(CaptureRecord {
+            direction,
+            time,
+            peer: Peer::new(host, port),
+            bytes: r.to_vec()
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        msg::{
+            data::MessagePayload,
+            header::{
+                Command,
+                Magic
+            },
+            network::VersionMessage
+        },
+        net::{
+            connection::Connection,
+            mock::TempFile
+        }
+    };
+    use std::net::{
+        Ipv4Addr,
+        TcpListener,
+        TcpStream
+    };
+
+    #[test]
+    fn captures_messages() {
+        let path = TempFile::new("capture.cap");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, addr.port());
+        let responder = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::accept(stream, Magic::Regtest, VersionMessage::builder(Address::me()).build()).unwrap();
+            conn.send_payload(MessagePayload::PingPong(2), Command::Pong).unwrap();
+            conn.recv().unwrap()
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = Connection::handshake(stream, Magic::Regtest, VersionMessage::builder(Address::from(addr)).build()).unwrap();
+        conn.set_capture(Some((Capture::create(&path).unwrap(), peer)));
+        conn.send_payload(MessagePayload::PingPong(1), Command::Ping).unwrap();
+        conn.recv().unwrap();
+        responder.join().unwrap();
+
+        // Appending to an existing capture keeps its records
+        conn.set_capture(Some((Capture::create(&path).unwrap(), peer)));
+        conn.send_payload(MessagePayload::EmptyPayload, Command::GetAddr).unwrap();
+
+        let records: Vec<CaptureRecord> = CaptureReader::open(&path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records.iter().map(|r| r.direction).collect::<Vec<_>>(), vec![Direction::Sent, Direction::Received, Direction::Sent]);
+        assert!(records.iter().all(|r| r.peer == peer));
+        assert_eq!(records[1].message().unwrap().payload, MessagePayload::PingPong(2));
+        assert_eq!(records[2].message().unwrap().header.command, Command::GetAddr);
+        assert!(CaptureReader::new(&b"BNMC\x02"[..]).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+// compact.rs
+//
+// Module for reconstructing BIP152 compact blocks: matching their short ids
+// against transactions already seen and requesting the rest with getblocktxn.
+//
+
+use crate::{
+    bitcoin::{
+        Block,
+        BlockHeader
+    },
+    blockdata::{
+        BlockHash,
+        Hash,
+        Transaction
+    },
+    msg::{
+        compact::{
+            short_id,
+            BlockTransactions,
+            BlockTransactionsRequest,
+            HeaderAndShortIds
+        },
+        data::MessagePayload,
+        header::Command
+    },
+    net::{
+        connection::Connection,
+        misbehavior::Misbehavior,
+        Error
+    }
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    io::{
+        Read,
+        Write
+    }
+};
+
+/// Most transactions a compact block can hold, a block of minimal transactions at the weight limit
+pub const MAX_BLOCK_TXS: usize = 4_000_000 / 240;
+
+fn violation(reason: String) -> Error {
+    Error::Misbehavior(Misbehavior::ProtocolViolation(reason))
+}
+
+/// A compact block with its transactions filled in as far as possible.
+///
+/// Short ids are matched against wtxids, as in version 2 compact blocks.
+pub struct PartialBlock {
+    header: BlockHeader,
+    // Transactions by position in the block, `None` where still missing
+    txs: Vec<Option<Transaction>>
+}
+
+impl PartialBlock {
+    /// Fill in a compact block from its prefilled transactions and those in `known`, such as
+    /// transactions seen relayed. Positions whose short id matches more than one transaction
+    /// are left missing.
+    pub fn new<'a>(block: &HeaderAndShortIds, known: impl IntoIterator<Item = &'a Transaction>) -> Result<Self, Error> {
+        let count = block.tx_count();
+        if count == 0 || count > MAX_BLOCK_TXS {
+            return Err(violation(format!("Compact block with {} transactions", count)))
+        }
+
+        let mut txs = vec![None; count];
+        for prefilled in &block.prefilled {
+            match txs.get_mut(prefilled.index as usize) {
+                Some(slot @ None) => *slot = Some(prefilled.tx.clone()),
+                _ => return Err(violation(format!("Invalid prefilled transaction index {}", prefilled.index)))
+            }
+        }
+
+        // Short ids fill the positions not prefilled, in order
+        let mut positions = HashMap::new();
+        let mut ambiguous = HashSet::new();
+        let free = (0..count).filter(|i| txs[*i].is_none());
+        for (id, position) in block.short_ids.iter().zip(free) {
+            if positions.insert(*id, position).is_some() {
+                ambiguous.insert(*id);
+            }
+        }
+
+        let keys = block.keys();
+        for tx in known {
+            let id = short_id(keys, &tx.wtxid().into_inner());
+            if let Some(position) = positions.get(&id) {
+                if txs[*position].replace(tx.clone()).is_some_and(|other| other != *tx) {
+                    ambiguous.insert(id);
+                }
+            }
+        }
+        for id in ambiguous {
+            txs[positions[&id]] = None;
+        }
+
+        Ok(Self {
+            header: block.header,
+            txs
+        })
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        self.header.block_hash()
+    }
+
+    /// Whether every transaction has been filled in
+    pub fn is_complete(&self) -> bool {
+        self.txs.iter().all(Option::is_some)
+    }
+
+    /// Request for the transactions still missing, to send with getblocktxn
+    pub fn missing(&self) -> BlockTransactionsRequest {
+        BlockTransactionsRequest {
+            block_hash: self.block_hash(),
+            indexes: (0..self.txs.len()).filter(|i| self.txs[*i].is_none()).map(|i| i as u16).collect()
+        }
+    }
+
+    /// Complete the block with the missing transactions, in the order they were requested.
+    ///
+    /// Fails if the transactions do not match the header, which can also be caused by a short
+    /// id collision. The full block should then be downloaded instead.
+    pub fn fill(mut self, missing: BlockTransactions) -> Result<Block, Error> {
+        let slots: Vec<&mut Option<Transaction>> = self.txs.iter_mut().filter(|t| t.is_none()).collect();
+        if missing.block_hash != self.header.block_hash() || missing.transactions.len() != slots.len() {
+            return Err(violation(format!("Transactions sent do not fill block {}", self.header.block_hash())))
+        }
+        for (slot, tx) in slots.into_iter().zip(missing.transactions) {
+            *slot = Some(tx);
+        }
+
+        let block = Block {
+            header: self.header,
+            txdata: self.txs.into_iter().flatten().collect()
+        };
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            return Err(violation(format!("Block {} does not match its header", block.block_hash())))
+        }
+        Ok(block)
+    }
+}
+
+/// Reconstruct a compact block received from a peer, requesting any missing transactions from
+/// it with getblocktxn. Other messages received while waiting are dropped.
+pub fn reconstruct<'a, S: Read + Write>(conn: &mut Connection<S>, block: &HeaderAndShortIds, known: impl IntoIterator<Item = &'a Transaction>) -> Result<Block, Error> {
+    let partial = PartialBlock::new(block, known)?;
+    let hash = partial.block_hash();
+    if partial.is_complete() {
+        return partial.fill(BlockTransactions { block_hash: hash, transactions: vec![] })
+    }
+
+    conn.send_payload(MessagePayload::GetBlockTxn(partial.missing()), Command::GetBlockTxn)?;
+    loop {
+        if let MessagePayload::BlockTxn(txs) = conn.recv()?.payload {
+            if txs.block_hash == hash {
+                return partial.fill(txs)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::{
+            hashes::sha256d,
+            blockdata::constants::genesis_block,
+            Network,
+            TxIn,
+            TxMerkleNode
+        },
+        msg::{
+            compact::PrefilledTransaction,
+            header::Magic,
+            network::VersionMessage
+        }
+    };
+    use std::{
+        net::{
+            TcpListener,
+            TcpStream
+        },
+        thread
+    };
+
+    fn block(txs: Vec<Transaction>) -> Block {
+        // Merkle root of the txids, duplicating the last hash of odd levels
+        let mut level: Vec<[u8; 32]> = txs.iter().map(|t| t.txid().into_inner()).collect();
+        while level.len() > 1 {
+            level = level.chunks(2)
+                .map(|pair| sha256d::Hash::hash(&[pair[0], *pair.last().unwrap()].concat()).into_inner())
+                .collect();
+        }
+
+        let mut header = genesis_block(Network::Regtest).header;
+        header.merkle_root = TxMerkleNode::from_inner(level[0]);
+        Block { header, txdata: txs }
+    }
+
+    fn compact(block: &Block) -> HeaderAndShortIds {
+        let mut cmpct = HeaderAndShortIds {
+            header: block.header,
+            nonce: 42,
+            short_ids: vec![],
+            prefilled: vec![PrefilledTransaction { index: 0, tx: block.txdata[0].clone() }]
+        };
+        let keys = cmpct.keys();
+        cmpct.short_ids = block.txdata[1..].iter().map(|t| short_id(keys, &t.wtxid().into_inner())).collect();
+        cmpct
+    }
+
+    #[test]
+    fn reconstructs_compact_blocks() {
+        let txs: Vec<Transaction> = (0..4)
+            .map(|i| Transaction { version: 2, lock_time: i, input: vec![TxIn::default()], output: vec![] })
+            .collect();
+        let full = block(txs.clone());
+        let cmpct = compact(&full);
+        let unrelated = Transaction { lock_time: 99, ..txs[1].clone() };
+
+        let partial = PartialBlock::new(&cmpct, vec![&txs[1], &txs[3], &unrelated]).unwrap();
+        assert!(!partial.is_complete());
+        assert_eq!(partial.missing().indexes, vec![2]);
+        assert!(PartialBlock::new(&cmpct, vec![]).unwrap().fill(BlockTransactions { block_hash: full.block_hash(), transactions: vec![] }).is_err());
+
+        // A peer answers getblocktxn with the transactions asked for
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = full.clone();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::accept(stream, Magic::Regtest, VersionMessage::builder(Address::me()).build()).unwrap();
+            while let Ok(msg) = conn.recv() {
+                if let MessagePayload::GetBlockTxn(request) = msg.payload {
+                    let transactions = request.indexes.iter().map(|i| served.txdata[*i as usize].clone()).collect();
+                    let txs = BlockTransactions { block_hash: request.block_hash, transactions };
+                    conn.send_payload(MessagePayload::BlockTxn(txs), Command::BlockTxn).unwrap();
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = Connection::handshake(stream, Magic::Regtest, VersionMessage::builder(Address::from(addr)).build()).unwrap();
+        assert_eq!(reconstruct(&mut conn, &cmpct, vec![&txs[1], &txs[3]]).unwrap(), full);
+        assert_eq!(reconstruct(&mut conn, &cmpct, &txs).unwrap(), full);
+
+        // Prefilled indexes must be within the block
+        let mut invalid = cmpct.clone();
+        invalid.prefilled[0].index = 4;
+        assert!(matches!(PartialBlock::new(&invalid, vec![]), Err(Error::Misbehavior(_))));
+    }
+}
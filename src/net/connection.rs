@@ -0,0 +1,1178 @@
+// connection.rs
+//
+// Module for connections with peers that have completed the version handshake.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Magic,
+            Command
+        },
+        inventory::Inventory,
+        network::{
+            NetAddress,
+            VersionMessage,
+            VersionMessageBuilder,
+            ProtocolVersion,
+            ServicesList
+        }
+    },
+    encode::Encode,
+    address::Address,
+    net::{
+        capture::{
+            Capture,
+            Direction
+        },
+        peer::Peer,
+        reader::{
+            ChecksumPolicy,
+            MessageReader
+        },
+        misbehavior::{
+            Misbehavior,
+            Violation
+        },
+        nonce::NonceTracker,
+        ping::{
+            Keepalive,
+            is_timeout
+        },
+        ratelimit::{
+            RateLimit,
+            TokenBucket
+        },
+        responder::{
+            Responder,
+            ANSWERED
+        },
+        stream::{
+            stream_with,
+            StreamOptions
+        },
+        traffic::Traffic,
+        v2::{
+            negotiate,
+            Negotiated,
+            PacketEncoder
+        },
+        Error
+    }
+};
+use std::{
+    collections::{
+        HashSet,
+        VecDeque
+    },
+    io::{
+        Read,
+        Write
+    },
+    net::{
+        Shutdown,
+        SocketAddr,
+        TcpStream
+    },
+    sync::{
+        mpsc::{
+            sync_channel,
+            Receiver,
+            SyncSender,
+            TrySendError
+        },
+        Arc,
+        Mutex
+    },
+    thread,
+    time::Duration
+};
+use tracing::{
+    debug,
+    debug_span,
+    Span
+};
+
+/// Messages a connection's send queue holds before senders have to wait, see
+/// [`Connection::start_send_queue`]
+pub const SEND_QUEUE_CAPACITY: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Transport protocol used on a connection
+pub enum Transport {
+    /// Cleartext messages with a header and checksum
+    V1,
+    /// BIP324 encrypted packets
+    V2
+}
+
+/// Frames outgoing messages for a connection's transport.
+enum Framer {
+    V1,
+    V2(PacketEncoder)
+}
+
+impl Framer {
+    fn write<W: Write>(&mut self, w: &mut W, msg: &Message) -> Result<(), Error> {
+        match self {
+            Framer::V1 => write_message(w, msg),
+            Framer::V2(encoder) => {
+                w.write_all(&encoder.encode_message(msg))?;
+                w.flush()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Outgoing side of a connection, shared with its writers so v2 packets are encrypted in
+/// the order they are sent and every message counts towards the rate limit and traffic.
+struct Outbound {
+    framer: Framer,
+    limiter: Option<TokenBucket>,
+    traffic: Arc<Mutex<Traffic>>,
+    capture: Option<(Capture, Peer)>,
+    span: Span
+}
+
+impl Outbound {
+    fn send<W: Write>(&mut self, w: &mut W, msg: &Message) -> Result<(), Error> {
+        let _enter = self.span.enter();
+        if let Some(limiter) = &mut self.limiter {
+            limiter.acquire();
+        }
+        if let Err(e) = self.framer.write(w, msg) {
+            debug!(command = msg.header.command.to_str(), error = ?e, "Failed to send message");
+            return Err(e)
+        }
+        debug!(command = msg.header.command.to_str(), length = msg.header.length, "Sent message");
+        self.traffic.lock().expect("Traffic lock poisoned").record_sent(msg);
+        if let Some((capture, peer)) = &self.capture {
+            let mut bytes = Vec::new();
+            msg.net_encode(&mut bytes);
+            let _ = capture.record(Direction::Sent, peer, &bytes);
+        }
+        Ok(())
+    }
+}
+
+// Sends waiting for a connection's writer thread
+enum Queued {
+    Message(Box<Message>),
+    // Close the connection once everything before it has been sent
+    Close
+}
+
+// Send queue shared by every half of a connection, unset until it is started
+type SendQueue = Arc<Mutex<Option<SyncSender<Queued>>>>;
+
+// The connection's send queue, if it has been started
+fn sender(queue: &SendQueue) -> Option<SyncSender<Queued>> {
+    queue.lock().expect("Queue lock poisoned").clone()
+}
+
+// Blocks while the queue is full, fails once the writer thread has stopped
+fn enqueue(sender: &SyncSender<Queued>, queued: Queued) -> Result<(), Error> {
+    sender.send(queued).map_err(|_| Error::Io(std::io::ErrorKind::BrokenPipe.into()))
+}
+
+// Writer thread: send queued messages in order until the connection is closed or a write
+// fails, in which case the stream is shut down so the reading side fails too
+fn drain(mut stream: TcpStream, outbound: Arc<Mutex<Outbound>>, queue: Receiver<Queued>) {
+    for queued in queue {
+        let sent = match queued {
+            Queued::Message(msg) => outbound.lock().expect("Outbound lock poisoned").send(&mut stream, &msg),
+            Queued::Close => break
+        };
+        if sent.is_err() {
+            break
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// A connection with a peer that has completed the version handshake.
+pub struct Connection<S> {
+    reader: MessageReader<S>,
+    magic: Magic,
+    nonce: u64,
+    version: ProtocolVersion,
+    peer_version: VersionMessage,
+    // Messages received during the handshake that were not part of it
+    pending: VecDeque<Message>,
+    // Messages the peer sent out of order during the handshake
+    violations: Vec<Violation>,
+    // Nonces of all our open connections, shared with other connections
+    nonces: NonceTracker,
+    keepalive: Keepalive,
+    // Answers the peer's requests when set
+    responder: Option<Responder>,
+    outbound: Arc<Mutex<Outbound>>,
+    queue: SendQueue,
+    // Messages sent and received, shared with the connection's writers
+    traffic: Arc<Mutex<Traffic>>,
+    // Commands of the messages returned by recv, all if unset
+    filter: Option<HashSet<Command>>,
+    // Drop transactions and their announcements, which we asked not to be sent
+    block_relay_only: bool,
+    // Where received messages are captured, shared with the outbound side
+    capture: Option<(Capture, Peer)>,
+    // Span the connection's events are logged in
+    span: Span
+}
+
+/// Write half of a connection, for sending messages from another thread
+pub struct ConnectionWriter {
+    stream: TcpStream,
+    outbound: Arc<Mutex<Outbound>>,
+    queue: SendQueue
+}
+
+impl ConnectionWriter {
+    /// Send a message to the peer, waiting for the connection's rate limit if there is one,
+    /// or queue it if the connection has a [send queue](Connection::start_send_queue)
+    pub fn send(&mut self, msg: &Message) -> Result<(), Error> {
+        match sender(&self.queue) {
+            Some(sender) => enqueue(&sender, Queued::Message(Box::new(msg.clone()))),
+            None => self.outbound.lock().expect("Outbound lock poisoned").send(&mut self.stream, msg)
+        }
+    }
+
+    /// Queue a message without waiting, failing with [`WouldBlock`](std::io::ErrorKind::WouldBlock)
+    /// if the connection's send queue is full. Connections without a send queue are sent to
+    /// directly, as with [`send`](Self::send).
+    pub fn try_send(&mut self, msg: &Message) -> Result<(), Error> {
+        match sender(&self.queue) {
+            Some(sender) => sender.try_send(Queued::Message(Box::new(msg.clone()))).map_err(|e| match e {
+                TrySendError::Full(_) => Error::Io(std::io::ErrorKind::WouldBlock.into()),
+                TrySendError::Disconnected(_) => Error::Io(std::io::ErrorKind::BrokenPipe.into())
+            }),
+            None => self.send(msg)
+        }
+    }
+
+    /// Messages sent and received on the connection so far
+    pub fn traffic(&self) -> Traffic {
+        self.outbound.lock().expect("Outbound lock poisoned").traffic.lock().expect("Traffic lock poisoned").clone()
+    }
+
+    /// Close the connection in both directions, failing any read blocked on it. A message
+    /// being sent from another half of the connection is finished first, as are the messages
+    /// already in the send queue, which the writer thread sends before closing.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        // Closed directly if the writer thread has already stopped
+        if sender(&self.queue).is_some_and(|sender| enqueue(&sender, Queued::Close).is_ok()) {
+            return Ok(())
+        }
+        let _outbound = self.outbound.lock().expect("Outbound lock poisoned");
+        self.stream.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    /// Close the connection like [`shutdown`](Self::shutdown), without waiting on a full send
+    /// queue: the connection is then cut straight away and the queued messages are dropped.
+    pub fn shutdown_now(&self) -> Result<(), Error> {
+        match sender(&self.queue).map(|sender| sender.try_send(Queued::Close)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(TrySendError::Full(_))) => Ok(self.stream.shutdown(Shutdown::Both)?),
+            _ => self.shutdown()
+        }
+    }
+}
+
+impl Connection<TcpStream> {
+    /// Connect to a peer and complete the version handshake using a default version message
+    pub fn connect(peer: Peer, magic: Magic) -> Result<Self, Error> {
+        Self::connect_with(peer, magic, &StreamOptions::default())
+    }
+
+    /// Connect to a peer using the given stream options and complete the version handshake
+    pub fn connect_with(peer: Peer, magic: Magic, options: &StreamOptions) -> Result<Self, Error> {
+        Self::connect_tracked(peer, magic, options, &NonceTracker::new())
+    }
+
+    /// Connect to a peer using the given stream options, detecting self connections using
+    /// the nonces of all connections sharing `nonces`.
+    ///
+    /// If `options.v2` is set the v2 transport is attempted first, reconnecting over v1 if
+    /// the peer does not complete the key exchange.
+    pub fn connect_tracked(peer: Peer, magic: Magic, options: &StreamOptions, nonces: &NonceTracker) -> Result<Self, Error> {
+        // The connection's span is created within this one, so its events name the peer
+        let span = debug_span!("peer", peer = %peer.to_string());
+        let _enter = span.enter();
+        if options.v2 {
+            match Self::dial(peer, magic, options, nonces, true) {
+                Err(e @ Error::Handshake(_)) | Err(e @ Error::Io(_)) | Err(e @ Error::Decode(_)) => {
+                    debug!(error = ?e, "v2 handshake failed, reconnecting over v1");
+                },
+                conn => return conn
+            }
+        }
+        Self::dial(peer, magic, options, nonces, false)
+    }
+
+    fn dial(peer: Peer, magic: Magic, options: &StreamOptions, nonces: &NonceTracker, v2: bool) -> Result<Self, Error> {
+        let stream = stream_with(peer, options)?;
+        let version = version_builder(peer, stream.peer_addr().ok(), options).build();
+
+        let mut conn = Self::open(stream, magic, version, nonces, true, v2)?;
+        require_version(conn.peer_version(), options.min_version)?;
+        conn.set_ping_timeout(options.ping_timeout);
+        conn.set_inactivity_timeout(options.inactivity_timeout);
+        // Wake up in time to send pings to quiet peers and notice stalled ones
+        conn.get_ref().set_read_timeout(Some(options.wake_interval()))?;
+        Ok(conn)
+    }
+
+    /// Get a write half of the connection that can be used from another thread
+    pub fn writer(&self) -> Result<ConnectionWriter, Error> {
+        Ok(ConnectionWriter {
+            stream: self.get_ref().try_clone()?,
+            outbound: Arc::clone(&self.outbound),
+            queue: Arc::clone(&self.queue)
+        })
+    }
+
+    /// Send every message through a queue of `capacity` messages drained by a writer thread,
+    /// including those of the connection's writers, pings and responder.
+    ///
+    /// Sending returns once the message is queued, and blocks while the queue is full, so a
+    /// slow peer holds back whoever sends to it rather than messages piling up in memory.
+    /// Messages from different threads are sent whole, in the order they were queued. If a
+    /// write fails the connection is closed and later sends fail. Does nothing if the queue
+    /// was already started.
+    pub fn start_send_queue(&mut self, capacity: usize) -> Result<(), Error> {
+        let mut queue = self.queue.lock().expect("Queue lock poisoned");
+        if queue.is_some() {
+            return Ok(())
+        }
+        let stream = self.get_ref().try_clone()?;
+        let (sender, receiver) = sync_channel(capacity);
+        let outbound = Arc::clone(&self.outbound);
+        thread::spawn(move || drain(stream, outbound, receiver));
+        *queue = Some(sender);
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Perform the version handshake over a stream.
+    ///
+    /// Sends `version`, waits for the peer's version message and verack, replies with a
+    /// verack and returns once both sides have acknowledged each other. The connection is
+    /// rejected if the peer's version message carries our own nonce.
+    pub fn handshake(stream: S, magic: Magic, version: VersionMessage) -> Result<Self, Error> {
+        Self::handshake_tracked(stream, magic, version, &NonceTracker::new())
+    }
+
+    /// Perform the version handshake, detecting self connections using the nonces of all
+    /// connections sharing `nonces`.
+    ///
+    /// The nonce in `version` is tracked for as long as the connection is open, so a
+    /// connection back to ourselves on another socket is rejected. It is replaced with a
+    /// fresh one if another connection sharing `nonces` already uses it.
+    pub fn handshake_tracked(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, true, false)
+    }
+
+    /// Perform the v2 key exchange as the initiator, then the version handshake over the
+    /// encrypted transport. Fails if the peer does not speak v2.
+    pub fn handshake_v2(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, true, true)
+    }
+
+    /// Perform the responder side of the version handshake on an inbound stream.
+    ///
+    /// Waits for the peer's version message before replying with `version` and a verack,
+    /// then returns once the peer's verack has been received.
+    pub fn accept(stream: S, magic: Magic, version: VersionMessage) -> Result<Self, Error> {
+        Self::accept_tracked(stream, magic, version, &NonceTracker::new())
+    }
+
+    /// Perform the responder side of the version handshake, detecting self connections
+    /// using the nonces of all connections sharing `nonces`.
+    pub fn accept_tracked(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, false, false)
+    }
+
+    /// Perform the responder side of the v2 key exchange and version handshake.
+    /// Peers that open with a v1 version message are served over v1 instead.
+    pub fn accept_v2(stream: S, magic: Magic, version: VersionMessage, nonces: &NonceTracker) -> Result<Self, Error> {
+        Self::open(stream, magic, version, nonces, false, true)
+    }
+
+    fn open(stream: S, magic: Magic, mut version: VersionMessage, nonces: &NonceTracker, outbound: bool, v2: bool) -> Result<Self, Error> {
+        let nonce = nonces.claim(version.nonce);
+        version.nonce = nonce;
+
+        let span = debug_span!("connection", outbound, v2, nonce);
+        let _enter = span.enter();
+        let conn = Self::framing(stream, magic, outbound, v2)
+            .and_then(|(reader, framer)| Self::exchange_versions(reader, framer, magic, version, nonces, outbound, &span));
+        if let Err(e) = &conn {
+            debug!(error = ?e, "Handshake failed");
+            nonces.remove(nonce);
+        }
+        conn
+    }
+
+    /// Set up the transport, running the v2 key exchange if requested
+    fn framing(mut stream: S, magic: Magic, outbound: bool, v2: bool) -> Result<(MessageReader<S>, Framer), Error> {
+        if !v2 {
+            return Ok((MessageReader::new(stream, magic), Framer::V1))
+        }
+
+        Ok(match negotiate(&mut stream, magic, outbound)? {
+            Negotiated::V2(encoder, decoder, buf) => (MessageReader::resume(stream, magic, Some(*decoder), buf), Framer::V2(encoder)),
+            Negotiated::V1(buf) => (MessageReader::resume(stream, magic, None, buf), Framer::V1)
+        })
+    }
+
+    fn exchange_versions(mut reader: MessageReader<S>, framer: Framer, magic: Magic, version: VersionMessage, nonces: &NonceTracker, outbound: bool, span: &Span) -> Result<Self, Error> {
+        let nonce = version.nonce;
+        let mut handshake = Handshake::new(&version);
+        let traffic = Arc::new(Mutex::new(Traffic::new()));
+        // No rate limit is set yet, so the handshake is never held up
+        let mut out = Outbound { framer, limiter: None, traffic: Arc::clone(&traffic), capture: None, span: span.clone() };
+
+        // The initiator sends its version first, the responder waits for the peer's
+        let mut ours = Some(version);
+        if outbound {
+            out.send(reader.get_mut(), &version_message(&mut ours, magic))?;
+        }
+        while !handshake.is_complete() {
+            let msg = reader.read_message()?;
+            traffic.lock().expect("Traffic lock poisoned").record_received(&msg);
+            if handshake.receive(msg, nonces)? {
+                if ours.is_some() {
+                    out.send(reader.get_mut(), &version_message(&mut ours, magic))?;
+                }
+                out.send(reader.get_mut(), &Message::new(MessagePayload::EmptyPayload, magic, Command::Verack))?;
+            }
+        }
+
+        let (version, peer_version, pending, violations) = handshake.finish();
+        let conn = Self {
+            reader,
+            magic,
+            nonce,
+            version,
+            peer_version,
+            pending,
+            violations,
+            nonces: nonces.clone(),
+            keepalive: Keepalive::new(version),
+            responder: None,
+            outbound: Arc::new(Mutex::new(out)),
+            queue: Arc::new(Mutex::new(None)),
+            traffic,
+            filter: None,
+            block_relay_only: false,
+            capture: None,
+            span: span.clone()
+        };
+        debug!(version = conn.version.0, agent = %conn.peer_version.agent, transport = ?conn.transport(), "Handshake complete");
+        Ok(conn)
+    }
+
+    /// Send a message to the peer, waiting for the connection's rate limit if there is one,
+    /// or queue it if the connection has a [send queue](Connection::start_send_queue)
+    pub fn send(&mut self, msg: &Message) -> Result<(), Error> {
+        match sender(&self.queue) {
+            Some(sender) => enqueue(&sender, Queued::Message(Box::new(msg.clone()))),
+            None => self.outbound.lock().expect("Outbound lock poisoned").send(self.reader.get_mut(), msg)
+        }
+    }
+
+    /// Wrap a payload in a message for this connection's network and send it
+    pub fn send_payload(&mut self, payload: MessagePayload, command: Command) -> Result<(), Error> {
+        self.send(&Message::new(payload, self.magic, command))
+    }
+
+    /// Receive the next message from the peer.
+    ///
+    /// Pings that fall due are sent while waiting. Read timeouts are treated as a chance to
+    /// send a ping rather than an error, so the stream's read timeout should not exceed the
+    /// ping interval. Fails with [`Error::PingTimeout`] if the peer stops answering pings, and
+    /// with [`Error::Stalled`] if it sends nothing within the
+    /// [inactivity timeout](Self::set_inactivity_timeout).
+    ///
+    /// Version and verack messages after the handshake are dropped, failing with
+    /// [`Misbehavior::Handshake`] after which the connection can be read from again.
+    pub fn recv(&mut self) -> Result<Message, Error> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let msg = self.receive();
+        match &msg {
+            Ok(msg) => debug!(command = msg.header.command.to_str(), length = msg.header.length, "Received message"),
+            Err(e) => debug!(error = ?e, "Failed to receive message")
+        }
+        msg
+    }
+
+    fn receive(&mut self) -> Result<Message, Error> {
+        loop {
+            if let Some(nonce) = self.keepalive.poll()? {
+                self.send_payload(MessagePayload::PingPong(nonce), Command::Ping)?;
+            }
+
+            // Pending messages were counted during the handshake
+            let msg = match self.pending.pop_front() {
+                Some(msg) => msg,
+                None => match self.reader.read_message() {
+                    Ok(msg) => {
+                        self.traffic.lock().expect("Traffic lock poisoned").record_received(&msg);
+                        if let Some((capture, peer)) = &self.capture {
+                            let _ = capture.record(Direction::Received, peer, self.reader.last_raw());
+                        }
+                        msg
+                    },
+                    Err(Error::Io(e)) if is_timeout(&e) && self.keepalive.enabled() => continue,
+                    Err(e) => return Err(e)
+                }
+            };
+            if let Some(violation) = Violation::check(&msg.header.command, true, true) {
+                return Err(Error::Misbehavior(Misbehavior::Handshake(violation)))
+            }
+            self.keepalive.receive(&msg);
+            if let Some((payload, command)) = self.responder.as_mut().and_then(|r| r.reply(&msg)) {
+                self.send_payload(payload, command)?;
+            }
+            if self.filter.as_ref().is_some_and(|f| !f.contains(&msg.header.command)) {
+                continue
+            }
+            if self.block_relay_only {
+                match without_transactions(msg, self.magic) {
+                    Some(msg) => return Ok(msg),
+                    None => continue
+                }
+            }
+            return Ok(msg)
+        }
+    }
+
+    /// Only receive messages with the given commands, `None` receives every message.
+    ///
+    /// Other messages are dropped as they are read, before their payload is verified or
+    /// decoded, and are not counted in the [`traffic`](Self::traffic). Pongs are still read
+    /// to measure latency, and the requests of a [`responder`](Self::set_responder) to answer them.
+    pub fn set_filter(&mut self, commands: Option<&[Command]>) {
+        self.filter = commands.map(|c| c.iter().cloned().collect());
+        self.update_reader_filter();
+    }
+
+    /// Set how messages with a checksum that does not match their payload are handled.
+    /// [`ChecksumPolicy::Strict`] by default, where they fail [`recv`](Self::recv) with
+    /// [`Error::Misbehavior`].
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) {
+        self.reader.set_checksum_policy(policy);
+    }
+
+    /// Drop the transactions and transaction announcements the peer sends, as bitcoin core does
+    /// on block relay only connections. For connections whose version message asked peers not
+    /// to relay transactions, where any that arrive were not asked for. Inv messages announcing
+    /// blocks as well keep their blocks. Off by default.
+    pub fn set_block_relay_only(&mut self, block_relay_only: bool) {
+        self.block_relay_only = block_relay_only;
+    }
+
+    /// Automatically answer pings, getheaders and getaddr with a [`Responder`], `None` leaves
+    /// them to the caller. The requests are still returned by [`recv`](Self::recv). Off by default.
+    pub fn set_responder(&mut self, responder: Option<Responder>) {
+        self.responder = responder;
+        self.update_reader_filter();
+    }
+
+    // Commands the reader decodes: those returned by recv and those handled internally
+    fn update_reader_filter(&mut self) {
+        let answered = self.responder.as_ref().map_or(&[][..], |_| &ANSWERED[..]);
+        self.reader.set_filter(self.filter.clone().map(|mut f| {
+            f.insert(Command::Pong);
+            f.extend(answered.iter().cloned());
+            f
+        }));
+    }
+
+    /// Append the messages sent and received from now on to a capture, recorded as exchanged
+    /// with the given peer. `None` stops capturing. Messages dropped by the
+    /// [`filter`](Self::set_filter) are not captured, and failures to write to the capture are ignored.
+    pub fn set_capture(&mut self, capture: Option<(Capture, Peer)>) {
+        self.reader.keep_raw(capture.is_some());
+        self.capture = capture;
+        self.outbound.lock().expect("Outbound lock poisoned").capture = self.capture.clone();
+    }
+
+    /// Set how often the peer is pinged, `None` disables pings.
+    /// Defaults to [`PING_INTERVAL`](crate::net::ping::PING_INTERVAL).
+    pub fn set_ping_interval(&mut self, interval: Option<Duration>) {
+        self.keepalive.set_interval(interval);
+    }
+
+    /// Set how long the peer has to answer a ping before [`recv`](Self::recv) fails with
+    /// [`Error::PingTimeout`]. Defaults to [`PING_TIMEOUT`](crate::net::ping::PING_TIMEOUT).
+    pub fn set_ping_timeout(&mut self, timeout: Duration) {
+        self.keepalive.set_timeout(timeout);
+    }
+
+    /// Set how long the peer may send nothing at all before [`recv`](Self::recv) fails with
+    /// [`Error::Stalled`], which catches peers that do not answer pings. The stream's read
+    /// timeout bounds how late this is noticed, and with pings disabled read timeouts are
+    /// returned instead. Off by default for connections made from a stream, connections
+    /// dialed with [`StreamOptions`] take theirs.
+    pub fn set_inactivity_timeout(&mut self, timeout: Option<Duration>) {
+        self.keepalive.set_inactivity_timeout(timeout);
+    }
+
+    /// Limit how fast messages are sent on this connection and its writers, `None` removes
+    /// the limit. Sending blocks until the limit allows another message. No limit by default.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.outbound.lock().expect("Outbound lock poisoned").limiter = limit.map(TokenBucket::new);
+    }
+
+    /// Round trip time of the most recently answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.keepalive.latency()
+    }
+
+    /// Messages sent and received on this connection so far, including the handshake
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.lock().expect("Traffic lock poisoned").clone()
+    }
+
+    /// Network magic used on this connection
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+
+    /// Nonce sent in our version message
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Transport protocol used on this connection
+    pub fn transport(&self) -> Transport {
+        match self.outbound.lock().expect("Outbound lock poisoned").framer {
+            Framer::V1 => Transport::V1,
+            Framer::V2(_) => Transport::V2
+        }
+    }
+
+    /// Protocol version negotiated with the peer
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Services advertised by the peer
+    pub fn services(&self) -> &ServicesList {
+        &self.peer_version.service
+    }
+
+    /// Version message received from the peer
+    pub fn peer_version(&self) -> &VersionMessage {
+        &self.peer_version
+    }
+
+    /// Messages the peer sent out of order during the handshake, which did not stop it
+    /// from completing. Those after it are reported by [`recv`](Self::recv).
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.reader.get_ref()
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        self.reader.get_mut()
+    }
+}
+
+/// Progress of a version handshake, independent of how messages are sent and received.
+pub(crate) struct Handshake {
+    nonce: u64,
+    version: ProtocolVersion,
+    peer_version: Option<VersionMessage>,
+    verack: bool,
+    // Messages received during the handshake that were not part of it
+    pending: VecDeque<Message>,
+    // Messages the peer sent out of order
+    violations: Vec<Violation>
+}
+
+impl Handshake {
+    /// Start a handshake in which we send `version`
+    pub(crate) fn new(version: &VersionMessage) -> Self {
+        Self {
+            nonce: version.nonce,
+            version: version.version,
+            peer_version: None,
+            verack: false,
+            pending: VecDeque::new(),
+            violations: Vec::new()
+        }
+    }
+
+    /// Process a message received during the handshake.
+    /// Returns true if a verack should be sent in reply.
+    ///
+    /// Messages out of order are noted as [violations](Violation). A duplicate version
+    /// message is ignored, a verack before the version and other messages are accepted.
+    pub(crate) fn receive(&mut self, msg: Message, nonces: &NonceTracker) -> Result<bool, Error> {
+        if let Some(violation) = Violation::check(&msg.header.command, self.peer_version.is_some(), self.verack) {
+            debug!(violation = ?violation, "Protocol violation");
+            let duplicate = violation == Violation::DuplicateVersion;
+            self.violations.push(violation);
+            if duplicate {
+                return Ok(false)
+            }
+        }
+        match (msg.header.command.clone(), msg.payload) {
+            (Command::Version, MessagePayload::Version(v)) => {
+                if v.nonce == self.nonce || nonces.contains(v.nonce) {
+                    return Err(Error::SelfConnection)
+                }
+
+                self.peer_version = Some(v);
+                Ok(true)
+            },
+            (Command::Verack, _) => {
+                self.verack = true;
+                Ok(false)
+            },
+            (_, payload) => {
+                self.pending.push_back(Message { header: msg.header, payload });
+                Ok(false)
+            }
+        }
+    }
+
+    /// Check if both the peer's version and verack have been received
+    pub(crate) fn is_complete(&self) -> bool {
+        self.peer_version.is_some() && self.verack
+    }
+
+    /// Return the negotiated version, the peer's version message, any pending messages and
+    /// the violations of the handshake
+    pub(crate) fn finish(self) -> (ProtocolVersion, VersionMessage, VecDeque<Message>, Vec<Violation>) {
+        let peer_version = self.peer_version.expect("Handshake incomplete");
+        (self.version.negotiate(peer_version.version), peer_version, self.pending, self.violations)
+    }
+}
+
+impl<S> Drop for Connection<S> {
+    fn drop(&mut self) {
+        self.nonces.remove(self.nonce);
+    }
+}
+
+/// Fail with [`Error::ObsoleteVersion`] if a peer's protocol version is below `min`
+pub(crate) fn require_version(peer: &VersionMessage, min: ProtocolVersion) -> Result<(), Error> {
+    match peer.version < min {
+        true => {
+            debug!(version = peer.version.0, min = min.0, "Peer protocol version too old");
+            Err(Error::ObsoleteVersion(peer.version))
+        },
+        false => Ok(())
+    }
+}
+
+/// Start our version message to a peer we are connected to, whose address the socket sees
+/// as `socket_peer`. addr_recv is that address, or the peer's own when connected through
+/// a proxy, zeroed for peers without one such as onion services. addr_from, the user agent,
+/// the start height and the relay flag are taken from `options`.
+pub fn version_builder(peer: Peer, socket_peer: Option<SocketAddr>, options: &StreamOptions) -> VersionMessageBuilder {
+    // The socket's peer address is the proxy's when connecting through one
+    let recv = match options.proxy {
+        Some(_) => peer.socket_addr(),
+        None => socket_peer.or_else(|| peer.socket_addr())
+    };
+    let from = options.external.map_or_else(Address::me, Address::from);
+    VersionMessage::builder(recv.map_or_else(Address::me, Address::from))
+        .addr_from(NetAddress::new(ServicesList::default(), from))
+        .user_agent(options.user_agent.clone())
+        .start_height(options.start_height)
+        .relay(options.relay)
+}
+
+// A message with its transaction announcements removed, or none if nothing else is left
+fn without_transactions(msg: Message, magic: Magic) -> Option<Message> {
+    let is_tx = |inv: &Inventory| matches!(inv, Inventory::Tx(_) | Inventory::WitnessTx(_) | Inventory::Wtx(_));
+    match &msg.payload {
+        _ if msg.header.command == Command::Tx => None,
+        MessagePayload::InvVect(inv) if msg.header.command == Command::Inv && inv.iter().any(is_tx) => {
+            let blocks: Vec<Inventory> = inv.iter().filter(|i| !is_tx(i)).cloned().collect();
+            match blocks.is_empty() {
+                true => None,
+                false => Some(Message::new(MessagePayload::InvVect(blocks), magic, Command::Inv))
+            }
+        },
+        _ => Some(msg)
+    }
+}
+
+/// Take our version message out of `ours` and wrap it for sending
+pub(crate) fn version_message(ours: &mut Option<VersionMessage>, magic: Magic) -> Message {
+    let version = ours.take().expect("Version already sent");
+    Message::new(MessagePayload::Version(version), magic, Command::Version)
+}
+
+/// Encode a message and write it to a stream in one go
+pub(crate) fn write_message<W: Write>(w: &mut W, msg: &Message) -> Result<(), Error> {
+    w.write_all(&msg.to_bytes())?;
+    w.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::traffic::Counter;
+    use std::net::{
+        Ipv4Addr,
+        TcpListener
+    };
+    use std::thread;
+
+    // Accept one connection and run the peer side of the handshake, echoing `nonce` if set
+    fn fake_peer(nonce: Option<u64>) -> (std::net::SocketAddr, thread::JoinHandle<Vec<Command>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = MessageReader::new(stream, Magic::Regtest);
+            let theirs = match reader.read_message().unwrap().payload {
+                MessagePayload::Version(v) => v,
+                _ => panic!("Expected version")
+            };
+
+            let ours = VersionMessage::builder(Address::me())
+                .version(ProtocolVersion::RELAY)
+                .nonce(nonce.unwrap_or(theirs.nonce.wrapping_add(1)))
+                .build();
+            for msg in [
+                Message::new(MessagePayload::Version(ours), Magic::Regtest, Command::Version),
+                Message::new(MessagePayload::EmptyPayload, Magic::Regtest, Command::SendHeaders),
+                Message::new(MessagePayload::EmptyPayload, Magic::Regtest, Command::Verack),
+                Message::new(MessagePayload::PingPong(9), Magic::Regtest, Command::Ping)
+            ] {
+                // The client may already have hung up after a failed handshake
+                let _ = write_message(reader.get_mut(), &msg);
+            }
+
+            // Record what the client sends until it hangs up
+            reader.map_while(Result::ok).map(|m| m.header.command).collect()
+        });
+
+        (addr, handle)
+    }
+
+    #[test]
+    fn addresses_version_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, addr.port());
+        let stream = TcpStream::connect(addr).unwrap();
+        let (inbound, remote) = listener.accept().unwrap();
+
+        // Each side addresses the other as its socket sees it
+        let options = StreamOptions::default();
+        let version = version_builder(peer, stream.peer_addr().ok(), &options).build();
+        assert_eq!((version.addr_recv.address, version.addr_from.address), (Address::from(addr), Address::me()));
+        let version = version_builder(Peer::new(remote.ip(), remote.port()), inbound.peer_addr().ok(), &options).build();
+        assert_eq!(version.addr_recv.address, Address::from(stream.local_addr().unwrap()));
+
+        // Through a proxy the socket leads to the proxy, and onion peers have no address
+        let external = SocketAddr::from(([203, 0, 113, 5], 8333));
+        let agent = crate::msg::agent::UserAgent::parse("/Satoshi:27.0.0/").unwrap();
+        let options = StreamOptions { proxy: Some(addr), external: Some(external), user_agent: agent.clone(), start_height: 850_000, ..options };
+        let version = version_builder(Peer::new(Ipv4Addr::new(198, 51, 100, 7), 8333), Some(addr), &options).build();
+        assert_eq!(version.addr_recv.address, Address::from(SocketAddr::from(([198, 51, 100, 7], 8333))));
+        assert_eq!((version.addr_from.address, version.agent, version.start_height), (Address::from(external), agent, 850_000));
+        let onion = Peer::new(crate::net::peer::Host::TorV3([7; 32]), 8333);
+        assert_eq!(version_builder(onion, Some(addr), &options).build().addr_recv.address, Address::me());
+    }
+
+    #[test]
+    fn completes_handshake() {
+        let (addr, peer) = fake_peer(None);
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).nonce(1).build();
+
+        let mut conn = Connection::handshake(stream, Magic::Regtest, version).unwrap();
+        assert_eq!(conn.version(), ProtocolVersion::RELAY);
+        assert_eq!(conn.nonce(), 1);
+
+        // Messages outside the handshake are delivered in order
+        assert_eq!(conn.recv().unwrap().header.command, Command::SendHeaders);
+        assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(9));
+        conn.send_payload(MessagePayload::PingPong(9), Command::Pong).unwrap();
+
+        // Handshake messages are counted, pending ones only once
+        let traffic = conn.traffic();
+        assert_eq!(traffic.total_sent().messages, 3);
+        assert_eq!(traffic.total_received().messages, 4);
+        assert_eq!(traffic.received[&Command::Ping], Counter { messages: 1, bytes: 32 });
+        assert_eq!(conn.writer().unwrap().traffic(), traffic);
+        drop(conn);
+
+        assert_eq!(peer.join().unwrap(), vec![Command::Verack, Command::Pong]);
+    }
+
+    #[test]
+    fn responder_answers_filtered_pings() {
+        let (addr, peer) = fake_peer(None);
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).nonce(1).build();
+
+        let mut conn = Connection::handshake(stream, Magic::Regtest, version).unwrap();
+        conn.set_filter(Some(&[Command::Headers]));
+        conn.set_responder(Some(Responder::new()));
+        conn.get_ref().set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        conn.set_ping_interval(None);
+        assert!(conn.recv().is_err());
+        drop(conn);
+
+        assert_eq!(peer.join().unwrap(), vec![Command::Verack, Command::Pong]);
+    }
+
+    #[test]
+    fn drops_transactions_when_block_relay_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, block) = (Inventory::from_id_and_hash(1, [1; 32]), Inventory::from_id_and_hash(2, [2; 32]));
+        let announced = block.clone();
+        let peer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::accept(stream, Magic::Regtest, VersionMessage::builder(Address::me()).build()).unwrap();
+            conn.send_payload(MessagePayload::InvVect(vec![tx.clone()]), Command::Inv).unwrap();
+            conn.send_payload(MessagePayload::InvVect(vec![tx, announced]), Command::Inv).unwrap();
+            conn.send_payload(MessagePayload::PingPong(5), Command::Ping).unwrap();
+            let _ = conn.recv();
+        });
+
+        let version = VersionMessage::builder(Address::from(addr)).build();
+        let mut conn = Connection::handshake(TcpStream::connect(addr).unwrap(), Magic::Regtest, version).unwrap();
+        conn.set_block_relay_only(true);
+        // Announcements of only transactions are dropped, blocks are kept
+        assert_eq!(conn.recv().unwrap().payload, MessagePayload::InvVect(vec![block]));
+        assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(5));
+        drop(conn);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn queues_sends() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::accept(stream, Magic::Regtest, VersionMessage::builder(Address::me()).build()).unwrap();
+            conn.set_ping_interval(None);
+            let mut nonces = vec![];
+            while let Ok(msg) = conn.recv() {
+                if let MessagePayload::PingPong(nonce) = msg.payload {
+                    nonces.push(nonce);
+                }
+            }
+            nonces
+        });
+
+        let version = VersionMessage::builder(Address::from(addr)).build();
+        let mut conn = Connection::handshake(TcpStream::connect(addr).unwrap(), Magic::Regtest, version).unwrap();
+        conn.set_ping_interval(None);
+        conn.start_send_queue(1).unwrap();
+        let mut writer = conn.writer().unwrap();
+        let producer = thread::spawn(move || {
+            for nonce in 0..100 {
+                writer.send(&Message::new(MessagePayload::PingPong(nonce), Magic::Regtest, Command::Ping)).unwrap();
+            }
+            writer
+        });
+        for nonce in 100..200 {
+            conn.send_payload(MessagePayload::PingPong(nonce), Command::Ping).unwrap();
+        }
+
+        // Queued messages are sent before the connection closes
+        producer.join().unwrap().shutdown().unwrap();
+        let nonces = peer.join().unwrap();
+        assert_eq!(nonces.len(), 200);
+        for producer in [0..100, 100..200] {
+            let sent: Vec<u64> = nonces.iter().copied().filter(|n| producer.contains(n)).collect();
+            assert_eq!(sent, producer.collect::<Vec<_>>());
+        }
+        assert_eq!(conn.traffic().sent[&Command::Ping].messages, 200);
+    }
+
+    #[test]
+    fn rejects_own_nonce() {
+        let (addr, peer) = fake_peer(Some(7));
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).nonce(7).build();
+
+        assert!(matches!(Connection::handshake(stream, Magic::Regtest, version), Err(Error::SelfConnection)));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn flags_protocol_violations() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let version = || Message::new(MessagePayload::Version(VersionMessage::builder(Address::me()).nonce(2).build()), Magic::Regtest, Command::Version);
+            let verack = || Message::new(MessagePayload::EmptyPayload, Magic::Regtest, Command::Verack);
+            let ping = |nonce| Message::new(MessagePayload::PingPong(nonce), Magic::Regtest, Command::Ping);
+            for msg in [version(), ping(1), version(), verack(), verack(), ping(2)] {
+                write_message(&mut stream, &msg).unwrap();
+            }
+            stream
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let version = VersionMessage::builder(Address::me()).nonce(1).build();
+        let mut conn = Connection::accept(stream, Magic::Regtest, version).unwrap();
+        assert_eq!(conn.violations(), [Violation::MessageBeforeVerack(Command::Ping), Violation::DuplicateVersion]);
+
+        // Violations after the handshake are reported in place of the message
+        assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(1));
+        assert!(matches!(conn.recv(), Err(Error::Misbehavior(Misbehavior::Handshake(Violation::DuplicateVerack)))));
+        assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(2));
+        drop(peer.join().unwrap());
+    }
+
+    #[test]
+    fn enforces_minimum_version() {
+        // The fake peer speaks protocol version 70001
+        let (addr, peer) = fake_peer(None);
+        let options = StreamOptions { min_version: ProtocolVersion::WTXID_RELAY, ..StreamOptions::default() };
+        let result = Connection::connect_with(Peer::new(addr.ip(), addr.port()), Magic::Regtest, &options);
+        assert!(matches!(result, Err(Error::ObsoleteVersion(ProtocolVersion::RELAY))));
+        peer.join().unwrap();
+
+        let (addr, peer) = fake_peer(None);
+        let conn = Connection::connect_with(Peer::new(addr.ip(), addr.port()), Magic::Regtest, &StreamOptions::default()).unwrap();
+        assert_eq!(conn.peer_version().version, ProtocolVersion::RELAY);
+        drop(conn);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn tracks_nonces_across_connections() {
+        // Nonce 7 belongs to another of our open connections
+        let nonces = NonceTracker::new();
+        nonces.insert(7);
+
+        let (addr, peer) = fake_peer(Some(7));
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).nonce(8).build();
+        assert!(matches!(Connection::handshake_tracked(stream, Magic::Regtest, version, &nonces), Err(Error::SelfConnection)));
+        assert!(!nonces.contains(8));
+        peer.join().unwrap();
+
+        // Nonces are tracked while the connection is open
+        let (addr, peer) = fake_peer(None);
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).build();
+        let conn = Connection::handshake_tracked(stream, Magic::Regtest, version, &nonces).unwrap();
+        assert!(nonces.contains(conn.nonce()));
+
+        let nonce = conn.nonce();
+        drop(conn);
+        assert!(!nonces.contains(nonce));
+        peer.join().unwrap();
+
+        // A version message reused while its nonce is in use is sent with a fresh one
+        let version = VersionMessage::builder(Address::me()).nonce(9).build();
+        let (addr, peer) = fake_peer(None);
+        let first = Connection::handshake_tracked(TcpStream::connect(addr).unwrap(), Magic::Regtest, version.clone(), &nonces).unwrap();
+        let (addr, other) = fake_peer(None);
+        let second = Connection::handshake_tracked(TcpStream::connect(addr).unwrap(), Magic::Regtest, version, &nonces).unwrap();
+        assert_eq!(first.nonce(), 9);
+        assert_ne!(second.nonce(), 9);
+        assert!(nonces.contains(9) && nonces.contains(second.nonce()));
+        drop((first, second));
+        peer.join().unwrap();
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn accepts_inbound_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (stream, peer) = listener.accept().unwrap();
+            let version = VersionMessage::builder(Address::from(peer)).nonce(2).build();
+            let mut conn = Connection::accept(stream, Magic::Regtest, version).unwrap();
+            assert_eq!(conn.peer_version().nonce, 1);
+            conn.recv().unwrap().payload
+        });
+
+        let version = VersionMessage::builder(Address::from(addr)).nonce(1).build();
+        let mut conn = Connection::handshake(TcpStream::connect(addr).unwrap(), Magic::Regtest, version).unwrap();
+        assert_eq!(conn.peer_version().nonce, 2);
+        conn.send_payload(MessagePayload::PingPong(3), Command::Ping).unwrap();
+
+        assert_eq!(responder.join().unwrap(), MessagePayload::PingPong(3));
+    }
+
+    #[test]
+    fn negotiates_v2_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            // v2 responders also serve v1 peers
+            let mut transports = Vec::new();
+            for nonce in [2, 3] {
+                let (stream, peer) = listener.accept().unwrap();
+                let version = VersionMessage::builder(Address::from(peer)).nonce(nonce).build();
+                let mut conn = Connection::accept_v2(stream, Magic::Regtest, version, &NonceTracker::new()).unwrap();
+                assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(nonce));
+                conn.send_payload(MessagePayload::PingPong(nonce), Command::Pong).unwrap();
+                transports.push(conn.transport());
+            }
+            transports
+        });
+
+        let options = StreamOptions { v2: true, ..StreamOptions::default() };
+        let mut conn = Connection::connect_with(Peer::new(Ipv4Addr::LOCALHOST, addr.port()), Magic::Regtest, &options).unwrap();
+        assert_eq!(conn.transport(), Transport::V2);
+        conn.send_payload(MessagePayload::PingPong(2), Command::Ping).unwrap();
+        assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(2));
+
+        let mut conn = Connection::connect(Peer::new(Ipv4Addr::LOCALHOST, addr.port()), Magic::Regtest).unwrap();
+        assert_eq!(conn.transport(), Transport::V1);
+        conn.send_payload(MessagePayload::PingPong(3), Command::Ping).unwrap();
+        assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(3));
+
+        assert_eq!(responder.join().unwrap(), vec![Transport::V2, Transport::V1]);
+    }
+
+    #[test]
+    fn falls_back_to_v1() {
+        // A v1 peer hangs up on the v2 key, then completes a v1 handshake on reconnect
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 4]).unwrap();
+            drop(stream);
+
+            let (stream, peer) = listener.accept().unwrap();
+            let version = VersionMessage::builder(Address::from(peer)).nonce(2).build();
+            Connection::accept(stream, Magic::Regtest, version).unwrap().transport()
+        });
+
+        let options = StreamOptions { v2: true, ..StreamOptions::default() };
+        let conn = Connection::connect_with(Peer::new(Ipv4Addr::LOCALHOST, addr.port()), Magic::Regtest, &options).unwrap();
+        assert_eq!(conn.transport(), Transport::V1);
+        assert_eq!(responder.join().unwrap(), Transport::V1);
+    }
+}
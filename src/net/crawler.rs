@@ -0,0 +1,316 @@
+// crawler.rs
+//
+// Module for crawling the network: connecting to peers, asking them for the
+// addresses they know with getaddr and visiting those in turn, producing a
+// snapshot of the nodes that were reached.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Command,
+            Magic
+        },
+        network::{
+            NetAddressV2,
+            ProtocolVersion,
+            ServicesList
+        }
+    },
+    net::{
+        connection::Connection,
+        peer::Peer,
+        stream::StreamOptions,
+        Error
+    }
+};
+use std::{
+    collections::{
+        HashSet,
+        VecDeque
+    },
+    convert::TryFrom,
+    sync::{
+        Condvar,
+        Mutex
+    },
+    thread,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Bounds on how much of the network a crawl visits
+pub struct CrawlOptions {
+    /// Number of peers visited at the same time
+    pub concurrency: usize,
+    /// How many hops away from the starting peers to go. Peers found at this depth are
+    /// recorded but not visited.
+    pub max_depth: u32,
+    /// Stop recording new peers once this many are known, bounding the crawl's memory
+    pub max_peers: usize,
+    /// How long to wait for a peer to answer getaddr
+    pub addr_timeout: Duration,
+    /// Options for connecting to each peer
+    pub stream: StreamOptions,
+    /// Visit peers at addresses that are not publicly routable, such as private and
+    /// loopback ones. Only the starting peers are visited otherwise.
+    pub unroutable: bool
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 32,
+            max_depth: 2,
+            max_peers: 10_000,
+            addr_timeout: Duration::from_secs(30),
+            stream: StreamOptions::default(),
+            unroutable: false
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What was learnt about a peer during a crawl
+pub struct NodeInfo {
+    pub peer: Peer,
+    /// Hops from the starting peers
+    pub depth: u32,
+    /// Details from the peer's version message, `None` if no connection could be made
+    pub version: Option<NodeVersion>,
+    /// Number of addresses the peer returned, leaving out unroutable ones unless allowed
+    pub addresses: usize
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Details a reachable peer sent in its version message
+pub struct NodeVersion {
+    pub version: ProtocolVersion,
+    pub services: ServicesList,
+    pub user_agent: String,
+    pub start_height: u32
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Result of a crawl
+pub struct Snapshot {
+    /// Every peer that was visited, in the order visits completed
+    pub nodes: Vec<NodeInfo>,
+    /// Peers that were found at the depth limit and not visited
+    pub unvisited: Vec<Peer>
+}
+
+impl Snapshot {
+    /// Peers that completed the handshake
+    pub fn reachable(&self) -> impl Iterator<Item = &NodeInfo> {
+        self.nodes.iter().filter(|n| n.version.is_some())
+    }
+}
+
+/// Crawls the network from a set of starting peers
+pub struct Crawler {
+    magic: Magic,
+    options: CrawlOptions
+}
+
+// Progress of a crawl, shared between its workers
+struct Progress {
+    queue: VecDeque<(Peer, u32)>,
+    known: HashSet<Peer>,
+    // Workers currently visiting a peer
+    visiting: usize,
+    snapshot: Snapshot
+}
+
+impl Crawler {
+    pub fn new(magic: Magic) -> Self {
+        Self::with_options(magic, CrawlOptions::default())
+    }
+
+    pub fn with_options(magic: Magic, options: CrawlOptions) -> Self {
+        Self {
+            magic,
+            options
+        }
+    }
+
+    /// Crawl breadth first from `start`, blocking until every reachable peer within the
+    /// limits has been visited.
+    pub fn crawl(&self, start: &[Peer]) -> Snapshot {
+        let progress = Mutex::new(Progress {
+            queue: start.iter().map(|p| (*p, 0)).collect(),
+            known: start.iter().copied().collect(),
+            visiting: 0,
+            snapshot: Snapshot::default()
+        });
+        let changed = Condvar::new();
+
+        thread::scope(|s| {
+            for _ in 0..self.options.concurrency.max(1) {
+                s.spawn(|| self.work(&progress, &changed));
+            }
+        });
+
+        progress.into_inner().expect("Progress lock poisoned").snapshot
+    }
+
+    /// Visit queued peers until the queue is empty and no other worker can add to it
+    fn work(&self, progress: &Mutex<Progress>, changed: &Condvar) {
+        loop {
+            let (peer, depth) = {
+                let mut state = progress.lock().expect("Progress lock poisoned");
+                loop {
+                    if let Some(next) = state.queue.pop_front() {
+                        state.visiting += 1;
+                        break next
+                    }
+                    if state.visiting == 0 {
+                        return
+                    }
+                    state = changed.wait(state).expect("Progress lock poisoned");
+                }
+            };
+
+            let (version, found) = match self.visit(peer) {
+                Ok((version, found)) => (Some(version), found),
+                Err(_) => (None, vec![])
+            };
+
+            let mut state = progress.lock().expect("Progress lock poisoned");
+            state.visiting -= 1;
+            state.snapshot.nodes.push(NodeInfo { peer, depth, version, addresses: found.len() });
+            for found in found {
+                if state.known.len() >= self.options.max_peers {
+                    break
+                }
+                if !state.known.insert(found) {
+                    continue
+                }
+                match depth < self.options.max_depth {
+                    true => state.queue.push_back((found, depth + 1)),
+                    false => state.snapshot.unvisited.push(found)
+                }
+            }
+            changed.notify_all();
+        }
+    }
+
+    /// Connect to a peer and collect the addresses it answers getaddr with
+    fn visit(&self, peer: Peer) -> Result<(NodeVersion, Vec<Peer>), Error> {
+        let mut conn = Connection::connect_with(peer, self.magic, &self.options.stream)?;
+        let version = NodeVersion {
+            version: conn.peer_version().version,
+            services: conn.services().clone(),
+            user_agent: conn.peer_version().agent.as_str().to_string(),
+            start_height: conn.peer_version().start_height
+        };
+        conn.send(&Message::new(MessagePayload::EmptyPayload, self.magic, Command::GetAddr))?;
+
+        // Read timeouts end the wait instead of sending pings
+        conn.set_ping_interval(None);
+        conn.set_filter(Some(&[Command::Addr, Command::AddrV2]));
+        let deadline = Instant::now() + self.options.addr_timeout;
+        let mut found = vec![];
+        while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) {
+            conn.get_ref().set_read_timeout(Some(left))?;
+            let addrs: Vec<NetAddressV2> = match conn.recv() {
+                Ok(Message { payload: MessagePayload::AddrList(list), .. }) => list.into_iter().map(NetAddressV2::from).collect(),
+                Ok(Message { payload: MessagePayload::AddrV2List(list), .. }) => list,
+                Ok(_) | Err(Error::Misbehavior(_)) | Err(Error::Decode(_)) => continue,
+                Err(_) => break
+            };
+
+            // Peers announce their own address on its own, anything else is the getaddr reply
+            let reply = addrs.len() != 1;
+            found.extend(addrs.into_iter()
+                .filter_map(|a| Peer::try_from(a).ok())
+                .filter(|p| self.options.unroutable || p.addr.is_routable()));
+            if reply {
+                break
+            }
+        }
+
+        Ok((version, found))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        msg::network::VersionMessage
+    };
+    use std::net::{
+        Ipv4Addr,
+        TcpListener
+    };
+
+    // Serve crawlers, answering getaddr with the addresses of `known`
+    fn fake_node(known: Vec<Peer>) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let version = VersionMessage::builder(Address::me()).build();
+                let mut conn = match Connection::accept(stream, Magic::Regtest, version) {
+                    Ok(conn) => conn,
+                    Err(_) => continue
+                };
+                while let Ok(msg) = conn.recv() {
+                    if msg.header.command == Command::GetAddr {
+                        let addrs = known.iter()
+                            .map(|p| NetAddressV2::new(Duration::from_secs(1), ServicesList::default(), p.addr.into(), p.port.to_u16()))
+                            .collect();
+                        conn.send_payload(MessagePayload::AddrV2List(addrs), Command::AddrV2).unwrap();
+                    }
+                }
+            }
+        });
+
+        peer
+    }
+
+    #[test]
+    fn crawls_breadth_first() {
+        let dead = Peer::new(Ipv4Addr::LOCALHOST, TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port());
+        let far = fake_node(vec![]);
+        let near = fake_node(vec![far, dead]);
+        let start = fake_node(vec![near, dead]);
+
+        let options = CrawlOptions { concurrency: 2, max_depth: 1, addr_timeout: Duration::from_secs(5), unroutable: true, ..CrawlOptions::default() };
+        let snapshot = Crawler::with_options(Magic::Regtest, options.clone()).crawl(&[start]);
+        let depth = |p: Peer| snapshot.nodes.iter().find(|n| n.peer == p).map(|n| n.depth);
+        assert_eq!((depth(start), depth(near), depth(dead)), (Some(0), Some(1), Some(1)));
+        assert_eq!(snapshot.reachable().count(), 2);
+        assert_eq!(snapshot.nodes.iter().find(|n| n.peer == start).unwrap().addresses, 2);
+
+        // Peers beyond the depth limit are found but not visited
+        assert_eq!(snapshot.unvisited, vec![far]);
+
+        let snapshot = Crawler::with_options(Magic::Regtest, CrawlOptions { max_depth: 2, ..options.clone() }).crawl(&[start]);
+        assert_eq!(snapshot.nodes.len(), 4);
+        assert_eq!(snapshot.reachable().count(), 3);
+        assert!(snapshot.unvisited.is_empty());
+
+        // Nothing more is recorded once the peer limit is reached
+        let snapshot = Crawler::with_options(Magic::Regtest, CrawlOptions { max_depth: 2, max_peers: 2, ..options.clone() }).crawl(&[start]);
+        assert_eq!(snapshot.nodes.len(), 2);
+        assert_eq!(snapshot.nodes.iter().map(|n| n.peer).collect::<Vec<_>>(), vec![start, near]);
+        assert!(snapshot.unvisited.is_empty());
+
+        // Loopback addresses are dropped unless allowed
+        let snapshot = Crawler::with_options(Magic::Regtest, CrawlOptions { unroutable: false, ..options }).crawl(&[start]);
+        assert_eq!(snapshot.nodes.len(), 1);
+        assert_eq!(snapshot.nodes[0].addresses, 0);
+    }
+}
@@ -0,0 +1,170 @@
+// events.rs
+//
+// Module for receiving what happens on a connection as a stream of typed
+// events, without owning the connection's read loop.
+//
+
+use crate::{
+    msg::{
+        data::Message,
+        header::Command,
+        network::VersionMessage
+    },
+    net::{
+        connection::{
+            Connection,
+            Transport
+        },
+        misbehavior::Misbehavior,
+        Error
+    }
+};
+use std::{
+    collections::VecDeque,
+    io::{
+        Read,
+        Write
+    },
+    sync::mpsc::{
+        channel,
+        Receiver
+    },
+    thread
+};
+
+#[derive(Debug)]
+/// Something that happened on a connection
+pub enum PeerEvent {
+    /// The handshake has completed. Always the first event.
+    Connected {
+        peer_version: VersionMessage,
+        transport: Transport
+    },
+    /// A message was received from the peer
+    Message(Message),
+    /// A corrupt or undecodable message was skipped, or the peer broke the order of the
+    /// handshake, the connection remains open
+    Error(Error),
+    /// The connection failed or was closed by the peer. Always the last event.
+    Disconnected(Error)
+}
+
+impl<S: Read + Write + Send + 'static> Connection<S> {
+    /// Run the connection's read loop on its own thread and deliver what happens on it as events.
+    ///
+    /// Pings keep being sent while reading, see [`recv`](Self::recv). Take a
+    /// [`writer`](Self::writer) first to send messages. The thread stops once the connection
+    /// fails or the receiver is dropped.
+    pub fn events(mut self) -> Receiver<PeerEvent> {
+        let (sender, receiver) = channel();
+        let connected = PeerEvent::Connected {
+            peer_version: self.peer_version().clone(),
+            transport: self.transport()
+        };
+
+        // Handshake violations follow the connection they were made on
+        let mut violations: VecDeque<PeerEvent> = self.violations().iter()
+            .map(|v| PeerEvent::Error(Error::Misbehavior(Misbehavior::Handshake(v.clone()))))
+            .collect();
+
+        thread::spawn(move || {
+            let mut event = connected;
+            while sender.send(event).is_ok() {
+                if let Some(violation) = violations.pop_front() {
+                    event = violation;
+                    continue
+                }
+                event = match self.recv() {
+                    Ok(msg) => PeerEvent::Message(msg),
+                    Err(e @ Error::Misbehavior(_)) | Err(e @ Error::Decode(_)) => PeerEvent::Error(e),
+                    Err(e) => {
+                        let _ = sender.send(PeerEvent::Disconnected(e));
+                        return
+                    }
+                };
+            }
+        });
+
+        receiver
+    }
+
+    /// Deliver events like [`events`](Self::events), with only messages of the given
+    /// commands. Other messages are dropped cheaply as they are read, see
+    /// [`set_filter`](Self::set_filter).
+    pub fn events_filtered(mut self, commands: &[Command]) -> Receiver<PeerEvent> {
+        self.set_filter(Some(commands));
+        self.events()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        msg::{
+            data::MessagePayload,
+            header::{
+                Command,
+                Magic
+            }
+        }
+    };
+    use std::net::{
+        TcpListener,
+        TcpStream
+    };
+
+    #[test]
+    fn delivers_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let version = VersionMessage::builder(Address::me()).nonce(2).build();
+            Connection::accept(stream, Magic::Regtest, version).unwrap()
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).nonce(1).build();
+        let events = Connection::handshake(stream, Magic::Regtest, version).unwrap().events();
+        let mut peer = responder.join().unwrap();
+
+        assert!(matches!(events.recv().unwrap(), PeerEvent::Connected { transport: Transport::V1, .. }));
+        peer.send_payload(MessagePayload::PingPong(3), Command::Pong).unwrap();
+        match events.recv().unwrap() {
+            PeerEvent::Message(msg) => assert_eq!(msg.payload, MessagePayload::PingPong(3)),
+            event => panic!("Unexpected event {:?}", event)
+        }
+
+        drop(peer);
+        assert!(matches!(events.recv().unwrap(), PeerEvent::Disconnected(_)));
+        assert!(events.recv().is_err());
+    }
+
+    #[test]
+    fn filters_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let version = VersionMessage::builder(Address::me()).nonce(2).build();
+            Connection::accept(stream, Magic::Regtest, version).unwrap()
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).nonce(1).build();
+        let events = Connection::handshake(stream, Magic::Regtest, version).unwrap().events_filtered(&[Command::Ping]);
+        let mut peer = responder.join().unwrap();
+
+        peer.send_payload(MessagePayload::EmptyPayload, Command::SendHeaders).unwrap();
+        peer.send_payload(MessagePayload::PingPong(3), Command::Pong).unwrap();
+        peer.send_payload(MessagePayload::PingPong(4), Command::Ping).unwrap();
+        assert!(matches!(events.recv().unwrap(), PeerEvent::Connected { .. }));
+        match events.recv().unwrap() {
+            PeerEvent::Message(msg) => assert_eq!(msg.payload, MessagePayload::PingPong(4)),
+            event => panic!("Unexpected event {:?}", event)
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// eviction.rs
+//
+// Choice of a connection to drop to make room for another, modelled on bitcoin
+// core's SelectNodeToEvict: peers that are fast, recently useful or long connected
+// are protected, and of the rest the youngest peer of the most represented network
+// group is evicted so no single operator can take over the slots.
+//
+
+use crate::net::{
+    addrman::group,
+    peer::Peer
+};
+use std::{
+    collections::HashMap,
+    time::Duration
+};
+
+/// Peers with the lowest ping times protected from eviction
+pub const PROTECT_BY_LATENCY: usize = 8;
+
+/// Peers that most recently sent a message protected from eviction
+pub const PROTECT_BY_ACTIVITY: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What eviction is decided on for a connected peer
+pub struct EvictionCandidate {
+    pub peer: Peer,
+    /// Time the handshake completed, as a unix timestamp
+    pub connected: Duration,
+    /// Round trip time of the peer's last answered ping
+    pub latency: Option<Duration>,
+    /// Last time a message was received from the peer, as a unix timestamp
+    pub last_seen: Duration,
+    /// Misbehavior score of the peer
+    pub score: u32
+}
+
+/// Pick the peer to evict from `candidates`, or `None` if every candidate is protected.
+///
+/// Misbehaving peers are evicted first. Otherwise the [`PROTECT_BY_LATENCY`] fastest peers,
+/// the [`PROTECT_BY_ACTIVITY`] most recently active peers and half of those left with the
+/// longest uptime are protected, and the most recently connected peer of the network group
+/// with the most remaining peers is evicted.
+pub fn select_to_evict(mut candidates: Vec<EvictionCandidate>) -> Option<Peer> {
+    if let Some(worst) = candidates.iter().filter(|c| c.score > 0).max_by_key(|c| c.score) {
+        return Some(worst.peer)
+    }
+
+    // Peers without a measured ping sort last
+    candidates.sort_by_key(|c| (c.latency.is_none(), c.latency));
+    candidates.drain(..PROTECT_BY_LATENCY.min(candidates.len()));
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.last_seen));
+    candidates.drain(..PROTECT_BY_ACTIVITY.min(candidates.len()));
+    candidates.sort_by_key(|c| c.connected);
+    candidates.drain(..candidates.len() / 2);
+
+    let mut groups: HashMap<Vec<u8>, Vec<&EvictionCandidate>> = HashMap::new();
+    for candidate in candidates.iter() {
+        groups.entry(group(candidate.peer.addr)).or_default().push(candidate);
+    }
+    // Ties go to the group with the youngest peer, as in bitcoin core
+    let youngest = |group: &[&EvictionCandidate]| group.iter().map(|c| c.connected).max();
+    let largest = groups.values().max_by_key(|g| (g.len(), youngest(g)))?;
+    largest.iter().max_by_key(|c| c.connected).map(|c| c.peer)
+}
+
+/// Pick the least useful of `candidates` to make room for a better peer, without the
+/// protections of [`select_to_evict`]: the most misbehaving peer, then one that has not
+/// answered a ping, then the slowest, then the one quiet for longest.
+pub fn select_worst(candidates: &[EvictionCandidate]) -> Option<Peer> {
+    candidates
+        .iter()
+        .max_by_key(|c| (c.score, c.latency.is_none(), c.latency, std::cmp::Reverse(c.last_seen)))
+        .map(|c| c.peer)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn candidate(a: u8, b: u8, connected: u64) -> EvictionCandidate {
+        EvictionCandidate {
+            peer: Peer::new(Ipv4Addr::new(a, b, 0, 1), 8333),
+            connected: Duration::from_secs(connected),
+            latency: None,
+            last_seen: Duration::ZERO,
+            score: 0
+        }
+    }
+
+    #[test]
+    fn evicts_youngest_of_largest_group() {
+        // Too few peers to leave any unprotected
+        assert_eq!(select_to_evict((0..12).map(|i| candidate(i, 0, 100)).collect()), None);
+
+        // Fast and active peers are protected whatever their group
+        let mut candidates: Vec<EvictionCandidate> = (0..12).map(|i| {
+            let mut c = candidate(1, i, 100 + i as u64);
+            c.latency = Some(Duration::from_millis(10));
+            c.last_seen = Duration::from_secs(1000);
+            c
+        }).collect();
+        // Of the rest, the two youngest share a group
+        candidates.extend([candidate(2, 1, 10), candidate(3, 1, 20), candidate(4, 1, 30), candidate(5, 1, 40), candidate(5, 2, 50)]);
+        candidates.push(candidate(5, 2, 60));
+        assert_eq!(select_to_evict(candidates.clone()), Some(Peer::new(Ipv4Addr::new(5, 2, 0, 1), 8333)));
+
+        // Without protections the slowest peer is the worst
+        candidates[11].latency = Some(Duration::from_secs(1));
+        assert_eq!(select_worst(&candidates[..12]), Some(candidates[11].peer));
+
+        // Misbehaving peers go first
+        candidates[0].score = 20;
+        assert_eq!(select_worst(&candidates), Some(candidates[0].peer));
+        assert_eq!(select_to_evict(candidates), Some(Peer::new(Ipv4Addr::new(1, 0, 0, 1), 8333)));
+    }
+}
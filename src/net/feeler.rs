@@ -0,0 +1,67 @@
+// feeler.rs
+//
+// Short lived feeler connections, made like bitcoin core's to check that addresses
+// heard of but never connected to belong to reachable nodes: dial the address,
+// complete the handshake, note what the peer sent and disconnect.
+//
+
+use crate::{
+    msg::{
+        header::Magic,
+        network::{
+            ProtocolVersion,
+            ServicesList
+        }
+    },
+    net::{
+        connection::Connection,
+        nonce::NonceTracker,
+        peer::Peer,
+        stream::StreamOptions,
+        Error
+    }
+};
+use std::{
+    net::Shutdown,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Time between feeler connections (FEELER_INTERVAL in bitcoin core)
+pub const FEELER_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What a feeler connection learnt about a peer
+pub struct FeelerReport {
+    pub peer: Peer,
+    pub version: ProtocolVersion,
+    /// Services the peer advertised in its version message
+    pub services: ServicesList,
+    pub user_agent: String,
+    /// Height of the peer's best chain
+    pub start_height: u32,
+    /// Time from dialing the peer to completing the handshake
+    pub latency: Duration
+}
+
+/// Connect to a peer, complete the handshake and disconnect straight away.
+/// Self connections are detected using the nonces of all connections sharing `nonces`.
+pub fn feel(peer: Peer, magic: Magic, options: &StreamOptions, nonces: &NonceTracker) -> Result<FeelerReport, Error> {
+    let started = Instant::now();
+    let conn = Connection::connect_tracked(peer, magic, options, nonces)?;
+    let latency = started.elapsed();
+    // Nothing is sent after the handshake, the peer sees the connection close
+    let _ = conn.get_ref().shutdown(Shutdown::Both);
+
+    let version = conn.peer_version();
+    Ok(FeelerReport {
+        peer,
+        version: version.version,
+        services: version.service.clone(),
+        user_agent: version.agent.as_str().to_string(),
+        start_height: version.start_height,
+        latency
+    })
+}
@@ -0,0 +1,186 @@
+// geoip.rs
+//
+// Module for annotating peers with their country and autonomous system, looked
+// up in local MaxMind DB files such as GeoLite2-Country and GeoLite2-ASN, to
+// see how the nodes of a crawl are spread across the network.
+// Only compiled with the `geoip` feature.
+//
+
+use crate::net::{
+    peer::Peer,
+    peerdb::PeerRecord,
+    Error
+};
+use maxminddb::Reader;
+use serde::Deserialize;
+use std::{
+    net::IpAddr,
+    path::Path
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What the databases know about an address
+pub struct GeoInfo {
+    /// ISO 3166 code of the country
+    pub country: Option<String>,
+    /// Number of the autonomous system announcing the address
+    pub asn: Option<u32>,
+    /// Organization operating the autonomous system
+    pub as_org: Option<String>
+}
+
+// The fields used from country, city and ASN databases
+#[derive(Deserialize)]
+struct Record {
+    country: Option<Country>,
+    autonomous_system_number: Option<u32>,
+    autonomous_system_organization: Option<String>
+}
+
+#[derive(Deserialize)]
+struct Country {
+    iso_code: Option<String>
+}
+
+/// Looks up addresses in one or more MaxMind DB files
+pub struct GeoIp {
+    databases: Vec<Reader<Vec<u8>>>
+}
+
+impl GeoIp {
+    /// Open a database
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self { databases: vec![] }.with_database(path)
+    }
+
+    /// Also look up addresses in another database, such as an ASN database next to a country one.
+    /// Databases opened first take precedence where both have a field.
+    pub fn with_database<P: AsRef<Path>>(self, path: P) -> Result<Self, Error> {
+        self.with_bytes(std::fs::read(path)?)
+    }
+
+    /// Add a database already read into memory
+    pub fn with_bytes(mut self, bytes: Vec<u8>) -> Result<Self, Error> {
+        self.databases.push(Reader::from_source(bytes).map_err(|e| Error::GeoIp(e.to_string()))?);
+        Ok(self)
+    }
+
+    /// Look up an address. Fields the databases do not have, or addresses they do not cover, are `None`.
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let mut info = GeoInfo::default();
+        for record in self.databases.iter().filter_map(|db| db.lookup::<Record>(ip).ok()) {
+            info.country = info.country.or(record.country.and_then(|c| c.iso_code));
+            info.asn = info.asn.or(record.autonomous_system_number);
+            info.as_org = info.as_org.or(record.autonomous_system_organization);
+        }
+        info
+    }
+
+    /// Look up a peer's address. Tor and I2P peers have no location.
+    pub fn lookup_peer(&self, peer: &Peer) -> GeoInfo {
+        peer.socket_addr().map_or_else(GeoInfo::default, |addr| self.lookup(addr.ip()))
+    }
+
+    /// Fill in the location of exported peers
+    pub fn annotate(&self, records: &mut [PeerRecord]) {
+        for record in records {
+            if let Ok(peer) = record.peer() {
+                let info = self.lookup_peer(&peer);
+                record.country = info.country;
+                record.asn = info.asn;
+                record.as_org = info.as_org;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // Data section types, see https://maxmind.github.io/MaxMind-DB/
+    fn string(s: &str) -> Vec<u8> {
+        // Sizes from 29 take an extra byte
+        let size = match s.len() {
+            n if n < 29 => vec![0x40 | n as u8],
+            n => vec![0x40 | 29, (n - 29) as u8]
+        };
+        [&size[..], s.as_bytes()].concat()
+    }
+
+    // Type 5 is a uint16, 6 a uint32
+    fn uint(kind: u8, value: u32) -> Vec<u8> {
+        let bytes = &value.to_be_bytes()[if kind == 5 { 2 } else { 0 }..];
+        [&[kind << 5 | bytes.len() as u8][..], bytes].concat()
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![0xE0 | entries.len() as u8];
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend(value);
+        }
+        out
+    }
+
+    // IPv4 database with a single record for 1.0.0.0/8
+    fn database() -> Vec<u8> {
+        const NODES: u32 = 8;
+        let mut tree = vec![];
+        for node in 0..NODES {
+            // The first 7 bits of 1.x.x.x are zero and the 8th is one
+            let (left, right) = match node {
+                7 => (NODES, NODES + 16),
+                _ => (node + 1, NODES)
+            };
+            tree.extend(&left.to_be_bytes()[1..]);
+            tree.extend(&right.to_be_bytes()[1..]);
+        }
+        let data = map(&[
+            ("country", map(&[("iso_code", string("AU"))])),
+            ("autonomous_system_number", uint(6, 13335)),
+            ("autonomous_system_organization", string("Cloudflare"))
+        ]);
+        let metadata = map(&[
+            ("binary_format_major_version", uint(5, 2)),
+            ("binary_format_minor_version", uint(5, 0)),
+            ("build_epoch", vec![0x00, 0x02]),
+            ("database_type", string("Test")),
+            ("description", map(&[])),
+            ("ip_version", uint(5, 4)),
+            ("languages", vec![0x00, 0x04]),
+            ("node_count", uint(6, NODES)),
+            ("record_size", uint(5, 24))
+        ]);
+        [tree, vec![0; 16], data, b"\xAB\xCD\xEFMaxMind.com".to_vec(), metadata].concat()
+    }
+
+    #[test]
+    fn annotates_peers() {
+        let geoip = GeoIp { databases: vec![] }.with_bytes(database()).unwrap();
+        let info = geoip.lookup_peer(&Peer::new(Ipv4Addr::new(1, 1, 1, 1), 8333));
+        assert_eq!(info, GeoInfo {
+            country: Some(String::from("AU")),
+            asn: Some(13335),
+            as_org: Some(String::from("Cloudflare"))
+        });
+        assert_eq!(geoip.lookup(IpAddr::V4(Ipv4Addr::new(2, 1, 1, 1))), GeoInfo::default());
+
+        let mut records = vec![PeerRecord {
+            address: String::from("1.2.3.4"),
+            port: 8333,
+            services: 0,
+            user_agent: None,
+            last_seen: 0,
+            latency_ms: None,
+            country: None,
+            asn: None,
+            as_org: None
+        }];
+        geoip.annotate(&mut records);
+        assert_eq!((records[0].country.as_deref(), records[0].asn), (Some("AU"), Some(13335)));
+        assert!(matches!(GeoIp { databases: vec![] }.with_bytes(vec![1, 2, 3]), Err(Error::GeoIp(_))));
+    }
+}
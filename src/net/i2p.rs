@@ -0,0 +1,213 @@
+// i2p.rs
+//
+// Module for dialing I2P peers through the SAM v3.1 bridge of an I2P router.
+//   https://geti2p.net/en/docs/api/samv3
+//
+
+use crate::net::{
+    peer::{
+        Peer,
+        Host
+    },
+    Error
+};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    io::{
+        Read,
+        Write
+    },
+    net::{
+        SocketAddr,
+        TcpStream
+    },
+    time::Duration
+};
+
+/// Default address of the SAM bridge of a local I2P router
+pub const DEFAULT_SAM: &str = "127.0.0.1:7656";
+
+/// A transient SAM session through which I2P destinations can be dialed.
+///
+/// The session lives as long as its control connection to the router, so it must be
+/// kept alive for as long as streams opened through it are in use.
+pub struct SamSession {
+    sam: SocketAddr,
+    id: String,
+    timeout: Duration,
+    // Closing this connection ends the session and all its streams
+    _control: TcpStream
+}
+
+impl SamSession {
+    /// Create a session with a new transient destination on the SAM bridge at `sam`
+    pub fn create(sam: SocketAddr, timeout: Duration) -> Result<Self, Error> {
+        let mut control = hello(sam, timeout)?;
+        let id: String = format!("btcnetmsg-{:x}", rand::thread_rng().gen::<u32>());
+
+        // Signature type 7 is EdDSA_SHA512_Ed25519, as used by bitcoin core
+        command(&mut control, &format!("SESSION CREATE STYLE=STREAM ID={} DESTINATION=TRANSIENT SIGNATURE_TYPE=7", id))?;
+
+        Ok(Self {
+            sam,
+            id,
+            timeout,
+            _control: control
+        })
+    }
+
+    /// Open a stream to an I2P destination, given as a `.b32.i2p` name or a full base64 destination
+    pub fn connect(&self, destination: &str) -> Result<TcpStream, Error> {
+        let mut conn = hello(self.sam, self.timeout)?;
+
+        // Resolve b32 names to full destinations first, as not every router accepts them
+        let destination = match destination.ends_with(".i2p") {
+            true => command(&mut conn, &format!("NAMING LOOKUP NAME={}", destination))?
+                .remove("VALUE")
+                .ok_or_else(|| Error::Proxy(String::from("SAM lookup returned no destination")))?,
+            false => destination.to_string()
+        };
+        // Everything after the status line is the stream itself
+        command(&mut conn, &format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false", self.id, destination))?;
+        Ok(conn)
+    }
+
+    /// Open a stream to an I2P peer
+    pub fn connect_peer(&self, peer: &Peer) -> Result<TcpStream, Error> {
+        match peer.addr {
+            Host::I2p(_) => self.connect(&peer.addr.to_string()),
+            _ => Err(Error::Proxy(format!("{} is not an I2P peer", peer)))
+        }
+    }
+}
+
+/// Connect to the SAM bridge and negotiate the protocol version
+fn hello(sam: SocketAddr, timeout: Duration) -> Result<TcpStream, Error> {
+    let mut conn = match TcpStream::connect_timeout(&sam, timeout) {
+        Ok(x) => x,
+        Err(_) => return Err(Error::FailedToConnect(sam.to_string()))
+    };
+    conn.set_read_timeout(Some(timeout))?;
+    conn.set_write_timeout(Some(timeout))?;
+    command(&mut conn, "HELLO VERSION MIN=3.1 MAX=3.1")?;
+
+    // Streams must not time out once handed to the caller
+    conn.set_read_timeout(None)?;
+    Ok(conn)
+}
+
+/// Send a command and return the key/value pairs of the reply.
+/// Fails if the reply does not have `RESULT=OK`.
+fn command(conn: &mut TcpStream, cmd: &str) -> Result<HashMap<String, String>, Error> {
+    conn.write_all(format!("{}\n", cmd).as_bytes())?;
+    let line = read_line(conn)?;
+
+    let reply = parse_reply(&line);
+    match reply.get("RESULT").map(String::as_str) {
+        Some("OK") => Ok(reply),
+        Some(result) => Err(Error::Proxy(format!("SAM {} failed: {}", cmd.split(' ').next().unwrap_or(""), result))),
+        None => Err(Error::Proxy(format!("Unexpected SAM reply: {}", line)))
+    }
+}
+
+/// Read a reply line one byte at a time, so no bytes of a following stream are consumed
+fn read_line(conn: &mut TcpStream) -> Result<String, Error> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    loop {
+        conn.read_exact(&mut byte)?;
+        match byte[0] {
+            b'\n' => return Ok(String::from_utf8_lossy(&line).into_owned()),
+            b => line.push(b)
+        }
+    }
+}
+
+/// Parse the `KEY=VALUE` pairs of a SAM reply line
+fn parse_reply(line: &str) -> HashMap<String, String> {
+    line
+        .split_whitespace()
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            Some((kv.next()?.to_string(), kv.next()?.trim_matches('"').to_string()))
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{
+            BufRead,
+            BufReader
+        },
+        net::TcpListener,
+        thread
+    };
+
+    // Answer every command from the expected script in order, on any connection
+    fn fake_sam(script: Vec<(&'static str, &'static str)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut script = script.into_iter();
+            let mut conns = Vec::new();
+            while let Ok((stream, _)) = listener.accept() {
+                let mut conn = BufReader::new(stream);
+                loop {
+                    let (expected, reply) = match script.next() {
+                        Some(x) => x,
+                        None => {
+                            // The stream is open, greet the client through it
+                            conn.get_mut().write_all(b"hello").unwrap();
+                            return
+                        }
+                    };
+                    let mut line = String::new();
+                    conn.read_line(&mut line).unwrap();
+                    assert!(line.starts_with(expected), "{} != {}", line, expected);
+                    conn.get_mut().write_all(format!("{}\n", reply).as_bytes()).unwrap();
+                    if expected.starts_with("SESSION") {
+                        break // Keep the control connection and wait for the next one
+                    }
+                }
+                conns.push(conn);
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn connects_through_sam() {
+        let sam = fake_sam(vec![
+            ("HELLO VERSION", "HELLO REPLY RESULT=OK VERSION=3.1"),
+            ("SESSION CREATE STYLE=STREAM", "SESSION STATUS RESULT=OK DESTINATION=abc"),
+            ("HELLO VERSION", "HELLO REPLY RESULT=OK VERSION=3.1"),
+            ("NAMING LOOKUP NAME=", "NAMING REPLY RESULT=OK NAME=x VALUE=dest~"),
+            ("STREAM CONNECT ID=btcnetmsg-", "STREAM STATUS RESULT=OK")
+        ]);
+
+        let session = SamSession::create(sam, Duration::from_secs(5)).unwrap();
+        let peer = Peer::new(Host::I2p([7; 32]), 0);
+        let mut stream = session.connect_peer(&peer).unwrap();
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn sam_errors() {
+        let sam = fake_sam(vec![("HELLO VERSION", "HELLO REPLY RESULT=NOVERSION")]);
+        assert!(matches!(SamSession::create(sam, Duration::from_secs(5)), Err(Error::Proxy(_))));
+
+        let reply = parse_reply("NAMING REPLY RESULT=OK NAME=\"a b\" VALUE=xyz\n");
+        assert_eq!(reply["RESULT"], "OK");
+        assert_eq!(reply["VALUE"], "xyz");
+    }
+}
@@ -0,0 +1,1754 @@
+// manager.rs
+//
+// Module for maintaining connections with multiple peers at once.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Command,
+            Magic
+        },
+        network::{
+            NetAddress,
+            NetAddressV2,
+            ServicesList,
+            TimestampedNetAddress,
+            VersionMessage
+        }
+    },
+    address::{
+        AddrV2,
+        Address
+    },
+    net::{
+        addrman::{
+            AddrMan,
+            MAX_ADDR_TO_SEND,
+            MAX_PCT_ADDR_TO_SEND
+        },
+        anchors::{
+            self,
+            MAX_ANCHORS
+        },
+        misbehavior::{
+            BanList,
+            Misbehavior,
+            Violation,
+            BAN_THRESHOLD,
+            DEFAULT_BAN_TIME
+        },
+        peer::{
+            ConnectionDirection,
+            Peer,
+            PeerInfo,
+            Host
+        },
+        eviction::{
+            select_to_evict,
+            select_worst,
+            EvictionCandidate
+        },
+        feeler::{
+            self,
+            FeelerReport
+        },
+        ratelimit::RateLimit,
+        responder::Responder,
+        capture::Capture,
+        traffic::Traffic,
+        connection::{
+            require_version,
+            version_builder,
+            Connection,
+            ConnectionWriter,
+            SEND_QUEUE_CAPACITY
+        },
+        nonce::NonceTracker,
+        stream::StreamOptions,
+        timedata::TimeData,
+        Error
+    }
+};
+#[cfg(feature = "metrics")]
+use crate::net::metrics::{
+    self,
+    Snapshot
+};
+use rand::Rng;
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    io,
+    net::{
+        SocketAddr,
+        TcpListener,
+        TcpStream,
+        ToSocketAddrs
+    },
+    sync::{
+        mpsc::{
+            channel,
+            Receiver,
+            Sender
+        },
+        Arc,
+        Mutex
+    },
+    path::Path,
+    thread,
+    time::{
+        Duration,
+        Instant,
+        SystemTime
+    }
+};
+use tracing::{
+    debug,
+    debug_span,
+    info
+};
+
+// Time a listener waits between checks for new connections and for shutdown
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Inbound connections accepted before existing ones are evicted for new ones. Bitcoin
+/// core allows 125 connections, of which 11 are taken by outbound and feeler connections.
+pub const MAX_INBOUND: usize = 114;
+
+// Inbound connections served at once per inbound slot, leaving room for evicted and rejected
+// connections that are still closing. Past it new connections are dropped unread.
+const INBOUND_THREADS_PER_SLOT: usize = 2;
+
+/// Time between advertisements of our external address to each peer
+/// (AVG_LOCAL_ADDRESS_BROADCAST_INTERVAL in bitcoin core)
+pub const ADVERTISE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Time the advertising thread waits between checks for peers due an advertisement
+const ADVERTISE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Connections open this long reset a peer's reconnect attempts. Shorter ones count as an
+// attempt, so peers that drop straight after the handshake are given up on eventually.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a dropped or failed connection is retried before moving on to another peer
+pub struct ReconnectPolicy {
+    /// Attempts made to reconnect to a peer before it is given up on. Connections that
+    /// drop within a minute count as failed attempts.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every following attempt
+    pub base_delay: Duration,
+    /// Upper bound on the delay between attempts
+    pub max_delay: Duration
+}
+
+impl ReconnectPolicy {
+    /// Delay before the given retry attempt (starting at 1), with jitter.
+    /// The delay is drawn uniformly from the upper half of the backoff window.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let window = self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .map_or(self.max_delay, |d| d.min(self.max_delay));
+
+        window / 2 + window.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Connection slots taken and available in each direction
+pub struct Slots {
+    pub outbound: usize,
+    /// Outbound connections kept open, the manager's target
+    pub max_outbound: usize,
+    pub inbound: usize,
+    /// Inbound connections accepted before existing ones are evicted for new ones
+    pub max_inbound: usize
+}
+
+impl std::fmt::Display for Slots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "outbound {}/{}, inbound {}/{}", self.outbound, self.max_outbound, self.inbound, self.max_inbound)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    /// 3 attempts starting 1 second apart, with at most a minute between attempts
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60)
+        }
+    }
+}
+
+/// Maintains a target number of concurrent peer connections.
+///
+/// Each connection runs on its own thread which performs the handshake and forwards
+/// every received message, tagged with the peer it came from, to a single channel.
+/// When a connection fails or drops it is retried with exponential backoff according
+/// to the [`ReconnectPolicy`], after which it is replaced with another peer picked at random
+/// from the [`AddrMan`]. Addresses that peers send in addr and addrv2 messages are added to it.
+/// Pings, getheaders and getaddr are answered by a [`Responder`] so that peers keep the
+/// connections open, with inbound peers sent a sample of the [`AddrMan`] on getaddr.
+/// Without [`relay`](StreamOptions::relay), transactions and their announcements are dropped
+/// rather than delivered, see [`Connection::set_block_relay_only`]. Messages to each peer go through a send queue of
+/// [`SEND_QUEUE_CAPACITY`] messages, see [`Connection::start_send_queue`].
+///
+/// Peers that send corrupt or undecodable messages accumulate a [`Misbehavior`] score and
+/// are disconnected and banned once it reaches [`BAN_THRESHOLD`]. Banned hosts are not
+/// connected to, reconnected to or accepted until their ban expires.
+///
+/// Once [`MAX_INBOUND`] inbound peers are connected or completing their handshake, a new
+/// inbound peer takes the slot of one picked by [`select_to_evict`], or is turned away if
+/// every inbound peer is protected.
+///
+/// A manager built with [`ConnectionManagerBuilder::connect_only`] only ever dials the peers
+/// it was given, like bitcoin core's -connect.
+pub struct ConnectionManager {
+    inner: Arc<Inner>,
+    messages: Receiver<(Peer, Message)>
+}
+
+// State shared between the manager and its connection threads
+struct Inner {
+    magic: Magic,
+    target: usize,
+    max_inbound: usize,
+    // The only peers dialed, if set, with addresses learned from peers not kept
+    connect_only: Option<HashSet<Peer>>,
+    options: StreamOptions,
+    reconnect: ReconnectPolicy,
+    rate_limit: Option<RateLimit>,
+    capture: Option<Capture>,
+    nonces: NonceTracker,
+    // Taken on shutdown so the channel closes once the connection threads exit
+    sender: Mutex<Option<Sender<(Peer, Message)>>>,
+    state: Mutex<State>
+}
+
+struct State {
+    // Known addresses that connection candidates are drawn from
+    addrman: AddrMan,
+    // Peers connected to before any from the address manager, such as anchors and
+    // replacements, and the outbound peers that were connected once the manager is shut down
+    anchors: Vec<Peer>,
+    // Peers currently being connected or reconnected to
+    connecting: HashSet<Peer>,
+    // Peers a feeler connection is being made to, which do not count towards the target
+    feeling: HashSet<Peer>,
+    // Peers disconnected to make room for others, which are not reconnected to
+    evicted: HashSet<Peer>,
+    // Write halves of established connections, inbound and outbound
+    active: HashMap<Peer, ConnectionWriter>,
+    // Number of active connections that were accepted from a listener
+    inbound: usize,
+    // Inbound peers whose handshake is under way, holding an inbound slot until it completes
+    accepting: HashSet<Peer>,
+    // Threads serving inbound connections, from accepting them until they are closed
+    serving: usize,
+    // Round trip time of the last answered ping per peer
+    latency: HashMap<Peer, Duration>,
+    // Misbehavior score of each active peer
+    scores: HashMap<Peer, u32>,
+    // Version message received from each active peer
+    versions: HashMap<Peer, VersionMessage>,
+    // What is known about each active peer, updated as its messages arrive
+    info: HashMap<Peer, PeerInfo>,
+    // Active peers that sent sendaddrv2 and are sent addresses in addrv2 messages (BIP155)
+    addrv2: HashSet<Peer>,
+    // Last time our external address was advertised to each active peer
+    advertised: HashMap<Peer, Instant>,
+    // Clock offsets of outbound peers, for network adjusted time
+    time: TimeData,
+    bans: BanList,
+    // Messages exchanged on connections that have closed
+    closed: Traffic,
+    // Connections that failed during the handshake, inbound and outbound
+    handshake_failures: u64,
+    // Connections closed because the peer stopped answering pings or sending anything
+    stalled: u64,
+    // Set once the manager is shut down, no connections are made or accepted after
+    stopping: bool
+}
+
+/// Builder for a [`ConnectionManager`], configured before it makes any connection.
+///
+/// Options that are not set use the following defaults:
+/// * [`StreamOptions::default`]
+/// * [`MAX_INBOUND`] inbound connections
+/// * [`ReconnectPolicy::default`]
+/// * No rate limit and no capture
+/// * No bans and no anchors
+/// * Clock skew warnings beyond [`SKEW_WARNING`](crate::net::timedata::SKEW_WARNING)
+pub struct ConnectionManagerBuilder {
+    magic: Magic,
+    target: usize,
+    max_inbound: usize,
+    connect_only: Option<HashSet<Peer>>,
+    options: StreamOptions,
+    reconnect: ReconnectPolicy,
+    rate_limit: Option<RateLimit>,
+    capture: Option<Capture>,
+    addrman: AddrMan,
+    bans: BanList,
+    time: TimeData,
+    anchors: Vec<Peer>
+}
+
+impl ConnectionManagerBuilder {
+    /// Start building a manager that keeps `target` connections open using peers from `pool`
+    pub fn new(magic: Magic, target: usize, pool: Vec<Peer>) -> Self {
+        let mut addrman = AddrMan::new();
+        for peer in pool {
+            addrman.add(peer, ServicesList::default(), now(), None);
+        }
+
+        Self {
+            magic,
+            target,
+            max_inbound: MAX_INBOUND,
+            connect_only: None,
+            options: StreamOptions::default(),
+            reconnect: ReconnectPolicy::default(),
+            rate_limit: None,
+            capture: None,
+            addrman,
+            bans: BanList::new(),
+            time: TimeData::new(),
+            anchors: Vec::new()
+        }
+    }
+
+    /// Start building a manager that keeps a connection open to each of `peers` and no others.
+    ///
+    /// Peers that drop or cannot be reached are retried for as long as the manager runs,
+    /// with delays up to the [`ReconnectPolicy`]'s longest, rather than being replaced. Addresses peers
+    /// send are delivered but not added to the [`AddrMan`], no feeler connections are made
+    /// and anchors or replacements are only connected to if they are among `peers`.
+    pub fn connect_only(magic: Magic, peers: Vec<Peer>) -> Self {
+        let mut builder = Self::new(magic, peers.len(), peers.clone());
+        builder.connect_only = Some(peers.into_iter().collect());
+        builder
+    }
+
+    /// Set the timeouts and transport used for connections.
+    /// With `v2` set, outbound peers are tried over v2 first and inbound peers may use either.
+    pub fn with_stream_options(mut self, options: StreamOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Accept at most `max` inbound connections, [`MAX_INBOUND`] by default. Past it new
+    /// inbound peers take the slot of an evicted one, and none are accepted with `max` 0.
+    pub fn with_max_inbound(mut self, max: usize) -> Self {
+        self.max_inbound = max;
+        self
+    }
+
+    /// Set how dropped connections are retried
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Limit how fast messages are sent to each peer, see [`Connection::set_rate_limit`]
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Capture the messages exchanged with every peer after the handshake, see [`Connection::set_capture`]
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Draw peers from a previously saved address manager as well as the pool
+    pub fn with_addrman(mut self, mut addrman: AddrMan) -> Self {
+        for info in self.addrman.iter() {
+            addrman.add(info.peer, info.services.clone(), info.last_seen, None);
+        }
+        self.addrman = addrman;
+        self
+    }
+
+    /// Keep the bans of a previously saved ban list, see [`BanList::load`]
+    pub fn with_ban_list(mut self, bans: BanList) -> Self {
+        self.bans = bans;
+        self
+    }
+
+    /// Warn when the median clock offset of peers exceeds `threshold`, see [`TimeData::set_warning_threshold`]
+    pub fn with_clock_skew_warning(mut self, threshold: Duration) -> Self {
+        self.time.set_warning_threshold(threshold);
+        self
+    }
+
+    /// Connect to `anchors`, such as those saved by a previous run with
+    /// [`save_anchors`](ConnectionManager::save_anchors), before any other peers
+    pub fn with_anchors(mut self, anchors: Vec<Peer>) -> Self {
+        self.anchors = anchors;
+        self
+    }
+
+    /// Create the manager. No connections are made until it is [started](ConnectionManager::start).
+    pub fn build(self) -> ConnectionManager {
+        let (sender, messages) = channel();
+        ConnectionManager {
+            inner: Arc::new(Inner {
+                magic: self.magic,
+                target: self.target,
+                max_inbound: self.max_inbound,
+                connect_only: self.connect_only,
+                options: self.options,
+                reconnect: self.reconnect,
+                rate_limit: self.rate_limit,
+                capture: self.capture,
+                nonces: NonceTracker::new(),
+                sender: Mutex::new(Some(sender)),
+                state: Mutex::new(State {
+                    addrman: self.addrman,
+                    anchors: self.anchors,
+                    connecting: HashSet::new(),
+                    feeling: HashSet::new(),
+                    evicted: HashSet::new(),
+                    active: HashMap::new(),
+                    inbound: 0,
+                    accepting: HashSet::new(),
+                    serving: 0,
+                    latency: HashMap::new(),
+                    scores: HashMap::new(),
+                    versions: HashMap::new(),
+                    info: HashMap::new(),
+                    addrv2: HashSet::new(),
+                    advertised: HashMap::new(),
+                    time: self.time,
+                    bans: self.bans,
+                    closed: Traffic::new(),
+                    handshake_failures: 0,
+                    stalled: 0,
+                    stopping: false
+                })
+            }),
+            messages
+        }
+    }
+}
+
+impl ConnectionManager {
+    /// Create a manager that keeps `target` connections open using peers from `pool`,
+    /// with the defaults of [`ConnectionManagerBuilder`]
+    pub fn new(magic: Magic, target: usize, pool: Vec<Peer>) -> Self {
+        Self::builder(magic, target, pool).build()
+    }
+
+    /// Start building a manager that keeps `target` connections open using peers from `pool`
+    pub fn builder(magic: Magic, target: usize, pool: Vec<Peer>) -> ConnectionManagerBuilder {
+        ConnectionManagerBuilder::new(magic, target, pool)
+    }
+
+    /// Start connecting to peers until the target number of connections is reached
+    pub fn start(&self) {
+        Inner::fill(&self.inner);
+    }
+
+    /// Accept inbound connections on `addr` (bitcoin nodes listen on port 8333 on mainnet).
+    ///
+    /// Each accepted connection completes the responder side of the handshake on its own
+    /// thread and its messages are delivered through [`recv`](Self::recv) like those of
+    /// outbound peers. Connections are dropped without a thread while twice the
+    /// [inbound limit](ConnectionManagerBuilder::with_max_inbound) are being served, counting those still closing.
+    /// Inbound connections do not count towards the outbound target.
+    /// Returns the address the listener was bound to.
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> Result<SocketAddr, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        // Accepting is polled so the listener can be closed on shutdown
+        listener.set_nonblocking(true)?;
+
+        let inner = Arc::clone(&self.inner);
+        thread::spawn(move || {
+            while !inner.state.lock().expect("State lock poisoned").stopping {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let mut state = inner.state.lock().expect("State lock poisoned");
+                        if state.serving >= inner.max_inbound.max(1) * INBOUND_THREADS_PER_SLOT {
+                            debug!("Dropped inbound connection, too many being served");
+                            continue
+                        }
+                        state.serving += 1;
+                        drop(state);
+
+                        let inner = Arc::clone(&inner);
+                        thread::spawn(move || {
+                            Arc::clone(&inner).serve(stream);
+                            inner.state.lock().expect("State lock poisoned").serving -= 1;
+                        });
+                    },
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                    Err(_) => continue
+                }
+            }
+        });
+        Ok(local)
+    }
+
+    /// Make a feeler connection to an address that has never been connected to, see [`feeler::feel`].
+    ///
+    /// The address is moved to the tried table if the handshake completes, and the services,
+    /// user agent and protocol version the peer sent are recorded along with the time the
+    /// handshake took. Feelers do not count towards the outbound target and their messages
+    /// are not delivered. Returns `None` if there is no address to test.
+    pub fn feel(&self) -> Option<(Peer, Result<FeelerReport, Error>)> {
+        self.inner.feel()
+    }
+
+    /// Make a feeler connection every `interval` ([`FEELER_INTERVAL`](feeler::FEELER_INTERVAL)
+    /// in bitcoin core) until the manager is shut down or dropped
+    pub fn start_feelers(&self, interval: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match inner.upgrade() {
+                Some(inner) if !inner.state.lock().expect("State lock poisoned").stopping => inner.feel(),
+                _ => return
+            };
+        });
+    }
+
+    /// Advertise our [external address](StreamOptions::external) in an addr, or an addrv2 to
+    /// peers that sent sendaddrv2, to every connected peer it was not sent to within `interval`.
+    /// The address is timestamped with the network adjusted time so peers relay it on.
+    /// Returns the peers it was sent to, none if there is no external address.
+    pub fn advertise(&self, interval: Duration) -> Vec<Peer> {
+        self.inner.advertise(interval)
+    }
+
+    /// Advertise our external address to each peer soon after it connects and then every
+    /// `interval` ([`ADVERTISE_INTERVAL`] in bitcoin core), so that listening nodes can be
+    /// found by others. Does nothing without an external address.
+    pub fn start_advertising(&self, interval: Duration) {
+        if self.inner.options.external.is_none() {
+            return
+        }
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || loop {
+            thread::sleep(ADVERTISE_POLL_INTERVAL.min(interval));
+            match inner.upgrade() {
+                Some(inner) if !inner.state.lock().expect("State lock poisoned").stopping => inner.advertise(interval),
+                _ => return
+            };
+        });
+    }
+
+    /// Stop the manager, for example before the process exits.
+    ///
+    /// No more connections are made, retried or accepted and listeners are closed. Every open
+    /// connection is closed once the messages queued for it have been sent.
+    /// Messages already received can still be read, after which [`recv`](Self::recv) returns
+    /// `None` once every connection thread has exited. Known addresses and bans are kept so
+    /// they can be saved afterwards.
+    pub fn shutdown(&self) {
+        let mut state = self.inner.state.lock().expect("State lock poisoned");
+        if state.stopping {
+            return
+        }
+        info!(peers = state.active.len(), "Shutting down");
+        state.stopping = true;
+        state.anchors = state.outbound();
+        for writer in state.active.values() {
+            let _ = writer.shutdown_now();
+        }
+        drop(state);
+        self.inner.sender.lock().expect("Sender lock poisoned").take();
+    }
+
+    /// Add candidate peers to the pool
+    pub fn add_peers(&self, peers: &[Peer]) {
+        let mut state = self.inner.state.lock().expect("State lock poisoned");
+        for peer in peers {
+            state.addrman.add(*peer, ServicesList::default(), now(), None);
+        }
+        drop(state);
+        Inner::fill(&self.inner);
+    }
+
+    /// Save the known addresses so a later run can resume with [`AddrMan::load`]
+    pub fn save_addresses<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.inner.state.lock().expect("State lock poisoned").addrman.save(path)
+    }
+
+    /// Up to [`MAX_ANCHORS`] outbound peers that are connected, or were when the manager
+    /// was shut down
+    pub fn anchors(&self) -> Vec<Peer> {
+        let state = self.inner.state.lock().expect("State lock poisoned");
+        match state.stopping {
+            true => state.anchors.clone(),
+            false => state.outbound()
+        }
+    }
+
+    /// Save the [`anchors`](Self::anchors) so a later run can connect to them first,
+    /// see [`anchors::load`]
+    pub fn save_anchors<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        anchors::save(path, &self.anchors())
+    }
+
+    /// Save the current bans so a later run can keep them with [`BanList::load`]
+    pub fn save_bans<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.inner.state.lock().expect("State lock poisoned").bans.save(path)
+    }
+
+    /// Export the known addresses to a JSON or CSV file, see [`AddrMan::export`]
+    #[cfg(feature = "export")]
+    pub fn export_peers<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.inner.state.lock().expect("State lock poisoned").addrman.export(path)
+    }
+
+    /// Add the peers in a JSON or CSV file to the pool, returning how many were new
+    #[cfg(feature = "export")]
+    pub fn import_peers<P: AsRef<Path>>(&self, path: P) -> Result<usize, Error> {
+        let added = self.inner.state.lock().expect("State lock poisoned").addrman.import(path)?;
+        Inner::fill(&self.inner);
+        Ok(added)
+    }
+
+    /// Block until a message is received from any peer
+    pub fn recv(&self) -> Option<(Peer, Message)> {
+        self.messages.recv().ok()
+    }
+
+    /// Block until a message is received from any peer or `timeout` passes
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<(Peer, Message)> {
+        self.messages.recv_timeout(timeout).ok()
+    }
+
+    /// Return the next received message if one is waiting
+    pub fn try_recv(&self) -> Option<(Peer, Message)> {
+        self.messages.try_recv().ok()
+    }
+
+    /// Send a message to a connected peer without waiting on it. A peer whose send queue is
+    /// full cannot keep up and is disconnected instead.
+    pub fn send(&self, peer: &Peer, msg: &Message) -> Result<(), Error> {
+        let mut state = self.inner.state.lock().expect("State lock poisoned");
+        let sent = match state.active.get_mut(peer) {
+            Some(writer) => writer.try_send(msg),
+            None => return Err(Error::FailedToConnect(peer.to_string()))
+        };
+        if is_full(&sent) {
+            state.drop_slow(peer);
+        }
+        sent
+    }
+
+    /// Send a message to every connected peer, disconnecting those whose send queue is full
+    pub fn broadcast(&self, msg: &Message) {
+        let mut state = self.inner.state.lock().expect("State lock poisoned");
+        let mut slow = Vec::new();
+        for (peer, writer) in state.active.iter_mut() {
+            // Failed writes surface as a closed connection on the reading thread
+            if is_full(&writer.try_send(msg)) {
+                slow.push(*peer);
+            }
+        }
+        for peer in slow {
+            state.drop_slow(&peer);
+        }
+    }
+
+    /// Round trip time of the last ping answered by a peer
+    pub fn latency(&self, peer: &Peer) -> Option<Duration> {
+        self.inner.state.lock().expect("State lock poisoned").latency.get(peer).copied()
+    }
+
+    /// Version message received from a connected peer
+    pub fn peer_version(&self, peer: &Peer) -> Option<VersionMessage> {
+        self.inner.state.lock().expect("State lock poisoned").versions.get(peer).cloned()
+    }
+
+    /// Services, user agent, height and activity of a connected peer
+    pub fn peer_info(&self, peer: &Peer) -> Option<PeerInfo> {
+        self.inner.state.lock().expect("State lock poisoned").info.get(peer).cloned()
+    }
+
+    /// Info on every connected peer, inbound and outbound
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.inner.state.lock().expect("State lock poisoned").info.values().cloned().collect()
+    }
+
+    /// Local time adjusted by the median clock offset of outbound peers
+    pub fn network_time(&self) -> SystemTime {
+        self.inner.state.lock().expect("State lock poisoned").time.network_time()
+    }
+
+    /// Seconds added to the local clock to get [`network_time`](Self::network_time)
+    pub fn time_offset(&self) -> i64 {
+        self.inner.state.lock().expect("State lock poisoned").time.offset()
+    }
+
+    /// Median clock offset of outbound peers in seconds, if it is large enough that
+    /// the local clock is likely wrong
+    pub fn clock_skew(&self) -> Option<i64> {
+        self.inner.state.lock().expect("State lock poisoned").time.skew()
+    }
+
+    /// Messages sent to and received from a connected peer so far
+    pub fn traffic(&self, peer: &Peer) -> Option<Traffic> {
+        self.inner.state.lock().expect("State lock poisoned").active.get(peer).map(ConnectionWriter::traffic)
+    }
+
+    /// Messages sent and received over all connections, including those that have closed
+    pub fn total_traffic(&self) -> Traffic {
+        self.inner.state.lock().expect("State lock poisoned").total_traffic()
+    }
+
+    /// Established connections and the maximum allowed in each direction
+    pub fn slots(&self) -> Slots {
+        self.inner.slots()
+    }
+
+    /// Number of connections that reached the peer but failed during the handshake
+    pub fn handshake_failures(&self) -> u64 {
+        self.inner.state.lock().expect("State lock poisoned").handshake_failures
+    }
+
+    /// Number of connections closed because the peer stalled, by leaving a ping unanswered
+    /// for [`StreamOptions::ping_timeout`] or sending nothing for [`StreamOptions::inactivity_timeout`]
+    pub fn stalled(&self) -> u64 {
+        self.inner.state.lock().expect("State lock poisoned").stalled
+    }
+
+    /// Current values of the exported metrics
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Snapshot {
+        self.inner.metrics()
+    }
+
+    /// Serve Prometheus metrics at `http://addr/metrics` until the manager is dropped.
+    /// Returns the address the endpoint was bound to.
+    #[cfg(feature = "metrics")]
+    pub fn serve_metrics<A: ToSocketAddrs>(&self, addr: A) -> Result<SocketAddr, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || metrics::serve(listener, || inner.upgrade().map(|inner| inner.metrics())));
+        Ok(local)
+    }
+
+    /// Log a summary of the traffic of every connected peer at info level each `interval`,
+    /// until the manager is dropped
+    pub fn print_traffic(&self, interval: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let inner = match inner.upgrade() {
+                Some(inner) => inner,
+                None => return
+            };
+
+            let state = inner.state.lock().expect("State lock poisoned");
+            for (peer, writer) in state.active.iter() {
+                info!(peer = %peer.to_string(), "Traffic\n{}", writer.traffic());
+            }
+        });
+    }
+
+    /// Peers with an established connection
+    pub fn connected(&self) -> Vec<Peer> {
+        self.inner.state.lock().expect("State lock poisoned").active.keys().copied().collect()
+    }
+
+    /// Connect to `candidate` in place of the least useful outbound peer, see [`select_worst`].
+    /// The evicted peer is not reconnected to and is returned. If the outbound target has
+    /// not been reached no peer is evicted and the candidate takes a free slot.
+    pub fn replace(&self, candidate: Peer) -> Option<Peer> {
+        if !self.inner.may_dial(&candidate) {
+            return None
+        }
+        let mut state = self.inner.state.lock().expect("State lock poisoned");
+        if state.stopping || state.active.contains_key(&candidate) || state.connecting.contains(&candidate) {
+            return None
+        }
+        state.anchors.insert(0, candidate);
+
+        let outbound = state.outbound_len() + state.connecting.len();
+        let evicted = match outbound >= self.inner.target {
+            true => select_worst(&state.eviction_candidates(ConnectionDirection::Outbound)),
+            false => None
+        };
+        if let Some(peer) = evicted {
+            info!(peer = %peer.to_string(), candidate = %candidate.to_string(), "Evicting outbound peer");
+            state.evict(&peer);
+        }
+        drop(state);
+        Inner::fill(&self.inner);
+        evicted
+    }
+
+    /// Report misbehavior by a peer detected outside of the manager, such as an invalid block.
+    /// Returns true if the peer's score reached the threshold and it was banned.
+    pub fn report(&self, peer: &Peer, misbehavior: &Misbehavior) -> bool {
+        self.inner.misbehaving(*peer, misbehavior)
+    }
+
+    /// Misbehavior score of a connected peer
+    pub fn score(&self, peer: &Peer) -> u32 {
+        self.inner.state.lock().expect("State lock poisoned").scores.get(peer).copied().unwrap_or(0)
+    }
+
+    /// Ban a host for `duration`, disconnecting any connections to it
+    pub fn ban(&self, host: Host, duration: Duration) {
+        self.inner.state.lock().expect("State lock poisoned").ban(host, duration);
+    }
+
+    /// Lift the ban on a host, returning whether it was banned
+    pub fn unban(&self, host: &Host) -> bool {
+        self.inner.state.lock().expect("State lock poisoned").bans.unban(host)
+    }
+
+    /// Banned hosts and when their bans expire
+    pub fn banned(&self) -> Vec<(Host, SystemTime)> {
+        self.inner.state.lock().expect("State lock poisoned").bans.banned()
+    }
+}
+
+impl State {
+    // Connected peers in one direction that have not already been evicted
+    fn eviction_candidates(&self, direction: ConnectionDirection) -> Vec<EvictionCandidate> {
+        self.info
+            .values()
+            .filter(|i| i.direction == direction && !self.evicted.contains(&i.peer))
+            .map(|i| EvictionCandidate {
+                peer: i.peer,
+                connected: i.connected,
+                latency: self.latency.get(&i.peer).copied(),
+                last_seen: i.last_seen,
+                score: self.scores.get(&i.peer).copied().unwrap_or(0)
+            })
+            .collect()
+    }
+
+    // Close a connection without reconnecting to the peer
+    fn evict(&mut self, peer: &Peer) {
+        self.evicted.insert(*peer);
+        if let Some(writer) = self.active.get(peer) {
+            // The connection's thread cleans up once its read fails
+            let _ = writer.shutdown_now();
+        }
+    }
+
+    // Disconnect a peer whose send queue filled up rather than wait on it with the lock held
+    fn drop_slow(&mut self, peer: &Peer) {
+        info!(peer = %peer.to_string(), "Send queue full, disconnecting");
+        self.evict(peer);
+    }
+
+    // Number of established outbound connections
+    fn outbound_len(&self) -> usize {
+        self.active.len().saturating_sub(self.inbound)
+    }
+
+    // Connected outbound peers to keep as anchors, those connected the longest first
+    fn outbound(&self) -> Vec<Peer> {
+        let mut outbound: Vec<&PeerInfo> = self.info.values().filter(|i| i.direction == ConnectionDirection::Outbound).collect();
+        outbound.sort_by_key(|i| i.connected);
+        outbound.iter().take(MAX_ANCHORS).map(|i| i.peer).collect()
+    }
+
+    fn ban(&mut self, host: Host, duration: Duration) {
+        info!(host = %host, "Banned for {} s", duration.as_secs());
+        self.bans.ban(host, duration);
+        for (_, writer) in self.active.iter().filter(|(peer, _)| peer.addr == host) {
+            // The connection's thread cleans up once its read fails
+            let _ = writer.shutdown_now();
+        }
+    }
+
+    fn total_traffic(&self) -> Traffic {
+        let mut total = self.closed.clone();
+        for writer in self.active.values() {
+            total.add(&writer.traffic());
+        }
+        total
+    }
+
+    // Forget a connection that has closed
+    fn disconnected(&mut self, peer: &Peer) {
+        if let Some(writer) = self.active.remove(peer) {
+            self.closed.add(&writer.traffic());
+        }
+        self.latency.remove(peer);
+        self.scores.remove(peer);
+        self.versions.remove(peer);
+        self.info.remove(peer);
+        self.addrv2.remove(peer);
+        self.advertised.remove(peer);
+    }
+}
+
+impl Inner {
+    // Whether a peer may be connected to outside of feelers, always unless in connect only mode
+    fn may_dial(&self, peer: &Peer) -> bool {
+        self.connect_only.as_ref().is_none_or(|peers| peers.contains(peer))
+    }
+
+    fn slots(&self) -> Slots {
+        let state = self.state.lock().expect("State lock poisoned");
+        Slots {
+            outbound: state.outbound_len(),
+            max_outbound: self.target,
+            inbound: state.inbound,
+            max_inbound: self.max_inbound
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn metrics(&self) -> Snapshot {
+        let slots = self.slots();
+        let state = self.state.lock().expect("State lock poisoned");
+        Snapshot {
+            peers: state.active.len(),
+            inbound: state.inbound,
+            slots,
+            traffic: state.total_traffic(),
+            handshake_failures: state.handshake_failures,
+            stalled: state.stalled,
+            latency: state.latency.iter().map(|(peer, rtt)| (*peer, *rtt)).collect()
+        }
+    }
+
+    /// Start connection threads for peers from the pool until the target is met
+    fn fill(inner: &Arc<Inner>) {
+        let mut guard = inner.state.lock().expect("State lock poisoned");
+        let state = &mut *guard;
+        while !state.stopping && state.outbound_len() + state.connecting.len() < inner.target {
+            let usable = |p: &Peer| {
+                inner.may_dial(p) &&
+                !state.active.contains_key(p) && !state.connecting.contains(p) && !state.feeling.contains(p) &&
+                !state.bans.is_banned(&p.addr) && inner.options.family.allows(&p.addr) &&
+                state.addrman.get(p).and_then(|i| i.version).is_none_or(|v| v >= inner.options.min_version)
+            };
+            let peer = match state.anchors.iter().position(&usable) {
+                Some(anchor) => state.anchors.remove(anchor),
+                None => match state.addrman.select(usable) {
+                    Some(peer) => peer,
+                    None => break
+                }
+            };
+            state.connecting.insert(peer);
+
+            let inner = Arc::clone(inner);
+            thread::spawn(move || inner.run(peer));
+        }
+    }
+
+    /// Connection thread: connect, handshake and forward messages, reconnecting with
+    /// backoff when the connection fails until the peer is given up on
+    fn run(self: Arc<Self>, peer: Peer) {
+        let sender = self.sender.lock().expect("Sender lock poisoned").clone();
+
+        let mut attempt = 0;
+        while let Some(sender) = &sender {
+            let state = self.state.lock().expect("State lock poisoned");
+            if state.stopping || state.bans.is_banned(&peer.addr) {
+                break
+            }
+            drop(state);
+
+            match self.connect(peer) {
+                Ok(conn) => {
+                    let connected = Instant::now();
+                    let open = self.forward(peer, conn, sender);
+                    if connected.elapsed() >= STABLE_CONNECTION {
+                        attempt = 0;
+                    }
+
+                    debug!(peer = %peer.to_string(), "Disconnected");
+                    let mut state = self.state.lock().expect("State lock poisoned");
+                    state.disconnected(&peer);
+                    let evicted = state.evicted.remove(&peer);
+                    if !open || state.stopping {
+                        return
+                    }
+                    // Its slot goes to the peer it was evicted for
+                    if evicted {
+                        drop(state);
+                        Inner::fill(&self);
+                        return
+                    }
+                    // Hold on to the slot while reconnecting
+                    state.connecting.insert(peer);
+                },
+                Err(e) => {
+                    debug!(peer = %peer.to_string(), error = ?e, "Failed to connect");
+                    let mut state = self.state.lock().expect("State lock poisoned");
+                    state.addrman.attempt(&peer);
+                    if is_handshake_failure(&e) {
+                        state.handshake_failures += 1;
+                    }
+                    // Old peers will not have upgraded by the next attempt
+                    if let Error::ObsoleteVersion(version) = e {
+                        state.addrman.record_protocol_version(&peer, version);
+                        break
+                    }
+                }
+            }
+
+            attempt += 1;
+            // Peers are never given up on in connect only mode, there is no one to replace them with
+            if attempt > self.reconnect.max_attempts && self.connect_only.is_none() {
+                break
+            }
+            thread::sleep(self.reconnect.delay(attempt));
+        }
+
+        self.state.lock().expect("State lock poisoned").connecting.remove(&peer);
+        Inner::fill(&self);
+    }
+
+    /// Send our external address to peers it was not sent to within `interval`
+    fn advertise(&self, interval: Duration) -> Vec<Peer> {
+        let external = match self.options.external {
+            Some(external) => Peer::new(external.ip(), external.port()),
+            None => return vec![]
+        };
+        let mut guard = self.state.lock().expect("State lock poisoned");
+        let state = &mut *guard;
+        let timestamp = state.time.network_time().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let addr_v2 = NetAddressV2::new(timestamp, ServicesList::default(), AddrV2::from(external.addr), external.port.to_u16());
+        let addr_v1 = TimestampedNetAddress::new(timestamp, NetAddress::new(ServicesList::default(), Address::from(external.socket_addr().expect("External address is an IP address"))));
+        let addr = Message::new(MessagePayload::AddrList(vec![addr_v1]), self.magic, Command::Addr);
+        let addrv2 = Message::new(MessagePayload::AddrV2List(vec![addr_v2]), self.magic, Command::AddrV2);
+
+        let (mut sent, mut slow) = (Vec::new(), Vec::new());
+        for (peer, writer) in state.active.iter_mut() {
+            if state.advertised.get(peer).is_some_and(|t| t.elapsed() < interval) {
+                continue
+            }
+            let msg = match state.addrv2.contains(peer) {
+                true => &addrv2,
+                false => &addr
+            };
+            // Failed writes surface as a closed connection on the reading thread
+            match writer.try_send(msg) {
+                Ok(()) => {
+                    state.advertised.insert(*peer, Instant::now());
+                    sent.push(*peer);
+                },
+                full if is_full(&full) => slow.push(*peer),
+                Err(_) => {}
+            }
+        }
+        for peer in slow {
+            state.drop_slow(&peer);
+        }
+        if !sent.is_empty() {
+            debug!(peers = sent.len(), addr = %external.to_string(), "Advertised external address");
+        }
+        sent
+    }
+
+    /// Test an address from the new table with a feeler connection
+    fn feel(&self) -> Option<(Peer, Result<FeelerReport, Error>)> {
+        let mut guard = self.state.lock().expect("State lock poisoned");
+        let state = &mut *guard;
+        if state.stopping || self.connect_only.is_some() {
+            return None
+        }
+        let usable = |p: &Peer| {
+            state.addrman.get(p).is_some_and(|i| !i.tried) && !state.active.contains_key(p) &&
+            !state.connecting.contains(p) && !state.feeling.contains(p) &&
+            !state.bans.is_banned(&p.addr) && self.options.family.allows(&p.addr)
+        };
+        let peer = state.addrman.select(usable)?;
+        state.feeling.insert(peer);
+        state.addrman.attempt(&peer);
+        drop(guard);
+
+        let result = feeler::feel(peer, self.magic, &self.options, &self.nonces);
+        let mut state = self.state.lock().expect("State lock poisoned");
+        state.feeling.remove(&peer);
+        match &result {
+            Ok(report) => {
+                debug!(peer = %peer.to_string(), latency = ?report.latency, agent = %report.user_agent, "Feeler connected");
+                state.addrman.good(&peer);
+                state.addrman.record_version(&peer, &report.services, &report.user_agent);
+                state.addrman.record_protocol_version(&peer, report.version);
+                state.addrman.record_latency(&peer, report.latency);
+            },
+            Err(e) => {
+                debug!(peer = %peer.to_string(), error = ?e, "Feeler failed");
+                if let Error::ObsoleteVersion(version) = e {
+                    state.addrman.record_protocol_version(&peer, *version);
+                }
+            }
+        }
+        Some((peer, result))
+    }
+
+    /// Inbound connection thread: complete the handshake and forward messages until the
+    /// connection fails. Inbound peers are not reconnected to.
+    fn serve(self: Arc<Self>, stream: TcpStream) {
+        let sender = match self.sender.lock().expect("Sender lock poisoned").clone() {
+            Some(sender) => sender,
+            None => return
+        };
+        let peer = match stream.peer_addr() {
+            Ok(addr) => Peer::new(addr.ip(), addr.port()),
+            Err(_) => return
+        };
+        let mut state = self.state.lock().expect("State lock poisoned");
+        if state.bans.is_banned(&peer.addr) {
+            debug!(peer = %peer.to_string(), "Rejected inbound connection from banned host");
+            return
+        }
+        // Slots are reserved before the handshake so concurrent handshakes cannot overfill them
+        if state.inbound + state.accepting.len() >= self.max_inbound {
+            match select_to_evict(state.eviction_candidates(ConnectionDirection::Inbound)) {
+                Some(evicted) => {
+                    debug!(peer = %peer.to_string(), evicted = %evicted.to_string(), "Evicting inbound peer");
+                    state.evict(&evicted);
+                },
+                None => {
+                    debug!(peer = %peer.to_string(), "Rejected inbound connection, every inbound peer is protected");
+                    return
+                }
+            }
+        }
+        state.accepting.insert(peer);
+        drop(state);
+
+        let span = debug_span!("peer", peer = %peer.to_string());
+        let _enter = span.enter();
+        match self.accept(peer, stream) {
+            Ok(conn) => {
+                self.forward(peer, conn, &sender);
+                debug!("Disconnected");
+
+                let mut state = self.state.lock().expect("State lock poisoned");
+                state.disconnected(&peer);
+                state.evicted.remove(&peer);
+                state.inbound -= 1;
+            },
+            Err(e) => {
+                let mut state = self.state.lock().expect("State lock poisoned");
+                state.accepting.remove(&peer);
+                if is_handshake_failure(&e) {
+                    state.handshake_failures += 1;
+                }
+            }
+        }
+    }
+
+    /// Add to a peer's misbehavior score, banning it once the threshold is reached.
+    /// Returns true if the peer was banned.
+    fn misbehaving(&self, peer: Peer, misbehavior: &Misbehavior) -> bool {
+        let mut state = self.state.lock().expect("State lock poisoned");
+        let score = state.scores.entry(peer).or_insert(0);
+        *score += misbehavior.score();
+        debug!(peer = %peer.to_string(), score = *score, misbehavior = ?misbehavior, "Misbehavior");
+        if *score < BAN_THRESHOLD {
+            return false
+        }
+
+        state.ban(peer.addr, DEFAULT_BAN_TIME);
+        true
+    }
+
+    /// Score the handshake violations of a new connection, failing if they get the peer banned
+    fn check_violations(&self, peer: Peer, violations: &[Violation]) -> Result<(), Error> {
+        for violation in violations {
+            let misbehavior = Misbehavior::Handshake(violation.clone());
+            if self.misbehaving(peer, &misbehavior) {
+                return Err(Error::Misbehavior(misbehavior))
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward messages from a connection until it fails or the peer is banned.
+    /// Returns false if the manager has been dropped.
+    fn forward(&self, peer: Peer, mut conn: Connection<TcpStream>, sender: &Sender<(Peer, Message)>) -> bool {
+        loop {
+            // Corrupt messages are skipped, unless they get the peer banned
+            let msg = match conn.recv() {
+                Ok(msg) => msg,
+                Err(Error::Misbehavior(misbehavior)) if !self.misbehaving(peer, &misbehavior) => continue,
+                Err(Error::Decode(_)) if !self.misbehaving(peer, &Misbehavior::MalformedPayload) => continue,
+                // The slot is freed for another peer as for any other failure
+                Err(e @ Error::PingTimeout) | Err(e @ Error::Stalled) => {
+                    debug!(error = ?e, "Disconnecting stalled peer");
+                    self.state.lock().expect("State lock poisoned").stalled += 1;
+                    return true
+                },
+                Err(_) => return true
+            };
+
+            let mut state = self.state.lock().expect("State lock poisoned");
+            if let Some(info) = state.info.get_mut(&peer) {
+                info.seen();
+            }
+            if let Some(latency) = conn.latency() {
+                state.latency.insert(peer, latency);
+                state.addrman.record_latency(&peer, latency);
+            }
+            if msg.header.command == Command::SendAddrV2 {
+                state.addrv2.insert(peer);
+            }
+            drop(state);
+            let addrs = match &msg.payload {
+                MessagePayload::AddrList(list) => list.iter().cloned().map(NetAddressV2::from).collect(),
+                MessagePayload::AddrV2List(list) => list.clone(),
+                _ => vec![]
+            };
+            if !addrs.is_empty() && self.connect_only.is_none() {
+                self.state.lock().expect("State lock poisoned").addrman.add_received(&addrs, peer.addr);
+            }
+            if sender.send((peer, msg)).is_err() {
+                return false
+            }
+        }
+    }
+
+    /// Complete the responder handshake on an inbound stream and register its write half
+    fn accept(self: &Arc<Self>, peer: Peer, stream: TcpStream) -> Result<Connection<TcpStream>, Error> {
+        let version = version_builder(peer, stream.peer_addr().ok(), &self.options).build();
+
+        // Sockets accepted from a non-blocking listener are non-blocking on some platforms
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        let mut conn = match self.options.v2 {
+            true => Connection::accept_v2(stream, self.magic, version, &self.nonces)?,
+            false => Connection::accept_tracked(stream, self.magic, version, &self.nonces)?
+        };
+        require_version(conn.peer_version(), self.options.min_version)?;
+        self.check_violations(peer, conn.violations())?;
+        conn.set_ping_timeout(self.options.ping_timeout);
+        conn.set_inactivity_timeout(self.options.inactivity_timeout);
+        conn.set_rate_limit(self.rate_limit);
+        conn.set_capture(self.capture.clone().map(|c| (c, peer)));
+        conn.set_block_relay_only(!self.options.relay);
+        // Only inbound peers are sent addresses, as in bitcoin core, which makes it harder
+        // for outbound peers to learn what we know
+        let inner = Arc::downgrade(self);
+        conn.set_responder(Some(Responder::new().with_addresses(move || match inner.upgrade() {
+            Some(inner) => inner.state.lock().expect("State lock poisoned").addrman.get_addr(MAX_ADDR_TO_SEND, MAX_PCT_ADDR_TO_SEND),
+            None => vec![]
+        })));
+        conn.start_send_queue(SEND_QUEUE_CAPACITY)?;
+        conn.get_ref().set_read_timeout(Some(self.options.wake_interval()))?;
+        let writer = conn.writer()?;
+
+        let mut state = self.state.lock().expect("State lock poisoned");
+        // Completed after a shutdown, closed again for the thread to clean up
+        if state.stopping {
+            let _ = writer.shutdown_now();
+        }
+        state.active.insert(peer, writer);
+        state.versions.insert(peer, conn.peer_version().clone());
+        state.info.insert(peer, PeerInfo::new(peer, conn.peer_version(), ConnectionDirection::Inbound));
+        state.accepting.remove(&peer);
+        state.inbound += 1;
+        Ok(conn)
+    }
+
+    /// Connect to a peer, complete the handshake and register its write half. Peers known
+    /// to advertise `NODE_P2P_V2` are tried over the v2 transport first.
+    fn connect(&self, peer: Peer) -> Result<Connection<TcpStream>, Error> {
+        let services = self.state.lock().expect("State lock poisoned").addrman.get(&peer).map(|info| info.services.clone());
+        let options = match services {
+            Some(services) if !self.options.v2 => self.options.clone().for_services(&services),
+            _ => self.options.clone()
+        };
+        let mut conn = Connection::connect_tracked(peer, self.magic, &options, &self.nonces)?;
+        self.check_violations(peer, conn.violations())?;
+        conn.set_rate_limit(self.rate_limit);
+        conn.set_capture(self.capture.clone().map(|c| (c, peer)));
+        conn.set_block_relay_only(!self.options.relay);
+        conn.set_responder(Some(Responder::new()));
+        conn.start_send_queue(SEND_QUEUE_CAPACITY)?;
+        let writer = conn.writer()?;
+
+        let mut state = self.state.lock().expect("State lock poisoned");
+        if state.stopping {
+            let _ = writer.shutdown_now();
+        }
+        state.connecting.remove(&peer);
+        state.addrman.good(&peer);
+        state.addrman.record_version(&peer, conn.services(), conn.peer_version().agent.as_str());
+        state.addrman.record_protocol_version(&peer, conn.peer_version().version);
+        // Only outbound peers are sampled, inbound ones could be made to skew our time
+        state.time.add_version(peer.addr, conn.peer_version(), SystemTime::now());
+        state.active.insert(peer, writer);
+        state.versions.insert(peer, conn.peer_version().clone());
+        state.info.insert(peer, PeerInfo::new(peer, conn.peer_version(), ConnectionDirection::Outbound));
+        Ok(conn)
+    }
+}
+
+// Whether a connection attempt failed after reaching the peer, rather than while opening the stream
+fn is_handshake_failure(e: &Error) -> bool {
+    !matches!(e, Error::FailedToConnect(_) | Error::Proxy(_))
+}
+
+// Whether a send failed because the peer's send queue is full
+fn is_full(sent: &Result<(), Error>) -> bool {
+    matches!(sent, Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get time")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        encode::Encode,
+        msg::{
+            data::MessagePayload,
+            header::Command,
+            network::{
+                ProtocolVersion,
+                Service
+            }
+        },
+        net::{
+            connection::{
+                write_message,
+                Transport
+            },
+            reader::MessageReader
+        }
+    };
+    use std::{
+        io::{
+            Read,
+            Write
+        },
+        net::Ipv4Addr
+    };
+
+    // Accept one connection per nonce, complete the handshake and send a ping with the nonce.
+    // Every connection but the last is closed straight after the ping.
+    fn fake_peer(nonces: &[u64]) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let nonces = nonces.to_vec();
+
+        thread::spawn(move || {
+            for nonce in nonces.iter() {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = MessageReader::new(stream, Magic::Regtest);
+                reader.read_message().unwrap();
+
+                let version = VersionMessage::builder(Address::me()).version(ProtocolVersion::RELAY).build();
+                for msg in [
+                    Message::new(MessagePayload::Version(version), Magic::Regtest, Command::Version),
+                    Message::new(MessagePayload::EmptyPayload, Magic::Regtest, Command::Verack)
+                ] {
+                    write_message(reader.get_mut(), &msg).unwrap();
+                }
+                // Closing with the verack unread would reset the connection before the ping arrives
+                reader.read_message().unwrap();
+                write_message(reader.get_mut(), &Message::new(MessagePayload::PingPong(*nonce), Magic::Regtest, Command::Ping)).unwrap();
+
+                // Hold the last connection open until the manager hangs up
+                if Some(nonce) == nonces.last() {
+                    reader.map_while(Result::ok).count();
+                }
+            }
+        });
+
+        Peer::new(Ipv4Addr::LOCALHOST, port)
+    }
+
+    fn fast_retries(max_attempts: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50)
+        }
+    }
+
+    #[test]
+    fn replaces_failed_peers() {
+        // Nothing listens on the first peer, so its slot goes to the third
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            Peer::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port())
+        };
+        let (a, b) = (fake_peer(&[1]), fake_peer(&[2]));
+
+        let manager = ConnectionManager::builder(Magic::Regtest, 2, vec![dead, a, b])
+            .with_reconnect_policy(fast_retries(2))
+            .build();
+        manager.start();
+
+        let mut pings = HashMap::new();
+        for _ in 0..2 {
+            let (peer, msg) = manager.recv().unwrap();
+            pings.insert(peer, msg.payload);
+        }
+        assert_eq!(pings[&a], MessagePayload::PingPong(1));
+        assert_eq!(pings[&b], MessagePayload::PingPong(2));
+
+        let mut connected = manager.connected();
+        connected.sort_by_key(|p| p.port.to_u16());
+        let mut expected = vec![a, b];
+        expected.sort_by_key(|p| p.port.to_u16());
+        assert_eq!(connected, expected);
+    }
+
+    #[test]
+    fn reconnects_dropped_peers() {
+        let (a, b) = (fake_peer(&[1, 2]), fake_peer(&[3]));
+
+        // a drops the first connection and is reconnected rather than replaced by b
+        let manager = ConnectionManager::builder(Magic::Regtest, 1, vec![a])
+            .with_reconnect_policy(fast_retries(1))
+            .build();
+        manager.start();
+
+        assert_eq!(manager.recv().unwrap(), (a, Message::new(MessagePayload::PingPong(1), Magic::Regtest, Command::Ping)));
+        manager.add_peers(&[b]);
+        assert_eq!(manager.recv().unwrap(), (a, Message::new(MessagePayload::PingPong(2), Magic::Regtest, Command::Ping)));
+        assert_eq!(manager.connected(), vec![a]);
+    }
+
+    #[test]
+    fn disconnects_stalled_peers() {
+        // The peer sends nothing after its ping and does not answer ours
+        let peer = fake_peer(&[1]);
+        let options = StreamOptions { inactivity_timeout: Some(Duration::from_millis(200)), ..StreamOptions::default() };
+        let manager = ConnectionManager::builder(Magic::Regtest, 1, vec![peer])
+            .with_stream_options(options)
+            .with_reconnect_policy(fast_retries(1))
+            .build();
+        manager.start();
+        assert_eq!(manager.recv().unwrap().0, peer);
+
+        let started = Instant::now();
+        while !manager.connected().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "Stalled peer still connected");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(manager.stalled(), 1);
+    }
+
+    #[test]
+    fn disconnects_peers_that_fall_behind() {
+        // The peer completes the handshake and then stops reading
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = MessageReader::new(stream, Magic::Regtest);
+            reader.read_message().unwrap();
+            let version = VersionMessage::builder(Address::me()).version(ProtocolVersion::RELAY).build();
+            for msg in [
+                Message::new(MessagePayload::Version(version), Magic::Regtest, Command::Version),
+                Message::new(MessagePayload::EmptyPayload, Magic::Regtest, Command::Verack)
+            ] {
+                write_message(reader.get_mut(), &msg).unwrap();
+            }
+            reader.read_message().unwrap();
+            write_message(reader.get_mut(), &Message::new(MessagePayload::PingPong(1), Magic::Regtest, Command::Ping)).unwrap();
+            thread::sleep(Duration::from_secs(30));
+        });
+
+        let manager = ConnectionManager::builder(Magic::Regtest, 1, vec![peer])
+            .with_reconnect_policy(fast_retries(1))
+            .build();
+        manager.start();
+        assert_eq!(manager.recv().unwrap().0, peer);
+
+        let command = Command::Unknown("bulk".to_string());
+        let bulk = Message::new(MessagePayload::Raw { command: command.clone(), bytes: vec![0; 1 << 18] }, Magic::Regtest, command);
+        let started = Instant::now();
+        while manager.send(&peer, &bulk).is_ok() {
+            assert!(started.elapsed() < Duration::from_secs(10), "Sends to a peer that stopped reading never failed");
+        }
+        while !manager.connected().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(10), "Slow peer still connected");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let policy = ReconnectPolicy::default();
+        for (attempt, window) in [(1, 1), (2, 2), (3, 4), (8, 60), (100, 60)] {
+            let delay = policy.delay(attempt);
+            let window = Duration::from_secs(window);
+            assert!(delay >= window / 2 && delay <= window);
+        }
+    }
+
+    #[test]
+    fn accepts_inbound_peers() {
+        let manager = ConnectionManager::new(Magic::Regtest, 0, vec![]);
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let local = stream.local_addr().unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).start_height(120).build();
+        let mut conn = Connection::handshake(stream, Magic::Regtest, version).unwrap();
+        conn.send_payload(MessagePayload::PingPong(4), Command::Ping).unwrap();
+
+        let (peer, msg) = manager.recv().unwrap();
+        assert_eq!(peer.socket_addr(), Some(local));
+        assert_eq!(msg.payload, MessagePayload::PingPong(4));
+        assert_eq!(manager.connected(), vec![peer]);
+        assert_eq!(manager.traffic(&peer).unwrap().received[&Command::Ping].messages, 1);
+
+        let info = manager.peer_info(&peer).unwrap();
+        assert_eq!((info.direction, info.start_height), (ConnectionDirection::Inbound, 120));
+        assert!(info.last_seen > Duration::ZERO);
+        assert_eq!(manager.peers(), vec![info]);
+    }
+
+    #[test]
+    fn advertises_external_address() {
+        let external = SocketAddr::from(([203, 0, 113, 80], 18444));
+        let options = StreamOptions { external: Some(external), ..StreamOptions::default() };
+        let manager = ConnectionManager::builder(Magic::Regtest, 0, vec![]).with_stream_options(options).build();
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+
+        let mut conns = Vec::new();
+        for addrv2 in [false, true] {
+            let stream = TcpStream::connect(addr).unwrap();
+            let version = VersionMessage::builder(Address::from(addr)).build();
+            let mut conn = Connection::handshake(stream, Magic::Regtest, version).unwrap();
+            if addrv2 {
+                conn.send_payload(MessagePayload::EmptyPayload, Command::SendAddrV2).unwrap();
+            }
+            conn.send_payload(MessagePayload::PingPong(1), Command::Ping).unwrap();
+            while manager.recv().unwrap().1.header.command != Command::Ping {}
+            conns.push(conn);
+        }
+
+        // Each peer is sent the address once per interval
+        assert_eq!(manager.advertise(ADVERTISE_INTERVAL).len(), 2);
+        assert!(manager.advertise(ADVERTISE_INTERVAL).is_empty());
+        for (conn, command) in conns.iter_mut().zip([Command::Addr, Command::AddrV2]) {
+            let msg = std::iter::repeat_with(|| conn.recv().unwrap()).find(|m| m.header.command != Command::Pong).unwrap();
+            assert_eq!(msg.header.command, command);
+            let addrs: Vec<Peer> = match msg.payload {
+                MessagePayload::AddrList(list) => list.into_iter().map(|a| Peer::from(a.netaddress)).collect(),
+                MessagePayload::AddrV2List(list) => list.into_iter().map(|a| std::convert::TryFrom::try_from(a).unwrap()).collect(),
+                _ => panic!("Expected addresses")
+            };
+            assert_eq!(addrs, vec![Peer::new(external.ip(), external.port())]);
+        }
+
+        // Nothing is advertised without an external address
+        let manager = ConnectionManager::new(Magic::Regtest, 0, vec![]);
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = Connection::handshake(stream, Magic::Regtest, VersionMessage::builder(Address::from(addr)).build()).unwrap();
+        conn.send_payload(MessagePayload::PingPong(1), Command::Ping).unwrap();
+        manager.recv().unwrap();
+        assert!(manager.advertise(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn connects_only_to_given_peers() {
+        let (a, b) = (fake_peer(&[1]), fake_peer(&[2]));
+        // Anchors, added peers and replacements outside the list are not dialed
+        let manager = ConnectionManagerBuilder::connect_only(Magic::Regtest, vec![a]).with_anchors(vec![b]).build();
+        manager.start();
+
+        assert_eq!(manager.recv().unwrap().0, a);
+        manager.add_peers(&[b]);
+        assert_eq!(manager.replace(b), None);
+        assert!(manager.feel().is_none());
+        assert!(manager.recv_timeout(Duration::from_millis(200)).is_none());
+        assert_eq!(manager.connected(), vec![a]);
+    }
+
+    #[test]
+    fn connects_to_anchors_first() {
+        let (a, b) = (fake_peer(&[1]), fake_peer(&[2]));
+        let manager = ConnectionManager::builder(Magic::Regtest, 1, vec![a])
+            .with_anchors(vec![b])
+            .build();
+        manager.start();
+
+        assert_eq!(manager.recv().unwrap().0, b);
+        assert_eq!(manager.anchors(), vec![b]);
+        // Anchors are those connected at shutdown, not after the connections close
+        manager.shutdown();
+        while manager.recv().is_some() {}
+        assert_eq!(manager.anchors(), vec![b]);
+    }
+
+    #[test]
+    fn replaces_outbound_peers() {
+        let (a, b) = (fake_peer(&[1]), fake_peer(&[2]));
+        let manager = ConnectionManager::new(Magic::Regtest, 1, vec![a]);
+        manager.start();
+        assert_eq!(manager.recv().unwrap().0, a);
+
+        assert_eq!(manager.replace(b), Some(a));
+        assert_eq!(manager.recv().unwrap().0, b);
+        assert_eq!(manager.connected(), vec![b]);
+    }
+
+    #[test]
+    fn rejects_inbound_peers_when_full() {
+        let manager = ConnectionManager::builder(Magic::Regtest, 0, vec![]).with_max_inbound(1).build();
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+
+        let version = VersionMessage::builder(Address::from(addr)).build();
+        let _first = Connection::handshake(TcpStream::connect(addr).unwrap(), Magic::Regtest, version.clone()).unwrap();
+        while manager.connected().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Too few inbound peers for any to be left unprotected
+        assert!(Connection::handshake(TcpStream::connect(addr).unwrap(), Magic::Regtest, version).is_err());
+        assert_eq!(manager.slots(), Slots { outbound: 0, max_outbound: 0, inbound: 1, max_inbound: 1 });
+    }
+
+    #[test]
+    fn reserves_inbound_slots_during_handshakes() {
+        let manager = ConnectionManager::builder(Magic::Regtest, 0, vec![]).with_max_inbound(1).build();
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).build();
+
+        // A handshake that has not finished holds the only slot
+        let pending = TcpStream::connect(addr).unwrap();
+        while manager.inner.state.lock().unwrap().accepting.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(Connection::handshake(TcpStream::connect(addr).unwrap(), Magic::Regtest, version.clone()).is_err());
+
+        let _first = Connection::handshake(pending, Magic::Regtest, version).unwrap();
+        while manager.connected().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(manager.slots(), Slots { outbound: 0, max_outbound: 0, inbound: 1, max_inbound: 1 });
+        assert!(manager.inner.state.lock().unwrap().accepting.is_empty());
+    }
+
+    #[test]
+    fn drops_inbound_connections_beyond_thread_limit() {
+        let manager = ConnectionManager::builder(Magic::Regtest, 0, vec![]).with_max_inbound(1).build();
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+
+        // Stand in for connections still being served, so the next is closed unread
+        manager.inner.state.lock().unwrap().serving = INBOUND_THREADS_PER_SLOT;
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        assert!(manager.inner.state.lock().unwrap().accepting.is_empty());
+    }
+
+    #[test]
+    fn dials_v2_peers_over_v2() {
+        // The peer serves both transports, and is dialed over v2 once known to advertise it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        let (sender, transports) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (stream, sender) = (stream.unwrap(), sender.clone());
+                thread::spawn(move || {
+                    let version = VersionMessage::builder(Address::me()).version(ProtocolVersion::RELAY).build();
+                    let mut conn = Connection::accept_v2(stream, Magic::Regtest, version, &NonceTracker::new()).unwrap();
+                    sender.send(conn.transport()).unwrap();
+                    conn.send_payload(MessagePayload::PingPong(1), Command::Ping).unwrap();
+                    while conn.recv().is_ok() {}
+                });
+            }
+        });
+
+        let manager = ConnectionManager::new(Magic::Regtest, 1, vec![peer]);
+        manager.start();
+        assert_eq!(manager.recv().unwrap().0, peer);
+        assert_eq!(transports.recv().unwrap(), Transport::V1);
+        manager.shutdown();
+
+        let mut addrman = AddrMan::new();
+        let mut services = ServicesList::new();
+        services.add_flag(Service::P2PV2);
+        addrman.add(peer, services, now(), None);
+        let manager = ConnectionManager::builder(Magic::Regtest, 1, vec![]).with_addrman(addrman).build();
+        manager.start();
+        assert_eq!(manager.recv().unwrap().0, peer);
+        assert_eq!(transports.recv().unwrap(), Transport::V2);
+    }
+
+    #[test]
+    fn feels_new_addresses() {
+        let peer = fake_peer(&[1]);
+        let manager = ConnectionManager::new(Magic::Regtest, 0, vec![peer]);
+
+        let (felt, report) = manager.feel().unwrap();
+        let report = report.unwrap();
+        assert_eq!((felt, report.version), (peer, ProtocolVersion::RELAY));
+        assert!(manager.connected().is_empty());
+        assert!(manager.try_recv().is_none());
+
+        // The address is now tried and not felt again
+        assert!(manager.feel().is_none());
+    }
+
+    #[test]
+    fn shuts_down() {
+        let manager = ConnectionManager::builder(Magic::Regtest, 1, vec![fake_peer(&[1])])
+            .with_reconnect_policy(fast_retries(3))
+            .build();
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+        manager.start();
+        assert_eq!(manager.recv().unwrap().1.payload, MessagePayload::PingPong(1));
+
+        // The channel closes once the connection is, and the peer is not reconnected to
+        manager.shutdown();
+        assert_eq!(manager.recv(), None);
+        assert!(manager.connected().is_empty());
+        thread::sleep(ACCEPT_POLL_INTERVAL * 3);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn bans_misbehaving_peers() {
+        let manager = ConnectionManager::new(Magic::Regtest, 0, vec![]);
+        let addr = manager.listen("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).build();
+        let mut conn = Connection::handshake(stream.try_clone().unwrap(), Magic::Regtest, version).unwrap();
+
+        // Each ping with a broken checksum adds 20 to the score
+        let mut ping = vec![];
+        Message::new(MessagePayload::PingPong(1), Magic::Regtest, Command::Ping).net_encode(&mut ping);
+        ping[20] ^= 0xff;
+        for _ in 0..5 {
+            stream.write_all(&ping).unwrap();
+        }
+
+        assert!(conn.recv().is_err());
+        let banned = manager.banned();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].0, Host::Ipv4(Ipv4Addr::LOCALHOST));
+
+        // Banned hosts are turned away
+        let stream = TcpStream::connect(addr).unwrap();
+        let version = VersionMessage::builder(Address::from(addr)).build();
+        assert!(Connection::handshake(stream, Magic::Regtest, version).is_err());
+    }
+}
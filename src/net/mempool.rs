@@ -0,0 +1,257 @@
+// mempool.rs
+//
+// Module for watching transactions being relayed: requesting the transactions
+// peers announce with inv and reporting them as they arrive.
+//
+
+use crate::{
+    blockdata::Transaction,
+    msg::{
+        data::MessagePayload,
+        header::Command,
+        inventory::{
+            Inventory,
+            Txid
+        }
+    },
+    net::{
+        connection::Connection,
+        Error
+    }
+};
+use std::{
+    collections::{
+        HashMap,
+        VecDeque
+    },
+    fmt,
+    io::{
+        Read,
+        Write
+    }
+};
+
+/// Default number of transactions remembered by a monitor
+pub const DEFAULT_CAPACITY: usize = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A transaction received from a peer
+pub struct TxEntry {
+    pub txid: Txid,
+    /// Serialized size in bytes, including witness data
+    pub size: usize,
+    /// Virtual size, a quarter of the weight rounded up
+    pub vsize: usize,
+    /// Fee in satoshis, known only when every output spent was seen by the monitor
+    pub fee: Option<u64>
+}
+
+impl fmt::Display for TxEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:>7} B {:>7} vB", self.txid, self.size, self.vsize)?;
+        match self.fee {
+            Some(fee) => write!(f, " {:>10} sat {:>8.2} sat/vB", fee, fee as f64 / self.vsize as f64),
+            None => write!(f, " {:>10}", "fee ?")
+        }
+    }
+}
+
+/// Requests announced transactions and keeps track of those already seen.
+///
+/// Peers only announce transactions on connections whose version message set the relay
+/// flag, see [`StreamOptions::relay`](crate::net::stream::StreamOptions::relay).
+pub struct MempoolMonitor {
+    capacity: usize,
+    // Known transactions, `None` until requested ones arrive
+    known: HashMap<Txid, Option<Transaction>>,
+    // Known transactions, oldest first
+    order: VecDeque<Txid>
+}
+
+impl MempoolMonitor {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Remember up to `capacity` transactions, forgetting the oldest beyond that.
+    /// Fees can only be worked out for transactions spending remembered outputs.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            known: HashMap::new(),
+            order: VecDeque::new()
+        }
+    }
+
+    /// Inventory to request with getdata for the transactions in an inv that are not yet known.
+    /// Witness transactions are requested so sizes include witness data.
+    pub fn requests(&mut self, inv: &[Inventory]) -> Vec<Inventory> {
+        inv.iter()
+            .filter_map(|i| match i {
+                Inventory::Tx(txid) | Inventory::WitnessTx(txid) => Some(*txid),
+                _ => None
+            })
+            .filter(|txid| self.remember(*txid))
+            .map(Inventory::WitnessTx)
+            .collect()
+    }
+
+    /// Record a received transaction and describe it. Transactions whose output values
+    /// overflow a u64 are invalid and rejected, returning None without being recorded.
+    pub fn transaction(&mut self, tx: &Transaction) -> Option<TxEntry> {
+        let txid = tx.txid();
+        let sent = tx.output.iter().try_fold(0u64, |sum, o| sum.checked_add(o.value))?;
+        let spent = tx.input.iter()
+            .try_fold(0u64, |sum, i| {
+                let parent = self.known.get(&i.previous_output.txid)?.as_ref()?;
+                sum.checked_add(parent.output.get(i.previous_output.vout as usize)?.value)
+            });
+        let fee = spent.and_then(|s| s.checked_sub(sent));
+
+        self.remember(txid);
+        self.known.insert(txid, Some(tx.clone()));
+        Some(TxEntry {
+            txid,
+            size: tx.get_size(),
+            vsize: tx.get_weight().div_ceil(4),
+            fee
+        })
+    }
+
+    /// Transactions received and still remembered, such as to reconstruct compact blocks with
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.known.values().flatten()
+    }
+
+    /// Request the transactions a peer announces and pass each one received to `on_tx`,
+    /// until it returns false or the connection fails. Other messages are dropped.
+    pub fn watch<S: Read + Write>(&mut self, conn: &mut Connection<S>, mut on_tx: impl FnMut(&TxEntry) -> bool) -> Result<(), Error> {
+        conn.set_filter(Some(&[Command::Inv, Command::Tx]));
+        loop {
+            match conn.recv() {
+                Ok(msg) => match msg.payload {
+                    MessagePayload::InvVect(inv) => {
+                        let requests = self.requests(&inv);
+                        if !requests.is_empty() {
+                            conn.send_payload(MessagePayload::InvVect(requests), Command::GetData)?;
+                        }
+                    },
+                    MessagePayload::Transction(tx) => match self.transaction(&tx) {
+                        Some(entry) if !on_tx(&entry) => return Ok(()),
+                        _ => {}
+                    },
+                    _ => {}
+                },
+                Err(Error::Misbehavior(_)) | Err(Error::Decode(_)) => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// Add a transaction to the known set, returning false if it was already there
+    fn remember(&mut self, txid: Txid) -> bool {
+        if self.known.contains_key(&txid) {
+            return false
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.known.remove(&oldest);
+            }
+        }
+        self.known.insert(txid, None);
+        self.order.push_back(txid);
+        true
+    }
+}
+
+impl Default for MempoolMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::{
+            OutPoint,
+            TxIn,
+            TxOut
+        },
+        msg::{
+            header::Magic,
+            network::VersionMessage
+        }
+    };
+    use std::{
+        net::{
+            TcpListener,
+            TcpStream
+        },
+        thread
+    };
+
+    fn tx(spends: OutPoint, values: &[u64]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn { previous_output: spends, ..Default::default() }],
+            output: values.iter().map(|v| TxOut { value: *v, ..Default::default() }).collect()
+        }
+    }
+
+    #[test]
+    fn watches_relayed_transactions() {
+        let parent = tx(OutPoint::default(), &[5000, 3000]);
+        let child = tx(OutPoint::new(parent.txid(), 1), &[2500]);
+        let txs = [parent.clone(), child.clone()];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::accept(stream, Magic::Regtest, VersionMessage::builder(Address::me()).build()).unwrap();
+            let inv: Vec<Inventory> = txs.iter().map(|t| Inventory::Tx(t.txid())).collect();
+            conn.send_payload(MessagePayload::InvVect(inv.clone()), Command::Inv).unwrap();
+            conn.send_payload(MessagePayload::InvVect(inv), Command::Inv).unwrap();
+            while let Ok(msg) = conn.recv() {
+                if let MessagePayload::InvVect(requested) = msg.payload {
+                    assert_eq!(msg.header.command, Command::GetData);
+                    for tx in txs.iter().filter(|t| requested.contains(&Inventory::WitnessTx(t.txid()))) {
+                        conn.send_payload(MessagePayload::Transction(tx.clone()), Command::Tx).unwrap();
+                    }
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = Connection::handshake(stream, Magic::Regtest, VersionMessage::builder(Address::from(addr)).build()).unwrap();
+        let mut monitor = MempoolMonitor::new();
+        let mut seen = vec![];
+        monitor.watch(&mut conn, |entry| {
+            seen.push(*entry);
+            seen.len() < 2
+        }).unwrap();
+
+        // The repeated inv is not requested again, and the child's fee comes from its parent
+        assert_eq!(seen.iter().map(|e| e.txid).collect::<Vec<_>>(), vec![parent.txid(), child.txid()]);
+        assert_eq!((seen[0].fee, seen[1].fee), (None, Some(500)));
+        assert_eq!(seen[1].size, seen[1].vsize);
+        assert!(monitor.requests(&[Inventory::Tx(child.txid())]).is_empty());
+        assert_eq!(monitor.transactions().count(), 2);
+
+        // The oldest transactions are forgotten beyond the capacity
+        let mut small = MempoolMonitor::with_capacity(1);
+        small.transaction(&parent);
+        small.transaction(&child);
+        assert_eq!(small.requests(&[Inventory::Tx(child.txid()), Inventory::Tx(parent.txid())]), vec![Inventory::WitnessTx(parent.txid())]);
+
+        // Outputs summing beyond a u64 are rejected
+        let overflowing = tx(OutPoint::default(), &[u64::MAX, 1]);
+        assert!(monitor.transaction(&overflowing).is_none());
+        assert_eq!(monitor.requests(&[Inventory::Tx(overflowing.txid())]), vec![Inventory::WitnessTx(overflowing.txid())]);
+    }
+}
@@ -0,0 +1,210 @@
+// metrics.rs
+//
+// Module for exporting the state of a connection manager as Prometheus
+// metrics over HTTP, so long running instances can be monitored.
+//
+
+use crate::{
+    msg::header::Command,
+    net::{
+        manager::Slots,
+        peer::Peer,
+        traffic::{
+            Counter,
+            Traffic
+        },
+        Error
+    }
+};
+use std::{
+    fmt::Write as _,
+    io::{
+        Read,
+        Write
+    },
+    net::{
+        TcpListener,
+        TcpStream
+    },
+    time::Duration
+};
+
+// Longest request head read from a scraper
+const MAX_REQUEST: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Values exported on each scrape
+pub struct Snapshot {
+    /// Established connections, inbound and outbound
+    pub peers: usize,
+    /// Established connections that were accepted from a listener
+    pub inbound: usize,
+    /// Connections allowed in each direction, and those taken
+    pub slots: Slots,
+    /// Messages exchanged over all connections, including closed ones
+    pub traffic: Traffic,
+    /// Connections that failed during the handshake
+    pub handshake_failures: u64,
+    /// Connections closed because the peer stopped answering pings or sending anything
+    pub stalled: u64,
+    /// Round trip time of the last answered ping of each connected peer
+    pub latency: Vec<(Peer, Duration)>
+}
+
+impl Snapshot {
+    /// Render in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP btcnetmsg_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE btcnetmsg_{} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "btcnetmsg_{}{} {}", name, labels, value);
+            }
+        };
+
+        metric("peers", "gauge", "Established connections.", vec![
+            (String::from("{direction=\"inbound\"}"), self.inbound.to_string()),
+            (String::from("{direction=\"outbound\"}"), (self.peers - self.inbound.min(self.peers)).to_string())
+        ]);
+        metric("connection_slots", "gauge", "Connections allowed.", vec![
+            (String::from("{direction=\"inbound\"}"), self.slots.max_inbound.to_string()),
+            (String::from("{direction=\"outbound\"}"), self.slots.max_outbound.to_string())
+        ]);
+        metric("messages_total", "counter", "Messages exchanged per command.", traffic_samples(&self.traffic, |c| c.messages));
+        metric("bytes_total", "counter", "Bytes exchanged per command, including message headers.", traffic_samples(&self.traffic, |c| c.bytes));
+        metric("handshake_failures_total", "counter", "Connections that failed during the handshake.", vec![
+            (String::new(), self.handshake_failures.to_string())
+        ]);
+        metric("stalled_disconnects_total", "counter", "Connections closed because the peer stalled.", vec![
+            (String::new(), self.stalled.to_string())
+        ]);
+        metric("ping_rtt_seconds", "gauge", "Round trip time of the last answered ping.", self.latency.iter()
+            .map(|(peer, rtt)| (format!("{{peer=\"{}\"}}", escape(&peer.to_string())), rtt.as_secs_f64().to_string()))
+            .collect());
+        out
+    }
+}
+
+// Samples per direction and command, ordered by command
+fn traffic_samples(traffic: &Traffic, value: impl Fn(&Counter) -> u64) -> Vec<(String, String)> {
+    let mut samples = vec![];
+    for (direction, counters) in [("received", &traffic.received), ("sent", &traffic.sent)] {
+        let mut commands: Vec<(&Command, &Counter)> = counters.iter().collect();
+        commands.sort_by_key(|(c, _)| c.to_str());
+        samples.extend(commands.into_iter().map(|(command, counter)| (
+            format!("{{direction=\"{}\",command=\"{}\"}}", direction, escape(command.to_str())),
+            value(counter).to_string()
+        )));
+    }
+    samples
+}
+
+// Escape a label value, unknown commands can hold any characters
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Answer scrapes on a listener until `snapshot` returns `None`, which is checked when a
+/// request arrives. `GET /metrics` is answered with the rendered snapshot, anything else with 404.
+pub(crate) fn serve(listener: TcpListener, snapshot: impl Fn() -> Option<Snapshot>) {
+    for stream in listener.incoming().flatten() {
+        let snapshot = match snapshot() {
+            Some(snapshot) => snapshot,
+            None => return
+        };
+        // A broken scrape only affects that request
+        let _ = respond(stream, &snapshot);
+    }
+}
+
+fn respond(mut stream: TcpStream, snapshot: &Snapshot) -> Result<(), Error> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // Only the request line matters, read up to the end of the head
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => head.extend_from_slice(&buf[..n])
+        }
+    }
+
+    let request = String::from_utf8_lossy(&head);
+    let target: Vec<&str> = request.lines().next().unwrap_or_default().split_whitespace().take(2).collect();
+    let (status, body) = match target[..] {
+        ["GET", "/metrics"] => ("200 OK", snapshot.render()),
+        _ => ("404 Not Found", String::from("Not found\n"))
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        msg::{
+            data::{
+                Message,
+                MessagePayload
+            },
+            header::Magic
+        }
+    };
+    use std::{
+        net::Ipv4Addr,
+        thread
+    };
+
+    #[test]
+    fn serves_metrics() {
+        let mut traffic = Traffic::new();
+        traffic.record_received(&Message::new(MessagePayload::PingPong(1), Magic::Main, Command::Ping));
+        traffic.record_sent(&Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::Unknown(String::from("a\"b"))));
+        let snapshot = Snapshot {
+            peers: 3,
+            inbound: 1,
+            slots: Slots { outbound: 2, max_outbound: 8, inbound: 1, max_inbound: 114 },
+            traffic,
+            handshake_failures: 2,
+            stalled: 1,
+            latency: vec![(Peer::new(Ipv4Addr::new(10, 0, 0, 1), 8333), Duration::from_millis(250))]
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = snapshot.clone();
+        thread::spawn(move || serve(listener, || Some(served.clone())));
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, snapshot.render());
+        for line in &[
+            "btcnetmsg_peers{direction=\"outbound\"} 2",
+            "btcnetmsg_connection_slots{direction=\"inbound\"} 114",
+            "btcnetmsg_messages_total{direction=\"received\",command=\"ping\"} 1",
+            "btcnetmsg_bytes_total{direction=\"sent\",command=\"a\\\"b\"} 24",
+            "btcnetmsg_handshake_failures_total 2",
+            "btcnetmsg_stalled_disconnects_total 1",
+            "btcnetmsg_ping_rtt_seconds{peer=\"10.0.0.1:8333\"} 0.25"
+        ] {
+            assert!(body.lines().any(|l| l == *line), "missing {}", line);
+        }
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+    }
+}
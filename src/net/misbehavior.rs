@@ -0,0 +1,263 @@
+// misbehavior.rs
+//
+// Scoring of peers that break the protocol, and the list of hosts banned for it.
+// Modelled on bitcoin core's misbehavior tracking: every offence adds to a peer's
+// score and a peer reaching the threshold is disconnected and banned.
+//
+
+use crate::{
+    msg::{
+        header::Command,
+        VariableInteger
+    },
+    address::AddrV2,
+    encode::{
+        self,
+        Decode,
+        Encode
+    },
+    net::{
+        peer::Host,
+        Error
+    }
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    path::Path,
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+
+/// Score at which a peer is disconnected and banned (DISCOURAGEMENT_THRESHOLD in bitcoin core)
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// How long misbehaving peers are banned for (DEFAULT_MISBEHAVING_BANTIME in bitcoin core)
+pub const DEFAULT_BAN_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest ban, short enough for its expiry to fit the system clock of every platform
+pub const MAX_BAN_TIME: Duration = Duration::from_secs(1000 * 365 * 24 * 60 * 60);
+
+// Header of ban list files
+const FILE_MAGIC: [u8; 4] = *b"BANS";
+const FILE_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Ways a peer can break the protocol
+pub enum Misbehavior {
+    /// A message's checksum did not match its payload: `expected` is the checksum of the
+    /// payload and `received` the one in the header
+    InvalidChecksum {
+        expected: [u8; 4],
+        received: [u8; 4]
+    },
+    /// A message header announced a payload larger than the protocol allows
+    OversizedPayload(u32),
+    /// A payload could not be decoded for its command
+    MalformedPayload,
+    /// A message that is not allowed at this point of the connection
+    ProtocolViolation(String),
+    /// A message out of order in the version handshake
+    Handshake(Violation)
+}
+
+impl Misbehavior {
+    /// Score added to the peer for this offence
+    pub fn score(&self) -> u32 {
+        match self {
+            Self::InvalidChecksum { .. } => 20,
+            Self::OversizedPayload(_) => BAN_THRESHOLD,
+            Self::MalformedPayload => 20,
+            Self::ProtocolViolation(_) => 50,
+            // As bitcoin core used to score them, only persistent offenders are banned
+            Self::Handshake(_) => 1
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Ways a peer can break the order of the version handshake
+pub enum Violation {
+    /// A message other than the version, verack and the feature negotiation allowed
+    /// between them (wtxidrelay, sendaddrv2 and sendtxrcncl) before the handshake completed
+    MessageBeforeVerack(Command),
+    /// A verack before the peer's version message
+    VerackBeforeVersion,
+    /// A second version message
+    DuplicateVersion,
+    /// A verack after the handshake completed
+    DuplicateVerack
+}
+
+impl Violation {
+    /// The violation, if any, of receiving a message with `command` at this point of a
+    /// handshake where the peer's version and verack have or have not been received
+    pub fn check(command: &Command, version: bool, verack: bool) -> Option<Self> {
+        match (command, version, verack) {
+            (Command::Version, true, _) => Some(Self::DuplicateVersion),
+            (Command::Version, false, _) => None,
+            (Command::Verack, _, true) => Some(Self::DuplicateVerack),
+            (Command::Verack, false, _) => Some(Self::VerackBeforeVersion),
+            (Command::Verack, true, false) => None,
+            (Command::WTxIdRelay | Command::SendAddrV2 | Command::SendTxRcncl, true, false) => None,
+            (command, _, false) => Some(Self::MessageBeforeVerack(command.clone())),
+            (_, _, true) => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Hosts that may not be connected to until their ban expires.
+/// Bans apply to every port of a host.
+pub struct BanList {
+    bans: HashMap<Host, SystemTime>
+}
+
+impl BanList {
+    /// Create an empty ban list
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ban a host for `duration` from now, at most [`MAX_BAN_TIME`]. An existing longer ban is kept.
+    pub fn ban(&mut self, host: Host, duration: Duration) {
+        self.ban_until(host, SystemTime::now() + duration.min(MAX_BAN_TIME));
+    }
+
+    /// Ban a host until the given time. An existing longer ban is kept.
+    pub fn ban_until(&mut self, host: Host, until: SystemTime) {
+        let expiry = self.bans.entry(host).or_insert(until);
+        *expiry = (*expiry).max(until);
+    }
+
+    /// Lift the ban on a host, returning whether it was banned
+    pub fn unban(&mut self, host: &Host) -> bool {
+        self.bans.remove(host).is_some()
+    }
+
+    /// Check if a host is currently banned
+    pub fn is_banned(&self, host: &Host) -> bool {
+        self.bans.get(host).is_some_and(|until| *until > SystemTime::now())
+    }
+
+    /// Banned hosts and when their bans expire, dropping expired bans
+    pub fn banned(&mut self) -> Vec<(Host, SystemTime)> {
+        let now = SystemTime::now();
+        self.bans.retain(|_, until| *until > now);
+        self.bans.iter().map(|(host, until)| (*host, *until)).collect()
+    }
+
+    /// Load a ban list saved with [`save`](Self::save). Bans that have expired since are dropped,
+    /// as are bans expiring beyond what the system clock can represent.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let data = fs::read(path)?;
+        let mut r = &data[..];
+
+        let magic: [u8; 4] = Decode::net_decode(&mut r)?;
+        let version: u8 = Decode::net_decode(&mut r)?;
+        if magic != FILE_MAGIC || version != FILE_VERSION {
+            return Err(Error::Decode(encode::Error::InvalidData))
+        }
+
+        let mut bans = Self::new();
+        for _ in 0..VariableInteger::net_decode(&mut r)?.inner() {
+            let host = Host::try_from(AddrV2::net_decode(&mut r)?)?;
+            let until: Duration = Decode::net_decode(&mut r)?;
+            if let Some(until) = SystemTime::UNIX_EPOCH.checked_add(until) {
+                bans.ban_until(host, until);
+            }
+        }
+        bans.banned();
+        Ok(bans)
+    }
+
+    /// Save the bans that have not expired to a file, replacing it
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let banned = self.banned();
+        let mut data = Vec::new();
+        FILE_MAGIC.net_encode(&mut data);
+        FILE_VERSION.net_encode(&mut data);
+        VariableInteger(banned.len() as u64).net_encode(&mut data);
+        for (host, until) in banned {
+            AddrV2::from(host).net_encode(&mut data);
+            until.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().net_encode(&mut data);
+        }
+
+        super::write_atomic(path.as_ref(), &data)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::mock::TempFile;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn bans_expire() {
+        let (a, b) = (Host::from(Ipv4Addr::new(1, 2, 3, 4)), Host::from(Ipv4Addr::new(5, 6, 7, 8)));
+        let mut bans = BanList::new();
+        bans.ban(a, DEFAULT_BAN_TIME);
+        bans.ban_until(b, SystemTime::now() - Duration::from_secs(1));
+        assert!(bans.is_banned(&a));
+        assert!(!bans.is_banned(&b));
+        assert_eq!(bans.banned().len(), 1);
+
+        // Shorter bans do not cut an existing one short
+        bans.ban(a, Duration::from_secs(1));
+        assert!(bans.banned()[0].1 > SystemTime::now() + Duration::from_secs(60));
+        assert!(bans.unban(&a));
+        assert!(!bans.is_banned(&a));
+
+        // Bans too long for the clock are cut to the longest ban
+        bans.ban(b, Duration::MAX);
+        assert!(bans.is_banned(&b));
+    }
+
+    #[test]
+    fn checks_handshake_order() {
+        let check = |command, version, verack| Violation::check(&command, version, verack);
+        assert_eq!(check(Command::Version, false, false), None);
+        assert_eq!(check(Command::Verack, false, false), Some(Violation::VerackBeforeVersion));
+        assert_eq!(check(Command::SendAddrV2, true, false), None);
+        assert_eq!(check(Command::SendAddrV2, false, false), Some(Violation::MessageBeforeVerack(Command::SendAddrV2)));
+        assert_eq!(check(Command::Ping, true, false), Some(Violation::MessageBeforeVerack(Command::Ping)));
+        assert_eq!(check(Command::Verack, true, false), None);
+        assert_eq!(check(Command::Ping, true, true), None);
+        assert_eq!(check(Command::Version, true, true), Some(Violation::DuplicateVersion));
+        assert_eq!(check(Command::Verack, true, true), Some(Violation::DuplicateVerack));
+    }
+
+    #[test]
+    fn persists_to_disk() {
+        let path = TempFile::new("bans.dat");
+        let (a, b) = (Host::from(Ipv4Addr::new(1, 2, 3, 4)), Host::TorV3([7; 32]));
+        let mut bans = BanList::new();
+        bans.ban(a, DEFAULT_BAN_TIME);
+        bans.ban(b, Duration::from_secs(60));
+        bans.ban_until(Host::from(Ipv4Addr::new(5, 6, 7, 8)), SystemTime::now() - Duration::from_secs(1));
+        bans.save(&path).unwrap();
+
+        // Expiry times are kept to the second
+        let loaded = BanList::load(&path).unwrap();
+        assert!(loaded.is_banned(&a) && loaded.is_banned(&b));
+        assert_eq!(loaded.bans.len(), 2);
+        let expiry = loaded.bans[&a].duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(expiry, bans.bans[&a].duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs());
+
+        // Expiry times the clock cannot represent are skipped
+        let mut data = Vec::new();
+        FILE_MAGIC.net_encode(&mut data);
+        FILE_VERSION.net_encode(&mut data);
+        VariableInteger(1).net_encode(&mut data);
+        AddrV2::from(a).net_encode(&mut data);
+        u64::MAX.net_encode(&mut data);
+        std::fs::write(&path, data).unwrap();
+        let loaded = BanList::load(&path).unwrap();
+        assert!(loaded.bans.is_empty());
+    }
+}
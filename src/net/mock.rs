@@ -0,0 +1,301 @@
+// mock.rs
+//
+// In-process mock peer for tests: a TCP listener on localhost that runs the peer
+// side of the handshake and answers pings and getaddr like a node would, so
+// connections, the handshake and the connection manager can be tested without
+// touching the real network. Built for the crate's own tests and with the
+// `test-support` feature.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Magic,
+            Command
+        },
+        network::{
+            NetAddressV2,
+            VersionMessage,
+            ProtocolVersion
+        }
+    },
+    address::Address,
+    net::{
+        connection::write_message,
+        peer::Peer,
+        reader::MessageReader,
+        responder::Responder
+    }
+};
+use std::{
+    fs,
+    io,
+    net::{
+        Ipv4Addr,
+        Shutdown,
+        SocketAddr,
+        TcpListener,
+        TcpStream
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering
+        },
+        Arc,
+        Condvar,
+        Mutex
+    },
+    ops::Deref,
+    path::{
+        Path,
+        PathBuf
+    },
+    thread,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+#[derive(Debug, Clone)]
+/// Script of a mock peer, started with [`spawn`](Self::spawn).
+///
+/// Every connection is answered with the peer's version and a verack once the client's
+/// version arrives. Once the client's verack arrives the scripted messages are sent, then
+/// pings are answered with pongs and the first getaddr with the peer's addresses.
+pub struct MockPeer {
+    magic: Magic,
+    version: VersionMessage,
+    verack: bool,
+    pongs: bool,
+    addresses: Vec<NetAddressV2>,
+    messages: Vec<(MessagePayload, Command)>
+}
+
+impl MockPeer {
+    /// A peer on `magic` at [`ProtocolVersion::RELAY`] that answers everything
+    pub fn new(magic: Magic) -> Self {
+        Self {
+            magic,
+            version: VersionMessage::builder(Address::me()).version(ProtocolVersion::RELAY).build(),
+            verack: true,
+            pongs: true,
+            addresses: vec![],
+            messages: vec![]
+        }
+    }
+
+    /// Answer with `version` instead. Its nonce is changed if it matches the client's,
+    /// so the connection is never taken for a self connection.
+    pub fn with_version(mut self, version: VersionMessage) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Answer getaddr with `addresses`, in an addrv2 if the client sent sendaddrv2
+    pub fn with_addresses(mut self, addresses: Vec<NetAddressV2>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+
+    /// Send a message once the handshake completes, after those already scripted
+    pub fn with_message(mut self, payload: MessagePayload, command: Command) -> Self {
+        self.messages.push((payload, command));
+        self
+    }
+
+    /// Never send verack, leaving the client's handshake to time out
+    pub fn without_verack(mut self) -> Self {
+        self.verack = false;
+        self
+    }
+
+    /// Leave pings unanswered, as a stalled peer would
+    pub fn without_pongs(mut self) -> Self {
+        self.pongs = false;
+        self
+    }
+
+    /// Listen on a free port of localhost and serve every connection on its own thread
+    /// until the returned server is dropped
+    pub fn spawn(self) -> io::Result<MockServer> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+
+        let script = Arc::new(self);
+        let accepting = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.stopped.load(Ordering::SeqCst) {
+                    break
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue
+                };
+                if let Ok(clone) = stream.try_clone() {
+                    accepting.streams.lock().expect("Mock lock poisoned").push(clone);
+                }
+                let (script, shared) = (script.clone(), accepting.clone());
+                thread::spawn(move || script.serve(stream, &shared));
+            }
+        });
+
+        Ok(MockServer { addr, shared })
+    }
+
+    // Run the peer side of one connection until the client hangs up
+    fn serve(&self, stream: TcpStream, shared: &Shared) {
+        let mut reader = MessageReader::new(stream, self.magic);
+        let mut responder = Responder::new().with_addresses({
+            let addresses = self.addresses.clone();
+            move || addresses.clone()
+        });
+        shared.record(None);
+
+        while let Ok(msg) = reader.read_message() {
+            let mut replies = vec![];
+            match (&msg.header.command, &msg.payload) {
+                (Command::Version, MessagePayload::Version(theirs)) => {
+                    let mut version = self.version.clone();
+                    if version.nonce == theirs.nonce {
+                        version.nonce = version.nonce.wrapping_add(1);
+                    }
+                    replies.push((MessagePayload::Version(version), Command::Version));
+                    if self.verack {
+                        replies.push((MessagePayload::EmptyPayload, Command::Verack));
+                    }
+                },
+                (Command::Verack, _) => replies.extend(self.messages.iter().cloned()),
+                (Command::Ping, _) if !self.pongs => {},
+                _ => replies.extend(responder.reply(&msg))
+            }
+            shared.record(Some(msg));
+
+            for (payload, command) in replies {
+                if write_message(reader.get_mut(), &Message::new(payload, self.magic, command)).is_err() {
+                    return
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    record: Mutex<Record>,
+    changed: Condvar,
+    streams: Mutex<Vec<TcpStream>>,
+    stopped: AtomicBool
+}
+
+#[derive(Debug, Default)]
+struct Record {
+    connections: usize,
+    received: Vec<Message>
+}
+
+impl Shared {
+    // Note a new connection, or a message received on any connection
+    fn record(&self, msg: Option<Message>) {
+        let mut record = self.record.lock().expect("Mock lock poisoned");
+        match msg {
+            Some(msg) => record.received.push(msg),
+            None => record.connections += 1
+        }
+        self.changed.notify_all();
+    }
+}
+
+#[derive(Debug)]
+/// A running [`MockPeer`]. Dropping it stops accepting and closes every connection.
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>
+}
+
+impl MockServer {
+    /// Address the mock listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The mock as a peer to connect to
+    pub fn peer(&self) -> Peer {
+        Peer::new(self.addr.ip(), self.addr.port())
+    }
+
+    /// Number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.shared.record.lock().expect("Mock lock poisoned").connections
+    }
+
+    /// Every message received so far, from all connections in the order they arrived
+    pub fn received(&self) -> Vec<Message> {
+        self.shared.record.lock().expect("Mock lock poisoned").received.clone()
+    }
+
+    /// Wait up to `timeout` for a message with `command`, returning the first received
+    pub fn wait_for(&self, command: Command, timeout: Duration) -> Option<Message> {
+        let deadline = Instant::now() + timeout;
+        let mut record = self.shared.record.lock().expect("Mock lock poisoned");
+        loop {
+            if let Some(msg) = record.received.iter().find(|m| m.header.command == command) {
+                return Some(msg.clone())
+            }
+            let left = deadline.checked_duration_since(Instant::now())?;
+            record = self.shared.changed.wait_timeout(record, left).expect("Mock lock poisoned").0;
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // Wake the accepting thread so it sees it has stopped
+        let _ = TcpStream::connect(self.addr);
+        for stream in self.shared.streams.lock().expect("Mock lock poisoned").iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[derive(Debug)]
+/// File in the system's temporary directory for a test to write, removed when dropped.
+/// The path holds the process id, so concurrent test runs do not share files.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Path for a file named after `name`, such as `"bans.dat"`, removing any left over
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
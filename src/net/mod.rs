@@ -4,9 +4,81 @@
 //
 
 pub mod peer;
+pub mod addrman;
+pub mod anchors;
+pub mod blocks;
+pub mod broadcast;
+pub mod capture;
+pub mod compact;
 pub mod stream;
+pub mod socks;
+pub mod i2p;
+pub mod reader;
+pub mod connection;
+pub mod crawler;
+pub mod events;
+pub mod eviction;
+pub mod feeler;
+pub mod nonce;
+pub mod ping;
+pub mod propagation;
+pub mod ratelimit;
+pub mod replay;
+pub mod responder;
+pub mod traffic;
+pub mod v2;
+pub mod manager;
+pub mod mempool;
+pub mod misbehavior;
+pub mod sync;
+pub mod timedata;
+#[cfg(feature = "export")]
+pub mod peerdb;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock;
 
 #[derive(Debug)]
 pub enum Error {
     FailedToConnect(String),
-}
\ No newline at end of file
+    Handshake(String),
+    SelfConnection,
+    PingTimeout,
+    /// The peer sent nothing within the inactivity timeout
+    Stalled,
+    Proxy(String),
+    PeerDb(String),
+    NotFound(String),
+    Config(String),
+    /// The peer's protocol version is below the minimum accepted
+    ObsoleteVersion(crate::msg::network::ProtocolVersion),
+    GeoIp(String),
+    Misbehavior(misbehavior::Misbehavior),
+    Io(std::io::Error),
+    Decode(crate::encode::Error)
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<crate::encode::Error> for Error {
+    fn from(err: crate::encode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
+// Write a file through a temporary file next to it, so a crash does not leave it truncated
+pub(crate) fn write_atomic(path: &std::path::Path, data: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+// nonce.rs
+//
+// Module for tracking the nonces placed in our outgoing version messages.
+//
+
+use rand::Rng;
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc,
+        Mutex
+    }
+};
+
+#[derive(Clone, Debug, Default)]
+/// Shared set of nonces sent in version messages on open connections.
+///
+/// A peer's version message carrying one of these nonces means we connected to
+/// ourselves. Clones share the same set, so one tracker can be handed to every
+/// connection made by a node.
+pub struct NonceTracker(Arc<Mutex<HashSet<u64>>>);
+
+impl NonceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate a random nonce that is not in use and start tracking it
+    pub fn generate(&self) -> u64 {
+        let mut rng = rand::thread_rng();
+        let mut nonces = self.0.lock().expect("Nonce lock poisoned");
+        loop {
+            let nonce = rng.gen_range(0..u64::MAX);
+            if nonces.insert(nonce) {
+                return nonce
+            }
+        }
+    }
+
+    /// Start tracking `nonce`, or a freshly generated one if it is already in use, such as
+    /// when one version message is sent on several connections. Returns the tracked nonce.
+    pub fn claim(&self, nonce: u64) -> u64 {
+        let inserted = self.0.lock().expect("Nonce lock poisoned").insert(nonce);
+        match inserted {
+            true => nonce,
+            false => self.generate()
+        }
+    }
+
+    /// Start tracking a nonce
+    pub fn insert(&self, nonce: u64) {
+        self.0.lock().expect("Nonce lock poisoned").insert(nonce);
+    }
+
+    /// Stop tracking a nonce
+    pub fn remove(&self, nonce: u64) {
+        self.0.lock().expect("Nonce lock poisoned").remove(&nonce);
+    }
+
+    /// Check if a nonce was sent by us
+    pub fn contains(&self, nonce: u64) -> bool {
+        self.0.lock().expect("Nonce lock poisoned").contains(&nonce)
+    }
+}
@@ -4,48 +4,237 @@
 //
 
 use crate::{
-    msg::network::NetAddress
+    seeds::Seeds,
+    msg::{
+        header::Magic,
+        network::{
+            NetAddress,
+            NetAddressV2,
+            ServicesList,
+            VersionMessage
+        }
+    },
+    address::AddrV2,
+    encode
+};
+use crate::net::{
+    stream::StreamOptions,
+    Error
 };
-use crate::net::Error;
 use rayon::prelude::*;
-use std::net::{
-    Ipv4Addr,
-    TcpStream
+use std::{
+    convert::TryFrom,
+    str::FromStr,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+        TcpStream
+    },
+    time::{
+        Duration,
+        SystemTime
+    }
 };
+use tracing::debug;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Peer {
-    pub addr: Ipv4Addr,
+    pub addr: Host,
     pub port: Port
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// Address a peer can be dialed at
+pub enum Host {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// Tor v3 onion service, reachable through a SOCKS5 proxy
+    TorV3([u8; 32]),
+    /// I2P destination hash, reachable through an I2P SAM session
+    I2p([u8; 32])
+}
+
+impl From<Ipv4Addr> for Host {
+    fn from(ip: Ipv4Addr) -> Self {
+        Self::Ipv4(ip)
+    }
+}
+
+impl From<Ipv6Addr> for Host {
+    fn from(ip: Ipv6Addr) -> Self {
+        Self::Ipv6(ip)
+    }
+}
+
+impl From<IpAddr> for Host {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self::Ipv4(ip),
+            IpAddr::V6(ip) => Self::Ipv6(ip)
+        }
+    }
+}
+
+impl From<Host> for AddrV2 {
+    fn from(host: Host) -> Self {
+        match host {
+            Host::Ipv4(ip) => AddrV2::Ipv4(ip),
+            Host::Ipv6(ip) => AddrV2::Ipv6(ip),
+            Host::TorV3(key) => AddrV2::TorV3(key),
+            Host::I2p(hash) => AddrV2::I2p(hash)
+        }
+    }
+}
+
+impl TryFrom<AddrV2> for Host {
+    type Error = encode::Error;
+
+    /// Fails for networks that cannot be dialed
+    fn try_from(addr: AddrV2) -> Result<Self, Self::Error> {
+        match addr {
+            AddrV2::Ipv4(ip) => Ok(Self::Ipv4(ip)),
+            AddrV2::Ipv6(ip) => Ok(Self::Ipv6(ip)),
+            AddrV2::TorV3(key) => Ok(Self::TorV3(key)),
+            AddrV2::I2p(hash) => Ok(Self::I2p(hash)),
+            addr => Err(encode::Error::InvalidAddress(addr.to_string()))
+        }
+    }
+}
+
+impl Host {
+    /// Check if the address can be reached over the public internet, following bitcoin core's
+    /// `IsRoutable`. Private, loopback, link-local, shared, documentation, multicast and other
+    /// reserved ranges are not. Tor and I2P addresses always are.
+    pub fn is_routable(&self) -> bool {
+        match self {
+            Host::Ipv4(ip) => {
+                let [a, b, ..] = ip.octets();
+                !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() ||
+                    ip.is_broadcast() || ip.is_multicast() || ip.is_documentation() ||
+                    a == 0 ||                       // "This network", RFC1122
+                    (a == 100 && b & 0xc0 == 64) || // Shared address space, RFC6598
+                    (a == 198 && b & 0xfe == 18) || // Benchmarking, RFC2544
+                    a >= 240)                       // Reserved
+            },
+            Host::Ipv6(ip) => {
+                let s = ip.segments();
+                if let Some(v4) = ip.to_ipv4_mapped() {
+                    return Host::Ipv4(v4).is_routable()
+                }
+                !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() ||
+                    s[0] & 0xfe00 == 0xfc00 ||                // Unique local, RFC4193
+                    s[0] & 0xffc0 == 0xfe80 ||                // Link-local, RFC4862
+                    s[0] & 0xffc0 == 0xfec0 ||                // Deprecated site-local, RFC3879
+                    (s[0] == 0x2001 && s[1] == 0x0db8) ||     // Documentation, RFC3849
+                    (s[0] == 0x2001 && matches!(s[1] & 0xfff0, 0x10 | 0x20)) || // ORCHID, RFC4843 and RFC7343
+                    (s[0] == 0x64 && s[1] == 0xff9b && s[2] == 1))  // Local use NAT64, RFC8215
+            },
+            Host::TorV3(_) | Host::I2p(_) => true
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+/// Which IP address family to use for peers. Overlay network peers are always allowed.
+pub enum AddressFamily {
+    #[default]
+    Any,
+    /// Try IPv4 peers before IPv6 ones
+    PreferIpv4,
+    /// Try IPv6 peers before IPv4 ones
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only
+}
+
+impl AddressFamily {
+    /// Whether peers on `host` may be used
+    pub fn allows(&self, host: &Host) -> bool {
+        !matches!((self, host), (Self::Ipv4Only, Host::Ipv6(_)) | (Self::Ipv6Only, Host::Ipv4(_)))
+    }
+
+    /// Remove the peers that are not allowed and move the preferred family to the front,
+    /// otherwise keeping their order
+    pub fn apply(&self, mut peers: Vec<Peer>) -> Vec<Peer> {
+        peers.retain(|p| self.allows(&p.addr));
+        match self {
+            Self::PreferIpv4 => peers.sort_by_key(|p| !matches!(p.addr, Host::Ipv4(_))),
+            Self::PreferIpv6 => peers.sort_by_key(|p| !matches!(p.addr, Host::Ipv6(_))),
+            _ => {}
+        }
+
+        peers
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = Error;
+
+    /// Parse `any`, `prefer-ipv4`, `prefer-ipv6`, `ipv4` or `ipv6`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "any" => Ok(Self::Any),
+            "prefer-ipv4" => Ok(Self::PreferIpv4),
+            "prefer-ipv6" => Ok(Self::PreferIpv6),
+            "ipv4" => Ok(Self::Ipv4Only),
+            "ipv6" => Ok(Self::Ipv6Only),
+            _ => Err(Error::Config(format!("unknown address family {}", s)))
+        }
+    }
+}
+
+impl std::fmt::Display for Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", AddrV2::from(*self))
+    }
+}
+
 impl Peer {
-    /// Get a list of working peers
-    pub fn get(min: usize, peerlist: &[[u8; 6]]) -> Result<Vec<Self>, Error> {
-        // Get a list of potential peers from the seeds module
-        let mut ut_peers: Vec<UntestedPeer> = peerlist
-            .iter()
-            .map(|x| UntestedPeer::from(*x))
-            .collect::<Vec<UntestedPeer>>();
+    pub fn new<H: Into<Host>>(addr: H, port: u16) -> Self {
+        Self {
+            addr: addr.into(),
+            port: Port::from(port)
+        }
+    }
+
+    /// A peer on the port nodes of a network listen on by default, see [`Params`](crate::params::Params)
+    pub fn for_network<H: Into<Host>>(addr: H, magic: Magic) -> Self {
+        Self::new(addr, magic.params().port)
+    }
+
+    /// Parse `host:port` like [`FromStr`], or a host alone which is given the default port
+    /// of the network
+    pub fn parse_for_network(s: &str, magic: Magic) -> Result<Self, encode::Error> {
+        s.parse().or_else(|e| match s.contains(':') && !s.starts_with('[') {
+            // A bare IPv6 address
+            true => format!("[{}]:{}", s, magic.params().port).parse(),
+            false => format!("{}:{}", s, magic.params().port).parse()
+        }.map_err(|_| e))
+    }
+
+    /// Get a list of working peers.
+    /// The DNS seeds are resolved concurrently and their results are tested before the fixed seeds.
+    /// Tested peers are not remembered, see [`AddrMan`](crate::net::addrman::AddrMan) for
+    /// keeping addresses and their connection history between runs.
+    pub fn get(min: usize, seeds: &Seeds) -> Result<Vec<Self>, Error> {
+        // Get a list of potential peers from the seeds
+        let ut_peers: Vec<UntestedPeer> = seeds.candidates();
 
         // While the minium number of peers is not met and there
         // are peers to test remaining, paralell test if a peer
         // is active or not.
         let mut peers: Vec<Peer> = vec![];
-        while peers.len() < min || ut_peers.len() < min {
-            peers.extend_from_slice(
-                &ut_peers
+        for batch in ut_peers.chunks(num_cpus::get()) {                         // 1 peer per CPU core (Rayon spawns 1 thread per core.)
+            if peers.len() >= min { break }
+            peers.extend(
+                batch
                     .par_iter()                                            // Paralell test
-                    .take(num_cpus::get())                            // 1 peer per CPU core (Rayon spawns 1 thread per core.)
-                    .map(|x| {
-                        if x.test_conn() { return Some(*x) }                         // If peer works, save it as Some
-                        None                                                         // else dont save it
-                    })
-                    .filter(|x| x.is_some()) // Remove None peers from the list
-                    .map(|x| x.unwrap())
-                    .collect::<Vec<Peer>>()                                          // Collect working peers to push
+                    .filter(|x| x.test_conn())                       // Only save working peers
+                    .copied()
+                    .collect::<Vec<Peer>>()
             );
-            ut_peers.drain(0..min);                                            // Remove tested peers
         }
 
         // If the minimum amount of connections could not be made, return an error.
@@ -56,35 +245,126 @@ impl Peer {
         Ok(peers)
     }
     
+    /// Socket address of the peer, if it is reachable over IP
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self.addr {
+            Host::Ipv4(ip) => Some(SocketAddr::new(IpAddr::V4(ip), self.port.to_u16())),
+            Host::Ipv6(ip) => Some(SocketAddr::new(IpAddr::V6(ip), self.port.to_u16())),
+            Host::TorV3(_) | Host::I2p(_) => None
+        }
+    }
+
     /// Test if a peer is accepting TCP connections
     fn test_conn(&self) -> bool {
-        let peer: String = format!("{}:{}", self.addr.to_string(), self.port.to_u16());
+        let peer: String = self.to_string();
+        let connected = match self.socket_addr() {
+            Some(addr) => TcpStream::connect_timeout(&addr, StreamOptions::default().connect_timeout).is_ok(),
+            None => false
+        };
 
-        if let Ok(_) = TcpStream::connect(&peer) {
-            println!("Connection established to {}", peer);
+        if connected {
+            debug!(peer = %peer, "Connection established");
             return true
         }
 
-        println!("Failed to connect to {}", peer);
+        debug!(peer = %peer, "Failed to connect");
         false
     }
 }
 
 impl From<NetAddress> for Peer {
     fn from(netaddr: NetAddress) -> Peer {
-        Peer {
-            addr: match netaddr.address.ip() {
-                std::net::IpAddr::V4(x) => x,
-                std::net::IpAddr::V6(_) => panic!("Peer struct does not support IPv6")
+        Peer::new(netaddr.address.ip(), netaddr.address.port())
+    }
+}
+
+impl TryFrom<NetAddressV2> for Peer {
+    type Error = encode::Error;
+
+    /// Fails for addresses on networks that cannot be dialed
+    fn try_from(addr: NetAddressV2) -> Result<Self, Self::Error> {
+        Ok(Peer::new(Host::try_from(addr.addr)?, addr.port))
+    }
+}
+
+impl FromStr for Peer {
+    type Err = encode::Error;
+
+    /// Parse a `host:port` pair, such as an address given to connect to directly.
+    /// The host can be an IPv4 address, a bracketed IPv6 address like `[::1]`,
+    /// or a Tor v3 onion or I2P b32 address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || encode::Error::InvalidAddress(s.to_string());
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let host = match host.strip_prefix('[') {
+            Some(v6) => match v6.strip_suffix(']') {
+                Some(v6) if v6.contains(':') => v6,
+                _ => return Err(invalid())
             },
-            port: Port::from(netaddr.address.port())
+            None if host.contains(':') => return Err(invalid()),
+            None => host
+        };
+
+        Ok(Peer::new(Host::try_from(AddrV2::from_str(host)?)?, port))
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.addr {
+            Host::Ipv6(ip) => write!(f, "[{}]:{}", ip, self.port.to_u16()),
+            _ => write!(f, "{}:{}", self.addr, self.port.to_u16())
         }
     }
 }
 
-impl std::string::ToString for Peer {
-    fn to_string(&self) -> String {
-        format!("{}:{}", self.addr.to_string(), self.port.to_u16())
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// Which side opened a connection
+pub enum ConnectionDirection {
+    /// Accepted from a listener
+    Inbound,
+    /// Dialed by us
+    Outbound
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// What is known about a connected peer from its handshake and the messages since.
+/// Kept alongside the [`Peer`] rather than in it, so peers stay cheap to copy and hash.
+pub struct PeerInfo {
+    pub peer: Peer,
+    /// Services the peer advertised in its version message
+    pub services: ServicesList,
+    pub user_agent: String,
+    /// Height of the peer's best chain when it connected
+    pub start_height: u32,
+    /// Time the handshake with the peer completed, as a unix timestamp
+    pub connected: Duration,
+    /// Last time a message was received from the peer, as a unix timestamp
+    pub last_seen: Duration,
+    pub direction: ConnectionDirection
+}
+
+impl PeerInfo {
+    /// Info on a peer that has just completed the handshake by sending `version`
+    pub fn new(peer: Peer, version: &VersionMessage, direction: ConnectionDirection) -> Self {
+        let mut info = Self {
+            peer,
+            services: version.service.clone(),
+            user_agent: version.agent.as_str().to_string(),
+            start_height: version.start_height,
+            connected: Duration::ZERO,
+            last_seen: Duration::ZERO,
+            direction
+        };
+        info.seen();
+        info.connected = info.last_seen;
+        info
+    }
+
+    /// Record that a message was just received from the peer
+    pub fn seen(&mut self) {
+        self.last_seen = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
     }
 }
 
@@ -94,13 +374,13 @@ pub type UntestedPeer = Peer;
 impl From<[u8; 6]> for UntestedPeer {
     fn from(seed: [u8; 6]) -> Self {
         Self {
-            addr: Ipv4Addr::from([seed[0], seed[1], seed[2], seed[3]]),
+            addr: Host::Ipv4(Ipv4Addr::from([seed[0], seed[1], seed[2], seed[3]])),
             port: Port::from([seed[4], seed[5]])
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 /// TCP/IP Port stored as big endian bytes
 //  Hence the use of [u8; 2] instead of u16.
 pub struct Port(pub [u8; 2]);
@@ -121,4 +401,77 @@ impl Port {
     pub fn to_u16(&self) -> u16 {
         ((self.0[0] as u16) << 8) | (self.0[1] as u16)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn tests_seeded_peers() {
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let seeds = Seeds::for_network(crate::Magic::Regtest)
+            .with_dns(dead.to_string())
+            .with_fixed(Peer::new(Ipv4Addr::LOCALHOST, live.local_addr().unwrap().port()));
+
+        assert_eq!(Peer::get(1, &seeds).unwrap(), seeds.fixed);
+        assert!(Peer::get(2, &seeds).is_err());
+    }
+
+    #[test]
+    fn address_families() {
+        let (v4, v6, onion) = (
+            Peer::new(Ipv4Addr::LOCALHOST, 1),
+            Peer::new(Ipv6Addr::LOCALHOST, 2),
+            Peer::new(Host::TorV3([7; 32]), 3)
+        );
+        let peers = vec![v4, v6, onion];
+
+        assert_eq!(AddressFamily::Any.apply(peers.clone()), peers);
+        assert_eq!(AddressFamily::PreferIpv6.apply(peers.clone()), vec![v6, v4, onion]);
+        assert_eq!(AddressFamily::Ipv4Only.apply(peers.clone()), vec![v4, onion]);
+        assert_eq!(AddressFamily::Ipv6Only.apply(peers), vec![v6, onion]);
+    }
+
+    #[test]
+    fn routable_addresses() {
+        for routable in ["8.8.8.8", "100.128.0.1", "2001:4860::8888", "::ffff:1.1.1.1"] {
+            assert!(Host::from(routable.parse::<IpAddr>().unwrap()).is_routable(), "{}", routable);
+        }
+        for unroutable in [
+            "0.1.2.3", "10.0.0.1", "127.0.0.1", "169.254.1.1", "172.16.0.1", "192.168.1.1", "100.64.0.1",
+            "198.18.0.1", "203.0.113.5", "224.0.0.1", "255.255.255.255", "::", "::1", "fd00::1",
+            "fe80::1", "2001:db8::1", "2001:10::1", "::ffff:10.0.0.1"
+        ] {
+            assert!(!Host::from(unroutable.parse::<IpAddr>().unwrap()).is_routable(), "{}", unroutable);
+        }
+        assert!(Host::TorV3([7; 32]).is_routable());
+    }
+
+    #[test]
+    fn parses_peers() {
+        let peer: Peer = "127.0.0.1:18444".parse().unwrap();
+        assert_eq!(peer, Peer::new(Ipv4Addr::LOCALHOST, 18444));
+        assert_eq!(peer.to_string().parse::<Peer>().unwrap(), peer);
+
+        let onion = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333";
+        assert!(matches!(onion.parse::<Peer>().unwrap().addr, Host::TorV3(_)));
+
+        let v6: Peer = "[2001:db8::1]:8333".parse().unwrap();
+        assert_eq!(v6, Peer::new("2001:db8::1".parse::<Ipv6Addr>().unwrap(), 8333));
+        assert_eq!(v6.to_string(), "[2001:db8::1]:8333");
+
+        for invalid in ["127.0.0.1", "127.0.0.1:port", "127.0.0.1:70000", "::1:8333", "[127.0.0.1]:8333", "node:8333"] {
+            assert!(invalid.parse::<Peer>().is_err(), "{}", invalid);
+        }
+
+        // Hosts without a port are given the network's, explicit ports are kept
+        assert_eq!(Peer::parse_for_network("127.0.0.1", Magic::Signet).unwrap(), Peer::new(Ipv4Addr::LOCALHOST, 38333));
+        assert_eq!(Peer::parse_for_network("::1", Magic::Regtest).unwrap(), Peer::for_network(Ipv6Addr::LOCALHOST, Magic::Regtest));
+        assert_eq!(Peer::parse_for_network("[::1]:1234", Magic::Test).unwrap().port.to_u16(), 1234);
+        assert!(Peer::parse_for_network("127.0.0.1:port", Magic::Main).is_err());
+    }
+}
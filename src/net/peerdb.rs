@@ -0,0 +1,285 @@
+// peerdb.rs
+//
+// Export and import of the known-peer set as JSON or CSV, so crawl results can be
+// analyzed with other tools and used to seed later runs.
+// Only compiled with the `export` feature.
+//
+
+use crate::{
+    msg::network::ServicesList,
+    address::AddrV2,
+    net::{
+        addrman::{
+            AddrInfo,
+            AddrMan
+        },
+        crawler::{
+            NodeInfo,
+            Snapshot
+        },
+        peer::{
+            Peer,
+            Host
+        },
+        Error
+    }
+};
+use serde::{
+    Deserialize,
+    Serialize
+};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{
+        Read,
+        Write
+    },
+    path::Path,
+    str::FromStr,
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A known peer as written to an export file
+pub struct PeerRecord {
+    /// IPv4 or IPv6 address, `.onion` or `.b32.i2p` name
+    pub address: String,
+    pub port: u16,
+    /// Service flags as the bit field used on the wire
+    pub services: u64,
+    pub user_agent: Option<String>,
+    /// Unix timestamp of when the peer was last heard of
+    pub last_seen: u64,
+    pub latency_ms: Option<u64>,
+    /// Country code, autonomous system number and organization, filled in with the `geoip`
+    /// feature. Absent from files written without them.
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub asn: Option<u32>,
+    #[serde(default)]
+    pub as_org: Option<String>
+}
+
+impl PeerRecord {
+    /// Address the record refers to
+    pub fn peer(&self) -> Result<Peer, Error> {
+        let addr = AddrV2::from_str(&self.address)?;
+        Ok(Peer::new(Host::try_from(addr)?, self.port))
+    }
+}
+
+impl From<&AddrInfo> for PeerRecord {
+    fn from(info: &AddrInfo) -> Self {
+        Self {
+            address: info.peer.addr.to_string(),
+            port: info.peer.port.to_u16(),
+            services: info.services.bits(),
+            user_agent: info.user_agent.clone(),
+            last_seen: info.last_seen.as_secs(),
+            latency_ms: info.latency.map(|l| l.as_millis() as u64),
+            country: None,
+            asn: None,
+            as_org: None
+        }
+    }
+}
+
+impl From<&NodeInfo> for PeerRecord {
+    /// A visited peer, with the services and user agent of its version message if it was reachable
+    fn from(node: &NodeInfo) -> Self {
+        Self {
+            address: node.peer.addr.to_string(),
+            port: node.peer.port.to_u16(),
+            services: node.version.as_ref().map_or(0, |v| v.services.bits()),
+            user_agent: node.version.as_ref().map(|v| v.user_agent.clone()),
+            last_seen: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
+            latency_ms: None,
+            country: None,
+            asn: None,
+            as_org: None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// File format of an export
+pub enum Format {
+    /// A JSON array of records
+    Json,
+    /// CSV with a header row
+    Csv
+}
+
+impl Format {
+    /// Guess the format from a file extension
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        path.as_ref().extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(Error::PeerDb(format!("Unknown peer file format {}", s)))
+        }
+    }
+}
+
+/// Write records in the given format
+pub fn write_records<W: Write>(records: &[PeerRecord], format: Format, w: W) -> Result<(), Error> {
+    match format {
+        Format::Json => serde_json::to_writer_pretty(w, records).map_err(|e| Error::PeerDb(e.to_string())),
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(w);
+            for record in records {
+                writer.serialize(record).map_err(|e| Error::PeerDb(e.to_string()))?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// Write records to a file, in the format given by its extension
+pub fn write_file<P: AsRef<Path>>(records: &[PeerRecord], path: P) -> Result<(), Error> {
+    let format = format_of(path.as_ref())?;
+    write_records(records, format, File::create(path)?)
+}
+
+/// Read records in the given format
+pub fn read_records<R: Read>(format: Format, r: R) -> Result<Vec<PeerRecord>, Error> {
+    match format {
+        Format::Json => serde_json::from_reader(r).map_err(|e| Error::PeerDb(e.to_string())),
+        Format::Csv => csv::Reader::from_reader(r)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|e| Error::PeerDb(e.to_string()))
+    }
+}
+
+impl Snapshot {
+    /// Records for the reachable peers of a crawl, seen at the time of the export
+    pub fn records(&self) -> Vec<PeerRecord> {
+        self.reachable().map(PeerRecord::from).collect()
+    }
+
+    /// Export the reachable peers to a file, in the format given by its extension
+    pub fn export<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        write_file(&self.records(), path)
+    }
+}
+
+impl AddrMan {
+    /// Records for every known address
+    pub fn records(&self) -> Vec<PeerRecord> {
+        self.iter().map(PeerRecord::from).collect()
+    }
+
+    /// Add the addresses in `records` with their services and last seen time.
+    /// Returns how many were new. Records with invalid addresses are skipped.
+    pub fn import_records(&mut self, records: &[PeerRecord]) -> usize {
+        let mut added = 0;
+        for record in records {
+            if let Ok(peer) = record.peer() {
+                let services = ServicesList::from_bits(record.services);
+                if self.add(peer, services, Duration::from_secs(record.last_seen), None) {
+                    added += 1;
+                }
+            }
+        }
+        added
+    }
+
+    /// Export every known address to a file, in the format given by its extension
+    pub fn export<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        write_file(&self.records(), path)
+    }
+
+    /// Import addresses from a file written by [`export`](Self::export) or another tool.
+    /// Returns how many were new.
+    pub fn import<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, Error> {
+        let format = format_of(path.as_ref())?;
+        Ok(self.import_records(&read_records(format, File::open(path)?)?))
+    }
+}
+
+fn format_of(path: &Path) -> Result<Format, Error> {
+    Format::from_path(path).ok_or_else(|| Error::PeerDb(format!("Cannot tell the format of {}, use .json or .csv", path.display())))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn records() -> Vec<PeerRecord> {
+        vec![
+            PeerRecord {
+                address: String::from("1.2.3.4"),
+                port: 8333,
+                services: 1 | 8 | 1024,
+                user_agent: Some(String::from("/Satoshi:27.0.0/")),
+                last_seen: 1_700_000_000,
+                latency_ms: Some(120),
+                country: Some(String::from("DE")),
+                asn: Some(24940),
+                as_org: None
+            },
+            PeerRecord {
+                address: Peer::new(Host::TorV3([7; 32]), 8333).addr.to_string(),
+                port: 8333,
+                services: 0,
+                user_agent: None,
+                last_seen: 1_700_000_500,
+                latency_ms: None,
+                country: None,
+                asn: None,
+                as_org: None
+            }
+        ]
+    }
+
+    #[test]
+    fn round_trips_formats() {
+        for format in [Format::Json, Format::Csv] {
+            let mut buf = Vec::new();
+            write_records(&records(), format, &mut buf).unwrap();
+            assert_eq!(read_records(format, &buf[..]).unwrap(), records());
+        }
+
+        let mut csv = Vec::new();
+        write_records(&records()[..1], Format::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "address,port,services,user_agent,last_seen,latency_ms,country,asn,as_org\n1.2.3.4,8333,1033,/Satoshi:27.0.0/,1700000000,120,DE,24940,\n"
+        );
+
+        // Files written before the location columns existed still read
+        let old = "address,port,services,user_agent,last_seen,latency_ms\n1.2.3.4,8333,1033,,1700000000,\n";
+        assert_eq!(read_records(Format::Csv, old.as_bytes()).unwrap()[0].country, None);
+    }
+
+    #[test]
+    fn seeds_addrman() {
+        let mut addrman = AddrMan::new();
+        let mut records = records();
+        records.push(PeerRecord { address: String::from("not an address"), ..records[0].clone() });
+        assert_eq!(addrman.import_records(&records), 2);
+
+        let info = addrman.get(&Peer::new(Ipv4Addr::new(1, 2, 3, 4), 8333)).unwrap();
+        assert_eq!(info.services.bits(), 1 | 8 | 1024);
+        assert_eq!(info.last_seen, Duration::from_secs(1_700_000_000));
+        assert_eq!(Format::from_path("peers.CSV"), Some(Format::Csv));
+        assert_eq!(Format::from_path("peers.dat"), None);
+    }
+}
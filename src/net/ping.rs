@@ -0,0 +1,220 @@
+// ping.rs
+//
+// Module for keeping connections alive with periodic pings and measuring
+// round trip latency from the matching pongs.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::Command,
+        network::ProtocolVersion
+    },
+    net::Error
+};
+use rand::Rng;
+use std::{
+    io,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Time between pings sent to a peer (PING_INTERVAL in bitcoin core)
+pub const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Time a peer has to answer a ping before the connection is considered dead
+/// (TIMEOUT_INTERVAL in bitcoin core)
+pub const PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Time a peer may send nothing at all before the connection is considered stalled
+/// (also TIMEOUT_INTERVAL in bitcoin core)
+pub const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Ping schedule, stall detection and latency of a single connection, independent of how
+/// messages are sent and received.
+pub(crate) struct Keepalive {
+    interval: Option<Duration>,
+    // Time a ping has to be answered in
+    timeout: Duration,
+    // Time the peer may send nothing in, unchecked if unset
+    inactivity: Option<Duration>,
+    // Peers before BIP31 do not reply to pings
+    expects_pong: bool,
+    last_ping: Instant,
+    last_received: Instant,
+    // Nonce and send time of the ping awaiting a pong
+    outstanding: Option<(u64, Instant)>,
+    latency: Option<Duration>
+}
+
+impl Keepalive {
+    /// Start a ping schedule for a connection that negotiated `version`
+    pub(crate) fn new(version: ProtocolVersion) -> Self {
+        Self {
+            interval: Some(PING_INTERVAL),
+            timeout: PING_TIMEOUT,
+            inactivity: None,
+            expects_pong: version >= ProtocolVersion::BIP0031,
+            last_ping: Instant::now(),
+            last_received: Instant::now(),
+            outstanding: None,
+            latency: None
+        }
+    }
+
+    /// Change the ping interval. `None` disables pings.
+    pub(crate) fn set_interval(&mut self, interval: Option<Duration>) {
+        self.interval = interval;
+    }
+
+    /// Change the time a ping has to be answered in
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Change the time the peer may send nothing in. `None`, the default, never gives up on a
+    /// quiet peer that is not being pinged.
+    pub(crate) fn set_inactivity_timeout(&mut self, timeout: Option<Duration>) {
+        self.inactivity = timeout;
+    }
+
+    /// Check if pings are being sent
+    pub(crate) fn enabled(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    /// Time at which [`poll`](Self::poll) next needs to be called
+    #[cfg(any(feature = "async", test))]
+    pub(crate) fn next_check(&self) -> Option<Instant> {
+        let ping = self.interval.map(|interval| match self.outstanding {
+            Some((_, sent)) => sent + self.timeout,
+            None => self.last_ping + interval
+        });
+        let inactive = self.inactivity.map(|timeout| self.last_received + timeout);
+        match (ping, inactive) {
+            (Some(ping), Some(inactive)) => Some(ping.min(inactive)),
+            (ping, inactive) => ping.or(inactive)
+        }
+    }
+
+    /// Return the nonce of a ping to send if one is due. Fails with [`Error::Stalled`] if
+    /// nothing has been received within the inactivity timeout, or [`Error::PingTimeout`]
+    /// if the previous ping has gone unanswered for too long.
+    pub(crate) fn poll(&mut self) -> Result<Option<u64>, Error> {
+        let now = Instant::now();
+        if self.inactivity.is_some_and(|timeout| now >= self.last_received + timeout) {
+            return Err(Error::Stalled)
+        }
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Ok(None)
+        };
+        if let Some((_, sent)) = self.outstanding {
+            return match now >= sent + self.timeout {
+                true => Err(Error::PingTimeout),
+                false => Ok(None)
+            }
+        }
+        if now < self.last_ping + interval {
+            return Ok(None)
+        }
+
+        let nonce = rand::thread_rng().gen_range(1..u64::MAX);
+        self.last_ping = now;
+        if self.expects_pong {
+            self.outstanding = Some((nonce, now));
+        }
+        Ok(Some(nonce))
+    }
+
+    /// Note that the peer is active, and record the latency if `msg` answers the outstanding ping
+    pub(crate) fn receive(&mut self, msg: &Message) {
+        self.last_received = Instant::now();
+        if let (Command::Pong, MessagePayload::PingPong(nonce)) = (&msg.header.command, &msg.payload) {
+            if let Some((expected, sent)) = self.outstanding {
+                if *nonce == expected {
+                    self.latency = Some(sent.elapsed());
+                    self.outstanding = None;
+                }
+            }
+        }
+    }
+
+    /// Round trip time of the most recently answered ping
+    pub(crate) fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
+
+/// Check if an io error is a read or write timeout
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::header::Magic;
+
+    fn pong(nonce: u64) -> Message {
+        Message::new(MessagePayload::PingPong(nonce), Magic::Main, Command::Pong)
+    }
+
+    #[test]
+    fn ping_schedule() {
+        let mut keepalive = Keepalive::new(ProtocolVersion::RELAY);
+        assert_eq!(keepalive.poll().unwrap(), None);
+
+        keepalive.set_interval(Some(Duration::from_millis(0)));
+        let nonce = keepalive.poll().unwrap().expect("Ping due");
+        // Only one ping is outstanding at a time
+        assert_eq!(keepalive.poll().unwrap(), None);
+
+        keepalive.receive(&pong(nonce.wrapping_add(1)));
+        assert!(keepalive.latency().is_none());
+        keepalive.receive(&pong(nonce));
+        assert!(keepalive.latency().is_some());
+        assert!(keepalive.poll().unwrap().is_some());
+    }
+
+    #[test]
+    fn pre_bip31_peers() {
+        let mut keepalive = Keepalive::new(ProtocolVersion::MIN_PEER);
+        keepalive.set_interval(Some(Duration::from_millis(0)));
+
+        // No pong is expected, so pings keep being sent
+        assert!(keepalive.poll().unwrap().is_some());
+        assert!(keepalive.poll().unwrap().is_some());
+        assert!(keepalive.next_check().is_some());
+
+        keepalive.set_interval(None);
+        assert!(keepalive.next_check().is_none() && !keepalive.enabled());
+    }
+
+    #[test]
+    fn detects_stalled_peers() {
+        let mut keepalive = Keepalive::new(ProtocolVersion::RELAY);
+        keepalive.set_interval(Some(Duration::from_millis(0)));
+        keepalive.set_timeout(Duration::from_millis(20));
+        assert!(keepalive.poll().unwrap().is_some());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(matches!(keepalive.poll(), Err(Error::PingTimeout)));
+
+        // Without pings, a peer that sends nothing is only given up on if timed
+        let mut keepalive = Keepalive::new(ProtocolVersion::RELAY);
+        keepalive.set_interval(None);
+        keepalive.set_inactivity_timeout(Some(Duration::from_millis(20)));
+        assert!(!keepalive.enabled() && keepalive.next_check().is_some());
+        std::thread::sleep(Duration::from_millis(30));
+        keepalive.receive(&pong(1));
+        assert_eq!(keepalive.poll().unwrap(), None);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(matches!(keepalive.poll(), Err(Error::Stalled)));
+    }
+}
@@ -0,0 +1,204 @@
+// propagation.rs
+//
+// Module for measuring how transactions propagate: recording when each peer
+// first announces a transaction and producing a timeline per transaction.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::Command,
+        inventory::{
+            Inventory,
+            Txid
+        }
+    },
+    net::{
+        manager::ConnectionManager,
+        peer::Peer
+    }
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// When each peer first announced a transaction
+pub struct Timeline {
+    pub txid: Txid,
+    /// Time of the first announcement from any peer
+    pub first_seen: SystemTime,
+    /// Peers in the order they announced the transaction, with the delay after the first
+    pub announcements: Vec<(Peer, Duration)>
+}
+
+impl Timeline {
+    /// Delay between the first and last announcement
+    pub fn spread(&self) -> Duration {
+        self.announcements.last().map(|(_, d)| *d).unwrap_or_default()
+    }
+
+    /// The timeline as a JSON object, with the first announcement in unix seconds and the
+    /// delay of each peer in milliseconds
+    #[cfg(feature = "export")]
+    pub fn to_json(&self) -> serde_json::Value {
+        let announcements: Vec<serde_json::Value> = self.announcements.iter()
+            .map(|(peer, delay)| serde_json::json!({ "peer": peer.to_string(), "delay_ms": delay.as_millis() as u64 }))
+            .collect();
+        serde_json::json!({
+            "txid": self.txid.to_string(),
+            "first_seen": self.first_seen.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            "spread_ms": self.spread().as_millis() as u64,
+            "announcements": announcements
+        })
+    }
+}
+
+impl fmt::Display for Timeline {
+    /// The txid and announcement count, then a line per peer with its delay in milliseconds
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} announced by {} peers over {} ms", self.txid, self.announcements.len(), self.spread().as_millis())?;
+        for (peer, delay) in &self.announcements {
+            writeln!(f, "  +{:>6} ms {}", delay.as_millis(), peer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records transaction announcements from many peers.
+///
+/// Connections must ask for transaction relay, see
+/// [`StreamOptions::relay`](crate::net::stream::StreamOptions::relay).
+pub struct PropagationTracker {
+    timelines: HashMap<Txid, Timeline>
+}
+
+impl PropagationTracker {
+    pub fn new() -> Self {
+        Self {
+            timelines: HashMap::new()
+        }
+    }
+
+    /// Record the transactions announced in a message received now, returning how many
+    /// were announced by this peer for the first time
+    pub fn record(&mut self, peer: Peer, msg: &Message) -> usize {
+        match (&msg.header.command, &msg.payload) {
+            (Command::Inv, MessagePayload::InvVect(inv)) => self.record_at(peer, inv, SystemTime::now()),
+            _ => 0
+        }
+    }
+
+    /// Record the transactions in an inv announced by a peer at `at`
+    pub fn record_at(&mut self, peer: Peer, inv: &[Inventory], at: SystemTime) -> usize {
+        let mut recorded = 0;
+        for item in inv {
+            let txid = match item {
+                Inventory::Tx(txid) | Inventory::WitnessTx(txid) => *txid,
+                _ => continue
+            };
+            let timeline = self.timelines.entry(txid).or_insert_with(|| Timeline {
+                txid,
+                first_seen: at,
+                announcements: vec![]
+            });
+            if timeline.announcements.iter().any(|(p, _)| *p == peer) {
+                continue
+            }
+            timeline.announcements.push((peer, at.duration_since(timeline.first_seen).unwrap_or_default()));
+            recorded += 1;
+        }
+        recorded
+    }
+
+    /// Timeline of a transaction still being recorded
+    pub fn timeline(&self, txid: &Txid) -> Option<&Timeline> {
+        self.timelines.get(txid)
+    }
+
+    /// Number of transactions being recorded
+    pub fn len(&self) -> usize {
+        self.timelines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timelines.is_empty()
+    }
+
+    /// Stop recording transactions first seen at least `window` before `now` and return their
+    /// timelines, oldest first. Later announcements of them start new timelines.
+    pub fn complete(&mut self, window: Duration, now: SystemTime) -> Vec<Timeline> {
+        let done: Vec<Txid> = self.timelines.values()
+            .filter(|t| now.duration_since(t.first_seen).is_ok_and(|age| age >= window))
+            .map(|t| t.txid)
+            .collect();
+        let mut timelines: Vec<Timeline> = done.iter().filter_map(|txid| self.timelines.remove(txid)).collect();
+        timelines.sort_by_key(|t| t.first_seen);
+        timelines
+    }
+
+    /// Record announcements from every peer of a manager, passing each timeline to `on_timeline`
+    /// once `window` has passed since the transaction was first seen. Runs until `on_timeline`
+    /// returns false. Messages other than inv are dropped.
+    pub fn run(&mut self, manager: &ConnectionManager, window: Duration, mut on_timeline: impl FnMut(Timeline) -> bool) {
+        loop {
+            if let Some((peer, msg)) = manager.recv_timeout(window / 4) {
+                self.record(peer, &msg);
+            }
+            for timeline in self.complete(window, SystemTime::now()) {
+                if !on_timeline(timeline) {
+                    return
+                }
+            }
+        }
+    }
+}
+
+impl Default for PropagationTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdata::Hash;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn records_timelines() {
+        let (a, b) = (Peer::new(Ipv4Addr::new(10, 0, 0, 1), 8333), Peer::new(Ipv4Addr::new(10, 0, 0, 2), 8333));
+        let (tx1, tx2) = (Txid::from_inner([1; 32]), Txid::from_inner([2; 32]));
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut tracker = PropagationTracker::new();
+        assert_eq!(tracker.record_at(a, &[Inventory::Tx(tx1), Inventory::Block(Hash::from_inner([3; 32]))], at(0)), 1);
+        assert_eq!(tracker.record_at(b, &[Inventory::Tx(tx2), Inventory::WitnessTx(tx1)], at(250)), 2);
+        // Repeated announcements keep the first time
+        assert_eq!(tracker.record_at(a, &[Inventory::Tx(tx1), Inventory::Tx(tx2)], at(400)), 1);
+
+        assert_eq!(tracker.timeline(&tx1).unwrap().announcements, vec![(a, Duration::ZERO), (b, Duration::from_millis(250))]);
+        assert!(tracker.complete(Duration::from_secs(1), at(900)).is_empty());
+
+        let done = tracker.complete(Duration::from_secs(1), at(1100));
+        assert_eq!(done.len(), 1);
+        assert_eq!((done[0].txid, done[0].spread()), (tx1, Duration::from_millis(250)));
+        assert_eq!(done[0].to_string().lines().count(), 3);
+        #[cfg(feature = "export")]
+        assert_eq!(done[0].to_json()["announcements"][1], serde_json::json!({ "peer": "10.0.0.2:8333", "delay_ms": 250 }));
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.complete(Duration::from_secs(1), at(1250))[0].announcements, vec![(b, Duration::ZERO), (a, Duration::from_millis(150))]);
+        assert!(tracker.is_empty());
+    }
+}
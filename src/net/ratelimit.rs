@@ -0,0 +1,122 @@
+// ratelimit.rs
+//
+// Module for limiting how fast messages are sent to a peer, so bursts of
+// getdata or getaddr requests do not get us disconnected or banned.
+//
+
+use crate::net::Error;
+use std::time::{
+    Duration,
+    Instant
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Token bucket limit on outgoing messages.
+/// Up to `burst` messages can be sent at once, after which one more is allowed every `interval`.
+pub struct RateLimit {
+    burst: u32,
+    interval: Duration
+}
+
+impl RateLimit {
+    /// Allow bursts of `burst` messages and one more every `interval` after them.
+    /// Fails for a burst of 0, which would never allow a message.
+    pub fn new(burst: u32, interval: Duration) -> Result<Self, Error> {
+        if burst == 0 {
+            return Err(Error::Config(String::from("Rate limit burst must be at least 1")))
+        }
+        Ok(Self { burst, interval })
+    }
+
+    /// Allow `messages` per second on average, in bursts of up to `messages`
+    pub fn per_second(messages: u32) -> Self {
+        Self {
+            burst: messages.max(1),
+            interval: Duration::from_secs(1) / messages.max(1)
+        }
+    }
+
+    /// Messages that can be sent at once
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Time after which one more message is allowed
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// Tokens available to a single connection, independent of how messages are sent.
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: u32,
+    // Time the last token was added
+    refilled: Instant
+}
+
+impl TokenBucket {
+    /// Start with a full bucket
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            refilled: Instant::now()
+        }
+    }
+
+    /// Take a token at `now`, or return how long to wait until one is available
+    pub(crate) fn take(&mut self, now: Instant) -> Option<Duration> {
+        let interval = self.limit.interval.max(Duration::from_nanos(1));
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let added = (elapsed.as_nanos() / interval.as_nanos()).min(u32::MAX as u128) as u32;
+        if self.tokens.saturating_add(added) >= self.limit.burst {
+            self.tokens = self.limit.burst;
+            self.refilled = now;
+        } else {
+            self.tokens += added;
+            self.refilled += interval * added;
+        }
+
+        if self.tokens == 0 {
+            return Some(interval - now.saturating_duration_since(self.refilled))
+        }
+        self.tokens -= 1;
+        None
+    }
+
+    /// Block until a token is available and take it
+    pub(crate) fn acquire(&mut self) {
+        while let Some(wait) = self.take(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        assert!(RateLimit::new(0, Duration::from_millis(100)).is_err());
+        let mut bucket = TokenBucket::new(RateLimit::new(3, Duration::from_millis(100)).unwrap());
+        let start = bucket.refilled;
+
+        // The burst is allowed straight away
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), None);
+        }
+        assert_eq!(bucket.take(start + Duration::from_millis(40)), Some(Duration::from_millis(60)));
+
+        // Tokens come back one per interval and never exceed the burst
+        assert_eq!(bucket.take(start + Duration::from_millis(150)), None);
+        assert_eq!(bucket.take(start + Duration::from_millis(150)), Some(Duration::from_millis(50)));
+        let later = start + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(later), None);
+        }
+        assert!(bucket.take(later).is_some());
+    }
+}
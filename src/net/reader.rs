@@ -0,0 +1,353 @@
+// reader.rs
+//
+// Module for reading complete network messages out of a byte stream.
+//
+
+use crate::{
+    blockdata::hash::Hash,
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Command,
+            MessageHeader,
+            Magic
+        }
+    },
+    encode::{
+        Decode,
+        Encode
+    },
+    net::{
+        misbehavior::Misbehavior,
+        v2::PacketDecoder,
+        Error
+    },
+    bitcoin::hashes::hex::ToHex
+};
+use std::{
+    collections::HashSet,
+    io::{
+        Read,
+        ErrorKind
+    }
+};
+use tracing::warn;
+
+pub use crate::msg::header::{
+    HEADER_SIZE,
+    MAX_PAYLOAD_SIZE
+};
+
+// Bytes requested from the inner reader per read call
+pub(crate) const READ_CHUNK: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What to do with a v1 message whose checksum does not match its payload.
+/// The v2 transport authenticates packets instead of using checksums.
+pub enum ChecksumPolicy {
+    /// Drop the message and return [`Misbehavior::InvalidChecksum`], which counts
+    /// against the peer
+    #[default]
+    Strict,
+    /// Log both checksums and decode the message anyway, for dissecting traffic from
+    /// implementations that get them wrong
+    Lenient
+}
+
+/// Buffered reader that yields complete network messages from a stream.
+///
+/// Bytes are buffered across reads until a full header and payload are available.
+/// Any bytes preceding the network magic (or following a header with an impossible
+/// payload length) are discarded so the reader can resynchronize with the stream.
+/// Oversized payloads and checksum mismatches are returned as
+/// [`Error::Misbehavior`] and reading can continue with the next message,
+/// unless mismatches are let through with [`ChecksumPolicy::Lenient`].
+/// Over the v2 transport messages are decrypted from packets instead.
+pub struct MessageReader<R> {
+    inner: R,
+    magic: [u8; 4],
+    buf: Vec<u8>,
+    decoder: Option<PacketDecoder>,
+    checksum: ChecksumPolicy,
+    // Commands of the messages to decode, others are dropped
+    filter: Option<HashSet<Command>>,
+    // Bytes of the last message read, kept only if set
+    raw: Option<Vec<u8>>
+}
+
+impl<R: Read> MessageReader<R> {
+    /// Create a new reader expecting messages for the given network
+    pub fn new(inner: R, magic: Magic) -> Self {
+        Self {
+            inner,
+            magic: magic.bytes().to_le_bytes(),
+            buf: Vec::new(),
+            decoder: None,
+            checksum: ChecksumPolicy::Strict,
+            filter: None,
+            raw: None
+        }
+    }
+
+    /// Create a reader for a stream that has already been read from, such as during the
+    /// v2 key exchange. Messages are decrypted with `decoder` if set.
+    pub(crate) fn resume(inner: R, magic: Magic, decoder: Option<PacketDecoder>, buf: Vec<u8>) -> Self {
+        Self {
+            inner,
+            magic: magic.bytes().to_le_bytes(),
+            buf,
+            decoder,
+            checksum: ChecksumPolicy::Strict,
+            filter: None,
+            raw: None
+        }
+    }
+
+    /// Read the next complete message from the stream.
+    ///
+    /// Blocks until a full message has been received. If the inner reader returns an error
+    /// (such as a timeout) the buffered bytes are kept and the call can be retried.
+    /// A payload that fails to decode is consumed and returned as an error so the
+    /// next call continues with the following message.
+    pub fn read_message(&mut self) -> Result<Message, Error> {
+        loop {
+            let next = match &mut self.decoder {
+                Some(decoder) => {
+                    let next = decoder.next_message(&mut self.buf, self.filter.as_ref());
+                    // Packets are decrypted, keep the message as it would be framed over v1
+                    if let (Some(raw), Some(Ok(msg))) = (&mut self.raw, &next) {
+                        raw.clear();
+                        msg.net_encode(raw);
+                    }
+                    next
+                },
+                None => next_message(&mut self.buf, self.magic, self.checksum, self.filter.as_ref(), self.raw.as_mut())
+            };
+            if let Some(msg) = next {
+                return msg
+            }
+
+            self.fill()?;
+        }
+    }
+
+    /// Only decode messages with the given commands, dropping all others unread.
+    /// `None` decodes every message, which is the default.
+    pub fn set_filter(&mut self, commands: Option<HashSet<Command>>) {
+        self.filter = commands;
+    }
+
+    /// Set how checksum mismatches are handled, [`ChecksumPolicy::Strict`] by default
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) {
+        self.checksum = policy;
+    }
+
+    /// Keep the bytes of each message read, see [`last_raw`](Self::last_raw). Off by default.
+    pub fn keep_raw(&mut self, keep: bool) {
+        self.raw = keep.then(Vec::new);
+    }
+
+    /// Bytes of the last message read as they were received, or as they would be framed over
+    /// v1 for the v2 transport. Empty unless [`keep_raw`](Self::keep_raw) is set.
+    pub fn last_raw(&self) -> &[u8] {
+        self.raw.as_deref().unwrap_or_default()
+    }
+
+    /// Get a reference to the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying reader
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Return the underlying reader, discarding any buffered bytes
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Read another chunk from the inner reader into the buffer
+    fn fill(&mut self) -> Result<(), Error> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(())
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e))
+            }
+        }
+    }
+}
+
+/// Take the next complete message out of a buffer of received bytes.
+/// Returns `None` if more bytes are needed. Shared by the blocking and async readers.
+/// The bytes of the message taken are copied into `raw` if given.
+pub(crate) fn next_message(buf: &mut Vec<u8>, magic: [u8; 4], checksum: ChecksumPolicy, filter: Option<&HashSet<Command>>, raw: Option<&mut Vec<u8>>) -> Option<Result<Message, Error>> {
+    loop {
+        resync(buf, magic);
+        if buf.len() < HEADER_SIZE {
+            return None
+        }
+
+        let header: MessageHeader = match Decode::net_decode(&buf[..HEADER_SIZE]) {
+            Ok(header) => header,
+            Err(e) => return Some(Err(e.into()))
+        };
+
+        // A payload this large cannot be genuine, skip past this magic so the next call rescans.
+        if header.length > MAX_PAYLOAD_SIZE {
+            buf.drain(..1);
+            return Some(Err(Error::Misbehavior(Misbehavior::OversizedPayload(header.length))))
+        }
+
+        let total = HEADER_SIZE + header.length as usize;
+        if buf.len() < total {
+            return None
+        }
+
+        // Unwanted messages are dropped without verifying or decoding them
+        if filter.is_some_and(|f| !f.contains(&header.command)) {
+            buf.drain(..total);
+            continue
+        }
+
+        let expected = Hash::sha256d(&buf[HEADER_SIZE..total]).checksum();
+        if expected != header.checksum {
+            match checksum {
+                ChecksumPolicy::Strict => {
+                    buf.drain(..total);
+                    return Some(Err(Error::Misbehavior(Misbehavior::InvalidChecksum { expected, received: header.checksum })))
+                },
+                ChecksumPolicy::Lenient => warn!(
+                    command = header.command.to_str(),
+                    expected = %expected.to_hex(),
+                    received = %header.checksum.to_hex(),
+                    "Checksum mismatch, decoding anyway"
+                )
+            }
+        }
+
+        let payload = MessagePayload::decode_with(&header, &buf[HEADER_SIZE..total]);
+        if let Some(raw) = raw {
+            raw.clear();
+            raw.extend_from_slice(&buf[..total]);
+        }
+        buf.drain(..total);
+
+        return Some(payload.map(|payload| Message { header, payload }).map_err(Error::from))
+    }
+}
+
+/// Discard buffered bytes up to the first occurence of the network magic.
+/// If the magic is not found, the last 3 bytes are kept in case they are the start of it.
+fn resync(buf: &mut Vec<u8>, magic: [u8; 4]) {
+    match buf.windows(4).position(|w| w == magic) {
+        Some(pos) => { buf.drain(..pos); },
+        None => {
+            let keep = buf.len().min(3);
+            buf.drain(..buf.len() - keep);
+        }
+    }
+}
+
+impl<R: Read> Iterator for MessageReader<R> {
+    type Item = Result<Message, Error>;
+
+    /// Yields messages until the stream is closed
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_message() {
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => None,
+            x => Some(x)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        msg::header::Command,
+        encode::Encode
+    };
+
+    // Reader that hands out at most one byte per read call
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() { return Ok(0) }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn partial_reads_and_resync() {
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping);
+        let verack = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::Verack);
+
+        // Garbage, a partial magic, then two messages
+        let mut stream = vec![0x01, 0x02, 0xF9, 0xBE, 0x03];
+        ping.net_encode(&mut stream);
+        verack.net_encode(&mut stream);
+
+        let reader = MessageReader::new(Trickle(&stream), Magic::Main);
+        let msgs: Vec<Message> = reader.map(|m| m.expect("Failed to read")).collect();
+
+        assert_eq!(msgs, vec![ping, verack]);
+    }
+
+    #[test]
+    fn rejects_corrupt_messages() {
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping);
+        let mut stream = Vec::new();
+        ping.net_encode(&mut stream);
+        let last = stream.len() - 1;
+        stream[last] ^= 1;
+
+        // Header announcing a 64MB payload
+        let mut oversized = MessageHeader::new(Magic::Main, Command::Block, 0, [0; 4]);
+        oversized.length = MAX_PAYLOAD_SIZE + 1;
+        oversized.net_encode(&mut stream);
+        ping.net_encode(&mut stream);
+
+        let mut reader = MessageReader::new(&stream[..], Magic::Main);
+        let checksum = ping.header.checksum;
+        match reader.read_message() {
+            Err(Error::Misbehavior(Misbehavior::InvalidChecksum { expected, received })) => {
+                assert_ne!(expected, checksum);
+                assert_eq!(received, checksum);
+            },
+            other => panic!("Expected a checksum mismatch, got {:?}", other)
+        }
+        assert!(matches!(reader.read_message(), Err(Error::Misbehavior(Misbehavior::OversizedPayload(_)))));
+        assert_eq!(reader.read_message().unwrap(), ping);
+
+        // Lenient readers keep the corrupt ping
+        let mut reader = MessageReader::new(&stream[..], Magic::Main);
+        reader.set_checksum_policy(ChecksumPolicy::Lenient);
+        assert_eq!(reader.read_message().unwrap().payload, MessagePayload::PingPong(7 ^ (1 << 56)));
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping);
+        let mut stream = Vec::new();
+        ping.net_encode(&mut stream);
+        stream.pop();
+
+        let mut reader = MessageReader::new(&stream[..], Magic::Main);
+        assert!(matches!(reader.read_message(), Err(Error::Io(_))));
+    }
+}
@@ -0,0 +1,324 @@
+// replay.rs
+//
+// Module for decoding recorded traffic offline, without any network access.
+// Reads capture files written by `Capture` or raw dumps of v1 messages, such
+// as one side of a TCP stream extracted from a packet capture.
+//
+
+use crate::{
+    msg::{
+        data::Message,
+        header::Magic
+    },
+    net::{
+        capture::{
+            CaptureReader,
+            Direction,
+            FILE_MAGIC
+        },
+        peer::Peer,
+        reader::{
+            ChecksumPolicy,
+            MessageReader
+        },
+        Error
+    }
+};
+use std::{
+    fmt,
+    fs::File,
+    io::{
+        self,
+        BufReader,
+        Cursor,
+        Read,
+        Write
+    },
+    path::Path,
+    time::SystemTime
+};
+
+// A reader with the bytes read to detect its format put back in front
+type Prefixed<R> = io::Chain<Cursor<[u8; 4]>, R>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A message decoded from a recording
+pub struct ReplayedMessage {
+    /// Direction, time and peer are only known for messages read from a capture file
+    pub direction: Option<Direction>,
+    pub time: Option<SystemTime>,
+    pub peer: Option<Peer>,
+    pub message: Message
+}
+
+impl ReplayedMessage {
+    /// A message just received from a peer, to output it the same way as recorded ones
+    pub fn received(peer: Peer, message: Message) -> Self {
+        Self {
+            direction: Some(Direction::Received),
+            time: Some(SystemTime::now()),
+            peer: Some(peer),
+            message
+        }
+    }
+
+    /// The message as a JSON object, see [`Message::to_json`], with its time in unix seconds,
+    /// direction and peer added where known
+    #[cfg(feature = "export")]
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = self.message.to_json();
+        if let Some(time) = self.time {
+            json["time"] = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64().into();
+        }
+        if let Some(direction) = self.direction {
+            json["direction"] = match direction {
+                Direction::Received => "received",
+                Direction::Sent => "sent"
+            }.into();
+        }
+        if let Some(peer) = self.peer {
+            json["peer"] = peer.to_string().into();
+        }
+        json
+    }
+}
+
+impl fmt::Display for ReplayedMessage {
+    /// One line with the capture time in unix seconds, the direction, the peer and a summary
+    /// of the message, see [`Message`]'s `Display`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(time) = self.time {
+            let since = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+            write!(f, "{}.{:06} ", since.as_secs(), since.subsec_micros())?;
+        }
+        match self.direction {
+            Some(Direction::Received) => write!(f, "<- ")?,
+            Some(Direction::Sent) => write!(f, "-> ")?,
+            None => {}
+        }
+        if let Some(peer) = self.peer {
+            write!(f, "{} ", peer)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+enum Source<R> {
+    Capture(CaptureReader<Prefixed<R>>),
+    Dump(Box<MessageReader<Prefixed<R>>>)
+}
+
+/// Decodes the messages of a capture file or raw dump in order.
+///
+/// Messages that fail to decode are returned as errors and the following messages are still
+/// read. In raw dumps, bytes that are not part of a message are skipped.
+pub struct Replay<R> {
+    source: Source<R>
+}
+
+impl Replay<BufReader<File>> {
+    /// Open a capture file or raw dump
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Replay<R> {
+    /// Read a capture or raw dump, telling them apart by their first bytes. A raw dump must
+    /// start with a message, whose magic is then expected for every message.
+    pub fn new(inner: R) -> Result<Self, Error> {
+        Self::detect(inner, None)
+    }
+
+    /// Read a capture or raw dump like [`new`](Self::new), expecting the messages of a raw
+    /// dump to be of the given network
+    pub fn with_magic(inner: R, magic: Magic) -> Result<Self, Error> {
+        Self::detect(inner, Some(magic))
+    }
+
+    fn detect(mut inner: R, magic: Option<Magic>) -> Result<Self, Error> {
+        let mut start = [0; 4];
+        inner.read_exact(&mut start)?;
+        let inner = Cursor::new(start).chain(inner);
+        let source = match start {
+            FILE_MAGIC => Source::Capture(CaptureReader::new(inner)?),
+            first => Source::Dump(Box::new(MessageReader::new(inner, magic.unwrap_or_else(|| Magic::from(first)))))
+        };
+        Ok(Self { source })
+    }
+
+    /// Read a raw dump of messages for the given network
+    pub fn dump(inner: R, magic: Magic) -> Self {
+        // Nothing was read to detect the format, so the prefix starts out consumed
+        let mut start = Cursor::new([0; 4]);
+        start.set_position(4);
+        Self {
+            source: Source::Dump(Box::new(MessageReader::new(start.chain(inner), magic)))
+        }
+    }
+
+    /// Set how messages of a raw dump with a checksum that does not match their payload are
+    /// handled, see [`ChecksumPolicy`]. Capture files hold messages as they were accepted
+    /// and are not checked again.
+    pub fn with_checksum_policy(mut self, policy: ChecksumPolicy) -> Self {
+        if let Source::Dump(reader) = &mut self.source {
+            reader.set_checksum_policy(policy);
+        }
+        self
+    }
+
+    /// Write each message on its own line, see [`ReplayedMessage`]'s `Display`, and each
+    /// error as a line starting with "error". Returns the number of messages decoded.
+    pub fn print<W: Write>(self, out: &mut W) -> Result<usize, Error> {
+        self.print_with(out, |out, msg| writeln!(out, "{}", msg))
+    }
+
+    /// Write each message like [`print`](Self::print) followed by an annotated hexdump of its
+    /// bytes, see [`Message::hexdump`], and a blank line
+    pub fn print_hexdump<W: Write>(self, out: &mut W) -> Result<usize, Error> {
+        self.print_with(out, |out, msg| writeln!(out, "{}\n{}", msg, msg.message.hexdump()))
+    }
+
+    fn print_with<W: Write, F>(self, out: &mut W, mut print: F) -> Result<usize, Error>
+    where F: FnMut(&mut W, &ReplayedMessage) -> io::Result<()> {
+        let mut decoded = 0;
+        for msg in self {
+            match msg {
+                Ok(msg) => {
+                    print(out, &msg)?;
+                    decoded += 1;
+                },
+                Err(e) => writeln!(out, "error {:?}", e)?
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// Write each message as a JSON object on its own line, see [`ReplayedMessage::to_json`],
+    /// and each error as an object with an `error` field. Returns the number of messages decoded.
+    #[cfg(feature = "export")]
+    pub fn write_jsonl<W: Write>(self, out: &mut W) -> Result<usize, Error> {
+        let mut decoded = 0;
+        for msg in self {
+            let json = match msg {
+                Ok(msg) => {
+                    decoded += 1;
+                    msg.to_json()
+                },
+                Err(e) => serde_json::json!({ "error": format!("{:?}", e) })
+            };
+            writeln!(out, "{}", json)?;
+        }
+        Ok(decoded)
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = Result<ReplayedMessage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Capture(reader) => reader.next().map(|record| {
+                let record = record?;
+                Ok(ReplayedMessage {
+                    direction: Some(record.direction),
+                    time: Some(record.time),
+                    peer: Some(record.peer),
+                    message: record.message()?
+                })
+            }),
+            Source::Dump(reader) => reader.next().map(|msg| msg.map(|message| ReplayedMessage {
+                direction: None,
+                time: None,
+                peer: None,
+                message
+            }))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encode::Encode,
+        msg::{
+            data::MessagePayload,
+            header::Command
+        },
+        net::capture::Capture
+    };
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            Arc,
+            Mutex
+        }
+    };
+
+    // Writer whose bytes can be read back after the capture takes ownership of it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replays_recordings() {
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping);
+        let getaddr = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::GetAddr);
+        let (mut ping_bytes, mut getaddr_bytes) = (vec![], vec![]);
+        ping.net_encode(&mut ping_bytes);
+        getaddr.net_encode(&mut getaddr_bytes);
+        let bytes = [&ping_bytes[..], b"junk", &getaddr_bytes[..]].concat();
+
+        // Raw dumps yield the messages alone, skipping bytes between them
+        let replayed: Vec<ReplayedMessage> = Replay::new(&bytes[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(replayed.iter().map(|m| m.message.clone()).collect::<Vec<_>>(), vec![ping.clone(), getaddr.clone()]);
+        assert_eq!(replayed[0].to_string(), "ping: nonce=7");
+        assert_eq!(Replay::dump(&bytes[..], Magic::Test).count(), 0);
+        assert_eq!(Replay::with_magic(&bytes[..], Magic::Test).unwrap().count(), 0);
+
+        let out = Shared::default();
+        let peer = Peer::new(Ipv4Addr::new(10, 0, 0, 1), 8333);
+        let capture = Capture::new(out.clone()).unwrap();
+        capture.record(Direction::Sent, &peer, &ping_bytes).unwrap();
+        capture.record(Direction::Received, &peer, b"not a message").unwrap();
+        capture.record(Direction::Received, &peer, &getaddr_bytes[..20]).unwrap();
+        capture.record(Direction::Received, &peer, &getaddr_bytes).unwrap();
+
+        let file = out.0.lock().unwrap().clone();
+        let replayed: Vec<Result<ReplayedMessage, Error>> = Replay::new(&file[..]).unwrap().collect();
+        assert_eq!(replayed.len(), 4);
+        assert!(replayed[1].is_err() && replayed[2].is_err());
+        let last = replayed[3].as_ref().unwrap();
+        assert_eq!((last.direction, last.peer, &last.message), (Some(Direction::Received), Some(peer), &getaddr));
+        assert!(last.to_string().ends_with(" <- 10.0.0.1:8333 getaddr"));
+
+        let mut printed = vec![];
+        assert_eq!(Replay::new(&file[..]).unwrap().print(&mut printed).unwrap(), 2);
+        assert_eq!(String::from_utf8(printed).unwrap().lines().filter(|l| l.starts_with("error")).count(), 2);
+
+        let mut dumped = vec![];
+        assert_eq!(Replay::new(&file[..]).unwrap().print_hexdump(&mut dumped).unwrap(), 2);
+        assert!(String::from_utf8(dumped).unwrap().contains(&getaddr.hexdump()));
+
+        #[cfg(feature = "export")]
+        {
+            let mut lines = vec![];
+            assert_eq!(Replay::new(&file[..]).unwrap().write_jsonl(&mut lines).unwrap(), 2);
+            let objects: Vec<serde_json::Value> = String::from_utf8(lines).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+            assert_eq!(objects.len(), 4);
+            assert_eq!((&objects[0]["direction"], &objects[0]["peer"], &objects[0]["payload"]["nonce"]), (&"sent".into(), &"10.0.0.1:8333".into(), &7.into()));
+            assert!(objects[0]["time"].is_f64() && objects[1]["error"].is_string());
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// responder.rs
+//
+// Module answering the requests every node is expected to reply to, so that
+// sessions kept open for listening are not dropped as unresponsive: pongs to
+// pings, empty headers to getheaders and an addr to getaddr. Verack is already
+// part of the handshake.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::Command,
+        network::{
+            NetAddress,
+            NetAddressV2,
+            TimestampedNetAddress
+        }
+    },
+    address::Address,
+    net::peer::Peer
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    sync::Arc
+};
+
+/// Commands of the messages a [`Responder`] replies to
+pub const ANSWERED: [Command; 3] = [Command::Ping, Command::GetHeaders, Command::GetAddr];
+
+/// Source of the addresses sent in reply to getaddr
+pub type AddressSource = Arc<dyn Fn() -> Vec<NetAddressV2> + Send + Sync>;
+
+#[derive(Clone, Default)]
+/// Replies to the requests of a single connection, independent of how messages
+/// are sent and received.
+pub struct Responder {
+    // Like bitcoin core, getaddr is only answered once per connection
+    sent_addr: bool,
+    // Set once the peer sends sendaddrv2 (BIP155)
+    addrv2: bool,
+    addresses: Option<AddressSource>
+}
+
+impl Responder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer getaddr with the addresses returned by `source`, such as a sample from an
+    /// [`AddrMan`](crate::net::addrman::AddrMan). Getaddr is answered with an empty addr otherwise.
+    pub fn with_addresses<F: Fn() -> Vec<NetAddressV2> + Send + Sync + 'static>(mut self, source: F) -> Self {
+        self.addresses = Some(Arc::new(source));
+        self
+    }
+
+    /// The reply owed for a received message, if any.
+    ///
+    /// Pings are answered with a pong carrying the same nonce, getheaders with no headers
+    /// as we have no chain to serve, and the first getaddr with the addresses of the
+    /// [source](Self::with_addresses). They are sent in an addrv2 if the peer sent sendaddrv2,
+    /// otherwise in an addr without the addresses it cannot carry.
+    pub fn reply(&mut self, msg: &Message) -> Option<(MessagePayload, Command)> {
+        match (&msg.header.command, &msg.payload) {
+            // Pings before BIP31 have no nonce and expect no pong
+            (Command::Ping, MessagePayload::PingPong(nonce)) => Some((MessagePayload::PingPong(*nonce), Command::Pong)),
+            (Command::GetHeaders, _) => Some((MessagePayload::Headers(vec![]), Command::Headers)),
+            (Command::SendAddrV2, _) => {
+                self.addrv2 = true;
+                None
+            },
+            (Command::GetAddr, _) if !self.sent_addr => {
+                self.sent_addr = true;
+                let addrs = self.addresses.as_ref().map_or_else(Vec::new, |source| source());
+                Some(match self.addrv2 {
+                    true => (MessagePayload::AddrV2List(addrs), Command::AddrV2),
+                    false => (MessagePayload::AddrList(addrs.into_iter().filter_map(v1_address).collect()), Command::Addr)
+                })
+            },
+            _ => None
+        }
+    }
+}
+
+impl fmt::Debug for Responder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Responder")
+            .field("sent_addr", &self.sent_addr)
+            .field("addrv2", &self.addrv2)
+            .field("addresses", &self.addresses.is_some())
+            .finish()
+    }
+}
+
+// An address as sent in addr messages, which only carry IPv4 and IPv6 addresses
+fn v1_address(addr: NetAddressV2) -> Option<TimestampedNetAddress> {
+    let socket = Peer::try_from(addr.clone()).ok()?.socket_addr()?;
+    Some(TimestampedNetAddress::new(addr.timestamp, NetAddress::new(addr.services, Address::from(socket))))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::AddrV2,
+        msg::{
+            header::Magic,
+            network::ServicesList
+        }
+    };
+    use std::{
+        net::Ipv4Addr,
+        time::Duration
+    };
+
+    #[test]
+    fn answers_requests() {
+        let mut responder = Responder::new();
+        let ping = Message::new(MessagePayload::PingPong(42), Magic::Main, Command::Ping);
+        assert_eq!(responder.reply(&ping), Some((MessagePayload::PingPong(42), Command::Pong)));
+
+        let pong = Message::new(MessagePayload::PingPong(42), Magic::Main, Command::Pong);
+        assert_eq!(responder.reply(&pong), None);
+
+        let getaddr = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::GetAddr);
+        assert_eq!(responder.reply(&getaddr), Some((MessagePayload::AddrList(vec![]), Command::Addr)));
+        assert_eq!(responder.reply(&getaddr), None);
+    }
+
+    #[test]
+    fn serves_addresses() {
+        let ip = NetAddressV2::new(Duration::from_secs(1_700_000_000), ServicesList::default(), AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), 8333);
+        let onion = NetAddressV2::new(Duration::from_secs(1_700_000_000), ServicesList::default(), AddrV2::TorV3([7; 32]), 8333);
+        let addrs = vec![ip.clone(), onion];
+        let source = move || addrs.clone();
+        let getaddr = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::GetAddr);
+
+        // Onion addresses do not fit in an addr
+        let mut responder = Responder::new().with_addresses(source.clone());
+        assert_eq!(responder.reply(&getaddr), Some((MessagePayload::AddrList(vec![
+            TimestampedNetAddress::new(ip.timestamp, NetAddress::new(ServicesList::default(), Address::new(Ipv4Addr::new(1, 2, 3, 4).into(), 8333)))
+        ]), Command::Addr)));
+
+        let mut responder = Responder::new().with_addresses(source.clone());
+        let sendaddrv2 = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::SendAddrV2);
+        assert_eq!(responder.reply(&sendaddrv2), None);
+        assert_eq!(responder.reply(&getaddr), Some((MessagePayload::AddrV2List(source()), Command::AddrV2)));
+    }
+}
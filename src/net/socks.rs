@@ -0,0 +1,245 @@
+// socks.rs
+//
+// Module for connecting to peers through a SOCKS5 proxy such as Tor.
+//   https://datatracker.ietf.org/doc/html/rfc1928
+//
+
+use crate::net::Error;
+use std::{
+    io::{
+        Read,
+        Write
+    },
+    net::{
+        IpAddr,
+        SocketAddr,
+        TcpStream
+    },
+    time::Duration
+};
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const CONNECT: u8 = 0x01;
+
+// Address types
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// Open a connection to `host:port` through the SOCKS5 proxy at `proxy`.
+///
+/// `host` may be an IP address or a hostname. Hostnames are resolved by the proxy,
+/// which is required for onion addresses when connecting over Tor.
+pub fn connect(proxy: SocketAddr, host: &str, port: u16, timeout: Duration) -> Result<TcpStream, Error> {
+    let mut stream = match TcpStream::connect_timeout(&proxy, timeout) {
+        Ok(x) => x,
+        Err(_) => return Err(Error::FailedToConnect(proxy.to_string()))
+    };
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    handshake(&mut stream, host, port)?;
+    Ok(stream)
+}
+
+/// Negotiate a connection to `host:port` over a stream connected to a SOCKS5 proxy
+pub fn handshake<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> Result<(), Error> {
+    let (mut negotiation, mut step) = Negotiation::new(host, port)?;
+    loop {
+        stream.write_all(&step.send)?;
+        let mut received = vec![0; step.receive];
+        stream.read_exact(&mut received)?;
+        step = match negotiation.next(&received)? {
+            Some(step) => step,
+            None => return Ok(())
+        };
+    }
+}
+
+/// Bytes to send to the proxy, then the number of bytes to read from it
+pub(crate) struct Step {
+    pub send: Vec<u8>,
+    pub receive: usize
+}
+
+/// Progress of a SOCKS5 negotiation, independent of how bytes are sent and received,
+/// so that blocking and async streams negotiate alike
+pub(crate) struct Negotiation {
+    request: Vec<u8>,
+    stage: Stage
+}
+
+// What the bytes read for the last step are
+enum Stage {
+    Method,
+    Reply,
+    DomainLength,
+    BoundAddress
+}
+
+impl Negotiation {
+    /// Start negotiating a connection to `host:port`, with the greeting as the first step
+    pub(crate) fn new(host: &str, port: u16) -> Result<(Self, Step), Error> {
+        let negotiation = Self {
+            request: connect_request(host, port)?,
+            stage: Stage::Method
+        };
+        Ok((negotiation, Step { send: vec![VERSION, 1, NO_AUTH], receive: 2 }))
+    }
+
+    /// Handle the bytes read for the last step. Returns the next step, or `None` once the
+    /// proxy has connected to the host.
+    pub(crate) fn next(&mut self, received: &[u8]) -> Result<Option<Step>, Error> {
+        let (stage, step) = match self.stage {
+            Stage::Method => {
+                check_method([received[0], received[1]])?;
+                (Stage::Reply, Step { send: std::mem::take(&mut self.request), receive: 4 })
+            },
+            Stage::Reply => match bound_address_len([received[0], received[1], received[2], received[3]])? {
+                Some(len) => (Stage::BoundAddress, Step { send: vec![], receive: len }),
+                None => (Stage::DomainLength, Step { send: vec![], receive: 1 })
+            },
+            Stage::DomainLength => (Stage::BoundAddress, Step { send: vec![], receive: received[0] as usize + 2 }),
+            // The address the proxy bound to is of no use to us
+            Stage::BoundAddress => return Ok(None)
+        };
+        self.stage = stage;
+        Ok(Some(step))
+    }
+}
+
+/// Check the proxy accepted connecting without authentication
+fn check_method(reply: [u8; 2]) -> Result<(), Error> {
+    match reply {
+        [VERSION, NO_AUTH] => Ok(()),
+        [VERSION, _] => Err(Error::Proxy(String::from("Proxy requires authentication"))),
+        _ => Err(Error::Proxy(String::from("Not a SOCKS5 proxy")))
+    }
+}
+
+/// Build a CONNECT request for `host:port`
+fn connect_request(host: &str, port: u16) -> Result<Vec<u8>, Error> {
+    let mut req = vec![VERSION, CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(IPV4);
+            req.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            req.push(IPV6);
+            req.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            if host.is_empty() || host.len() > 255 {
+                return Err(Error::Proxy(format!("Invalid hostname {}", host)))
+            }
+            req.push(DOMAIN);
+            req.push(host.len() as u8);
+            req.extend_from_slice(host.as_bytes());
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    Ok(req)
+}
+
+/// Check the reply to a CONNECT request and return the length of the bound address and
+/// port that follow it, or `None` for a domain whose length byte must be read first.
+fn bound_address_len(reply: [u8; 4]) -> Result<Option<usize>, Error> {
+    if reply[0] != VERSION {
+        return Err(Error::Proxy(String::from("Not a SOCKS5 proxy")))
+    }
+    if reply[1] != 0x00 {
+        return Err(Error::Proxy(String::from(reply_message(reply[1]))))
+    }
+
+    match reply[3] {
+        IPV4 => Ok(Some(4 + 2)),
+        IPV6 => Ok(Some(16 + 2)),
+        DOMAIN => Ok(None),
+        _ => Err(Error::Proxy(String::from("Unknown address type in proxy reply")))
+    }
+}
+
+/// Describe a SOCKS5 reply code
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "General SOCKS server failure",
+        0x02 => "Connection not allowed by ruleset",
+        0x03 => "Network unreachable",
+        0x04 => "Host unreachable",
+        0x05 => "Connection refused",
+        0x06 => "TTL expired",
+        0x07 => "Command not supported",
+        0x08 => "Address type not supported",
+        _ => "Unknown proxy error"
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        thread
+    };
+
+    // Accept one connection, check the request is for `host` and reply with `code`
+    fn fake_proxy(host: &'static str, code: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 1, NO_AUTH]);
+            stream.write_all(&[VERSION, NO_AUTH]).unwrap();
+
+            let mut req = vec![0; 5 + host.len() + 2];
+            stream.read_exact(&mut req).unwrap();
+            assert_eq!(req, connect_request(host, 8333).unwrap());
+            stream.write_all(&[VERSION, code, 0x00, IPV4, 127, 0, 0, 1, 0x20, 0x8D]).unwrap();
+            stream.write_all(b"hello").unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    fn connects_through_proxy() {
+        let host = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion";
+        let proxy = fake_proxy(host, 0x00);
+
+        let mut stream = connect(proxy, host, 8333, Duration::from_secs(5)).unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn proxy_errors() {
+        let proxy = fake_proxy("example.com", 0x05);
+        assert!(matches!(connect(proxy, "example.com", 8333, Duration::from_secs(5)), Err(Error::Proxy(_))));
+
+        assert_eq!(connect_request("1.2.3.4", 8333).unwrap(), vec![VERSION, CONNECT, 0, IPV4, 1, 2, 3, 4, 0x20, 0x8D]);
+        assert!(connect_request(&"a".repeat(256), 8333).is_err());
+        assert!(check_method([VERSION, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn negotiates_domain_replies() {
+        let (mut negotiation, step) = Negotiation::new("example.com", 8333).unwrap();
+        assert_eq!((step.send, step.receive), (vec![VERSION, 1, NO_AUTH], 2));
+        let step = negotiation.next(&[VERSION, NO_AUTH]).unwrap().unwrap();
+        assert_eq!((step.send, step.receive), (connect_request("example.com", 8333).unwrap(), 4));
+
+        // A bound domain is read by its length first
+        let step = negotiation.next(&[VERSION, 0x00, 0x00, DOMAIN]).unwrap().unwrap();
+        assert_eq!((step.send.len(), step.receive), (0, 1));
+        let step = negotiation.next(&[9]).unwrap().unwrap();
+        assert_eq!((step.send.len(), step.receive), (0, 9 + 2));
+        assert!(negotiation.next(&[0; 11]).unwrap().is_none());
+    }
+}
@@ -3,25 +3,272 @@
 // Module for TCP related code.
 //
 
-use crate::net::{
-    peer::{
-        Peer
+use crate::{
+    msg::{
+        agent::UserAgent,
+        network::{
+            ProtocolVersion,
+            Service,
+            ServicesList
+        }
     },
-    Error
+    net::{
+        peer::{
+            AddressFamily,
+            Peer,
+            Host
+        },
+        ping::{
+            INACTIVITY_TIMEOUT,
+            PING_INTERVAL,
+            PING_TIMEOUT
+        },
+        socks,
+        sync::HeaderChain,
+        Error
+    }
+};
+use socket2::{
+    Domain,
+    Socket,
+    Type
 };
 use std::{
-    net::TcpStream,
-    io::{
-        Write,
-        Read,
-        BufReader
-    }
+    io,
+    net::{
+        SocketAddr,
+        TcpStream
+    },
+    time::Duration
 };
 
-/// Create a tcp stream from a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Socket options applied to outbound streams
+pub struct StreamOptions {
+    /// Time allowed to establish a connection
+    pub connect_timeout: Duration,
+    /// Time a read may block before failing. `None` blocks forever. Only bounds the handshake
+    /// of a [`Connection`](super::connection::Connection): once it is established, how long a
+    /// peer may stay silent is bounded by [`inactivity_timeout`](Self::inactivity_timeout).
+    pub read_timeout: Option<Duration>,
+    /// Time a write may block before failing. `None` blocks forever.
+    pub write_timeout: Option<Duration>,
+    /// SOCKS5 proxy to connect through, such as a local Tor client
+    pub proxy: Option<SocketAddr>,
+    /// Local address and port to connect from, to pick the interface of a multi-homed host.
+    /// Port 0 lets the system choose the source port.
+    pub bind: Option<SocketAddr>,
+    /// Attempt the BIP324 v2 transport, falling back to v1 if the peer does not speak it
+    pub v2: bool,
+    /// IP address families that may be dialed
+    pub family: AddressFamily,
+    /// Ask peers to announce transactions to us, the relay flag of our version message
+    pub relay: bool,
+    /// Address peers can reach us at, sent as addr_from in our version message. Zeroed
+    /// when unknown, as bitcoin core sends it.
+    pub external: Option<SocketAddr>,
+    /// User agent sent in our version message
+    pub user_agent: UserAgent,
+    /// Height of our best chain sent in our version message, see [`for_chain`](Self::for_chain)
+    pub start_height: u32,
+    /// Oldest protocol version accepted from peers, older ones are disconnected after the handshake
+    pub min_version: ProtocolVersion,
+    /// Time a peer has to answer a ping before it is disconnected
+    pub ping_timeout: Duration,
+    /// Time a peer may send nothing at all before it is disconnected. `None` waits forever.
+    pub inactivity_timeout: Option<Duration>
+}
+
+impl Default for StreamOptions {
+    /// Defaults follow bitcoin core:
+    /// * 5 second connect timeout
+    /// * 20 minute read timeout until the handshake completes
+    /// * 20 minute write timeout
+    /// * No proxy
+    /// * Connections from any local address
+    /// * v1 transport
+    /// * IPv4 and IPv6
+    /// * No transaction relay
+    /// * No external address
+    /// * This library's user agent
+    /// * Start height of 0, as we have no chain until headers are synced
+    /// * Peers older than protocol version 31800 are disconnected
+    /// * Peers are disconnected after 20 minutes without answering a ping or sending anything
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Some(Duration::from_secs(20 * 60)),
+            write_timeout: Some(Duration::from_secs(20 * 60)),
+            proxy: None,
+            bind: None,
+            v2: false,
+            family: AddressFamily::Any,
+            relay: false,
+            external: None,
+            user_agent: UserAgent::default(),
+            start_height: 0,
+            min_version: ProtocolVersion::MIN_PEER,
+            ping_timeout: PING_TIMEOUT,
+            inactivity_timeout: Some(INACTIVITY_TIMEOUT)
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Use the v2 transport with a peer if it advertises `NODE_P2P_V2` in `services`
+    pub fn for_services(mut self, services: &ServicesList) -> Self {
+        self.v2 = services.has(Service::P2PV2);
+        self
+    }
+
+    /// Advertise the tip of a synced header chain as our start height
+    pub fn for_chain(mut self, chain: &HeaderChain) -> Self {
+        self.start_height = chain.height();
+        self
+    }
+
+    /// Read timeout of established connections, short enough for pings to be sent and
+    /// stalled peers noticed on time
+    pub(crate) fn wake_interval(&self) -> Duration {
+        self.inactivity_timeout.map_or(PING_INTERVAL, |t| t.min(PING_INTERVAL)).min(self.ping_timeout)
+    }
+}
+
+/// Create a tcp stream from a peer using the default stream options. Peers carry their port,
+/// create them with [`Peer::for_network`] to dial a network's default port.
 pub fn stream_from(peer: Peer) -> Result<TcpStream, Error> {
-    match TcpStream::connect(peer.to_string()) {
-        Ok(x) => Ok(x),
-        Err(_) => Err(Error::FailedToConnect(peer.to_string()))
+    stream_with(peer, &StreamOptions::default())
+}
+
+/// Create a tcp stream from a peer, applying the given timeouts, proxy and bind address
+pub fn stream_with(peer: Peer, options: &StreamOptions) -> Result<TcpStream, Error> {
+    if let Host::I2p(_) = peer.addr {
+        return Err(Error::Proxy(format!("I2P peer {} must be dialed through a SamSession", peer)))
+    }
+    if !options.family.allows(&peer.addr) {
+        return Err(Error::FailedToConnect(format!("{} is excluded by the address family", peer)))
+    }
+
+    // Onion peers can only be reached through the proxy
+    let stream = match (peer.socket_addr(), options.proxy) {
+        (Some(addr), None) => match connect(addr, options) {
+            Ok(x) => x,
+            Err(_) => return Err(Error::FailedToConnect(peer.to_string()))
+        },
+        _ => return stream_to_host(&peer.addr.to_string(), peer.port.to_u16(), options)
+    };
+
+    stream.set_read_timeout(options.read_timeout)?;
+    stream.set_write_timeout(options.write_timeout)?;
+    Ok(stream)
+}
+
+/// Create a tcp stream to a host by name through the configured proxy.
+/// The proxy resolves the name, so this works for onion addresses over Tor.
+pub fn stream_to_host(host: &str, port: u16, options: &StreamOptions) -> Result<TcpStream, Error> {
+    let proxy = match options.proxy {
+        Some(proxy) => proxy,
+        None => return Err(Error::Proxy(format!("No proxy configured to reach {}", host)))
+    };
+
+    let mut stream = match connect(proxy, options) {
+        Ok(x) => x,
+        Err(_) => return Err(Error::FailedToConnect(proxy.to_string()))
+    };
+    stream.set_read_timeout(Some(options.connect_timeout))?;
+    stream.set_write_timeout(Some(options.connect_timeout))?;
+    socks::handshake(&mut stream, host, port)?;
+
+    stream.set_read_timeout(options.read_timeout)?;
+    stream.set_write_timeout(options.write_timeout)?;
+    Ok(stream)
+}
+
+// Open a TCP connection from the bind address of the options, if any
+fn connect(addr: SocketAddr, options: &StreamOptions) -> io::Result<TcpStream> {
+    let bind = match options.bind {
+        Some(bind) => bind,
+        None => return TcpStream::connect_timeout(&addr, options.connect_timeout)
+    };
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // Lets a fixed source port be reused while old connections from it are in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    socket.bind(&bind.into())?;
+    socket.connect_timeout(&addr.into(), options.connect_timeout)?;
+    Ok(socket.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        msg::header::Magic,
+        net::reader::MessageReader
+    };
+    use std::{
+        io::ErrorKind,
+        net::{
+            Ipv4Addr,
+            Ipv6Addr,
+            TcpListener
+        }
+    };
+
+    #[test]
+    fn read_timeout() {
+        // Listener that accepts but never sends anything
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let options = StreamOptions {
+            read_timeout: Some(Duration::from_millis(50)),
+            ..StreamOptions::default()
+        };
+
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, port);
+        let stream = stream_with(peer, &options).unwrap();
+        let mut reader = MessageReader::new(stream, Magic::Regtest);
+        match reader.read_message() {
+            Err(Error::Io(e)) => assert!(matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)),
+            _ => panic!("Expected a timeout")
+        }
+    }
+
+    #[test]
+    fn dials_ipv6() {
+        let listener = TcpListener::bind("[::1]:0").unwrap();
+        let peer = Peer::new(Ipv6Addr::LOCALHOST, listener.local_addr().unwrap().port());
+
+        assert!(stream_from(peer).is_ok());
+        let options = StreamOptions {
+            family: AddressFamily::Ipv4Only,
+            ..StreamOptions::default()
+        };
+        assert!(matches!(stream_with(peer, &options), Err(Error::FailedToConnect(_))));
+    }
+
+    #[test]
+    fn binds_local_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        let source = TcpListener::bind("127.0.0.2:0").map(|l| l.local_addr().unwrap());
+
+        // Loopback addresses besides 127.0.0.1 are not configured on every platform
+        if let Ok(source) = source {
+            let options = StreamOptions {
+                bind: Some(source),
+                ..StreamOptions::default()
+            };
+            stream_with(peer, &options).unwrap();
+            assert_eq!(listener.accept().unwrap().1, source);
+        }
+
+        // An address of another family cannot reach the peer
+        let options = StreamOptions {
+            bind: Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 0))),
+            ..StreamOptions::default()
+        };
+        assert!(matches!(stream_with(peer, &options), Err(Error::FailedToConnect(_))));
     }
-}
\ No newline at end of file
+}
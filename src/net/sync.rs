@@ -0,0 +1,271 @@
+// sync.rs
+//
+// Module for synchronizing block headers with peers: requesting them with
+// getheaders and a block locator, and keeping the chain of headers with the
+// most work in memory.
+//
+
+use crate::{
+    bitcoin::util::uint::Uint256,
+    blockdata::{
+        header::BlockHeader,
+        BlockHash,
+        Hash
+    },
+    msg::{
+        data::MessagePayload,
+        header::{
+            Command,
+            Magic
+        },
+        inventory::BlockdataLocatorInfo
+    },
+    params::Params,
+    net::{
+        connection::Connection,
+        misbehavior::Misbehavior,
+        Error
+    }
+};
+use std::{
+    collections::HashMap,
+    io::{
+        Read,
+        Write
+    }
+};
+
+/// Most headers sent in a single headers message (MAX_HEADERS_RESULTS in bitcoin core)
+pub const MAX_HEADERS: usize = 2000;
+
+/// The header chain with the most work known so far, from the genesis block to the tip.
+///
+/// Headers must link up and meet the target in their bits field. Bits are not checked
+/// against the difficulty adjustment rules.
+pub struct HeaderChain {
+    // Headers by height
+    headers: Vec<BlockHeader>,
+    // Cumulative work up to each height
+    work: Vec<Uint256>,
+    heights: HashMap<BlockHash, u32>
+}
+
+impl HeaderChain {
+    /// Start a chain from its genesis block header
+    pub fn new(genesis: BlockHeader) -> Self {
+        Self {
+            heights: vec![(genesis.block_hash(), 0)].into_iter().collect(),
+            work: vec![genesis.work()],
+            headers: vec![genesis]
+        }
+    }
+
+    /// Start the chain of a network from the genesis block of its [`Params`]. The genesis
+    /// block of custom networks is not known, use [`new`](Self::new) with their genesis header.
+    pub fn for_network(magic: Magic) -> Option<Self> {
+        Params::for_network(magic).genesis.map(Self::new)
+    }
+
+    /// Height of the tip
+    pub fn height(&self) -> u32 {
+        (self.headers.len() - 1) as u32
+    }
+
+    /// Hash of the tip
+    pub fn tip(&self) -> BlockHash {
+        self.headers[self.headers.len() - 1].block_hash()
+    }
+
+    /// Header at a height
+    pub fn header(&self, height: u32) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    /// Height of a header in the chain
+    pub fn height_of(&self, hash: &BlockHash) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Block locator for the tip: the last 10 hashes, then hashes at exponentially growing
+    /// steps back, ending with the genesis block
+    pub fn locator(&self) -> Vec<BlockHash> {
+        let mut hashes = vec![];
+        let mut height = self.height() as i64;
+        let mut step = 1;
+        while height > 0 {
+            hashes.push(self.headers[height as usize].block_hash());
+            if hashes.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+        hashes.push(self.headers[0].block_hash());
+        hashes
+    }
+
+    /// Headers following the first hash of `locator` found in the chain, up to `limit`.
+    /// This is how getheaders requests are answered.
+    pub fn headers_after(&self, locator: &[BlockHash], limit: usize) -> Vec<BlockHeader> {
+        let start = locator.iter().find_map(|h| self.height_of(h)).unwrap_or(0) as usize + 1;
+        self.headers.iter().skip(start).take(limit).copied().collect()
+    }
+
+    /// Add headers received from a peer, returning how many became part of the chain.
+    ///
+    /// The headers must follow on from a header in the chain. A branch that forks off
+    /// before the tip replaces the tip only if it has more work.
+    pub fn extend(&mut self, headers: &[BlockHeader]) -> Result<usize, Error> {
+        let violation = |reason: &str| Error::Misbehavior(Misbehavior::ProtocolViolation(reason.to_string()));
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(0)
+        };
+        let fork = self.height_of(&first.prev_blockhash.into()).ok_or_else(|| violation("Headers do not connect to the chain"))?;
+
+        let mut work = self.work[fork as usize];
+        let mut prev = first.prev_blockhash;
+        for header in headers {
+            if header.prev_blockhash != prev {
+                return Err(violation("Headers are not continuous"))
+            }
+            if !header.check_pow() {
+                return Err(violation("Header with invalid proof of work"))
+            }
+            prev = header.block_hash().into();
+            work = work + header.work();
+        }
+
+        // Skip headers the chain already has
+        let mut height = fork as usize;
+        let mut new = headers;
+        while !new.is_empty() && self.headers.get(height + 1) == Some(&new[0]) {
+            height += 1;
+            new = &new[1..];
+        }
+        if new.is_empty() || work <= self.work[self.work.len() - 1] {
+            return Ok(0)
+        }
+
+        for header in self.headers.drain(height + 1..) {
+            self.heights.remove(&header.block_hash());
+        }
+        self.work.truncate(height + 1);
+        for header in new {
+            let work = self.work[self.work.len() - 1] + header.work();
+            self.heights.insert(header.block_hash(), self.headers.len() as u32);
+            self.headers.push(*header);
+            self.work.push(work);
+        }
+        Ok(new.len())
+    }
+}
+
+/// Request headers from a peer until it has no more to send, returning the height of the tip.
+/// Other messages received in the meantime are dropped.
+pub fn sync_headers<S: Read + Write>(conn: &mut Connection<S>, chain: &mut HeaderChain) -> Result<u32, Error> {
+    loop {
+        let locator = BlockdataLocatorInfo::new(conn.version().0, chain.locator(), BlockHash::from_inner([0; 32]));
+        conn.send_payload(MessagePayload::BlockLocator(locator), Command::GetHeaders)?;
+
+        let headers = loop {
+            if let MessagePayload::Headers(headers) = conn.recv()?.payload {
+                break headers
+            }
+        };
+        chain.extend(&headers)?;
+        if headers.len() < MAX_HEADERS {
+            return Ok(chain.height())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        msg::network::VersionMessage
+    };
+    use std::{
+        net::{
+            TcpListener,
+            TcpStream
+        },
+        thread
+    };
+
+    // Build on `prev` with a regtest difficulty header
+    fn mine(prev: &BlockHeader, time: u32) -> BlockHeader {
+        let mut header = BlockHeader { prev_blockhash: prev.block_hash().into(), time, nonce: 0, ..*prev };
+        while !header.check_pow() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn branch(from: &BlockHeader, len: usize, time: u32) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for _ in 0..len {
+            let header = mine(headers.last().unwrap_or(from), time);
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn follows_most_work() {
+        let mut chain = HeaderChain::for_network(Magic::Regtest).unwrap();
+        let genesis = *chain.header(0).unwrap();
+        let main = branch(&genesis, 20, 1);
+        assert_eq!(chain.extend(&main).unwrap(), 20);
+        assert_eq!(chain.tip(), main[19].block_hash());
+        assert_eq!(chain.extend(&main[5..10]).unwrap(), 0);
+
+        // 10 recent hashes, then steps of 2, 4 and 8 back
+        let heights: Vec<u32> = chain.locator().iter().map(|h| chain.height_of(h).unwrap()).collect();
+        assert_eq!(heights, vec![20, 19, 18, 17, 16, 15, 14, 13, 12, 11, 9, 5, 0]);
+        assert_eq!(chain.headers_after(&chain.locator()[3..], 2), main[17..19].to_vec());
+
+        // A shorter fork is ignored, a longer one takes over
+        assert_eq!(chain.extend(&branch(&main[9], 5, 2)).unwrap(), 0);
+        let fork = branch(&main[9], 11, 3);
+        assert_eq!(chain.extend(&fork).unwrap(), 11);
+        assert_eq!((chain.height(), chain.tip()), (21, fork[10].block_hash()));
+        assert_eq!(chain.height_of(&main[19].block_hash()), None);
+
+        assert!(chain.extend(&branch(&mine(&genesis, 4), 1, 4)).is_err());
+        let mut invalid = mine(&fork[10], 5);
+        invalid.bits = 0x1d00ffff;
+        assert!(chain.extend(&[invalid]).is_err());
+    }
+
+    #[test]
+    fn syncs_from_peer() {
+        let mut theirs = HeaderChain::for_network(Magic::Regtest).unwrap();
+        theirs.extend(&branch(theirs.header(0).unwrap(), MAX_HEADERS + 5, 1)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let version = VersionMessage::builder(Address::me()).start_height(theirs.height()).build();
+            let mut conn = Connection::accept(stream, Magic::Regtest, version).unwrap();
+            while let Ok(msg) = conn.recv() {
+                if let MessagePayload::BlockLocator(locator) = msg.payload {
+                    let headers = theirs.headers_after(&locator.hashes, MAX_HEADERS);
+                    conn.send_payload(MessagePayload::Headers(headers), Command::Headers).unwrap();
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = Connection::handshake(stream, Magic::Regtest, VersionMessage::builder(Address::from(addr)).build()).unwrap();
+        let mut ours = HeaderChain::for_network(Magic::Regtest).unwrap();
+        assert_eq!(sync_headers(&mut conn, &mut ours).unwrap(), conn.peer_version().start_height);
+
+        // Later connections advertise the synced tip
+        let options = crate::net::stream::StreamOptions::default().for_chain(&ours);
+        assert_eq!(options.start_height, conn.peer_version().start_height);
+    }
+}
@@ -0,0 +1,233 @@
+// timedata.rs
+//
+// Network adjusted time, modelled on bitcoin core's timedata: the clocks of outbound
+// peers, as reported in their version messages, are sampled and the median offset
+// from the local clock is applied to it.
+//
+
+use crate::{
+    msg::network::VersionMessage,
+    net::peer::Host
+};
+use std::{
+    collections::{
+        HashSet,
+        VecDeque
+    },
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+use tracing::warn;
+
+/// Number of most recent offsets the median is taken over
+pub const MAX_SAMPLES: usize = 200;
+
+/// Number of samples needed before the local clock is adjusted, counting the local clock itself
+pub const MIN_SAMPLES: usize = 5;
+
+/// Largest median offset that is applied to the local clock (DEFAULT_MAX_TIME_ADJUSTMENT in bitcoin core)
+pub const MAX_ADJUSTMENT: Duration = Duration::from_secs(70 * 60);
+
+/// Median offset above which the local clock is considered skewed (WARN_THRESHOLD in bitcoin core)
+pub const SKEW_WARNING: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone)]
+/// Offsets of peers' clocks from the local clock, in seconds.
+///
+/// Like bitcoin core, each host is sampled once, the local clock counts as the first sample
+/// and the median is only used with an odd number of at least [`MIN_SAMPLES`] samples.
+/// Medians beyond [`MAX_ADJUSTMENT`] are not applied.
+pub struct TimeData {
+    samples: VecDeque<i64>,
+    sampled: HashSet<Host>,
+    offset: i64,
+    warning: Duration,
+    warned: bool
+}
+
+impl TimeData {
+    /// Start with only the local clock sampled
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::from(vec![0]),
+            sampled: HashSet::new(),
+            offset: 0,
+            warning: SKEW_WARNING,
+            warned: false
+        }
+    }
+
+    /// Change the median offset above which the local clock is reported as skewed
+    pub fn set_warning_threshold(&mut self, threshold: Duration) {
+        self.warning = threshold;
+    }
+
+    /// Sample the clock of a peer that sent `version` at `received`.
+    /// Returns false if the host has already been sampled.
+    pub fn add_version(&mut self, host: Host, version: &VersionMessage, received: SystemTime) -> bool {
+        self.add(host, version_offset(version, received))
+    }
+
+    /// Sample a host whose clock is `offset` seconds ahead of ours.
+    /// Returns false if the host has already been sampled.
+    pub fn add(&mut self, host: Host, offset: i64) -> bool {
+        if !self.sampled.insert(host) {
+            return false
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(offset);
+
+        // An even number of samples keeps the previous offset, as in bitcoin core
+        if self.samples.len() >= MIN_SAMPLES && self.samples.len() % 2 == 1 {
+            let median = self.median();
+            self.offset = match median.unsigned_abs() <= MAX_ADJUSTMENT.as_secs() {
+                true => median,
+                false => 0
+            };
+        }
+
+        match self.skew() {
+            Some(skew) if !self.warned => {
+                self.warned = true;
+                warn!(offset = skew, peers = self.samples.len() - 1, "Local clock differs from peers by over {} s, check the system time", self.warning.as_secs());
+            },
+            Some(_) => {},
+            None => self.warned = false
+        }
+        true
+    }
+
+    /// Median of the sampled offsets in seconds, including the local clock's
+    pub fn median(&self) -> i64 {
+        let mut sorted: Vec<i64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        match sorted.len() % 2 {
+            // Summed wider so that offsets near the limits of i64 cannot overflow
+            0 => ((sorted[sorted.len() / 2 - 1] as i128 + sorted[sorted.len() / 2] as i128) / 2) as i64,
+            _ => sorted[sorted.len() / 2]
+        }
+    }
+
+    /// Seconds added to the local clock to get network adjusted time
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    /// Local time adjusted by the median offset of peers
+    pub fn network_time(&self) -> SystemTime {
+        let offset = Duration::from_secs(self.offset.unsigned_abs());
+        match self.offset < 0 {
+            true => SystemTime::now() - offset,
+            false => SystemTime::now() + offset
+        }
+    }
+
+    /// Median offset if it exceeds the warning threshold, once there are enough samples
+    pub fn skew(&self) -> Option<i64> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None
+        }
+        let median = self.median();
+        match median.unsigned_abs() > self.warning.as_secs() {
+            true => Some(median),
+            false => None
+        }
+    }
+
+    /// Number of peers sampled, out of at most the last [`MAX_SAMPLES`]
+    pub fn len(&self) -> usize {
+        self.samples.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for TimeData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Seconds `version`'s timestamp is ahead of the local clock at `received`, clamped to
+/// the range of i64 for peer timestamps no real clock could report
+pub fn version_offset(version: &VersionMessage, received: SystemTime) -> i64 {
+    let received = received.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let offset = version.timestamp.as_secs() as i128 - received.as_secs() as i128;
+    offset.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn host(n: u8) -> Host {
+        Host::from(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    #[test]
+    fn adjusts_by_median_offset() {
+        let mut time = TimeData::new();
+        for (n, offset) in [30, 40, 50].iter().enumerate() {
+            assert!(time.add(host(n as u8), *offset));
+        }
+        // Too few samples to adjust, and each host counts once
+        assert_eq!(time.offset(), 0);
+        assert!(!time.add(host(0), 1000));
+
+        assert!(time.add(host(3), 60));
+        assert_eq!((time.len(), time.median(), time.offset()), (4, 40, 40));
+        // Even numbers of samples keep the last offset
+        time.add(host(4), -20);
+        assert_eq!(time.offset(), 40);
+        assert!(time.skew().is_none());
+
+        let ahead = time.network_time().duration_since(SystemTime::now()).unwrap();
+        assert!(ahead > Duration::from_secs(35) && ahead <= Duration::from_secs(40));
+    }
+
+    #[test]
+    fn reports_skew_beyond_threshold() {
+        let mut time = TimeData::new();
+        for n in 0..4 {
+            time.add(host(n), 2 * 60 * 60);
+        }
+        // Skewed beyond the largest adjustment, so the local clock is used as is
+        assert_eq!(time.offset(), 0);
+        assert_eq!(time.skew(), Some(2 * 60 * 60));
+
+        let mut time = TimeData::new();
+        time.set_warning_threshold(Duration::from_secs(60));
+        let received = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let version = VersionMessage::builder(crate::address::Address::me()).timestamp(Duration::from_secs(1_600_000_090)).build();
+        assert_eq!(version_offset(&version, received), 90);
+        for n in 0..4 {
+            time.add_version(host(n), &version, received);
+        }
+        assert_eq!((time.offset(), time.skew()), (90, Some(90)));
+    }
+
+    #[test]
+    fn tolerates_extreme_timestamps() {
+        let received = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let version = VersionMessage::builder(crate::address::Address::me()).timestamp(Duration::from_secs(u64::MAX)).build();
+        assert_eq!(version_offset(&version, received), i64::MAX);
+        let version = VersionMessage::builder(crate::address::Address::me()).timestamp(Duration::from_secs(1 << 63)).build();
+        assert_eq!(version_offset(&version, SystemTime::UNIX_EPOCH), i64::MAX);
+
+        // The median of an even number of samples averages the middle two without overflowing
+        let mut time = TimeData::new();
+        for n in 0..3 {
+            time.add(host(n), i64::MAX);
+        }
+        assert_eq!(time.median(), i64::MAX);
+        time.add(host(3), i64::MAX);
+        assert_eq!((time.median(), time.offset()), (i64::MAX, 0));
+    }
+}
@@ -0,0 +1,136 @@
+// traffic.rs
+//
+// Module for counting the messages and bytes exchanged with a peer, per command.
+//
+
+use crate::{
+    msg::{
+        data::Message,
+        header::Command
+    },
+    net::reader::HEADER_SIZE
+};
+use std::{
+    collections::HashMap,
+    fmt
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Number of messages and their total size in bytes
+pub struct Counter {
+    pub messages: u64,
+    pub bytes: u64
+}
+
+impl Counter {
+    fn add(&mut self, other: Counter) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Messages sent to and received from a peer, per command.
+///
+/// Sizes are of the serialized message including its 24 byte header, which is what
+/// goes over the wire with the v1 transport. The extra overhead of v2 packets is not counted.
+pub struct Traffic {
+    pub sent: HashMap<Command, Counter>,
+    pub received: HashMap<Command, Counter>
+}
+
+impl Traffic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record_sent(&mut self, msg: &Message) {
+        Self::record(&mut self.sent, msg);
+    }
+
+    pub(crate) fn record_received(&mut self, msg: &Message) {
+        Self::record(&mut self.received, msg);
+    }
+
+    /// Add the messages of another connection, such as one that has closed
+    pub fn add(&mut self, other: &Traffic) {
+        for (counters, others) in [(&mut self.sent, &other.sent), (&mut self.received, &other.received)] {
+            for (command, counter) in others {
+                counters.entry(command.clone()).or_default().add(*counter);
+            }
+        }
+    }
+
+    fn record(counters: &mut HashMap<Command, Counter>, msg: &Message) {
+        counters.entry(msg.header.command.clone()).or_default().add(Counter {
+            messages: 1,
+            bytes: (HEADER_SIZE + msg.header.length as usize) as u64
+        });
+    }
+
+    /// Messages and bytes sent over all commands
+    pub fn total_sent(&self) -> Counter {
+        Self::total(&self.sent)
+    }
+
+    /// Messages and bytes received over all commands
+    pub fn total_received(&self) -> Counter {
+        Self::total(&self.received)
+    }
+
+    fn total(counters: &HashMap<Command, Counter>) -> Counter {
+        let mut total = Counter::default();
+        counters.values().for_each(|c| total.add(*c));
+        total
+    }
+}
+
+impl fmt::Display for Traffic {
+    /// Table of the messages and bytes sent and received per command, ordered by command
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut commands: Vec<&Command> = self.sent.keys().chain(self.received.keys()).collect();
+        commands.sort_by_key(|c| c.to_str());
+        commands.dedup();
+
+        writeln!(f, "{:<12} {:>10} {:>12} {:>10} {:>12}", "command", "sent", "sent bytes", "received", "recv bytes")?;
+        let row = |f: &mut fmt::Formatter<'_>, name: &str, sent: Counter, received: Counter| {
+            writeln!(f, "{:<12} {:>10} {:>12} {:>10} {:>12}", name, sent.messages, sent.bytes, received.messages, received.bytes)
+        };
+        for command in commands {
+            let sent = self.sent.get(command).copied().unwrap_or_default();
+            let received = self.received.get(command).copied().unwrap_or_default();
+            row(f, command.to_str(), sent, received)?;
+        }
+        row(f, "total", self.total_sent(), self.total_received())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        msg::data::MessagePayload,
+        Magic
+    };
+
+    #[test]
+    fn counts_traffic() {
+        let ping = Message::new(MessagePayload::PingPong(1), Magic::Main, Command::Ping);
+        let verack = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::Verack);
+
+        let mut traffic = Traffic::new();
+        traffic.record_sent(&ping);
+        traffic.record_sent(&ping);
+        traffic.record_sent(&verack);
+        traffic.record_received(&verack);
+
+        assert_eq!(traffic.sent[&Command::Ping], Counter { messages: 2, bytes: 64 });
+        assert_eq!(traffic.total_sent(), Counter { messages: 3, bytes: 88 });
+        assert_eq!(traffic.total_received(), Counter { messages: 1, bytes: 24 });
+
+        let table = traffic.to_string();
+        let rows: Vec<&str> = table.lines().map(|l| l.split_whitespace().next().unwrap()).collect();
+        assert_eq!(rows, vec!["command", "ping", "verack", "total"]);
+    }
+}
@@ -0,0 +1,741 @@
+// v2.rs
+//
+// BIP324 encrypted v2 transport.
+//   https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki
+//
+// Peers exchange ElligatorSwift encoded public keys followed by random garbage,
+// derive session keys from the x-only ECDH secret and then exchange messages in
+// packets encrypted with ChaCha20Poly1305. Packet lengths are encrypted separately
+// with ChaCha20 so the stream looks uniformly random to an observer.
+//
+
+use crate::{
+    blockdata::hash::Hash,
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Command,
+            Magic,
+            MessageHeader
+        }
+    },
+    encode::Encode,
+    net::{
+        reader::{
+            MAX_PAYLOAD_SIZE,
+            READ_CHUNK
+        },
+        Error
+    }
+};
+use chacha20::{
+    cipher::{
+        KeyIvInit,
+        StreamCipher
+    },
+    ChaCha20
+};
+use chacha20poly1305::{
+    aead::{
+        Aead,
+        KeyInit,
+        Payload
+    },
+    ChaCha20Poly1305
+};
+use hkdf::Hkdf;
+use rand::Rng;
+use secp256k1::{
+    ellswift::{
+        ElligatorSwift,
+        ElligatorSwiftParty
+    },
+    Secp256k1,
+    SecretKey
+};
+use sha2::Sha256;
+use std::{
+    collections::HashSet,
+    io::{
+        ErrorKind,
+        Read,
+        Write
+    }
+};
+
+/// Number of packets (or lengths) encrypted with a key before it is replaced
+const REKEY_INTERVAL: u64 = 224;
+
+/// Length of an ElligatorSwift encoded public key
+const KEY_SIZE: usize = 64;
+
+/// Most garbage a peer may send before its garbage terminator
+const MAX_GARBAGE: usize = 4095;
+
+/// Length of a garbage terminator
+const TERMINATOR_SIZE: usize = 16;
+
+/// Length of the encrypted contents length that starts every packet
+const LENGTH_SIZE: usize = 3;
+
+/// Length of the packet header byte and the authentication tag
+const HEADER_SIZE: usize = 1;
+const TAG_SIZE: usize = 16;
+
+/// Header bit marking a decoy packet that must be ignored
+const IGNORE: u8 = 0x80;
+
+/// Largest contents length accepted from a peer (matches the v1 payload limit plus a command)
+const MAX_CONTENTS: usize = MAX_PAYLOAD_SIZE as usize + 13;
+
+/// Commands sent as a single byte, indexed by their short ID. ID 0 means a 12 byte
+/// command follows.
+const SHORT_IDS: [&str; 29] = [
+    "", "addr", "block", "blocktxn", "cmpctblock", "feefilter", "filteradd", "filterclear",
+    "filterload", "getblocks", "getblocktxn", "getdata", "getheaders", "headers", "inv",
+    "mempool", "merkleblock", "notfound", "ping", "pong", "sendcmpct", "tx", "getcfilters",
+    "cfilter", "getcfheaders", "cfheaders", "getcfcheckpt", "cfcheckpt", "addrv2"
+];
+
+/// ChaCha20 stream that is rekeyed every `REKEY_INTERVAL` chunks, used to encrypt lengths
+struct FsChaCha20 {
+    cipher: ChaCha20,
+    chunk_counter: u64
+}
+
+impl FsChaCha20 {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: Self::cipher(&key, 0),
+            chunk_counter: 0
+        }
+    }
+
+    // The nonce is the rekey epoch, the keystream continues across chunks within an epoch
+    fn cipher(key: &[u8; 32], epoch: u64) -> ChaCha20 {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&epoch.to_le_bytes());
+        ChaCha20::new(key.into(), &nonce.into())
+    }
+
+    /// Encrypt or decrypt a chunk in place
+    fn crypt(&mut self, chunk: &mut [u8]) {
+        self.cipher.apply_keystream(chunk);
+        self.chunk_counter += 1;
+
+        if self.chunk_counter.is_multiple_of(REKEY_INTERVAL) {
+            let mut key = [0; 32];
+            self.cipher.apply_keystream(&mut key);
+            self.cipher = Self::cipher(&key, self.chunk_counter / REKEY_INTERVAL);
+        }
+    }
+}
+
+/// ChaCha20Poly1305 AEAD that is rekeyed every `REKEY_INTERVAL` packets
+struct FsChaCha20Poly1305 {
+    key: [u8; 32],
+    packet_counter: u64
+}
+
+impl FsChaCha20Poly1305 {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            packet_counter: 0
+        }
+    }
+
+    fn nonce(prefix: [u8; 4], epoch: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..4].copy_from_slice(&prefix);
+        nonce[4..].copy_from_slice(&epoch.to_le_bytes());
+        nonce
+    }
+
+    /// Encrypt (or decrypt, if `decrypt` is set) `text` authenticating `aad` along with it
+    fn crypt(&mut self, aad: &[u8], text: &[u8], decrypt: bool) -> Result<Vec<u8>, Error> {
+        let epoch = self.packet_counter / REKEY_INTERVAL;
+        let index = (self.packet_counter % REKEY_INTERVAL) as u32;
+        let aead = ChaCha20Poly1305::new(&self.key.into());
+        let nonce = Self::nonce(index.to_le_bytes(), epoch);
+        let payload = Payload { msg: text, aad };
+
+        let out = match decrypt {
+            true => aead.decrypt(&nonce.into(), payload),
+            false => aead.encrypt(&nonce.into(), payload)
+        }.map_err(|_| Error::Handshake(String::from("Failed to authenticate v2 packet")))?;
+
+        self.packet_counter += 1;
+        if self.packet_counter.is_multiple_of(REKEY_INTERVAL) {
+            let rekey = aead.encrypt(&Self::nonce([0xFF; 4], epoch).into(), &[0u8; 32][..]).expect("Encryption cannot fail");
+            self.key.copy_from_slice(&rekey[..32]);
+        }
+        Ok(out)
+    }
+}
+
+/// Sending half of a v2 session
+pub struct PacketEncoder {
+    length: FsChaCha20,
+    packet: FsChaCha20Poly1305
+}
+
+impl PacketEncoder {
+    /// Encrypt packet contents. Decoy packets set `ignore`.
+    pub(crate) fn encrypt(&mut self, contents: &[u8], aad: &[u8], ignore: bool) -> Vec<u8> {
+        let mut len = (contents.len() as u32).to_le_bytes()[..LENGTH_SIZE].to_vec();
+        self.length.crypt(&mut len);
+
+        let mut plaintext = Vec::with_capacity(HEADER_SIZE + contents.len());
+        plaintext.push(if ignore { IGNORE } else { 0 });
+        plaintext.extend_from_slice(contents);
+
+        let mut packet = len;
+        packet.extend(self.packet.crypt(aad, &plaintext, false).expect("Encryption cannot fail"));
+        packet
+    }
+
+    /// Encrypt a message into a packet
+    pub fn encode_message(&mut self, msg: &Message) -> Vec<u8> {
+        self.encrypt(&message_contents(msg), &[], false)
+    }
+}
+
+/// Receiving half of a v2 session
+pub struct PacketDecoder {
+    magic: Magic,
+    length: FsChaCha20,
+    packet: FsChaCha20Poly1305,
+    // Decrypted length of the packet being received, lengths can only be decrypted once
+    pending: Option<usize>,
+    // The first packet after the garbage terminator authenticates the garbage
+    aad: Vec<u8>
+}
+
+impl PacketDecoder {
+    /// Take the next packet out of a buffer of received bytes, returning whether it is
+    /// a decoy and its contents. Returns `None` if more bytes are needed.
+    pub(crate) fn next_packet(&mut self, buf: &mut Vec<u8>) -> Option<Result<(bool, Vec<u8>), Error>> {
+        let len = match self.pending {
+            Some(len) => len,
+            None => {
+                if buf.len() < LENGTH_SIZE {
+                    return None
+                }
+                let mut len = [0; 4];
+                len[..LENGTH_SIZE].copy_from_slice(&buf[..LENGTH_SIZE]);
+                self.length.crypt(&mut len[..LENGTH_SIZE]);
+                buf.drain(..LENGTH_SIZE);
+
+                let len = u32::from_le_bytes(len) as usize;
+                if len > MAX_CONTENTS {
+                    return Some(Err(Error::Handshake(format!("Oversized v2 packet of {} bytes", len))))
+                }
+                self.pending = Some(len);
+                len
+            }
+        };
+
+        let total = HEADER_SIZE + len + TAG_SIZE;
+        if buf.len() < total {
+            return None
+        }
+        self.pending = None;
+
+        let aad = std::mem::take(&mut self.aad);
+        let plaintext = self.packet.crypt(&aad, &buf[..total], true);
+        buf.drain(..total);
+
+        Some(plaintext.map(|p| (p[0] & IGNORE != 0, p[HEADER_SIZE..].to_vec())))
+    }
+
+    /// Take the next message out of a buffer of received bytes, skipping decoy packets and
+    /// messages with commands outside of `filter`. Returns `None` if more bytes are needed.
+    pub(crate) fn next_message(&mut self, buf: &mut Vec<u8>, filter: Option<&HashSet<Command>>) -> Option<Result<Message, Error>> {
+        loop {
+            match self.next_packet(buf)? {
+                Ok((true, _)) => continue,
+                Ok((false, contents)) => {
+                    let unwanted = |(command, _): (Command, &[u8])| filter.is_some_and(|f| !f.contains(&command));
+                    if split_contents(&contents).is_ok_and(unwanted) {
+                        continue
+                    }
+                    return Some(decode_contents(&contents, self.magic))
+                },
+                Err(e) => return Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Encode a message's command and payload as v2 packet contents
+pub(crate) fn message_contents(msg: &Message) -> Vec<u8> {
+    let command = msg.header.command.to_str();
+    let mut contents = match SHORT_IDS.iter().skip(1).position(|c| *c == command) {
+        Some(i) => vec![i as u8 + 1],
+        None => {
+            let mut contents = vec![0; 13];
+            contents[1..1 + command.len().min(12)].copy_from_slice(&command.as_bytes()[..command.len().min(12)]);
+            contents
+        }
+    };
+    msg.payload.net_encode(&mut contents);
+    contents
+}
+
+/// Decode v2 packet contents into a message for the given network
+pub(crate) fn decode_contents(contents: &[u8], magic: Magic) -> Result<Message, Error> {
+    let (command, payload) = split_contents(contents)?;
+    let header = MessageHeader::new(magic, command, payload.len(), Hash::sha256d(payload).checksum());
+    let payload = MessagePayload::decode_with(&header, payload)?;
+    Ok(Message { header, payload })
+}
+
+/// Split v2 packet contents into the message's command and its encoded payload
+fn split_contents(contents: &[u8]) -> Result<(Command, &[u8]), Error> {
+    let invalid = || Error::Handshake(String::from("Invalid v2 message contents"));
+    let (command, payload) = match contents.first() {
+        Some(0) if contents.len() >= 13 => {
+            let name = &contents[1..13];
+            let end = name.iter().position(|b| *b == 0).unwrap_or(12);
+            (String::from_utf8_lossy(&name[..end]).into_owned(), &contents[13..])
+        },
+        Some(id) => (SHORT_IDS.get(*id as usize).filter(|c| !c.is_empty()).ok_or_else(invalid)?.to_string(), &contents[1..]),
+        None => return Err(invalid())
+    };
+
+    let command = Command::from_str(command).unwrap_or_else(|e| match e {
+        crate::encode::Error::UnknownCommand(c) => Command::Unknown(c),
+        _ => Command::Unknown(String::new())
+    });
+    Ok((command, payload))
+}
+
+/// Progress of the v2 key exchange, independent of how bytes are sent and received.
+///
+/// Completes once the peer's garbage terminator and version packet have been received.
+/// The version packet carries no data yet, the v1 version handshake follows over the
+/// encrypted transport.
+pub(crate) struct Handshake {
+    magic: Magic,
+    initiator: bool,
+    secret: SecretKey,
+    ours: ElligatorSwift,
+    garbage: Vec<u8>,
+    // Set once the peer's key has been received
+    session: Option<(PacketEncoder, PacketDecoder, [u8; TERMINATOR_SIZE])>,
+    garbage_received: bool,
+    complete: bool
+}
+
+impl Handshake {
+    /// Start a key exchange, returning the bytes to send straight away.
+    /// The initiator sends its key immediately, the responder waits for the initiator's.
+    pub(crate) fn new(magic: Magic, initiator: bool) -> (Self, Vec<u8>) {
+        let mut rng = rand::thread_rng();
+        let secret = loop {
+            if let Ok(key) = SecretKey::from_slice(&rng.gen::<[u8; 32]>()) {
+                break key
+            }
+        };
+        let ours = ElligatorSwift::from_seckey(&Secp256k1::new(), secret, Some(rng.gen()));
+        let garbage: Vec<u8> = (0..rng.gen_range(0..=MAX_GARBAGE)).map(|_| rng.gen()).collect();
+
+        let handshake = Self {
+            magic,
+            initiator,
+            secret,
+            ours,
+            garbage,
+            session: None,
+            garbage_received: false,
+            complete: false
+        };
+        let hello = match initiator {
+            true => handshake.hello(),
+            false => Vec::new()
+        };
+        (handshake, hello)
+    }
+
+    // Our public key followed by our garbage
+    fn hello(&self) -> Vec<u8> {
+        let mut hello = self.ours.to_array().to_vec();
+        hello.extend_from_slice(&self.garbage);
+        hello
+    }
+
+    /// Process received bytes, consuming them from `buf`. Returns bytes to send in reply.
+    pub(crate) fn receive(&mut self, buf: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        if self.session.is_none() {
+            if buf.len() < KEY_SIZE {
+                return Ok(out)
+            }
+
+            let mut theirs = [0; KEY_SIZE];
+            theirs.copy_from_slice(&buf[..KEY_SIZE]);
+            buf.drain(..KEY_SIZE);
+            if !self.initiator {
+                out.extend(self.hello());
+            }
+
+            let (mut encoder, decoder, send_terminator, recv_terminator) = self.derive(ElligatorSwift::from_array(theirs));
+            out.extend_from_slice(&send_terminator);
+            out.extend(encoder.encrypt(&[], &self.garbage, false));
+            self.session = Some((encoder, decoder, recv_terminator));
+        }
+
+        let (_, decoder, terminator) = self.session.as_mut().expect("Session keys derived");
+        if !self.garbage_received {
+            match buf.windows(TERMINATOR_SIZE).position(|w| w == terminator) {
+                Some(pos) if pos <= MAX_GARBAGE => {
+                    decoder.aad = buf[..pos].to_vec();
+                    buf.drain(..pos + TERMINATOR_SIZE);
+                    self.garbage_received = true;
+                },
+                _ if buf.len() >= MAX_GARBAGE + TERMINATOR_SIZE => {
+                    return Err(Error::Handshake(String::from("Missing v2 garbage terminator")))
+                },
+                _ => return Ok(out)
+            }
+        }
+
+        // Decoys may precede the version packet, whose contents are ignored
+        while let Some(packet) = decoder.next_packet(buf) {
+            if !packet?.0 {
+                self.complete = true;
+                break
+            }
+        }
+        Ok(out)
+    }
+
+    /// Key derivation of the session, keyed by the ECDH secret with the peer's public key
+    fn session_keys(&self, theirs: ElligatorSwift) -> Hkdf<Sha256> {
+        let (a, b, party) = match self.initiator {
+            true => (self.ours, theirs, ElligatorSwiftParty::A),
+            false => (theirs, self.ours, ElligatorSwiftParty::B)
+        };
+        let secret = ElligatorSwift::shared_secret(a, b, self.secret, party, None);
+
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&self.magic.bytes().to_le_bytes());
+        Hkdf::<Sha256>::new(Some(&salt), secret.as_secret_bytes())
+    }
+
+    /// Derive the session ciphers and garbage terminators from the peer's public key
+    fn derive(&self, theirs: ElligatorSwift) -> (PacketEncoder, PacketDecoder, [u8; TERMINATOR_SIZE], [u8; TERMINATOR_SIZE]) {
+        let hkdf = self.session_keys(theirs);
+        let expand = |info: &str| {
+            let mut key = [0; 32];
+            hkdf.expand(info.as_bytes(), &mut key).expect("Valid length");
+            key
+        };
+
+        let (send, recv) = match self.initiator {
+            true => ("initiator", "responder"),
+            false => ("responder", "initiator")
+        };
+        let terminators = expand("garbage_terminators");
+        let (mut send_terminator, mut recv_terminator) = ([0; TERMINATOR_SIZE], [0; TERMINATOR_SIZE]);
+        let (first, second) = terminators.split_at(TERMINATOR_SIZE);
+        match self.initiator {
+            true => { send_terminator.copy_from_slice(first); recv_terminator.copy_from_slice(second); },
+            false => { send_terminator.copy_from_slice(second); recv_terminator.copy_from_slice(first); }
+        }
+
+        let encoder = PacketEncoder {
+            length: FsChaCha20::new(expand(&format!("{}_L", send))),
+            packet: FsChaCha20Poly1305::new(expand(&format!("{}_P", send)))
+        };
+        let decoder = PacketDecoder {
+            magic: self.magic,
+            length: FsChaCha20::new(expand(&format!("{}_L", recv))),
+            packet: FsChaCha20Poly1305::new(expand(&format!("{}_P", recv))),
+            pending: None,
+            aad: Vec::new()
+        };
+        (encoder, decoder, send_terminator, recv_terminator)
+    }
+
+    /// Check if received bytes show the initiator is a v1 peer sending its version message.
+    /// Such a peer should be served over the v1 transport using the bytes received so far.
+    pub(crate) fn detects_v1(&self, buf: &[u8]) -> bool {
+        !self.initiator && self.session.is_none() && is_v1_version(buf, self.magic)
+    }
+
+    /// Check if the key exchange has completed
+    pub(crate) fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Return the session's encoder and decoder
+    pub(crate) fn finish(self) -> (PacketEncoder, PacketDecoder) {
+        let (encoder, decoder, _) = self.session.expect("Handshake incomplete");
+        (encoder, decoder)
+    }
+}
+
+/// Transport agreed on when opening a stream
+pub(crate) enum Negotiated {
+    /// Session ciphers and any bytes received after the key exchange
+    V2(PacketEncoder, Box<PacketDecoder>, Vec<u8>),
+    /// The peer is speaking v1, the bytes received so far are the start of its version message
+    V1(Vec<u8>)
+}
+
+/// Run the key exchange over a blocking stream
+pub(crate) fn negotiate<S: Read + Write>(stream: &mut S, magic: Magic, initiator: bool) -> Result<Negotiated, Error> {
+    let (mut handshake, hello) = Handshake::new(magic, initiator);
+    stream.write_all(&hello)?;
+    stream.flush()?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_CHUNK];
+    while !handshake.is_complete() {
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Io(e))
+        }
+        if handshake.detects_v1(&buf) {
+            return Ok(Negotiated::V1(buf))
+        }
+
+        let reply = handshake.receive(&mut buf)?;
+        if !reply.is_empty() {
+            stream.write_all(&reply)?;
+            stream.flush()?;
+        }
+    }
+
+    let (encoder, decoder) = handshake.finish();
+    Ok(Negotiated::V2(encoder, Box::new(decoder), buf))
+}
+
+/// Check if received bytes start like a v1 version message, meaning the peer does not
+/// speak the v2 transport
+fn is_v1_version(buf: &[u8], magic: Magic) -> bool {
+    let mut prefix = magic.bytes().to_le_bytes().to_vec();
+    prefix.extend_from_slice(b"version\0\0\0\0\0");
+    buf.len() >= prefix.len() && buf[..prefix.len()] == prefix[..]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use std::convert::TryInto;
+
+    // Run a key exchange between two handshakes in memory
+    fn exchange() -> ((PacketEncoder, PacketDecoder), (PacketEncoder, PacketDecoder)) {
+        let (mut a, hello) = Handshake::new(Magic::Main, true);
+        let (mut b, _) = Handshake::new(Magic::Main, false);
+
+        let (mut to_a, mut to_b) = (Vec::new(), hello);
+        while !a.is_complete() || !b.is_complete() {
+            to_a.extend(b.receive(&mut to_b).unwrap());
+            to_b.extend(a.receive(&mut to_a).unwrap());
+        }
+        assert!(to_a.is_empty() && to_b.is_empty());
+        (a.finish(), b.finish())
+    }
+
+    #[test]
+    fn key_exchange() {
+        let ((mut a_enc, mut a_dec), (mut b_enc, mut b_dec)) = exchange();
+
+        // Decoys are skipped and rekeying stays in step past the rekey interval
+        let mut wire = b_enc.encrypt(&[1, 2, 3], &[], true);
+        for i in 0..(REKEY_INTERVAL * 2) {
+            let msg = Message::new(MessagePayload::PingPong(i), Magic::Main, Command::Ping);
+            wire.extend(b_enc.encode_message(&msg));
+        }
+        for i in 0..(REKEY_INTERVAL * 2) {
+            assert_eq!(a_dec.next_message(&mut wire, None).unwrap().unwrap().payload, MessagePayload::PingPong(i));
+        }
+        assert!(a_dec.next_message(&mut wire, None).is_none());
+
+        // Messages arriving a byte at a time
+        let msg = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::SendAddrV2);
+        let wire = a_enc.encode_message(&msg);
+        let mut buf = Vec::new();
+        for (i, byte) in wire.iter().enumerate() {
+            buf.push(*byte);
+            let next = b_dec.next_message(&mut buf, None);
+            assert_eq!(next.is_some(), i == wire.len() - 1);
+        }
+    }
+
+    // Handshake with fixed keys, as in the BIP324 test vectors
+    fn keyed(initiator: bool, secret: &str, ours: &str) -> Handshake {
+        Handshake {
+            magic: Magic::Main,
+            initiator,
+            secret: SecretKey::from_slice(&unhex(secret)).unwrap(),
+            ours: ElligatorSwift::from_array(unhex(ours).try_into().unwrap()),
+            garbage: Vec::new(),
+            session: None,
+            garbage_received: false,
+            complete: false
+        }
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        Vec::<u8>::from_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn packet_encoding_vectors() {
+        // Rows of packet_encoding_test_vectors.csv from BIP324: packet index, initiating, our
+        // secret key and ElligatorSwift key, theirs, contents, aad, ignore, then the session
+        // id, garbage terminators sent and received, and the packet's ciphertext
+        let vectors = vec![
+            (1, true, "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+             "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+             "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+             "8e", "", false,
+             "ce72dffb015da62b0d0f5474cab8bc72605225b0cee3f62312ec680ec5f41ba5",
+             "faef555dfcdb936425d84aba524758f3", "02cb8ff24307a6e27de3b4e7ea3fa65b",
+             "7530d2a18720162ac09c25329a60d75adf36eda3c3")
+        ];
+
+        for (index, initiating, secret, ours, theirs, contents, aad, ignore, session_id, send_terminator, recv_terminator, ciphertext) in vectors {
+            let handshake = keyed(initiating, secret, ours);
+            let theirs = ElligatorSwift::from_array(unhex(theirs).try_into().unwrap());
+
+            let mut id = [0; 32];
+            handshake.session_keys(theirs).expand(b"session_id", &mut id).unwrap();
+            assert_eq!(id.to_vec(), unhex(session_id));
+
+            let (mut encoder, _, sent, received) = handshake.derive(theirs);
+            assert_eq!((sent.to_vec(), received.to_vec()), (unhex(send_terminator), unhex(recv_terminator)));
+
+            // Earlier packets only move the ciphers on, their contents do not matter
+            for _ in 0..index {
+                encoder.encrypt(&[], &[], false);
+            }
+            assert_eq!(encoder.encrypt(&unhex(contents), &unhex(aad), ignore), unhex(ciphertext));
+        }
+    }
+
+    // Packet `index` of a session as BIP324's reference code encrypts it, taking keystream
+    // block by block and rekeying after the last packet of each epoch
+    fn reference_packet(mut length_key: [u8; 32], mut packet_key: [u8; 32], index: u64, contents: &[u8], aad: &[u8]) -> Vec<u8> {
+        use chacha20::cipher::StreamCipherSeek;
+
+        fn nonce(prefix: [u8; 4], epoch: u64) -> [u8; 12] {
+            let mut nonce = [0; 12];
+            nonce[..4].copy_from_slice(&prefix);
+            nonce[4..].copy_from_slice(&epoch.to_le_bytes());
+            nonce
+        }
+        // Keystream left over from the last block and the number of blocks taken this epoch
+        struct Keystream(Vec<u8>, u64);
+        impl Keystream {
+            fn take(&mut self, key: &[u8; 32], epoch: u64, n: usize) -> Vec<u8> {
+                while self.0.len() < n {
+                    let mut block = [0; 64];
+                    let mut cipher = ChaCha20::new(key.into(), &nonce([0; 4], epoch).into());
+                    cipher.seek(self.1 * 64);
+                    cipher.apply_keystream(&mut block);
+                    self.0.extend_from_slice(&block);
+                    self.1 += 1;
+                }
+                self.0.drain(..n).collect()
+            }
+        }
+        let mut keystream = Keystream(Vec::new(), 0);
+
+        let mut packet = Vec::new();
+        for n in 0..=index {
+            let (epoch, last) = (n / REKEY_INTERVAL, (n + 1) % REKEY_INTERVAL == 0);
+            let (contents, aad) = match n == index {
+                true => (contents, aad),
+                false => (&[][..], &[][..])
+            };
+
+            let len = (contents.len() as u32).to_le_bytes();
+            packet = keystream.take(&length_key, epoch, LENGTH_SIZE).iter().zip(len.iter()).map(|(k, b)| k ^ b).collect();
+            if last {
+                length_key = keystream.take(&length_key, epoch, 32).try_into().unwrap();
+                keystream = Keystream(Vec::new(), 0);
+            }
+
+            let aead = ChaCha20Poly1305::new(&packet_key.into());
+            let plaintext = [&[0][..], contents].concat();
+            let index = ((n % REKEY_INTERVAL) as u32).to_le_bytes();
+            packet.extend(aead.encrypt(&nonce(index, epoch).into(), Payload { msg: &plaintext, aad }).unwrap());
+            if last {
+                packet_key.copy_from_slice(&aead.encrypt(&nonce([0xFF; 4], epoch).into(), &[0; 32][..]).unwrap()[..32]);
+            }
+        }
+        packet
+    }
+
+    #[test]
+    fn rekeys_as_reference() {
+        // The session of the first packet encoding vector, checked against the reference
+        // code around each rekey up to packet 999
+        let handshake = keyed(true, "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+            "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b");
+        let theirs = ElligatorSwift::from_array(unhex("a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5").try_into().unwrap());
+        let (mut length_key, mut packet_key) = ([0; 32], [0; 32]);
+        handshake.session_keys(theirs).expand(b"initiator_L", &mut length_key).unwrap();
+        handshake.session_keys(theirs).expand(b"initiator_P", &mut packet_key).unwrap();
+
+        let (contents, aad) = (unhex("8e"), unhex("c0ffee"));
+        for index in [REKEY_INTERVAL - 1, REKEY_INTERVAL, 2 * REKEY_INTERVAL + 1, 999] {
+            let (mut encoder, ..) = handshake.derive(theirs);
+            for _ in 0..index {
+                encoder.encrypt(&[], &[], false);
+            }
+            let packet = encoder.encrypt(&contents, &aad, false);
+            assert_eq!(packet, reference_packet(length_key, packet_key, index, &contents, &aad), "Packet {}", index);
+        }
+    }
+
+    #[test]
+    fn tampered_packets() {
+        let ((mut a_enc, _), (_, mut b_dec)) = exchange();
+        let mut wire = a_enc.encode_message(&Message::new(MessagePayload::PingPong(1), Magic::Main, Command::Ping));
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+        assert!(matches!(b_dec.next_message(&mut wire, None), Some(Err(_))));
+    }
+
+    #[test]
+    fn short_command_ids() {
+        let ping = Message::new(MessagePayload::PingPong(7), Magic::Main, Command::Ping);
+        assert_eq!(message_contents(&ping)[0], 18);
+        assert_eq!(decode_contents(&message_contents(&ping), Magic::Main).unwrap(), ping);
+
+        // Commands without a short ID are sent in full
+        let verack = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::Verack);
+        let contents = message_contents(&verack);
+        assert_eq!(contents, b"\0verack\0\0\0\0\0\0");
+        assert_eq!(decode_contents(&contents, Magic::Main).unwrap(), verack);
+
+        assert!(decode_contents(&[29], Magic::Main).is_err());
+    }
+
+    #[test]
+    fn detects_v1_peers() {
+        let (b, _) = Handshake::new(Magic::Main, false);
+        let version = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::Version);
+        let mut wire = Vec::new();
+        version.net_encode(&mut wire);
+        assert!(!b.detects_v1(&wire[..15]));
+        assert!(b.detects_v1(&wire));
+
+        // Initiators never expect a v1 reply to their key
+        let (a, _) = Handshake::new(Magic::Main, true);
+        assert!(!a.detects_v1(&wire));
+    }
+}
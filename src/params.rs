@@ -0,0 +1,174 @@
+// params.rs
+//
+// Parameters of each bitcoin network: the magic bytes its messages start with,
+// the port its nodes listen on, the DNS seeds to discover them through and the
+// genesis block its header chain starts from. Code that depends on the network
+// looks these up with `Params::for_network` instead of keeping tables of its own.
+//
+// DNS seeds are from `chainparams.cpp` in bitcoin core.
+//
+
+use crate::{
+    blockdata::{
+        hash::Hash,
+        header::BlockHeader,
+        BlockHash
+    },
+    msg::header::Magic
+};
+
+pub const MAIN_DNS_SEEDS: [&str; 10] = [
+    "seed.bitcoin.sipa.be",
+    "dnsseed.bluematt.me",
+    "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+    "seed.bitcoinstats.com",
+    "seed.bitcoin.jonasschnelli.ch",
+    "seed.btc.petertodd.net",
+    "seed.bitcoin.sprovoost.nl",
+    "dnsseed.emzy.de",
+    "seed.bitcoin.wiz.biz",
+    "seed.mainnet.achownodes.xyz"
+];
+
+pub const TEST_DNS_SEEDS: [&str; 5] = [
+    "testnet-seed.bitcoin.jonasschnelli.ch",
+    "seed.tbtc.petertodd.net",
+    "seed.testnet.bitcoin.sprovoost.nl",
+    "testnet-seed.bluematt.me",
+    "seed.testnet.achownodes.xyz"
+];
+
+pub const TESTNET4_DNS_SEEDS: [&str; 2] = [
+    "seed.testnet4.bitcoin.sprovoost.nl",
+    "seed.testnet4.wiz.biz"
+];
+
+pub const SIGNET_DNS_SEEDS: [&str; 2] = [
+    "seed.signet.bitcoin.sprovoost.nl",
+    "seed.signet.achownodes.xyz"
+];
+
+// Merkle root of the genesis coinbase shared by mainnet, testnet3, signet and regtest
+const GENESIS_MERKLE_ROOT: Hash = Hash::from_bytes([
+    0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+    0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a
+]);
+
+// Testnet4's genesis coinbase has a message of its own
+const TESTNET4_MERKLE_ROOT: Hash = Hash::from_bytes([
+    0x4e, 0x7b, 0x2b, 0x91, 0x28, 0xfe, 0x02, 0x91, 0xdb, 0x06, 0x93, 0xaf, 0x2a, 0xe4, 0x18, 0xb7,
+    0x67, 0xe6, 0x57, 0xcd, 0x40, 0x7e, 0x80, 0xcb, 0x14, 0x34, 0x22, 0x1e, 0xae, 0xa7, 0xa0, 0x7a
+]);
+
+const fn genesis(merkle_root: Hash, time: u32, bits: u32, nonce: u32) -> BlockHeader {
+    BlockHeader {
+        version: 1,
+        prev_blockhash: Hash::ZERO,
+        merkle_root,
+        time,
+        bits,
+        nonce
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Parameters of a network
+pub struct Params {
+    pub magic: Magic,
+    /// Port nodes listen on unless configured otherwise
+    pub port: u16,
+    /// Hostnames that resolve to addresses of nodes
+    pub dns_seeds: &'static [&'static str],
+    /// Header of the genesis block, unknown for custom networks
+    pub genesis: Option<BlockHeader>
+}
+
+impl Params {
+    pub const MAIN: Self = Self {
+        magic: Magic::Main,
+        port: 8333,
+        dns_seeds: &MAIN_DNS_SEEDS,
+        genesis: Some(genesis(GENESIS_MERKLE_ROOT, 1231006505, 0x1d00ffff, 2083236893))
+    };
+
+    pub const TESTNET: Self = Self {
+        magic: Magic::Test,
+        port: 18333,
+        dns_seeds: &TEST_DNS_SEEDS,
+        genesis: Some(genesis(GENESIS_MERKLE_ROOT, 1296688602, 0x1d00ffff, 414098458))
+    };
+
+    pub const TESTNET4: Self = Self {
+        magic: Magic::Testnet4,
+        port: 48333,
+        dns_seeds: &TESTNET4_DNS_SEEDS,
+        genesis: Some(genesis(TESTNET4_MERKLE_ROOT, 1714777860, 0x1d00ffff, 393743547))
+    };
+
+    pub const SIGNET: Self = Self {
+        magic: Magic::Signet,
+        port: 38333,
+        dns_seeds: &SIGNET_DNS_SEEDS,
+        genesis: Some(genesis(GENESIS_MERKLE_ROOT, 1598918400, 0x1e0377ae, 52613770))
+    };
+
+    pub const REGTEST: Self = Self {
+        magic: Magic::Regtest,
+        port: 18444,
+        dns_seeds: &[],
+        genesis: Some(genesis(GENESIS_MERKLE_ROOT, 1296688602, 0x207fffff, 2))
+    };
+
+    /// Parameters of the network with the given magic. Custom networks are assumed to
+    /// listen on mainnet's port and have no seeds or known genesis block.
+    pub fn for_network(magic: Magic) -> Self {
+        match magic {
+            Magic::Main => Self::MAIN,
+            Magic::Test => Self::TESTNET,
+            Magic::Testnet4 => Self::TESTNET4,
+            Magic::Signet => Self::SIGNET,
+            Magic::Regtest => Self::REGTEST,
+            Magic::Custom(_) => Self {
+                magic,
+                port: Self::MAIN.port,
+                dns_seeds: &[],
+                genesis: None
+            }
+        }
+    }
+
+    /// Hash of the genesis block
+    pub fn genesis_hash(&self) -> Option<BlockHash> {
+        self.genesis.map(|g| g.block_hash())
+    }
+}
+
+impl Magic {
+    /// Parameters of the network, see [`Params::for_network`]
+    pub fn params(&self) -> Params {
+        Params::for_network(*self)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{
+        blockdata::constants::genesis_block,
+        Network
+    };
+
+    #[test]
+    fn genesis_blocks() {
+        for (magic, network) in [(Magic::Main, Network::Bitcoin), (Magic::Test, Network::Testnet), (Magic::Signet, Network::Signet), (Magic::Regtest, Network::Regtest)] {
+            assert_eq!(magic.params().genesis, Some(genesis_block(network).header.into()));
+        }
+        let testnet4 = Magic::Testnet4.params().genesis_hash().unwrap();
+        assert_eq!(testnet4.to_string(), "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043");
+        assert_eq!(Params::MAIN.genesis_hash().map(Hash::from), Some(Hash::from({ let mut b = crate::blockdata::GENESIS_HASH; b.reverse(); b })));
+
+        let custom = Params::for_network(Magic::Custom([1, 2, 3, 4]));
+        assert_eq!((custom.port, custom.dns_seeds.len(), custom.genesis), (8333, 0, None));
+    }
+}
@@ -0,0 +1,307 @@
+// python.rs
+//
+// Python bindings built with PyO3, exposing messages, version messages and
+// the decoders of recorded traffic to scripts:
+//
+//      import btcnetmsg
+//      for time, direction, peer, msg in btcnetmsg.replay("session.cap"):
+//          if msg.version:
+//              print(peer, msg.version.user_agent, msg.version.start_height)
+//
+// Only compiled with the `python` feature. `pip install .` builds the extension
+// module with maturin, see pyproject.toml.
+//
+
+use crate::{
+    address::Address,
+    encode::{
+        self,
+        decode_partial
+    },
+    msg::{
+        agent::UserAgent,
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::{
+            Command,
+            Magic,
+            MessageHeader,
+            HEADER_SIZE
+        },
+        network::{
+            ProtocolVersion,
+            ServicesList,
+            VersionMessage,
+            VersionMessageBuilder
+        }
+    },
+    net::{
+        capture::Direction,
+        replay::Replay,
+        Error
+    }
+};
+use pyo3::{
+    exceptions::{
+        PyOSError,
+        PyValueError
+    },
+    prelude::*,
+    types::PyBytes
+};
+use std::{
+    net::SocketAddr,
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+
+fn value_error(e: encode::Error) -> PyErr {
+    PyValueError::new_err(format!("{:?}", e))
+}
+
+fn net_error(e: Error) -> PyErr {
+    match e {
+        Error::Io(e) => PyOSError::new_err(e.to_string()),
+        Error::Decode(e) => value_error(e),
+        e => PyValueError::new_err(format!("{:?}", e))
+    }
+}
+
+fn parse_network(name: &str) -> PyResult<Magic> {
+    name.parse().map_err(value_error)
+}
+
+/// A message of the bitcoin P2P protocol
+#[pyclass(name = "Message", module = "btcnetmsg", frozen)]
+#[derive(Clone)]
+pub struct PyMessage(Message);
+
+#[pymethods]
+impl PyMessage {
+    /// Create a message of a network, such as "main" or "signet", from a command and its
+    /// encoded payload. Payloads of commands without a decoder are kept as given.
+    #[new]
+    #[pyo3(signature = (network, command, payload = None))]
+    fn new(network: &str, command: &str, payload: Option<&[u8]>) -> PyResult<Self> {
+        let magic = parse_network(network)?;
+        let command = Command::from_str(command.to_string()).unwrap_or_else(|_| Command::Unknown(command.to_string()));
+        let payload = payload.unwrap_or_default();
+        let header = MessageHeader::new(magic, command.clone(), payload.len(), [0; 4]);
+        let payload = MessagePayload::decode_with(&header, payload).map_err(value_error)?;
+        Ok(Self(Message::new(payload, magic, command)))
+    }
+
+    /// Decode a message from the start of `data`. The checksum is not verified.
+    #[staticmethod]
+    fn decode(data: &[u8]) -> PyResult<Self> {
+        decode_partial::<Message>(data).map(|(msg, _)| Self(msg)).map_err(value_error)
+    }
+
+    /// The encoded message
+    fn to_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.0.to_bytes())
+    }
+
+    /// Network magic as the little endian integer of its wire bytes
+    #[getter]
+    fn magic(&self) -> u32 {
+        self.0.header.magic.bytes()
+    }
+
+    #[getter]
+    fn command(&self) -> &str {
+        self.0.header.command.to_str()
+    }
+
+    /// The encoded payload
+    #[getter]
+    fn payload<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.0.to_bytes()[HEADER_SIZE..])
+    }
+
+    /// The decoded payload of a version message, None for other messages
+    #[getter]
+    fn version(&self) -> Option<PyVersionMessage> {
+        match &self.0.payload {
+            MessagePayload::Version(v) => Some(PyVersionMessage(v.clone())),
+            _ => None
+        }
+    }
+
+    /// The encoded message as an annotated hexdump
+    fn hexdump(&self) -> String {
+        self.0.hexdump()
+    }
+
+    /// The fields of the encoded message as (name, start, end, value) tuples, byte offsets
+    /// counted from the start of the header
+    fn dissect(&self) -> Vec<(String, usize, usize, String)> {
+        self.0.dissect().into_iter().map(|f| (f.name, f.range.start, f.range.end, f.value)).collect()
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Message {}>", self.0)
+    }
+}
+
+/// The payload of a version message, sent first on every connection
+#[pyclass(name = "VersionMessage", module = "btcnetmsg", frozen)]
+#[derive(Clone)]
+pub struct PyVersionMessage(VersionMessage);
+
+#[pymethods]
+impl PyVersionMessage {
+    /// Create a version message to send to the peer at `addr_recv`, such as "203.0.113.5:8333"
+    #[new]
+    #[pyo3(signature = (addr_recv, version = None, services = 0, user_agent = None, start_height = 0, relay = false, nonce = None))]
+    fn new(addr_recv: &str, version: Option<u32>, services: u64, user_agent: Option<&str>, start_height: u32, relay: bool, nonce: Option<u64>) -> PyResult<Self> {
+        let addr: SocketAddr = addr_recv.parse().map_err(|_| PyValueError::new_err(format!("Invalid address {}", addr_recv)))?;
+        let mut builder = VersionMessageBuilder::new(Address::from(addr))
+            .services(ServicesList::from_bits(services))
+            .start_height(start_height)
+            .relay(relay);
+        if let Some(version) = version {
+            builder = builder.version(ProtocolVersion(version));
+        }
+        if let Some(agent) = user_agent {
+            builder = builder.user_agent(UserAgent::parse(agent).map_err(value_error)?);
+        }
+        if let Some(nonce) = nonce {
+            builder = builder.nonce(nonce);
+        }
+        Ok(Self(builder.build()))
+    }
+
+    /// A message of the given network carrying this version message
+    fn to_message(&self, network: &str) -> PyResult<PyMessage> {
+        Ok(PyMessage(Message::new(MessagePayload::Version(self.0.clone()), parse_network(network)?, Command::Version)))
+    }
+
+    #[getter]
+    fn version(&self) -> u32 {
+        self.0.version.0
+    }
+
+    /// Service flags as bits
+    #[getter]
+    fn services(&self) -> u64 {
+        self.0.service.bits()
+    }
+
+    /// Unix time in seconds
+    #[getter]
+    fn timestamp(&self) -> u64 {
+        self.0.timestamp.as_secs()
+    }
+
+    #[getter]
+    fn addr_recv(&self) -> String {
+        self.0.addr_recv.address.0.to_string()
+    }
+
+    #[getter]
+    fn addr_from(&self) -> String {
+        self.0.addr_from.address.0.to_string()
+    }
+
+    #[getter]
+    fn nonce(&self) -> u64 {
+        self.0.nonce
+    }
+
+    #[getter]
+    fn user_agent(&self) -> &str {
+        self.0.agent.as_str()
+    }
+
+    #[getter]
+    fn start_height(&self) -> u32 {
+        self.0.start_height
+    }
+
+    #[getter]
+    fn relay(&self) -> bool {
+        self.0.relay
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<VersionMessage {}>", MessagePayload::Version(self.0.clone()))
+    }
+}
+
+/// Decode the messages of a raw dump, as sent on the wire, skipping bytes between them.
+/// Messages are expected to be of the given network, otherwise of the first message's.
+/// Messages that fail to decode are left out.
+#[pyfunction]
+#[pyo3(signature = (data, network = None))]
+fn decode_messages(data: &[u8], network: Option<&str>) -> PyResult<Vec<PyMessage>> {
+    let replay = match network {
+        Some(name) => Replay::dump(data, parse_network(name)?),
+        None => Replay::new(data).map_err(net_error)?
+    };
+    Ok(replay.filter_map(Result::ok).map(|m| PyMessage(m.message)).collect())
+}
+
+/// Decode a capture file or raw dump, returning (time, direction, peer, message) tuples.
+/// Time is in unix seconds and direction is "sent" or "received"; both are None, like peer,
+/// for raw dumps. Messages that fail to decode are left out.
+#[pyfunction]
+#[allow(clippy::type_complexity)]
+fn replay(path: &str) -> PyResult<Vec<(Option<f64>, Option<&'static str>, Option<String>, PyMessage)>> {
+    let replay = Replay::open(path).map_err(net_error)?;
+    Ok(replay.filter_map(Result::ok).map(|m| (
+        m.time.map(|t| t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs_f64()),
+        m.direction.map(|d| match d {
+            Direction::Sent => "sent",
+            Direction::Received => "received"
+        }),
+        m.peer.map(|p| p.to_string()),
+        PyMessage(m.message)
+    )).collect())
+}
+
+#[pymodule]
+fn btcnetmsg(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyMessage>()?;
+    m.add_class::<PyVersionMessage>()?;
+    m.add_function(wrap_pyfunction!(decode_messages, m)?)?;
+    m.add_function(wrap_pyfunction!(replay, m)?)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    #[test]
+    fn scripts_messages() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new_bound(py, "btcnetmsg").unwrap();
+            btcnetmsg(&module).unwrap();
+            let globals = PyDict::new_bound(py);
+            globals.set_item("btcnetmsg", module).unwrap();
+            py.run_bound(r#"
+version = btcnetmsg.VersionMessage("203.0.113.5:8333", user_agent="/Satoshi:27.0/", start_height=850000, relay=True)
+msg = btcnetmsg.Message.decode(version.to_message("main").to_bytes())
+assert msg.command == "version" and msg.version.start_height == 850000
+assert msg.version.user_agent == "/Satoshi:27.0/" and msg.version.addr_recv == "203.0.113.5:8333"
+
+ping = btcnetmsg.Message("main", "ping", (7).to_bytes(8, "little"))
+assert str(ping) == "ping: nonce=7" and ping.payload == (7).to_bytes(8, "little")
+assert [m.command for m in btcnetmsg.decode_messages(ping.to_bytes() + b"junk" + msg.to_bytes())] == ["ping", "version"]
+assert ping.dissect()[-1] == ("nonce", 24, 32, "7")
+"#, Some(&globals), None).unwrap();
+        });
+    }
+}
@@ -0,0 +1,168 @@
+// resolver.rs
+//
+// Name resolution for the DNS seeds. Seeds are looked up with the system
+// resolver unless given another Resolver: a table of hosts for labs and
+// offline setups, a closure for anything custom such as DNS over HTTPS, or
+// the hickory resolver with the `hickory` feature.
+//
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{
+        IpAddr,
+        SocketAddr,
+        ToSocketAddrs
+    }
+};
+#[cfg(feature = "hickory")]
+pub use hickory_resolver::config::{
+    ResolverConfig,
+    ResolverOpts
+};
+
+/// Looks up the addresses behind a DNS seed's hostname.
+///
+/// Implemented for closures taking the host and port, so custom resolution can be
+/// supplied without a type of its own.
+pub trait Resolver: Send + Sync {
+    /// Resolve `host` to addresses on `port`
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+impl<F: Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Send + Sync> Resolver for F {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self(host, port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// The system's resolver, through [`ToSocketAddrs`]. Blocks until the system answers.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Fixed table of hosts, such as one read from a hosts file. Hosts that are not in the
+/// table fail to resolve, nothing is looked up elsewhere.
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve `host` to `addrs`, after any addresses it already resolves to
+    pub fn with_host<S: Into<String>>(mut self, host: S, addrs: &[IpAddr]) -> Self {
+        self.hosts.entry(host.into()).or_default().extend_from_slice(addrs);
+        self
+    }
+
+    /// Read a table in the hosts file format: an address followed by the names it is
+    /// given on each line, with `#` starting a comment
+    pub fn parse(hosts: &str) -> Result<Self, String> {
+        let mut resolver = Self::new();
+        for (n, line) in hosts.lines().enumerate() {
+            let mut fields = line.split('#').next().unwrap_or("").split_whitespace();
+            let addr = match fields.next() {
+                Some(addr) => addr.parse::<IpAddr>().map_err(|e| format!("line {}: {}", n + 1, e))?,
+                None => continue
+            };
+            for name in fields {
+                resolver = resolver.with_host(name, &[addr]);
+            }
+        }
+        Ok(resolver)
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match self.hosts.get(host) {
+            Some(addrs) => Ok(addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a known host", host)))
+        }
+    }
+}
+
+#[cfg(feature = "hickory")]
+/// The asynchronous hickory resolver, which can be configured with name servers of its
+/// own including DNS over TLS or HTTPS.
+///
+/// Async code can call [`lookup`](Self::lookup) directly. As a [`Resolver`] each lookup
+/// blocks on a runtime owned by the resolver, so it must not be used from within an
+/// async context.
+pub struct HickoryResolver {
+    resolver: hickory_resolver::TokioAsyncResolver,
+    runtime: tokio::runtime::Runtime
+}
+
+#[cfg(feature = "hickory")]
+impl HickoryResolver {
+    /// Resolve with the given name servers and options
+    pub fn new(config: ResolverConfig, options: ResolverOpts) -> io::Result<Self> {
+        Ok(Self {
+            resolver: hickory_resolver::TokioAsyncResolver::tokio(config, options),
+            runtime: tokio::runtime::Builder::new_current_thread().enable_all().build()?
+        })
+    }
+
+    /// Resolve with the system's name servers, as configured in /etc/resolv.conf on unix
+    pub fn from_system_conf() -> io::Result<Self> {
+        Ok(Self {
+            resolver: hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()?,
+            runtime: tokio::runtime::Builder::new_current_thread().enable_all().build()?
+        })
+    }
+
+    /// Resolve `host` to addresses on `port` without blocking
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let ips = self.resolver.lookup_ip(host).await?;
+        Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl Resolver for HickoryResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.runtime.block_on(self.lookup(host, port))
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl std::fmt::Debug for HickoryResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HickoryResolver").finish_non_exhaustive()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn resolves_static_hosts() {
+        let resolver = StaticResolver::parse("
+            # Lab nodes
+            10.0.0.1    seed.lab node1.lab
+            10.0.0.2    seed.lab   # second answer
+        ").unwrap();
+        let addrs = resolver.resolve("seed.lab", 18444).unwrap();
+        assert_eq!(addrs, vec![SocketAddr::from(([10, 0, 0, 1], 18444)), SocketAddr::from(([10, 0, 0, 2], 18444))]);
+        assert_eq!(resolver.resolve("node1.lab", 1).unwrap(), vec![SocketAddr::from(([10, 0, 0, 1], 1))]);
+        assert_eq!(resolver.resolve("seed.invalid", 1).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(StaticResolver::parse("not-an-ip host").is_err());
+
+        let closure = |host: &str, port| Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port + host.len() as u16)]);
+        assert_eq!(Resolver::resolve(&closure, "ab", 10).unwrap(), vec![SocketAddr::from(([127, 0, 0, 1], 12))]);
+        assert_eq!(SystemResolver.resolve("127.0.0.1", 8333).unwrap(), vec![SocketAddr::from(([127, 0, 0, 1], 8333))]);
+    }
+}
@@ -6,6 +6,399 @@
 //      Port: 2 bytes
 //
 // Only contains IPv4 seeds.
+//
+// Seeds start from the DNS seeds of each network's params, which can be
+// extended with user supplied seeds to bootstrap private networks. DNS seeds
+// are looked up with a Resolver, the system's by default, and their answers
+// can be cached in a file to start from when DNS is slow or unavailable.
+
+use crate::{
+    msg::{
+        header::Magic,
+        VariableInteger
+    },
+    address::AddrV2,
+    encode::{
+        self,
+        Decode,
+        Encode
+    },
+    params::Params,
+    net::{
+        misbehavior::BanList,
+        peer::{
+            AddressFamily,
+            Host,
+            Peer,
+            Port
+        },
+        Error
+    },
+    resolver::{
+        Resolver,
+        SystemResolver
+    }
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs,
+    path::Path,
+    sync::{
+        mpsc::channel,
+        Arc
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+        SystemTime
+    }
+};
+use tracing::debug;
+
+/// How long to wait for DNS seeds to answer
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Age past which peers in a seed cache are no longer used
+pub const CACHE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Header of seed cache files
+const CACHE_MAGIC: [u8; 4] = *b"SEED";
+const CACHE_VERSION: u8 = 1;
+
+// A seed's answer and the time it took
+type Answer = (Result<Vec<Peer>, String>, Duration);
+
+pub use crate::params::{
+    MAIN_DNS_SEEDS,
+    TEST_DNS_SEEDS,
+    TESTNET4_DNS_SEEDS,
+    SIGNET_DNS_SEEDS
+};
+
+#[derive(Clone)]
+/// Where to look for peers on a network
+pub struct Seeds {
+    /// Hostnames that resolve to addresses of peers, optionally with a `:port` suffix
+    pub dns: Vec<String>,
+    /// Peers to fall back on when no DNS seed answers
+    pub fixed: Vec<Peer>,
+    /// Port of the peers returned by DNS seeds without a port of their own
+    pub port: u16,
+    /// How long each DNS seed is given to answer
+    pub timeout: Duration,
+    /// IP address families to keep from the DNS seeds
+    pub family: AddressFamily,
+    /// Hosts left out of the results
+    pub bans: BanList,
+    /// Looks up the DNS seeds
+    pub resolver: Arc<dyn Resolver>
+}
+
+impl Seeds {
+    /// Seeds of a known network, from its [`Params`]. Regtest and custom networks have none.
+    pub fn for_network(magic: Magic) -> Self {
+        let params = Params::for_network(magic);
+        let fixed = match magic {
+            Magic::Main => MAIN_SEEDS.iter().map(|x| Peer::from(*x)).collect(),
+            _ => vec![]
+        };
+
+        Self {
+            dns: params.dns_seeds.iter().map(|x| x.to_string()).collect(),
+            fixed,
+            port: params.port,
+            timeout: RESOLVE_TIMEOUT,
+            family: AddressFamily::Any,
+            bans: BanList::new(),
+            resolver: Arc::new(SystemResolver)
+        }
+    }
+
+    /// Add a DNS seed, such as one given on the command line or in a config file
+    pub fn with_dns<S: Into<String>>(mut self, seed: S) -> Self {
+        self.dns.push(seed.into());
+        self
+    }
+
+    /// Add a fixed seed
+    pub fn with_fixed(mut self, peer: Peer) -> Self {
+        self.fixed.push(peer);
+        self
+    }
+
+    /// Set how long each DNS seed is given to answer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Only keep or prefer DNS seed results of an address family
+    pub fn with_family(mut self, family: AddressFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Leave out banned hosts, such as a ban list loaded with [`BanList::load`]
+    pub fn with_bans(mut self, bans: BanList) -> Self {
+        self.bans = bans;
+        self
+    }
+
+    /// Look up the DNS seeds with `resolver` instead of the system's resolver
+    pub fn with_resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Resolve all DNS seeds concurrently, skipping the ones that fail or do not answer
+    /// within the timeout. Each peer is returned at most once, filtered and ordered by
+    /// the address family. Banned hosts are left out.
+    pub fn resolve(&self) -> Vec<Peer> {
+        self.report().peers
+    }
+
+    /// Resolve all DNS seeds like [`resolve`](Self::resolve), also reporting how each seed answered
+    pub fn report(&self) -> SeedReport {
+        // Lookups cannot be cancelled, so seeds that time out are left to finish on their own
+        let (sender, receiver) = channel();
+        for (i, seed) in self.dns.iter().enumerate() {
+            let (sender, seed, port, resolver) = (sender.clone(), seed.clone(), self.port, Arc::clone(&self.resolver));
+            thread::spawn(move || {
+                let started = Instant::now();
+                let peers = lookup(&*resolver, &seed, port);
+                let _ = sender.send((i, peers, started.elapsed()));
+            });
+        }
+        drop(sender);
+
+        // Every lookup starts at once, so they all share the same deadline
+        let deadline = Instant::now() + self.timeout;
+        let mut answers: Vec<Option<Answer>> = vec![None; self.dns.len()];
+        while let Ok((i, peers, elapsed)) = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            answers[i] = Some((peers, elapsed));
+        }
+        let results: Vec<&[Peer]> = answers
+            .iter()
+            .map(|answer| match answer {
+                Some((Ok(peers), _)) => &peers[..],
+                _ => &[]
+            })
+            .collect();
+
+        // Merge in seed order so the result does not depend on which seed answered first
+        let mut peers: Vec<Peer> = vec![];
+        for peer in results.iter().copied().flatten() {
+            if !peers.contains(peer) && !self.bans.is_banned(&peer.addr) {
+                peers.push(*peer);
+            }
+        }
+        let peers = self.family.apply(peers);
+
+        let seeds = self.dns.iter().zip(answers.iter()).enumerate().map(|(i, (seed, answer))| {
+            let records = results[i];
+            let elsewhere = |peer: &Peer| results.iter().enumerate().any(|(j, other)| j != i && other.contains(peer));
+            SeedHealth {
+                seed: seed.clone(),
+                error: match answer {
+                    Some((Err(e), _)) => Some(e.clone()),
+                    Some(_) => None,
+                    None => Some(String::from("timed out"))
+                },
+                elapsed: answer.as_ref().map(|(_, elapsed)| *elapsed),
+                records: records.len(),
+                unique: records.iter().filter(|p| !elsewhere(p)).count(),
+                usable: records.iter().filter(|p| peers.contains(p)).count()
+            }
+        }).collect();
+
+        SeedReport {
+            seeds,
+            peers
+        }
+    }
+
+    /// Resolve the DNS seeds followed by the fixed seeds that they did not return
+    pub fn candidates(&self) -> Vec<Peer> {
+        self.with_fixed_seeds(self.resolve())
+    }
+
+    /// Like [`candidates`](Self::candidates), starting from the DNS seeds' answers cached at `path`.
+    ///
+    /// If the cache holds peers younger than [`CACHE_MAX_AGE`] they are returned straight away
+    /// and the DNS seeds are resolved in the background to refresh the cache for next time.
+    /// Otherwise the seeds are resolved now and the cache written. The cache is only
+    /// replaced when a seed answers, so it outlives DNS outages.
+    pub fn candidates_cached<P: AsRef<Path>>(&self, path: P) -> Vec<Peer> {
+        let path = path.as_ref().to_path_buf();
+        let cached: Vec<Peer> = match load_cache(&path) {
+            Ok(cached) => cached.into_iter().filter(|(p, _)| !self.bans.is_banned(&p.addr)).map(|(p, _)| p).collect(),
+            Err(_) => vec![]
+        };
+        if cached.is_empty() {
+            return self.with_fixed_seeds(self.refresh_cache(&path))
+        }
+
+        debug!(peers = cached.len(), "Using cached seed answers");
+        let seeds = self.clone();
+        thread::spawn(move || seeds.refresh_cache(&path));
+        self.with_fixed_seeds(self.family.apply(cached))
+    }
+
+    // Resolve the DNS seeds and cache their answers if there are any
+    fn refresh_cache(&self, path: &Path) -> Vec<Peer> {
+        let peers = self.resolve();
+        if !peers.is_empty() {
+            if let Err(e) = save_cache(path, &peers) {
+                debug!(error = ?e, "Failed to save seed cache");
+            }
+        }
+        peers
+    }
+
+    // Add the fixed seeds missing from `peers`
+    fn with_fixed_seeds(&self, mut peers: Vec<Peer>) -> Vec<Peer> {
+        for peer in self.fixed.iter() {
+            if !peers.contains(peer) && !self.bans.is_banned(&peer.addr) {
+                peers.push(*peer);
+            }
+        }
+
+        peers
+    }
+}
+
+impl fmt::Debug for Seeds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Seeds")
+            .field("dns", &self.dns)
+            .field("fixed", &self.fixed)
+            .field("port", &self.port)
+            .field("timeout", &self.timeout)
+            .field("family", &self.family)
+            .field("bans", &self.bans)
+            .finish_non_exhaustive()
+    }
+}
+
+// Resolve a single seed, given as a hostname with an optional port
+fn lookup(resolver: &dyn Resolver, seed: &str, port: u16) -> Result<Vec<Peer>, String> {
+    // IPv6 addresses with a port are bracketed, as in [::1]:8333
+    let (host, port) = match seed.rsplit_once(':') {
+        Some((host, p)) if !host.contains(':') || host.ends_with(']') => {
+            let port = p.parse().map_err(|_| format!("invalid port {}", p))?;
+            (host.trim_start_matches('[').trim_end_matches(']'), port)
+        },
+        _ => (seed, port)
+    };
+
+    match resolver.resolve(host, port) {
+        Ok(addrs) => Ok(addrs.into_iter().map(|addr| Peer::new(addr.ip(), addr.port())).collect()),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// How a DNS seed answered a lookup
+pub struct SeedHealth {
+    pub seed: String,
+    /// Why the lookup failed, including not answering within the timeout
+    pub error: Option<String>,
+    /// Time the seed took to answer, `None` if it did not answer in time
+    pub elapsed: Option<Duration>,
+    /// Number of addresses the seed returned
+    pub records: usize,
+    /// Addresses returned by no other seed
+    pub unique: usize,
+    /// Addresses kept after leaving out banned hosts and unwanted address families
+    pub usable: usize
+}
+
+impl SeedHealth {
+    /// Check if the seed answered with at least one address
+    pub fn is_alive(&self) -> bool {
+        self.error.is_none() && self.records > 0
+    }
+}
+
+impl std::fmt::Display for SeedHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.error, self.elapsed) {
+            (Some(e), _) => write!(f, "{} dead: {}", self.seed, e),
+            (None, _) if self.records == 0 => write!(f, "{} dead: no records", self.seed),
+            (None, elapsed) => write!(
+                f, "{} alive in {} ms: {} records, {} unique, {} usable",
+                self.seed, elapsed.unwrap_or_default().as_millis(), self.records, self.unique, self.usable
+            )
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// Result of resolving the DNS seeds, see [`Seeds::report`]
+pub struct SeedReport {
+    /// How each DNS seed answered, in the order the seeds are listed
+    pub seeds: Vec<SeedHealth>,
+    /// Peers from all seeds, as returned by [`Seeds::resolve`]
+    pub peers: Vec<Peer>
+}
+
+impl SeedReport {
+    /// Seeds that answered with at least one address
+    pub fn alive(&self) -> impl Iterator<Item = &SeedHealth> {
+        self.seeds.iter().filter(|s| s.is_alive())
+    }
+}
+
+impl std::fmt::Display for SeedReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for seed in self.seeds.iter() {
+            writeln!(f, "{}", seed)?;
+        }
+        write!(f, "{} of {} seeds alive, {} peers", self.alive().count(), self.seeds.len(), self.peers.len())
+    }
+}
+
+/// Load the peers of a seed cache written with [`save_cache`] along with when each was
+/// resolved, leaving out those older than [`CACHE_MAX_AGE`]
+pub fn load_cache<P: AsRef<Path>>(path: P) -> Result<Vec<(Peer, SystemTime)>, Error> {
+    let data = fs::read(path)?;
+    let mut r = &data[..];
+
+    let magic: [u8; 4] = Decode::net_decode(&mut r)?;
+    let version: u8 = Decode::net_decode(&mut r)?;
+    if magic != CACHE_MAGIC || version != CACHE_VERSION {
+        return Err(Error::Decode(encode::Error::InvalidData))
+    }
+
+    let mut peers = Vec::new();
+    for _ in 0..VariableInteger::net_decode(&mut r)?.inner() {
+        let host = Host::try_from(AddrV2::net_decode(&mut r)?)?;
+        let port: [u8; 2] = Decode::net_decode(&mut r)?;
+        let resolved = SystemTime::UNIX_EPOCH + Duration::from_secs(u64::net_decode(&mut r)?);
+        if resolved.elapsed().unwrap_or_default() <= CACHE_MAX_AGE {
+            peers.push((Peer { addr: host, port: Port::from(port) }, resolved));
+        }
+    }
+    Ok(peers)
+}
+
+/// Save peers resolved from the DNS seeds to a seed cache, replacing it
+pub fn save_cache<P: AsRef<Path>>(path: P, peers: &[Peer]) -> Result<(), Error> {
+    let resolved = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut data = Vec::new();
+    CACHE_MAGIC.net_encode(&mut data);
+    CACHE_VERSION.net_encode(&mut data);
+    VariableInteger(peers.len() as u64).net_encode(&mut data);
+    for peer in peers {
+        AddrV2::from(peer.addr).net_encode(&mut data);
+        peer.port.0.net_encode(&mut data);
+        resolved.net_encode(&mut data);
+    }
+
+    crate::net::write_atomic(path.as_ref(), &data)
+}
 
 pub const MAIN_SEEDS: [[u8; 6]; 512] = [
     [0x02, 0x27, 0xad, 0x7e, 0x20, 0x8d],
@@ -520,4 +913,94 @@ pub const MAIN_SEEDS: [[u8; 6]; 512] = [
     [0xda, 0xff, 0xf2, 0x72, 0x20, 0x8d],
     [0xdc, 0x85, 0x27, 0x3d, 0x20, 0x8d],
     [0xdf, 0x10, 0x1e, 0xaf, 0x20, 0x8d]
-];
\ No newline at end of file
+];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::mock::TempFile,
+        resolver::StaticResolver
+    };
+    use std::net::{
+        Ipv4Addr,
+        Ipv6Addr
+    };
+
+    #[test]
+    fn network_seeds() {
+        let main = Seeds::for_network(Magic::Main);
+        assert_eq!(main.dns.len(), MAIN_DNS_SEEDS.len());
+        assert_eq!(main.fixed.len(), MAIN_SEEDS.len());
+        assert_eq!(Seeds::for_network(Magic::Signet).port, 38333);
+
+        // Private networks are bootstrapped from user seeds only
+        let seeds = Seeds::for_network(Magic::Regtest)
+            .with_dns("127.0.0.1")
+            .with_dns("127.0.0.2:18555")
+            .with_dns("127.0.0.1")
+            .with_dns("[::1]:18444")
+            .with_dns("seed.invalid");
+        assert!(seeds.fixed.is_empty());
+        assert_eq!(seeds.resolve(), vec![
+            Peer::new(Ipv4Addr::LOCALHOST, 18444),
+            Peer::new(Ipv4Addr::new(127, 0, 0, 2), 18555),
+            Peer::new(Ipv6Addr::LOCALHOST, 18444)
+        ]);
+        assert_eq!(seeds.clone().with_family(AddressFamily::Ipv6Only).resolve(), vec![Peer::new(Ipv6Addr::LOCALHOST, 18444)]);
+
+        let mut bans = BanList::new();
+        bans.ban(Ipv4Addr::LOCALHOST.into(), Duration::from_secs(60));
+        assert_eq!(seeds.with_bans(bans).resolve().len(), 2);
+    }
+
+    #[test]
+    fn resolves_with_custom_resolver() {
+        let resolver = StaticResolver::new().with_host("seed.lab", &[Ipv4Addr::new(10, 0, 0, 1).into()]);
+        let report = Seeds::for_network(Magic::Regtest)
+            .with_dns("seed.lab")
+            .with_dns("seed.lab:18555")
+            .with_dns("127.0.0.1")
+            .with_resolver(resolver)
+            .report();
+        assert_eq!(report.peers, vec![Peer::new(Ipv4Addr::new(10, 0, 0, 1), 18444), Peer::new(Ipv4Addr::new(10, 0, 0, 1), 18555)]);
+        // Nothing is looked up outside the table
+        assert!(report.seeds[2].error.is_some());
+    }
+
+    #[test]
+    fn starts_from_cached_seeds() {
+        let path = TempFile::new("seeds.dat");
+        let lab = Peer::new(Ipv4Addr::new(10, 0, 0, 1), 18444);
+        let seeds = Seeds::for_network(Magic::Regtest)
+            .with_dns("seed.lab")
+            .with_resolver(StaticResolver::new().with_host("seed.lab", &[Ipv4Addr::new(10, 0, 0, 1).into()]));
+
+        // Without a cache the seeds are resolved and their answers cached
+        assert_eq!(seeds.candidates_cached(&path), vec![lab]);
+        let cached = load_cache(&path).unwrap();
+        assert_eq!(cached[0].0, lab);
+        assert!(cached[0].1.elapsed().unwrap() < CACHE_MAX_AGE);
+
+        // The cache is used and kept while the seeds do not answer
+        let down = seeds.with_resolver(StaticResolver::new());
+        assert_eq!(down.candidates_cached(&path), vec![lab]);
+        assert_eq!(down.resolve(), vec![]);
+        assert_eq!(load_cache(&path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reports_seed_health() {
+        let report = Seeds::for_network(Magic::Regtest)
+            .with_dns("127.0.0.1")
+            .with_dns("[::1]:18444")
+            .with_dns("127.0.0.1:18444")
+            .with_dns("seed.invalid")
+            .with_family(AddressFamily::Ipv4Only)
+            .report();
+
+        let counts: Vec<(bool, usize, usize, usize)> = report.seeds.iter().map(|s| (s.is_alive(), s.records, s.unique, s.usable)).collect();
+        assert_eq!(counts, vec![(true, 1, 0, 1), (true, 1, 1, 0), (true, 1, 0, 1), (false, 0, 0, 0)]);
+        assert_eq!(report.peers, vec![Peer::new(Ipv4Addr::LOCALHOST, 18444)]);
+        assert!(report.to_string().ends_with("3 of 4 seeds alive, 1 peers"));
+    }
+}
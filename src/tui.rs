@@ -0,0 +1,287 @@
+// tui.rs
+//
+// Terminal dashboard for a connection manager: a live table of connected
+// peers above a scrolling pane of the messages received from them.
+//
+
+use crate::{
+    msg::data::Message,
+    net::{
+        manager::{
+            ConnectionManager,
+            Slots
+        },
+        peer::Peer,
+        replay::ReplayedMessage
+    }
+};
+use ratatui::{
+    crossterm::event::{
+        self,
+        Event,
+        KeyCode,
+        KeyEventKind,
+        KeyModifiers
+    },
+    layout::{
+        Constraint,
+        Layout
+    },
+    style::{
+        Modifier,
+        Style
+    },
+    text::Line,
+    widgets::{
+        Block,
+        List,
+        Row,
+        Table
+    },
+    Frame
+};
+use std::{
+    collections::{
+        HashMap,
+        VecDeque
+    },
+    io,
+    sync::atomic::{
+        AtomicBool,
+        Ordering
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Messages kept in the message pane
+pub const LOG_CAPACITY: usize = 500;
+// Longest message line kept, payloads such as blocks can be huge
+const MAX_LINE: usize = 256;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+/// A connected peer as shown in the table
+pub struct PeerRow {
+    pub peer: Peer,
+    pub agent: String,
+    pub services: u64,
+    /// Best block height when the peer connected
+    pub height: u32,
+    pub latency: Option<Duration>,
+    /// Messages per second received and sent since the previous refresh
+    pub received_rate: f64,
+    pub sent_rate: f64
+}
+
+/// State of the dashboard, refreshed from a manager and drawn to a frame
+pub struct Dashboard {
+    rows: Vec<PeerRow>,
+    slots: Slots,
+    log: VecDeque<String>,
+    // Messages received and sent per peer at the previous refresh
+    counts: HashMap<Peer, (u64, u64)>,
+    refreshed: Option<Instant>
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self {
+            rows: vec![],
+            slots: Slots::default(),
+            log: VecDeque::new(),
+            counts: HashMap::new(),
+            refreshed: None
+        }
+    }
+
+    /// Reload the connected peers from a manager, computing message rates since the last refresh
+    pub fn refresh(&mut self, manager: &ConnectionManager, now: Instant) {
+        let elapsed = self.refreshed.map_or(0.0, |t| now.duration_since(t).as_secs_f64());
+        let mut counts = HashMap::new();
+        self.rows = manager.connected().into_iter().filter_map(|peer| {
+            let version = manager.peer_version(&peer)?;
+            let traffic = manager.traffic(&peer)?;
+            let count = (traffic.total_received().messages, traffic.total_sent().messages);
+            let rate = |now: u64, before: u64| match elapsed > 0.0 {
+                true => now.saturating_sub(before) as f64 / elapsed,
+                false => 0.0
+            };
+            let before = self.counts.get(&peer).copied().unwrap_or(count);
+            counts.insert(peer, count);
+
+            Some(PeerRow {
+                peer,
+                agent: version.agent.as_str().to_string(),
+                services: version.service.bits(),
+                height: version.start_height,
+                latency: manager.latency(&peer),
+                received_rate: rate(count.0, before.0),
+                sent_rate: rate(count.1, before.1)
+            })
+        }).collect();
+        self.rows.sort_by_key(|r| r.peer.to_string());
+        self.slots = manager.slots();
+        self.counts = counts;
+        self.refreshed = Some(now);
+    }
+
+    /// Add a received message to the message pane, dropping the oldest beyond [`LOG_CAPACITY`]
+    pub fn push(&mut self, peer: Peer, msg: Message) {
+        let line: String = ReplayedMessage::received(peer, msg).to_string().chars().take(MAX_LINE).collect();
+        if self.log.len() == LOG_CAPACITY {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    /// Peers shown in the table, ordered by address
+    pub fn rows(&self) -> &[PeerRow] {
+        &self.rows
+    }
+
+    /// Draw the peer table and, below it, the latest messages that fit
+    pub fn draw(&self, frame: &mut Frame) {
+        let [top, bottom] = Layout::vertical([Constraint::Percentage(40), Constraint::Fill(1)]).areas(frame.area());
+
+        let header = Row::new(vec!["peer", "user agent", "services", "height", "ping ms", "recv/s", "sent/s"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = self.rows.iter().map(|r| Row::new(vec![
+            r.peer.to_string(),
+            r.agent.clone(),
+            format!("{:x}", r.services),
+            r.height.to_string(),
+            r.latency.map_or_else(|| String::from("-"), |l| l.as_millis().to_string()),
+            format!("{:.1}", r.received_rate),
+            format!("{:.1}", r.sent_rate)
+        ]));
+        let widths = [
+            Constraint::Length(24),
+            Constraint::Fill(1),
+            Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(8)
+        ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(Block::bordered().title(format!(" {} peers, {} ", self.rows.len(), self.slots)));
+        frame.render_widget(table, top);
+
+        // Newest messages at the bottom, as many as fit inside the border
+        let visible = bottom.height.saturating_sub(2) as usize;
+        let lines = self.log.iter().skip(self.log.len().saturating_sub(visible)).map(|l| Line::raw(l.as_str()));
+        let list = List::new(lines).block(Block::bordered().title(" messages (q to quit) "));
+        frame.render_widget(list, bottom);
+    }
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Show the dashboard for a manager in the terminal until q, Esc or Ctrl-C is pressed.
+///
+/// Messages received while the dashboard runs are taken from the manager's
+/// [`recv`](ConnectionManager::recv) channel to be displayed.
+pub fn run(manager: &ConnectionManager) -> io::Result<()> {
+    run_until(manager, &AtomicBool::new(false))
+}
+
+/// Show the dashboard like [`run`] until a key quits it or `stop` is set, such as from a
+/// signal handler
+pub fn run_until(manager: &ConnectionManager, stop: &AtomicBool) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut dashboard = Dashboard::new();
+    let result = (|| {
+        while !stop.load(Ordering::SeqCst) {
+            while let Some((peer, msg)) = manager.try_recv() {
+                dashboard.push(peer, msg);
+            }
+            let now = Instant::now();
+            if dashboard.refreshed.is_none_or(|t| now.duration_since(t) >= REFRESH_INTERVAL) {
+                dashboard.refresh(manager, now);
+            }
+            terminal.draw(|frame| dashboard.draw(frame))?;
+
+            if event::poll(Duration::from_millis(100))? {
+                if let Event::Key(key) = event::read()? {
+                    // The terminal is in raw mode, so Ctrl-C arrives as a key rather than a signal
+                    let interrupt = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if key.kind == KeyEventKind::Press && (interrupt || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)) {
+                        return Ok(())
+                    }
+                }
+            }
+        }
+        Ok(())
+    })();
+    ratatui::restore();
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        msg::{
+            data::MessagePayload,
+            header::{
+                Command,
+                Magic
+            },
+            network::VersionMessage
+        },
+        net::connection::Connection
+    };
+    use ratatui::{
+        backend::TestBackend,
+        Terminal
+    };
+    use std::net::{
+        Ipv4Addr,
+        TcpListener
+    };
+
+    #[test]
+    fn shows_peers_and_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let version = VersionMessage::builder(Address::me()).start_height(812_345).build();
+            let mut conn = Connection::accept(stream, Magic::Regtest, version).unwrap();
+            for nonce in 0..3 {
+                conn.send_payload(MessagePayload::PingPong(nonce), Command::Pong).unwrap();
+            }
+            while conn.recv().is_ok() {}
+        });
+
+        let peer = Peer::new(Ipv4Addr::LOCALHOST, addr.port());
+        let manager = ConnectionManager::new(Magic::Regtest, 1, vec![peer]);
+        manager.start();
+        let mut dashboard = Dashboard::new();
+        let start = Instant::now();
+        for _ in 0..3 {
+            let (from, msg) = manager.recv().unwrap();
+            dashboard.push(from, msg);
+        }
+        dashboard.refresh(&manager, start);
+        assert_eq!(dashboard.rows().len(), 1);
+        assert_eq!((dashboard.rows()[0].peer, dashboard.rows()[0].height), (peer, 812_345));
+
+        let mut terminal = Terminal::new(TestBackend::new(120, 20)).unwrap();
+        terminal.draw(|frame| dashboard.draw(frame)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|c| c.symbol()).collect();
+        assert!(screen.contains(" 1 peers, outbound 1/1, inbound 0/114 "));
+        assert!(screen.contains("812345"));
+        assert_eq!(screen.matches(" pong: nonce=").count(), 3);
+    }
+}
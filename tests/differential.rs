@@ -0,0 +1,296 @@
+// differential.rs
+//
+// Differential round-trip tests against rust-bitcoin.
+//
+// Each test builds the same logical message with this crate and with
+// `bitcoin::network::message`, asserts that both serialize to identical bytes
+// and that this crate decodes rust-bitcoin's bytes back into the same message.
+//
+
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    SocketAddr
+};
+use std::time::Duration;
+
+use btcnetmsg::{
+    Message,
+    MessagePayload,
+    Magic,
+    Command,
+    VersionMessage,
+    ServicesList,
+    Service,
+    Inventory,
+    Address,
+    Encode,
+    Decode
+};
+use btcnetmsg::address::AddrV2;
+use btcnetmsg::msg::{
+    network::{
+        NetAddress,
+        TimestampedNetAddress,
+        NetAddressV2,
+        ProtocolVersion
+    },
+    inventory::BlockdataLocatorInfo,
+    agent::UserAgent
+};
+
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    consensus::encode::serialize,
+    hashes::Hash,
+    network::{
+        address::{
+            Address as BtcAddress,
+            AddrV2 as BtcAddrV2,
+            AddrV2Message
+        },
+        constants::ServiceFlags,
+        message::{
+            NetworkMessage,
+            RawNetworkMessage
+        },
+        message_blockdata::{
+            GetBlocksMessage,
+            GetHeadersMessage,
+            Inventory as BtcInventory
+        },
+        message_network::VersionMessage as BtcVersionMessage
+    },
+    BlockHash,
+    Network,
+    Txid
+};
+
+/// Encode `ours` and `theirs`, assert the bytes match and that `ours` decodes
+/// the rust-bitcoin bytes back into itself.
+fn assert_differential(ours: Message, theirs: NetworkMessage) {
+    let theirs = RawNetworkMessage {
+        magic: ours.header.magic.bytes(),
+        payload: theirs
+    };
+
+    let mut enc = Vec::new();
+    let len = ours.net_encode(&mut enc);
+    let expected = serialize(&theirs);
+
+    assert_eq!(len, enc.len(), "reported length mismatch for {}", theirs.cmd());
+    assert_eq!(enc, expected, "encoding mismatch for {}", theirs.cmd());
+
+    let dec: Message = Decode::net_decode(&expected[..]).expect("Failed to decode");
+    assert_eq!(dec, ours, "decoding mismatch for {}", theirs.cmd());
+}
+
+fn socket(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), port)
+}
+
+fn services() -> (ServicesList, ServiceFlags) {
+    let mut ours = ServicesList::new();
+    ours.add_flag(Service::Network);
+    ours.add_flag(Service::Witness);
+
+    (ours, ServiceFlags::NETWORK | ServiceFlags::WITNESS)
+}
+
+#[test]
+fn empty_payloads() {
+    let cases = vec![
+        (Command::Verack, NetworkMessage::Verack),
+        (Command::SendHeaders, NetworkMessage::SendHeaders),
+        (Command::WTxIdRelay, NetworkMessage::WtxidRelay),
+        (Command::GetAddr, NetworkMessage::GetAddr)
+    ];
+
+    for (cmd, theirs) in cases {
+        assert_differential(Message::new(MessagePayload::EmptyPayload, Magic::Main, cmd), theirs);
+    }
+}
+
+#[test]
+fn network_magics() {
+    let networks = [
+        (Magic::Main, Network::Bitcoin),
+        (Magic::Test, Network::Testnet),
+        (Magic::Signet, Network::Signet),
+        (Magic::Regtest, Network::Regtest)
+    ];
+
+    for (magic, network) in networks {
+        assert_eq!(magic.bytes(), network.magic());
+        assert_differential(Message::new(MessagePayload::EmptyPayload, magic, Command::Verack), NetworkMessage::Verack);
+    }
+}
+
+#[test]
+fn ping_pong() {
+    let nonce = 0x0123_4567_89AB_CDEF;
+    assert_differential(
+        Message::new(MessagePayload::PingPong(nonce), Magic::Main, Command::Ping),
+        NetworkMessage::Ping(nonce)
+    );
+    assert_differential(
+        Message::new(MessagePayload::PingPong(nonce), Magic::Main, Command::Pong),
+        NetworkMessage::Pong(nonce)
+    );
+}
+
+#[test]
+fn version() {
+    let (our_services, their_services) = services();
+    let recv = socket(1, 2, 3, 4, 8333);
+    let from = socket(5, 6, 7, 8, 18333);
+
+    let ours = VersionMessage::new(
+        ProtocolVersion::INVALID_CB_NO_BAN,
+        our_services.clone(),
+        Duration::from_secs(1645835601),
+        NetAddress::new(our_services.clone(), Address::from(recv)),
+        NetAddress::new(ServicesList::default(), Address::from(from)),
+        0xDEAD_BEEF_CAFE_BABE,
+        UserAgent::new("btcnetmsg", "0.1.0").unwrap(),
+        725000,
+        true
+    );
+    let theirs = BtcVersionMessage {
+        version: 70015,
+        services: their_services,
+        timestamp: 1645835601,
+        receiver: BtcAddress::new(&recv, their_services),
+        sender: BtcAddress::new(&from, ServiceFlags::NONE),
+        nonce: 0xDEAD_BEEF_CAFE_BABE,
+        user_agent: String::from("/btcnetmsg:0.1.0/"),
+        start_height: 725000,
+        relay: true
+    };
+
+    assert_differential(
+        Message::new(MessagePayload::Version(ours), Magic::Main, Command::Version),
+        NetworkMessage::Version(theirs)
+    );
+}
+
+#[test]
+fn addr() {
+    let (our_services, their_services) = services();
+    let peers = [socket(1, 1, 1, 1, 8333), socket(93, 184, 216, 34, 8334)];
+
+    let ours = peers
+        .iter()
+        .enumerate()
+        .map(|(i, p)| TimestampedNetAddress::new(
+            Duration::from_secs(1645835601 + i as u64),
+            NetAddress::new(our_services.clone(), Address::from(*p))
+        ))
+        .collect();
+    let theirs = peers
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (1645835601 + i as u32, BtcAddress::new(p, their_services)))
+        .collect();
+
+    assert_differential(
+        Message::new(MessagePayload::AddrList(ours), Magic::Main, Command::Addr),
+        NetworkMessage::Addr(theirs)
+    );
+}
+
+#[test]
+fn addrv2() {
+    let (our_services, their_services) = services();
+    let key = [0x5A; 32];
+
+    let ours = vec![
+        NetAddressV2::new(Duration::from_secs(1645835601), our_services.clone(), AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), 8333),
+        NetAddressV2::new(Duration::from_secs(1645835602), our_services.clone(), AddrV2::TorV3(key), 8333),
+        NetAddressV2::new(Duration::from_secs(1645835603), our_services, AddrV2::Unknown(0x42, vec![1, 2, 3]), 0)
+    ];
+    let theirs = vec![
+        AddrV2Message { time: 1645835601, services: their_services, addr: BtcAddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), port: 8333 },
+        AddrV2Message { time: 1645835602, services: their_services, addr: BtcAddrV2::TorV3(key), port: 8333 },
+        AddrV2Message { time: 1645835603, services: their_services, addr: BtcAddrV2::Unknown(0x42, vec![1, 2, 3]), port: 0 }
+    ];
+
+    assert_differential(
+        Message::new(MessagePayload::AddrV2List(ours), Magic::Main, Command::AddrV2),
+        NetworkMessage::AddrV2(theirs)
+    );
+}
+
+#[test]
+fn inventory() {
+    let tx = [0xAB; 32];
+    let block = [0xCD; 32];
+
+    let ours = vec![
+        Inventory::Tx(Txid::from_inner(tx)),
+        Inventory::Block(BlockHash::from_inner(block)),
+        Inventory::WitnessTx(Txid::from_inner(tx)),
+        Inventory::WitnessBlock(BlockHash::from_inner(block))
+    ];
+    let theirs = vec![
+        BtcInventory::Transaction(Txid::from_inner(tx)),
+        BtcInventory::Block(BlockHash::from_inner(block)),
+        BtcInventory::WitnessTransaction(Txid::from_inner(tx)),
+        BtcInventory::WitnessBlock(BlockHash::from_inner(block))
+    ];
+
+    assert_differential(
+        Message::new(MessagePayload::InvVect(ours.clone()), Magic::Main, Command::Inv),
+        NetworkMessage::Inv(theirs.clone())
+    );
+    assert_differential(
+        Message::new(MessagePayload::InvVect(ours.clone()), Magic::Main, Command::GetData),
+        NetworkMessage::GetData(theirs.clone())
+    );
+    assert_differential(
+        Message::new(MessagePayload::InvVect(ours), Magic::Main, Command::NotFound),
+        NetworkMessage::NotFound(theirs)
+    );
+}
+
+#[test]
+fn block_locators() {
+    let hashes: Vec<BlockHash> = (1..=3).map(|i| BlockHash::from_inner([i; 32])).collect();
+    let stop = BlockHash::from_inner([0; 32]);
+    let locator = BlockdataLocatorInfo::new(70015, hashes.clone(), stop);
+
+    assert_differential(
+        Message::new(MessagePayload::BlockLocator(locator.clone()), Magic::Main, Command::GetHeaders),
+        NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: 70015,
+            locator_hashes: hashes.clone(),
+            stop_hash: stop
+        })
+    );
+    assert_differential(
+        Message::new(MessagePayload::BlockLocator(locator), Magic::Main, Command::GetBlocks),
+        NetworkMessage::GetBlocks(GetBlocksMessage {
+            version: 70015,
+            locator_hashes: hashes,
+            stop_hash: stop
+        })
+    );
+}
+
+#[test]
+fn blockdata() {
+    let genesis = genesis_block(Network::Bitcoin);
+
+    assert_differential(
+        Message::new(MessagePayload::Transction(genesis.txdata[0].clone()), Magic::Main, Command::Tx),
+        NetworkMessage::Tx(genesis.txdata[0].clone())
+    );
+    assert_differential(
+        Message::new(MessagePayload::Headers(vec![genesis.header.into(), genesis.header.into()]), Magic::Main, Command::Headers),
+        NetworkMessage::Headers(vec![genesis.header, genesis.header])
+    );
+    assert_differential(
+        Message::new(MessagePayload::Block(genesis.clone()), Magic::Main, Command::Block),
+        NetworkMessage::Block(genesis)
+    );
+}
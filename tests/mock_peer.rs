@@ -0,0 +1,121 @@
+// mock_peer.rs
+//
+// Integration tests of connections, the message reader and the connection
+// manager against the in-process mock peer of `net::mock`.
+//
+
+use std::{
+    collections::HashMap,
+    net::{
+        Ipv4Addr,
+        TcpStream
+    },
+    time::Duration
+};
+
+use btcnetmsg::{
+    Message,
+    MessagePayload,
+    Magic,
+    Command,
+    VersionMessage,
+    ServicesList,
+    Address,
+    Encode
+};
+use btcnetmsg::address::AddrV2;
+use btcnetmsg::msg::network::{
+    NetAddressV2,
+    ProtocolVersion
+};
+use btcnetmsg::net::{
+    connection::Connection,
+    manager::ConnectionManager,
+    mock::MockPeer,
+    reader::MessageReader,
+    stream::StreamOptions
+};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[test]
+fn handshakes_and_answers_requests() {
+    let known = NetAddressV2::new(Duration::from_secs(1_700_000_000), ServicesList::default(), AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), 8333);
+    let agent = btcnetmsg::msg::agent::UserAgent::parse("/Satoshi:27.0.0/").unwrap();
+    let mock = MockPeer::new(Magic::Regtest)
+        .with_version(VersionMessage::builder(Address::me()).version(ProtocolVersion::RELAY).user_agent(agent.clone()).build())
+        .with_addresses(vec![known])
+        .spawn()
+        .unwrap();
+
+    let mut conn = Connection::connect(mock.peer(), Magic::Regtest).unwrap();
+    assert_eq!(conn.version(), ProtocolVersion::RELAY);
+    assert_eq!(conn.peer_version().agent, agent);
+    assert!(conn.violations().is_empty());
+
+    conn.send_payload(MessagePayload::PingPong(7), Command::Ping).unwrap();
+    assert_eq!(conn.recv().unwrap().payload, MessagePayload::PingPong(7));
+    conn.send_payload(MessagePayload::EmptyPayload, Command::GetAddr).unwrap();
+    match conn.recv().unwrap().payload {
+        MessagePayload::AddrList(addrs) => assert_eq!(addrs.len(), 1),
+        other => panic!("Expected addr, got {:?}", other)
+    }
+
+    let commands: Vec<Command> = mock.received().into_iter().map(|m| m.header.command).collect();
+    assert_eq!(commands, vec![Command::Version, Command::Verack, Command::Ping, Command::GetAddr]);
+    assert_eq!(mock.connections(), 1);
+}
+
+#[test]
+fn reader_sees_scripted_messages() {
+    let mock = MockPeer::new(Magic::Regtest)
+        .with_message(MessagePayload::EmptyPayload, Command::SendHeaders)
+        .with_message(MessagePayload::PingPong(3), Command::Ping)
+        .spawn()
+        .unwrap();
+
+    // Run the client side of the handshake by hand
+    let mut reader = MessageReader::new(TcpStream::connect(mock.addr()).unwrap(), Magic::Regtest);
+    let version = VersionMessage::builder(Address::from(mock.addr())).nonce(1).build();
+    for msg in [
+        Message::new(MessagePayload::Version(version), Magic::Regtest, Command::Version),
+        Message::new(MessagePayload::EmptyPayload, Magic::Regtest, Command::Verack)
+    ] {
+        msg.net_encode(reader.get_mut());
+    }
+
+    let commands: Vec<Command> = (0..4).map(|_| reader.read_message().unwrap().header.command).collect();
+    assert_eq!(commands, vec![Command::Version, Command::Verack, Command::SendHeaders, Command::Ping]);
+    assert!(mock.wait_for(Command::Verack, WAIT).is_some());
+}
+
+#[test]
+fn handshake_times_out_without_verack() {
+    let mock = MockPeer::new(Magic::Regtest).without_verack().spawn().unwrap();
+    let options = StreamOptions { read_timeout: Some(Duration::from_millis(200)), ..StreamOptions::default() };
+    assert!(Connection::connect_with(mock.peer(), Magic::Regtest, &options).is_err());
+    assert!(mock.wait_for(Command::Version, WAIT).is_some());
+}
+
+#[test]
+fn manager_connects_to_mock_peers() {
+    let (a, b) = (
+        MockPeer::new(Magic::Regtest).with_message(MessagePayload::PingPong(1), Command::Ping).spawn().unwrap(),
+        MockPeer::new(Magic::Regtest).with_message(MessagePayload::PingPong(2), Command::Ping).spawn().unwrap()
+    );
+
+    let manager = ConnectionManager::new(Magic::Regtest, 2, vec![a.peer(), b.peer()]);
+    manager.start();
+    let mut pings = HashMap::new();
+    for _ in 0..2 {
+        let (peer, msg) = manager.recv_timeout(WAIT).unwrap();
+        pings.insert(peer, msg.payload);
+    }
+    assert_eq!(pings[&a.peer()], MessagePayload::PingPong(1));
+    assert_eq!(pings[&b.peer()], MessagePayload::PingPong(2));
+
+    // The manager answers the scripted pings
+    assert_eq!(a.wait_for(Command::Pong, WAIT).unwrap().payload, MessagePayload::PingPong(1));
+    assert_eq!(b.wait_for(Command::Pong, WAIT).unwrap().payload, MessagePayload::PingPong(2));
+    manager.shutdown();
+}